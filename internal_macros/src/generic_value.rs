@@ -0,0 +1,143 @@
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::parse::{Error, Result};
+use syn::{parse_quote, Data, DeriveInput, Fields, GenericParam, Index, Lifetime, LifetimeParam, Member};
+
+/// The fields of a struct deriving `ToGenericValue` or `FromGenericValue`, in declaration order.
+struct StructFields {
+    members: Vec<Member>,
+    types: Vec<syn::Type>,
+    named: bool,
+}
+
+impl StructFields {
+    fn new(input: &DeriveInput) -> Result<Self> {
+        let data = match &input.data {
+            Data::Struct(data) => data,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "GenericValue conversions can only be derived for structs",
+                ))
+            },
+        };
+
+        let (members, types) = match &data.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .map(|field| (Member::Named(field.ident.clone().unwrap()), field.ty.clone()))
+                .unzip(),
+            Fields::Unnamed(fields) => fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(idx, field)| (Member::Unnamed(Index::from(idx)), field.ty.clone()))
+                .unzip(),
+            Fields::Unit => (Vec::new(), Vec::new()),
+        };
+
+        Ok(StructFields {
+            members,
+            types,
+            named: matches!(data.fields, Fields::Named(_)),
+        })
+    }
+}
+
+/// Adds the `'__ctx` lifetime of the impl and a `trait_path<'__ctx>` bound on every field type.
+fn impl_generics(input: &DeriveInput, fields: &StructFields, trait_path: &TokenStream) -> syn::Generics {
+    let lifetime = Lifetime::new("'__ctx", Span::call_site());
+    let mut generics = input.generics.clone();
+
+    generics
+        .params
+        .insert(0, GenericParam::Lifetime(LifetimeParam::new(lifetime)));
+
+    let where_clause = generics.make_where_clause();
+
+    for ty in &fields.types {
+        where_clause.predicates.push(parse_quote!(#ty: #trait_path<'__ctx>));
+    }
+
+    generics
+}
+
+pub fn derive_to_generic_value(input: DeriveInput) -> Result<TokenStream> {
+    let fields = StructFields::new(&input)?;
+    let trait_path = quote!(::inkwell::values::ToGenericValue);
+    let generics = impl_generics(&input, &fields, &trait_path);
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let name = &input.ident;
+    let len = fields.members.len() as u64;
+    let members = &fields.members;
+    let indices = (0..fields.members.len()).map(Index::from);
+
+    Ok(quote! {
+        impl #impl_generics #trait_path<'__ctx> for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn to_generic_value(
+                &self,
+                ty: ::inkwell::types::BasicTypeEnum<'__ctx>,
+            ) -> ::std::result::Result<::inkwell::values::GenericValue<'__ctx>, ::inkwell::values::GenericValueError> {
+                if !ty.is_struct_type() {
+                    return ::std::result::Result::Err(::inkwell::values::GenericValueError::TypeMismatch {
+                        rust_type: ::std::any::type_name::<Self>(),
+                        llvm_type: ty.print_to_string().to_string(),
+                    });
+                }
+
+                let field_types = ::inkwell::values::__aggregate_field_types::<Self>(ty, #len)?;
+                let fields = ::std::vec![
+                    #(#trait_path::to_generic_value(&self.#members, field_types[#indices])?),*
+                ];
+
+                ::inkwell::values::__new_aggregate(ty, fields)
+            }
+        }
+    })
+}
+
+pub fn derive_from_generic_value(input: DeriveInput) -> Result<TokenStream> {
+    let fields = StructFields::new(&input)?;
+    let trait_path = quote!(::inkwell::values::FromGenericValue);
+    let generics = impl_generics(&input, &fields, &trait_path);
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let name = &input.ident;
+    let len = fields.members.len() as u64;
+    let values = (0..fields.members.len())
+        .map(Index::from)
+        .map(|idx| quote!(#trait_path::from_generic_value(&fields[#idx].0, fields[#idx].1)?));
+    let body = if fields.named {
+        let members = &fields.members;
+
+        quote!(#name { #(#members: #values),* })
+    } else if fields.members.is_empty() {
+        quote!(#name)
+    } else {
+        quote!(#name(#(#values),*))
+    };
+
+    Ok(quote! {
+        impl #impl_generics #trait_path<'__ctx> for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_generic_value(
+                value: &::inkwell::values::GenericValueRef<'_>,
+                ty: ::inkwell::types::BasicTypeEnum<'__ctx>,
+            ) -> ::std::result::Result<Self, ::inkwell::values::GenericValueError> {
+                if !ty.is_struct_type() {
+                    return ::std::result::Result::Err(::inkwell::values::GenericValueError::TypeMismatch {
+                        rust_type: ::std::any::type_name::<Self>(),
+                        llvm_type: ty.print_to_string().to_string(),
+                    });
+                }
+
+                let fields = ::inkwell::values::__aggregate_fields::<Self>(value, ty, #len)?;
+
+                ::std::result::Result::Ok(#body)
+            }
+        }
+    })
+}
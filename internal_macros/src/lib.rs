@@ -3,10 +3,11 @@
 //! Here be dragons 🐉
 
 use proc_macro::TokenStream;
-use syn::parse_macro_input;
+use syn::{parse_macro_input, DeriveInput};
 
 mod cfg;
 mod r#enum;
+mod generic_value;
 
 /// This macro can be used to specify version constraints for an enum/struct/union or
 /// other item which can be decorated with an attribute.
@@ -90,3 +91,36 @@ pub fn llvm_enum(args: TokenStream, input: TokenStream) -> TokenStream {
     let llvm_enum_type = parse_macro_input!(input as r#enum::LLVMEnumType);
     r#enum::llvm_enum(llvm_ty, llvm_enum_type).into()
 }
+
+/// Derives `inkwell::values::ToGenericValue` for a struct, converting each field in
+/// declaration order into the matching field of an LLVM struct type.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[derive(ToGenericValue, FromGenericValue)]
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// let point_type = context.struct_type(&[i32_type.into(), i32_type.into()], false);
+/// let value = Point { x: 1, y: 2 }.to_generic_value(point_type.into())?;
+/// ```
+#[proc_macro_derive(ToGenericValue)]
+pub fn derive_to_generic_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    generic_value::derive_to_generic_value(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `inkwell::values::FromGenericValue` for a struct, reading each field in
+/// declaration order from the matching field of an LLVM struct type.
+#[proc_macro_derive(FromGenericValue)]
+pub fn derive_from_generic_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    generic_value::derive_from_generic_value(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
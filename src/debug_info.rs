@@ -107,7 +107,7 @@ use crate::module::Module;
 use crate::values::{AsValueRef, BasicValueEnum, InstructionValue, MetadataValue, PointerValue};
 use crate::AddressSpace;
 
-use llvm_sys::core::LLVMMetadataAsValue;
+use llvm_sys::core::{LLVMGetMDNodeNumOperands, LLVMGetMDNodeOperands, LLVMGetMDString, LLVMMetadataAsValue};
 #[llvm_versions(8..)]
 use llvm_sys::debuginfo::LLVMDIBuilderCreateTypedef;
 pub use llvm_sys::debuginfo::LLVMDWARFTypeEncoding;
@@ -129,6 +129,10 @@ use llvm_sys::debuginfo::{
 };
 #[llvm_versions(8..)]
 use llvm_sys::debuginfo::{LLVMDIBuilderCreateConstantValueExpression, LLVMDIBuilderCreateGlobalVariableExpression};
+#[llvm_versions(9..)]
+use llvm_sys::debuginfo::{
+    LLVMDIFileGetDirectory, LLVMDIFileGetFilename, LLVMDILocationGetInlinedAt, LLVMDIScopeGetFile,
+};
 use llvm_sys::prelude::{LLVMDIBuilderRef, LLVMMetadataRef};
 use std::convert::TryInto;
 use std::marker::PhantomData;
@@ -158,6 +162,21 @@ pub struct DIScope<'ctx> {
 }
 
 impl<'ctx> DIScope<'ctx> {
+    /// Gets the file this scope belongs to, if any.
+    #[llvm_versions(9..)]
+    pub fn get_file(&self) -> Option<DIFile<'ctx>> {
+        let metadata_ref = unsafe { LLVMDIScopeGetFile(self.metadata_ref) };
+
+        if metadata_ref.is_null() {
+            return None;
+        }
+
+        Some(DIFile {
+            metadata_ref,
+            _marker: PhantomData,
+        })
+    }
+
     /// Acquires the underlying raw pointer belonging to this `DIScope` type.
     pub fn as_mut_ptr(&self) -> LLVMMetadataRef {
         self.metadata_ref
@@ -1061,12 +1080,41 @@ impl<'ctx> AsDIScope<'ctx> for DIFile<'ctx> {
 }
 
 impl<'ctx> DIFile<'ctx> {
+    /// Gets the name of this file. Metadata strings aren't NUL terminated, and needn't be valid UTF-8.
+    #[llvm_versions(9..)]
+    pub fn get_filename(&self) -> &'ctx [u8] {
+        let mut len = 0;
+        let ptr = unsafe { LLVMDIFileGetFilename(self.metadata_ref, &mut len) };
+
+        unsafe { metadata_string(ptr, len) }
+    }
+
+    /// Gets the directory this file is in. Metadata strings aren't NUL terminated, and needn't be
+    /// valid UTF-8.
+    #[llvm_versions(9..)]
+    pub fn get_directory(&self) -> &'ctx [u8] {
+        let mut len = 0;
+        let ptr = unsafe { LLVMDIFileGetDirectory(self.metadata_ref, &mut len) };
+
+        unsafe { metadata_string(ptr, len) }
+    }
+
     /// Acquires the underlying raw pointer belonging to this `DIFile` type.
     pub fn as_mut_ptr(&self) -> LLVMMetadataRef {
         self.metadata_ref
     }
 }
 
+/// Borrows the `len` bytes of a metadata string at `ptr`, which LLVM may leave null if it's empty.
+#[llvm_versions(9..)]
+unsafe fn metadata_string<'a>(ptr: *const libc::c_char, len: libc::c_uint) -> &'a [u8] {
+    if ptr.is_null() {
+        return &[];
+    }
+
+    std::slice::from_raw_parts(ptr as *const u8, len as usize)
+}
+
 /// Compilation unit scope for debug info
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DICompileUnit<'ctx> {
@@ -1135,6 +1183,37 @@ impl<'ctx> AsDIScope<'ctx> for DISubprogram<'ctx> {
 }
 
 impl<'ctx> DISubprogram<'ctx> {
+    /// Gets the name of the function in the source, as opposed to the possibly mangled name of the
+    /// `FunctionValue` this describes. Metadata strings aren't NUL terminated, and needn't be valid UTF-8.
+    pub fn get_name(&self, context: impl AsContextRef<'ctx>) -> Option<&'ctx [u8]> {
+        unsafe {
+            let node = LLVMMetadataAsValue(context.as_ctx_ref(), self.metadata_ref);
+            let count = LLVMGetMDNodeNumOperands(node) as usize;
+
+            // The name comes after the file and the scope
+            if count < 3 {
+                return None;
+            }
+
+            let mut operands = vec![std::ptr::null_mut(); count];
+
+            LLVMGetMDNodeOperands(node, operands.as_mut_ptr());
+
+            if operands[2].is_null() {
+                return None;
+            }
+
+            let mut len = 0;
+            let ptr = LLVMGetMDString(operands[2], &mut len);
+
+            if ptr.is_null() {
+                return None;
+            }
+
+            Some(std::slice::from_raw_parts(ptr as *const u8, len as usize))
+        }
+    }
+
     /// Acquires the underlying raw pointer belonging to this `DISubprogram` type.
     pub fn as_mut_ptr(&self) -> LLVMMetadataRef {
         self.metadata_ref
@@ -1326,6 +1405,21 @@ impl<'ctx> DILocation<'ctx> {
         }
     }
 
+    /// Gets the location of the call this location was inlined into, if it was inlined.
+    #[llvm_versions(9..)]
+    pub fn get_inlined_at(&self) -> Option<DILocation<'ctx>> {
+        let metadata_ref = unsafe { LLVMDILocationGetInlinedAt(self.metadata_ref) };
+
+        if metadata_ref.is_null() {
+            return None;
+        }
+
+        Some(DILocation {
+            metadata_ref,
+            _marker: PhantomData,
+        })
+    }
+
     /// Acquires the underlying raw pointer belonging to this `DILocation` type.
     pub fn as_mut_ptr(&self) -> LLVMMetadataRef {
         self.metadata_ref
@@ -10,6 +10,7 @@ use llvm_sys::execution_engine::{
     LLVMExecutionEngineGetConstructorAtIndex, LLVMExecutionEngineGetConstructorCount,
     LLVMExecutionEngineGetDestructorAtIndex, LLVMExecutionEngineGetDestructorCount,
     LLVMExecutionEngineGetThreadExitValue, LLVMExecutionEngineHasThread,
+    LLVMExecutionEngineRegisterJITEventListener,
     LLVMExecutionEngineSetMiriCallByNameHook, LLVMExecutionEngineSetMiriCallByPointerHook,
     LLVMExecutionEngineSetMiriFree, LLVMExecutionEngineSetMiriGetElementPointerHook,
     LLVMExecutionEngineSetMiriIntToPtr, LLVMExecutionEngineSetMiriInterpCxWrapper, LLVMExecutionEngineSetMiriLoadHook,
@@ -21,20 +22,44 @@ use llvm_sys::execution_engine::{
 pub use llvm_sys::miri::*;
 
 use crate::context::Context;
+use crate::jit_event_listener::JITEventListener;
+use crate::miri::{
+    global_name, target_name, unused_thread_id, BreakpointId, BreakpointLocation, FunctionPointerTarget, GlobalImage,
+    GlobalLayoutError, InterpreterLimit, InterpreterLimits, InterpreterSnapshot, InterpreterThread, MiriHookPanic,
+    MiriHookSlot, MiriHooks, RelocationTarget, StepResult, WatchKind,
+};
 use crate::module::Module;
+#[llvm_versions(12..)]
+use crate::orc::LLJIT;
 use crate::support::{to_c_str, LLVMString};
+use crate::symbol_map::{SymbolMap, SymbolMapError};
 use crate::targets::TargetData;
-use crate::values::{AnyValue, AsValueRef, FunctionValue, GenericValue, GenericValueRef};
+use crate::types::FunctionType;
+use crate::values::{
+    AnyValue, AsValueRef, FromGenericValue, FunctionValue, GenericValue, GenericValueError, GenericValueRef,
+    GlobalValue,
+};
+
+use once_cell::sync::Lazy;
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::mem::{forget, size_of, transmute_copy, MaybeUninit};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Mutex;
 
 static EE_INNER_PANIC: &str = "ExecutionEngineInner should exist until Drop";
 
+/// Held by `MainInvocation::run` while it changes the working directory and standard streams of the
+/// process, so that concurrent runs don't interleave their changes.
+static PROCESS_STATE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum FunctionLookupError {
     JITNotEnabled,
@@ -93,6 +118,376 @@ impl Display for RemoveModuleError {
     }
 }
 
+/// An error which stopped the interpreter from executing a function or thread.
+#[derive(Debug)]
+pub enum InterpreterError {
+    /// One of the installed `MiriHooks` panicked. The thread which was executing has been terminated.
+    HookPanicked(MiriHookPanic),
+    /// A thread with this id already exists.
+    ThreadIdInUse(u64),
+    /// A return value was provided to the thread with this id while it wasn't blocked on a call.
+    ThreadNotBlocked(u64),
+    /// A call through one of the engine's function pointers had type `found`, but the function
+    /// it points to has type `expected`. The thread which made it has been terminated.
+    SignatureMismatch { expected: String, found: String },
+    /// An argument of a call through one of the engine's function pointers couldn't be copied
+    /// for the callee, or a value couldn't be copied in or out of `ExecutionEngine::interpret_function`.
+    /// The thread which was executing has been terminated.
+    InvalidArgument(GenericValueError),
+    /// One of the `InterpreterLimits` set on the engine was exceeded. The thread which was
+    /// executing has been terminated.
+    LimitExceeded(InterpreterLimit),
+    /// One of the installed `MiriHooks` returned `false`, rejecting the operation it was handed, such as
+    /// an invalid `load` or `store`. The thread which was executing has been terminated.
+    HookRejected { hook: &'static str },
+    /// A conditional branch or switch, printed as `instruction`, depended on a value computed from
+    /// `undef` or `poison`. This is only caught once `MiriHooks` are installed, and for conditions
+    /// computed from `undef` or `poison` within the function itself rather than loaded from memory
+    /// or returned by a call. The thread which was executing has been terminated.
+    UndefinedBranch { instruction: String },
+}
+
+impl Error for InterpreterError {}
+
+impl Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpreterError::HookPanicked(panic) => write!(f, "InterpreterError({})", panic),
+            InterpreterError::ThreadIdInUse(id) => write!(f, "InterpreterError(Thread {} already exists)", id),
+            InterpreterError::ThreadNotBlocked(id) => {
+                write!(f, "InterpreterError(Thread {} isn't blocked on a call)", id)
+            },
+            InterpreterError::SignatureMismatch { expected, found } => write!(
+                f,
+                "InterpreterError(Function of type `{}` called as `{}`)",
+                expected, found
+            ),
+            InterpreterError::InvalidArgument(err) => write!(f, "InterpreterError({})", err),
+            InterpreterError::LimitExceeded(limit) => write!(f, "InterpreterError(Exceeded the {} limit)", limit),
+            InterpreterError::HookRejected { hook } => {
+                write!(f, "InterpreterError(Miri hook `{}` rejected the operation)", hook)
+            },
+            InterpreterError::UndefinedBranch { instruction } => {
+                write!(
+                    f,
+                    "InterpreterError(Branch on an undefined value: `{}`)",
+                    instruction.trim()
+                )
+            },
+        }
+    }
+}
+
+/// The `argv`, `envp`, working directory and standard streams to run a `main` function with, for
+/// `MainInvocation::run`. The working directory and standard streams are those of the process,
+/// changed for as long as the function runs, rather than ones isolated to the invocation.
+///
+/// Unlike a process, the function doesn't inherit the environment: only the variables set with
+/// `env` are passed in `envp`. Capturing and feeding the standard streams redirects the file
+/// descriptors of the whole process while the function runs, so that output of native code,
+/// whether JIT compiled or called by the interpreter, is captured as well. This is only supported
+/// on Unix.
+///
+/// The working directory and the standard streams are process-global: while the function runs,
+/// every thread of the process sees the changed directory and its output is captured along with the
+/// function's. Concurrent calls to `run` are serialized by a global lock, but code outside of
+/// inkwell isn't: other threads which read the working directory or use the standard streams in
+/// the meantime still see the changes.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::context::Context;
+/// use inkwell::execution_engine::MainInvocation;
+///
+/// let context = Context::create();
+/// let module = context.create_module("main");
+/// let main = module.add_function("main", context.i32_type().fn_type(&[], false), None);
+/// let execution_engine = module.create_interpreter_execution_engine().unwrap();
+/// let output = unsafe {
+///     MainInvocation::new("program")
+///         .arg("--verbose")
+///         .env("HOME", "/home/user")
+///         .stdin("input")
+///         .capture_stdout()
+///         .run(&execution_engine, main)
+/// }
+/// .unwrap();
+///
+/// println!("exited with {}: {}", output.exit_code, String::from_utf8_lossy(&output.stdout));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MainInvocation {
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    current_dir: Option<PathBuf>,
+    stdin: Option<Vec<u8>>,
+    capture_stdout: bool,
+    capture_stderr: bool,
+}
+
+impl MainInvocation {
+    /// Creates an invocation whose `argv[0]` is `program`.
+    pub fn new(program: &str) -> Self {
+        MainInvocation {
+            args: vec![program.to_owned()],
+            ..MainInvocation::default()
+        }
+    }
+
+    /// Appends an argument to `argv`.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    /// Appends arguments to `argv`.
+    pub fn args<'a>(mut self, args: impl IntoIterator<Item = &'a str>) -> Self {
+        self.args.extend(args.into_iter().map(str::to_owned));
+        self
+    }
+
+    /// Sets the environment variable `name` to `value`, replacing any previous value.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.retain(|(existing, _)| existing != name);
+        self.env.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Runs the function in `dir`, which also becomes the value of `PWD` unless it's set with `env`.
+    ///
+    /// This isn't a virtual working directory: the working directory of the whole process is changed
+    /// while the function runs, and changed back afterwards.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Feeds `input` to the function's standard input, which otherwise is that of the process.
+    pub fn stdin(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    /// Captures everything written to standard output into `MainOutput::stdout`.
+    pub fn capture_stdout(mut self) -> Self {
+        self.capture_stdout = true;
+        self
+    }
+
+    /// Captures everything written to standard error into `MainOutput::stderr`.
+    pub fn capture_stderr(mut self) -> Self {
+        self.capture_stderr = true;
+        self
+    }
+
+    /// Runs `function` as `main` on `execution_engine`.
+    ///
+    /// Returns an error if the working directory can't be changed or the standard streams can't be
+    /// redirected, including on platforms other than Unix. Changing the working directory back is
+    /// best-effort: if that fails, the output is returned anyway.
+    pub unsafe fn run<'ctx>(
+        &self,
+        execution_engine: &ExecutionEngine<'ctx>,
+        function: FunctionValue<'ctx>,
+    ) -> io::Result<MainOutput> {
+        let mut env: Vec<String> = self
+            .env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+
+        if let Some(dir) = &self.current_dir {
+            if !self.env.iter().any(|(name, _)| name == "PWD") {
+                env.push(format!("PWD={}", dir.display()));
+            }
+        }
+
+        let args: Vec<&str> = self.args.iter().map(String::as_str).collect();
+        let env: Vec<&str> = env.iter().map(String::as_str).collect();
+        // A run that panicked has already undone its changes, so a poisoned lock is still usable
+        let _guard = PROCESS_STATE.lock().unwrap_or_else(|err| err.into_inner());
+        let previous_dir = match &self.current_dir {
+            Some(dir) => {
+                let previous_dir = std::env::current_dir()?;
+
+                std::env::set_current_dir(dir)?;
+
+                Some(previous_dir)
+            },
+            None => None,
+        };
+
+        // The redirections are undone when dropped, even if the function panics
+        let redirections = self.redirect();
+        let result = redirections.and_then(|(stdin, stdout, stderr)| {
+            let exit_code = execution_engine.run_function_as_main_with_env(function, &args, &env);
+
+            drop(stdin);
+
+            Ok(MainOutput {
+                exit_code,
+                stdout: stdout.map_or(Ok(Vec::new()), Redirection::finish)?,
+                stderr: stderr.map_or(Ok(Vec::new()), Redirection::finish)?,
+            })
+        });
+
+        if let Some(previous_dir) = previous_dir {
+            // The function has already run, so its output is more useful than this error
+            let _ = std::env::set_current_dir(previous_dir);
+        }
+
+        result
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn redirect(&self) -> io::Result<(Option<Redirection>, Option<Redirection>, Option<Redirection>)> {
+        let stdin = match &self.stdin {
+            Some(input) => Some(Redirection::new(0, input)?),
+            None => None,
+        };
+        let stdout = if self.capture_stdout {
+            Some(Redirection::new(1, &[])?)
+        } else {
+            None
+        };
+        let stderr = if self.capture_stderr {
+            Some(Redirection::new(2, &[])?)
+        } else {
+            None
+        };
+
+        Ok((stdin, stdout, stderr))
+    }
+}
+
+/// What a function run with `MainInvocation::run` returned and wrote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MainOutput {
+    /// What the function returned, which is what a process would exit with.
+    pub exit_code: c_int,
+    /// Everything written to standard output, if it was captured.
+    pub stdout: Vec<u8>,
+    /// Everything written to standard error, if it was captured.
+    pub stderr: Vec<u8>,
+}
+
+/// A standard stream of the process, temporarily pointed at a temporary file.
+#[derive(Debug)]
+struct Redirection {
+    fd: c_int,
+    /// A duplicate of the stream as it was before being redirected.
+    saved_fd: c_int,
+    file: File,
+    restored: bool,
+}
+
+impl Redirection {
+    /// Points `fd` at a new temporary file, which starts out containing `input`.
+    #[cfg(unix)]
+    fn new(fd: c_int, input: &[u8]) -> io::Result<Self> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let mut file = unsafe {
+            let tmp = libc::tmpfile();
+
+            if tmp.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            let tmp_fd = libc::dup(libc::fileno(tmp));
+
+            libc::fclose(tmp);
+
+            if tmp_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            File::from_raw_fd(tmp_fd)
+        };
+
+        file.write_all(input)?;
+        file.seek(SeekFrom::Start(0))?;
+        flush_stdio();
+
+        unsafe {
+            let saved_fd = libc::dup(fd);
+
+            if saved_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::dup2(file.as_raw_fd(), fd) < 0 {
+                let err = io::Error::last_os_error();
+
+                libc::close(saved_fd);
+
+                return Err(err);
+            }
+
+            Ok(Redirection {
+                fd,
+                saved_fd,
+                file,
+                restored: false,
+            })
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_fd: c_int, _input: &[u8]) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "redirecting standard streams is only supported on Unix",
+        ))
+    }
+
+    /// Points the stream back where it was, and returns everything written to it meanwhile.
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+
+        self.restore();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut output)?;
+
+        Ok(output)
+    }
+
+    fn restore(&mut self) {
+        if self.restored {
+            return;
+        }
+
+        flush_stdio();
+
+        #[cfg(unix)]
+        unsafe {
+            libc::dup2(self.saved_fd, self.fd);
+            libc::close(self.saved_fd);
+        }
+
+        self.restored = true;
+    }
+}
+
+impl Drop for Redirection {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Flushes the buffers of both C's and Rust's standard streams, so that nothing written before a
+/// redirection ends up on the other side of it.
+fn flush_stdio() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+
+    #[cfg(unix)]
+    unsafe {
+        libc::fflush(std::ptr::null_mut());
+    }
+}
+
 /// A reference-counted wrapper around LLVM's execution engine.
 ///
 /// # Note
@@ -106,6 +501,7 @@ pub struct ExecutionEngine<'ctx> {
     execution_engine: Option<ExecEngineInner<'ctx>>,
     target_data: Option<TargetData>,
     jit_mode: bool,
+    miri_hooks: Rc<MiriHookSlot<'ctx>>,
 }
 
 impl<'ctx> ExecutionEngine<'ctx> {
@@ -119,6 +515,7 @@ impl<'ctx> ExecutionEngine<'ctx> {
             execution_engine: Some(ExecEngineInner(execution_engine, PhantomData)),
             target_data: Some(TargetData::new(target_data)),
             jit_mode,
+            miri_hooks: Rc::default(),
         }
     }
 
@@ -131,6 +528,10 @@ impl<'ctx> ExecutionEngine<'ctx> {
         &self.execution_engine.as_ref().expect(EE_INNER_PANIC).0
     }
 
+    pub(crate) fn miri_hooks(&self) -> &MiriHookSlot<'ctx> {
+        &self.miri_hooks
+    }
+
     #[inline]
     pub(crate) fn execution_engine_inner(&self) -> LLVMExecutionEngineRef {
         **self.execution_engine_rc()
@@ -195,6 +596,48 @@ impl<'ctx> ExecutionEngine<'ctx> {
         unsafe { LLVMAddGlobalMapping(self.execution_engine_inner(), value.as_value_ref(), addr as *mut _) }
     }
 
+    /// Maps every symbol `module` declares and `symbols` defines to its host address, after
+    /// checking that it's declared as what it is.
+    ///
+    /// This is how MCJIT resolves declarations to host code. The interpreter calls functions by
+    /// name through its `MiriHooks` instead, so for it `symbols` should be added to a
+    /// `ForeignFunctionRegistry`.
+    pub fn add_symbol_map(&self, module: &Module<'ctx>, symbols: &SymbolMap) -> Result<(), SymbolMapError> {
+        for (value, address) in symbols.resolve(module)? {
+            self.add_global_mapping(&value, address);
+        }
+
+        Ok(())
+    }
+
+    /// Tells `listener` about every object this `ExecutionEngine` emits from now on, such as GDB
+    /// or perf. Only MCJIT emits objects, so an interpreter never tells it anything.
+    ///
+    /// MCJIT already registers its objects with GDB, so the GDB registration listener needn't be
+    /// added to it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::context::Context;
+    /// use inkwell::jit_event_listener::JITEventListener;
+    /// use inkwell::targets::{InitializationConfig, Target};
+    /// use inkwell::OptimizationLevel;
+    ///
+    /// Target::initialize_native(&InitializationConfig::default()).unwrap();
+    ///
+    /// let context = Context::create();
+    /// let module = context.create_module("test");
+    /// let ee = module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
+    ///
+    /// if let Some(listener) = JITEventListener::create_perf_listener() {
+    ///     ee.register_event_listener(listener);
+    /// }
+    /// ```
+    pub fn register_event_listener(&self, listener: JITEventListener) {
+        unsafe { LLVMExecutionEngineRegisterJITEventListener(self.execution_engine_inner(), listener.as_mut_ptr()) }
+    }
+
     /// Adds a module to an `ExecutionEngine`.
     ///
     /// The method will be `Ok(())` if the module does not belong to an `ExecutionEngine` already and `Err(())` otherwise.
@@ -335,7 +778,7 @@ impl<'ctx> ExecutionEngine<'ctx> {
         let execution_engine = self.execution_engine.as_ref().expect(EE_INNER_PANIC);
 
         Ok(JitFunction {
-            _execution_engine: execution_engine.clone(),
+            _owner: JitFunctionOwner::ExecutionEngine(execution_engine.clone()),
             inner: transmute_copy(&address),
         })
     }
@@ -396,11 +839,29 @@ impl<'ctx> ExecutionEngine<'ctx> {
 
     // TODOC: Marked as unsafe because input function could very well do something unsafe. It's up to the caller
     // to ensure that doesn't happen by defining their function correctly.
+    /// Runs `function` to completion.
+    ///
+    /// Once `MiriHooks` are installed or `InterpreterLimits` set, this goes through `interpret_function`,
+    /// so that the function is never left running after its hooks failed. If it's stopped that way,
+    /// e.g. because a hook rejected a memory access or a limit was exceeded, a void `GenericValue` is
+    /// returned instead of its return value. Use `interpret_function` to find out why it stopped.
+    ///
+    /// # Panics
+    ///
+    /// Resumes the panic of one of the installed `MiriHooks`.
     pub unsafe fn run_function(
         &self,
         function: FunctionValue<'ctx>,
         args: &[&GenericValue<'ctx>],
     ) -> GenericValue<'ctx> {
+        if self.miri_hooks.is_installed() || self.get_interpreter_limits().is_limited() {
+            return match self.interpret_function(function, args) {
+                Ok(value) => value,
+                Err(InterpreterError::HookPanicked(panic)) => panic.resume_unwind(),
+                Err(_) => GenericValue::new_void(),
+            };
+        }
+
         let mut args: Vec<LLVMGenericValueRef> = args.iter().map(|val| val.generic_value_ref.generic_value).collect();
 
         let value = LLVMRunFunction(
@@ -413,21 +874,71 @@ impl<'ctx> ExecutionEngine<'ctx> {
         GenericValue::new(value)
     }
 
+    /// Runs `function` to completion on an `InterpreterThread` of its own, completing the calls it
+    /// hands to the installed `MiriHooks` with `MiriHooks::take_return_value`.
+    ///
+    /// The thread is terminated as soon as one of the hooks panics or fails, or the function exceeds
+    /// one of the `InterpreterLimits` set on the engine, and the reason is returned as an error.
+    pub unsafe fn interpret_function(
+        &self,
+        function: FunctionValue<'ctx>,
+        args: &[&GenericValue<'ctx>],
+    ) -> Result<GenericValue<'ctx>, InterpreterError> {
+        let fn_type = function.get_type();
+        let args = fn_type
+            .get_param_types()
+            .into_iter()
+            .zip(args)
+            .map(|(ty, arg)| GenericValue::from_generic_value(arg.as_ref(), ty))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(InterpreterError::InvalidArgument)?;
+        let mut thread = InterpreterThread::spawn(self, unused_thread_id(self), function, &args)?;
+
+        loop {
+            if let StepResult::Exited(value) = thread.step()? {
+                return Ok(value);
+            }
+
+            if thread.is_blocked() {
+                let return_value = self.miri_hooks.with_hooks(|hooks| hooks.take_return_value());
+
+                thread.set_pending_return(return_value.flatten())?;
+            }
+        }
+    }
+
     // TODOC: Marked as unsafe because input function could very well do something unsafe. It's up to the caller
     // to ensure that doesn't happen by defining their function correctly.
     // SubType: Only for JIT EEs?
     pub unsafe fn run_function_as_main(&self, function: FunctionValue<'ctx>, args: &[&str]) -> c_int {
+        self.run_function_as_main_with_env(function, args, &[])
+    }
+
+    /// Runs `function` as `main` with `args` as its `argv` and `env` as its `envp`, each entry of
+    /// which should have the form `NAME=value`.
+    pub(crate) unsafe fn run_function_as_main_with_env(
+        &self,
+        function: FunctionValue<'ctx>,
+        args: &[&str],
+        env: &[&str],
+    ) -> c_int {
         let cstring_args: Vec<_> = args.iter().map(|&arg| to_c_str(arg)).collect();
         let raw_args: Vec<*const _> = cstring_args.iter().map(|arg| arg.as_ptr()).collect();
 
-        let environment_variables = []; // TODO: Support envp. Likely needs to be null terminated
+        // envp is null terminated, since main isn't told how many entries it has
+        let cstring_env: Vec<_> = env.iter().map(|&var| to_c_str(var)).collect();
+        let raw_env: Vec<*const _> = cstring_env
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
 
         LLVMRunFunctionAsMain(
             self.execution_engine_inner(),
             function.as_value_ref(),
             raw_args.len() as u32,
             raw_args.as_ptr(),
-            environment_variables.as_ptr(),
+            raw_env.as_ptr(),
         ) // REVIEW: usize to u32 cast ok??
     }
 
@@ -488,15 +999,56 @@ impl<'ctx> ExecutionEngine<'ctx> {
         );
     }
 
-    pub unsafe fn step_thread(&self, thread_id: u64, pending_return: Option<GenericValueRef<'static>>) -> bool {
+    /// Steps the thread `thread_id`, handing it `pending_return` as the result of the call it is blocked on.
+    ///
+    /// If one of the installed `MiriHooks` panicked during the step, the thread is terminated and the
+    /// panic is returned as an error. The thread is likewise terminated if the engine has run out of
+    /// fuel, or the step exceeded another of its `InterpreterLimits`.
+    pub unsafe fn step_thread(
+        &self,
+        thread_id: u64,
+        pending_return: Option<GenericValueRef<'_>>,
+    ) -> Result<bool, InterpreterError> {
+        if !self.miri_hooks.limits().borrow_mut().consume_fuel() {
+            LLVMExecutionEngineTerminateThread(self.execution_engine_inner(), thread_id);
+
+            return Err(InterpreterError::LimitExceeded(InterpreterLimit::Fuel));
+        }
+
+        self.step_thread_unmetered(thread_id, pending_return)
+    }
+
+    /// Steps a thread like `step_thread`, without using up any fuel. `InterpreterThread` uses this for
+    /// the steps spent in its probes, which aren't instructions of the module.
+    pub(crate) unsafe fn step_thread_unmetered(
+        &self,
+        thread_id: u64,
+        pending_return: Option<GenericValueRef<'_>>,
+    ) -> Result<bool, InterpreterError> {
         let return_ptr = match pending_return {
             Some(ref val) => val.generic_value,
             None => std::ptr::null_mut(),
         };
-        LLVMExecutionEngineStepThread(self.execution_engine_inner(), thread_id, return_ptr) != 0
+        let stepped = LLVMExecutionEngineStepThread(self.execution_engine_inner(), thread_id, return_ptr) != 0;
+
+        if let Some(panic) = self.miri_hooks.take_panic() {
+            LLVMExecutionEngineTerminateThread(self.execution_engine_inner(), thread_id);
+
+            return Err(InterpreterError::HookPanicked(panic));
+        }
+
+        if let Some(err) = self.miri_hooks.take_call_error() {
+            LLVMExecutionEngineTerminateThread(self.execution_engine_inner(), thread_id);
+
+            return Err(err);
+        }
+
+        Ok(stepped)
     }
 
-    pub unsafe fn get_thread_exit_value(&self, thread_id: u64) -> Option<GenericValueRef<'static>> {
+    /// Gets the value a thread returned from its entry function. It is freed along with the thread,
+    /// so the reference is only valid until the thread is terminated.
+    pub unsafe fn get_thread_exit_value(&self, thread_id: u64) -> Option<GenericValueRef<'_>> {
         let value = LLVMExecutionEngineGetThreadExitValue(self.execution_engine_inner(), thread_id);
         if value.is_null() {
             None
@@ -513,6 +1065,229 @@ impl<'ctx> ExecutionEngine<'ctx> {
         LLVMExecutionEngineHasThread(self.execution_engine_inner(), thread_id) != 0
     }
 
+    /// Installs `hooks` as the Miri host of this `ExecutionEngine`, replacing any raw hooks
+    /// or interpcx wrapper set previously.
+    ///
+    /// The engine owns `hooks` from then on and keeps it alive until the engine and all of its
+    /// clones have been dropped, or until another set of hooks is installed.
+    pub fn install_miri_hooks(&self, hooks: Box<dyn MiriHooks<'ctx> + 'ctx>) {
+        self.miri_hooks.install(self, hooks)
+    }
+
+    /// Instruments the functions `module` defines with calls to `inkwell.miri.*` probes, through which
+    /// `InterpreterThread`s know which instruction they're at and what their frames hold. Frames,
+    /// breakpoints, tracing of instructions and the `max_call_depth` limit all rely on them, as does
+    /// the check for branches on undefined values.
+    ///
+    /// This rewrites `module` in place. The probes are only understood by an engine with `MiriHooks`
+    /// installed, so the module can't be compiled or run without them afterwards. Functions added
+    /// to `module` later are only instrumented by calling this again, while those which already are,
+    /// even by another engine, are left as they are. Threads have to be spawned afterwards to be
+    /// followed.
+    pub fn instrument_module(&self, module: &Module<'ctx>) {
+        self.miri_hooks.probes().borrow_mut().instrument(module.as_mut_ptr())
+    }
+
+    /// Starts recording what `InterpreterThread`s spawned from now on do to the interpreter, so that
+    /// they can be snapshotted with `take_interpreter_snapshot`. Each recording starts with a copy of
+    /// the memory of the installed `MiriHooks` when a thread is spawned while none of the recorded
+    /// ones are alive, and holds on to every step taken until then.
+    pub fn enable_interpreter_snapshots(&self) {
+        self.miri_hooks.journal().borrow_mut().enable()
+    }
+
+    /// Copies the state of `threads`, their frames and the values bound in them, along with the memory
+    /// of the installed `MiriHooks` and the resources used up towards the `InterpreterLimits`.
+    ///
+    /// Returns `None` unless snapshots were enabled with `enable_interpreter_snapshots` before any of
+    /// `threads` was spawned and the hooks can snapshot their memory, as `SimpleMiriHost` can.
+    pub fn take_interpreter_snapshot(&self, threads: &[InterpreterThread<'ctx>]) -> Option<InterpreterSnapshot<'ctx>> {
+        InterpreterSnapshot::take(self, threads)
+    }
+
+    /// Puts the threads of `snapshot`, which was taken on this engine, back the way they were, and
+    /// returns new handles to them. The memory of the installed `MiriHooks` and the resources used up
+    /// towards the `InterpreterLimits` are restored as well.
+    ///
+    /// The interpreter's side of the threads is restored by spawning them again from the memory they
+    /// started from and replaying every step they took, without using up any fuel. The hooks are
+    /// called again for the memory operations and calls made meanwhile, so they must behave the same
+    /// way as the first time. Changes to the memory made by anything other than the threads aren't
+    /// replayed.
+    ///
+    /// Returns an error if any thread the snapshot replays still exists, so the threads alive when it
+    /// was taken have to be dropped first, or if the replay fails, in which case no thread is restored.
+    pub unsafe fn restore_interpreter_snapshot(
+        &self,
+        snapshot: &InterpreterSnapshot<'ctx>,
+    ) -> Result<Vec<InterpreterThread<'ctx>>, InterpreterError> {
+        snapshot.restore(self)
+    }
+
+    /// Bounds the work the interpreter may do from now on, refilling its fuel. Each instruction a
+    /// thread executes uses up one unit of fuel, shared between all threads.
+    ///
+    /// Limits are kept when other hooks are installed, although the heap allocations made by the old
+    /// hooks no longer count towards `InterpreterLimits::max_heap_size`.
+    pub fn set_interpreter_limits(&self, limits: InterpreterLimits) {
+        self.miri_hooks.limits().borrow_mut().set_limits(limits)
+    }
+
+    /// Gets the limits set with `set_interpreter_limits`, which are unbounded by default.
+    pub fn get_interpreter_limits(&self) -> InterpreterLimits {
+        self.miri_hooks.limits().borrow().get_limits()
+    }
+
+    /// Gets how many more instructions may be executed, or `None` if the fuel isn't limited.
+    pub fn get_remaining_fuel(&self) -> Option<u64> {
+        self.miri_hooks.limits().borrow().get_remaining_fuel()
+    }
+
+    /// Sets a breakpoint which fires whenever an interpreter thread calls a function, enters a basic
+    /// block or is about to execute an instruction, depending on `location`.
+    ///
+    /// Breakpoints only fire in threads running functions instrumented with `instrument_module`, since
+    /// they rely on its probes. Calls to functions the module only declares fire as they are handed
+    /// to the hooks.
+    pub fn add_breakpoint(&self, location: impl Into<BreakpointLocation<'ctx>>) -> BreakpointId {
+        self.miri_hooks.breakpoints().borrow_mut().add_location(location.into())
+    }
+
+    /// Sets a watchpoint which fires whenever an interpreter thread accesses any of the `len`
+    /// bytes starting at `ptr` in a way matching `kind`. Only accesses through pointers with the
+    /// provenance of `ptr`, that is to the same allocation, are watched.
+    pub fn add_watchpoint(&self, ptr: MiriPointer, len: u64, kind: WatchKind) -> BreakpointId {
+        self.miri_hooks
+            .breakpoints()
+            .borrow_mut()
+            .add_watchpoint(ptr, len, kind)
+    }
+
+    /// Removes a breakpoint or watchpoint. Returns `false` if it had already been removed.
+    pub fn remove_breakpoint(&self, id: BreakpointId) -> bool {
+        self.miri_hooks.breakpoints().borrow_mut().remove(id)
+    }
+
+    /// Gets the pointer indirect calls to `function` can be made through, allocating it with the
+    /// installed `MiriHooks` the first time. Calls through it are checked against the type of
+    /// `function`, and run on a new thread by the `InterpreterThread` which made them.
+    ///
+    /// Returns `None` if no hooks are installed, or if this is called from inside one of them.
+    /// Installing other hooks forgets every function pointer, since they belong to the old hooks.
+    pub fn get_function_pointer(&self, function: FunctionValue<'ctx>) -> Option<MiriPointer> {
+        let function_pointers = self.miri_hooks.function_pointers();
+
+        if let Some(ptr) = function_pointers.borrow().get_pointer(function) {
+            return Some(ptr);
+        }
+
+        let ptr = self.miri_hooks.allocate_function_pointer()?;
+
+        function_pointers.borrow_mut().add_function(function, ptr);
+
+        Some(ptr)
+    }
+
+    /// Creates a pointer indirect calls of type `fn_type` can be made through to call `function`,
+    /// allocating it with the installed `MiriHooks`. `function` returns the value of the call,
+    /// or `None` for `void` functions.
+    ///
+    /// Returns `None` if no hooks are installed, or if this is called from inside one of them.
+    pub fn register_host_function<F>(&self, fn_type: FunctionType<'ctx>, function: F) -> Option<MiriPointer>
+    where
+        F: FnMut(&[GenericValueRef<'_>]) -> Option<GenericValue<'ctx>> + 'ctx,
+    {
+        let ptr = self.miri_hooks.allocate_function_pointer()?;
+
+        self.miri_hooks
+            .function_pointers()
+            .borrow_mut()
+            .add_host_function(fn_type, Box::new(function), ptr);
+
+        Some(ptr)
+    }
+
+    /// Gets what `ptr` points to, if it's one of the engine's function pointers.
+    pub fn resolve_function_pointer(&self, ptr: MiriPointer) -> Option<FunctionPointerTarget<'ctx>> {
+        self.miri_hooks.function_pointers().borrow().resolve(ptr)
+    }
+
+    /// Lays out every global variable `module` defines with the engine's `TargetData` and hands
+    /// it to the installed `MiriHooks`: each global is allocated with `MiriHooks::malloc`, its
+    /// serialized initializer written with `MiriHooks::memcpy`, every pointer it holds written
+    /// with `MiriHooks::store` and finally the global passed to `MiriHooks::register_global`.
+    ///
+    /// Pointers to functions are relocated to the engine's function pointers, as returned by
+    /// `get_function_pointer`. Pointers to globals `module` only declares can't be relocated.
+    ///
+    /// Returns each global along with its address.
+    pub fn materialize_globals(
+        &self,
+        module: &Module<'ctx>,
+    ) -> Result<Vec<(GlobalValue<'ctx>, MiriPointer)>, GlobalLayoutError> {
+        let images = GlobalImage::from_module(module, self.get_target_data())?;
+        let mut function_pointers = HashMap::new();
+
+        // Function pointers are allocated up front, since the hooks can't be borrowed twice
+        for relocation in images.iter().flat_map(|image| &image.relocations) {
+            if let RelocationTarget::Function(function) = relocation.target {
+                let ptr = self.get_function_pointer(function).ok_or(GlobalLayoutError::NoHooks)?;
+
+                function_pointers.insert(function, ptr);
+            }
+        }
+
+        self.miri_hooks
+            .with_hooks(|hooks| {
+                let globals: HashMap<_, _> = images
+                    .iter()
+                    .map(|image| (image.global, hooks.malloc(image.size, image.align as u64, false)))
+                    .collect();
+
+                for image in &images {
+                    let ptr = globals[&image.global];
+                    let name = global_name(image.global);
+
+                    if !image.bytes.is_empty() && !hooks.memcpy(ptr, &image.bytes) {
+                        return Err(GlobalLayoutError::Rejected(name));
+                    }
+
+                    for relocation in &image.relocations {
+                        let target = match relocation.target {
+                            RelocationTarget::Global(global) => globals.get(&global),
+                            RelocationTarget::Function(function) => function_pointers.get(&function),
+                        };
+                        let target = match target {
+                            Some(target) => hooks.get_element_pointer(*target, relocation.addend as u64),
+                            None => {
+                                return Err(GlobalLayoutError::UnresolvedTarget {
+                                    global: name,
+                                    target: target_name(relocation.target),
+                                })
+                            },
+                        };
+                        let dest = hooks.get_element_pointer(ptr, relocation.offset);
+                        let mut value = unsafe { GenericValue::create_generic_value_of_miri_pointer(target) };
+                        let align = self.get_target_data().get_abi_alignment(&relocation.ptr_type) as u64;
+
+                        if !hooks.store(value.as_mut(), dest, relocation.ptr_type.into(), align) {
+                            return Err(GlobalLayoutError::Rejected(name));
+                        }
+                    }
+
+                    if !hooks.register_global(&name, ptr) {
+                        return Err(GlobalLayoutError::Rejected(name));
+                    }
+                }
+
+                Ok(images
+                    .iter()
+                    .map(|image| (image.global, globals[&image.global]))
+                    .collect())
+            })
+            .unwrap_or(Err(GlobalLayoutError::NoHooks))
+    }
+
     pub fn set_miri_interpcx_wrapper(&self, wrapper: *mut MiriInterpCxOpaque) {
         unsafe { LLVMExecutionEngineSetMiriInterpCxWrapper(self.execution_engine_inner(), wrapper) }
     }
@@ -589,8 +1364,12 @@ impl Drop for ExecutionEngine<'_> {
 impl Clone for ExecutionEngine<'_> {
     fn clone(&self) -> Self {
         let execution_engine_rc = self.execution_engine_rc().clone();
+        let mut execution_engine = unsafe { ExecutionEngine::new(execution_engine_rc, self.jit_mode) };
+
+        // Clones must keep the installed hooks alive, since they all drive the same LLVM engine
+        execution_engine.miri_hooks = self.miri_hooks.clone();
 
-        unsafe { ExecutionEngine::new(execution_engine_rc, self.jit_mode) }
+        execution_engine
     }
 }
 
@@ -620,10 +1399,36 @@ impl Deref for ExecEngineInner<'_> {
 /// to doesn't accidentally outlive its execution engine.
 #[derive(Clone)]
 pub struct JitFunction<'ctx, F> {
-    _execution_engine: ExecEngineInner<'ctx>,
+    _owner: JitFunctionOwner<'ctx>,
     inner: F,
 }
 
+/// Whatever keeps the code a `JitFunction` points to alive.
+#[llvm_versioned_item]
+#[derive(Clone)]
+enum JitFunctionOwner<'ctx> {
+    ExecutionEngine(ExecEngineInner<'ctx>),
+    #[llvm_versions(12..)]
+    LLJIT(LLJIT),
+}
+
+impl<'ctx, F> JitFunction<'ctx, F> {
+    /// Wraps the function at `address` in a JIT compiled by `jit`.
+    #[llvm_versions(12..)]
+    pub(crate) unsafe fn from_lljit(jit: LLJIT, address: usize) -> Self {
+        assert_eq!(
+            size_of::<F>(),
+            size_of::<usize>(),
+            "The type `F` must have the same size as a function pointer"
+        );
+
+        JitFunction {
+            _owner: JitFunctionOwner::LLJIT(jit),
+            inner: transmute_copy(&address),
+        }
+    }
+}
+
 impl<'ctx, F: Copy> JitFunction<'ctx, F> {
     /// Returns the raw function pointer, consuming self in the process.
     /// This function is unsafe because the function pointer may dangle
@@ -687,146 +1492,3 @@ macro_rules! impl_unsafe_fn {
 }
 
 impl_unsafe_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M);
-
-#[cfg(feature = "experimental")]
-pub mod experimental {
-    use llvm_sys::error::{LLVMConsumeError, LLVMErrorRef, LLVMErrorTypeId, LLVMGetErrorMessage, LLVMGetErrorTypeId};
-    use llvm_sys::orc::{
-        LLVMOrcAddEagerlyCompiledIR, LLVMOrcAddLazilyCompiledIR, LLVMOrcCreateInstance, LLVMOrcDisposeInstance,
-        LLVMOrcDisposeMangledSymbol, LLVMOrcGetErrorMsg, LLVMOrcGetMangledSymbol, LLVMOrcJITStackRef,
-    };
-
-    use crate::module::Module;
-    use crate::support::to_c_str;
-    use crate::targets::TargetMachine;
-
-    use std::ffi::{CStr, CString};
-    use std::mem::MaybeUninit;
-    use std::ops::Deref;
-
-    #[derive(Debug)]
-    pub struct MangledSymbol(*mut libc::c_char);
-
-    impl Deref for MangledSymbol {
-        type Target = CStr;
-
-        fn deref(&self) -> &CStr {
-            unsafe { CStr::from_ptr(self.0) }
-        }
-    }
-
-    impl Drop for MangledSymbol {
-        fn drop(&mut self) {
-            unsafe { LLVMOrcDisposeMangledSymbol(self.0) }
-        }
-    }
-
-    #[derive(Debug)]
-    pub struct LLVMError(LLVMErrorRef);
-
-    impl LLVMError {
-        // Null type id == success
-        pub fn get_type_id(&self) -> LLVMErrorTypeId {
-            // FIXME: Don't expose LLVMErrorTypeId
-            unsafe { LLVMGetErrorTypeId(self.0) }
-        }
-    }
-
-    impl Deref for LLVMError {
-        type Target = CStr;
-
-        fn deref(&self) -> &CStr {
-            unsafe {
-                CStr::from_ptr(LLVMGetErrorMessage(self.0)) // FIXME: LLVMGetErrorMessage consumes the error, needs LLVMDisposeErrorMessage after
-            }
-        }
-    }
-
-    impl Drop for LLVMError {
-        fn drop(&mut self) {
-            unsafe { LLVMConsumeError(self.0) }
-        }
-    }
-
-    // TODO
-    #[derive(Debug)]
-    pub struct Orc(LLVMOrcJITStackRef);
-
-    impl Orc {
-        pub fn create(target_machine: TargetMachine) -> Self {
-            let stack_ref = unsafe { LLVMOrcCreateInstance(target_machine.target_machine) };
-
-            Orc(stack_ref)
-        }
-
-        pub fn add_compiled_ir<'ctx>(&self, module: &Module<'ctx>, lazily: bool) -> Result<(), ()> {
-            // let handle = MaybeUninit::uninit();
-            // let _err =  if lazily {
-            //     unsafe { LLVMOrcAddLazilyCompiledIR(self.0, handle.as_mut_ptr(), module.module.get(), sym_resolve, sym_resolve_ctx) }
-            // } else {
-            //     unsafe { LLVMOrcAddEagerlyCompiledIR(self.0, handle.as_mut_ptr(), module.module.get(), sym_resolve, sym_resolve_ctx) }
-            // };
-
-            Ok(())
-        }
-
-        /// Obtains an error message owned by the ORC JIT stack.
-        pub fn get_error(&self) -> &CStr {
-            let err_str = unsafe { LLVMOrcGetErrorMsg(self.0) };
-
-            if err_str.is_null() {
-                panic!("Needs to be optional")
-            }
-
-            unsafe { CStr::from_ptr(err_str) }
-        }
-
-        pub fn get_mangled_symbol(&self, symbol: &str) -> MangledSymbol {
-            let mut mangled_symbol = MaybeUninit::uninit();
-            let c_symbol = to_c_str(symbol);
-
-            unsafe { LLVMOrcGetMangledSymbol(self.0, mangled_symbol.as_mut_ptr(), c_symbol.as_ptr()) };
-
-            MangledSymbol(unsafe { mangled_symbol.assume_init() })
-        }
-    }
-
-    impl Drop for Orc {
-        fn drop(&mut self) {
-            // REVIEW: This returns an LLVMErrorRef, not sure what we can do with it...
-            // print to stderr maybe?
-            LLVMError(unsafe { LLVMOrcDisposeInstance(self.0) });
-        }
-    }
-
-    #[test]
-    fn test_mangled_str() {
-        use crate::targets::{CodeModel, InitializationConfig, RelocMode, Target};
-        use crate::OptimizationLevel;
-
-        Target::initialize_native(&InitializationConfig::default()).unwrap();
-
-        let target_triple = TargetMachine::get_default_triple();
-        let target = Target::from_triple(&target_triple).unwrap();
-        let target_machine = target
-            .create_target_machine(
-                &target_triple,
-                &"",
-                &"",
-                OptimizationLevel::None,
-                RelocMode::Default,
-                CodeModel::Default,
-            )
-            .unwrap();
-        let orc = Orc::create(target_machine);
-
-        assert_eq!(orc.get_error().to_str().unwrap(), "");
-
-        let mangled_symbol = orc.get_mangled_symbol("MyStructName");
-
-        assert_eq!(orc.get_error().to_str().unwrap(), "");
-
-        // REVIEW: This doesn't seem very mangled...
-        assert_eq!(mangled_symbol.to_str().unwrap(), "MyStructName");
-    }
-}
@@ -0,0 +1,375 @@
+//! Replacing the bodies of functions compiled by MCJIT while they may be running.
+
+use llvm_sys::execution_engine::LLVMGetGlobalValueAddress;
+
+use crate::attributes::{Attribute, AttributeLoc};
+use crate::builder::Builder;
+use crate::execution_engine::{ExecutionEngine, FunctionLookupError};
+use crate::module::{Linkage, Module};
+use crate::support::to_c_str;
+use crate::types::FunctionType;
+#[llvm_versions(..=14)]
+use crate::values::CallableValue;
+use crate::values::{BasicMetadataValueEnum, CallSiteValue, FunctionValue, PointerValue};
+use crate::{AtomicOrdering, AtomicRMWBinOp};
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Parameter attributes which change how an argument is passed, and which a stub therefore
+/// can't forward.
+const UNFORWARDABLE_ATTRIBUTES: &[&str] = &["byval", "sret", "inalloca", "preallocated"];
+
+/// An error making a function replaceable or replacing its body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HotReloadError {
+    /// The function doesn't belong to the module it was passed with.
+    NotInModule,
+    /// The function has no body to replace.
+    Declaration,
+    /// The function is variadic, so its arguments can't be forwarded.
+    VarArg,
+    /// The parameter at this index is passed in a way that can't be forwarded, such as `byval`.
+    UnforwardableParameter(u32),
+    /// The new body's type differs from the function's.
+    SignatureMismatch,
+    /// The new body's module already belongs to an `ExecutionEngine`.
+    ModuleAlreadyOwned,
+    /// The new body or the function's indirection couldn't be found after compiling it.
+    FunctionLookup(FunctionLookupError),
+}
+
+impl Error for HotReloadError {}
+
+impl Display for HotReloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HotReloadError::NotInModule => write!(f, "function doesn't belong to the module"),
+            HotReloadError::Declaration => write!(f, "function is only a declaration"),
+            HotReloadError::VarArg => write!(f, "variadic functions can't be replaced"),
+            HotReloadError::UnforwardableParameter(idx) => write!(f, "parameter {} can't be forwarded", idx),
+            HotReloadError::SignatureMismatch => write!(f, "new body has a different type than the function"),
+            HotReloadError::ModuleAlreadyOwned => write!(f, "module already belongs to an ExecutionEngine"),
+            HotReloadError::FunctionLookup(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<FunctionLookupError> for HotReloadError {
+    fn from(err: FunctionLookupError) -> Self {
+        HotReloadError::FunctionLookup(err)
+    }
+}
+
+/// A body a `HotFunction` was replaced with, along with the module it was compiled in, which is
+/// `None` for the function's original body.
+#[derive(Debug)]
+struct Version<'ctx> {
+    body: FunctionValue<'ctx>,
+    module: Option<Module<'ctx>>,
+}
+
+/// A function of a module run by MCJIT whose body can be replaced at runtime.
+///
+/// `HotFunction::prepare` moves the function's body into a function of its own, and turns the
+/// function into a stub which calls whatever body is current through a pointer stored in a
+/// global variable. Every caller, including ones compiled before a replacement and the bodies
+/// themselves, goes through the stub, so replacing the body redirects all of them at once. Calls
+/// already running keep running the body they started in.
+///
+/// The stub also counts the calls running through it, so that `collect` can tell when no frame
+/// may still be executing a replaced body.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::context::Context;
+/// use inkwell::hot_reload::HotFunction;
+/// use inkwell::OptimizationLevel;
+///
+/// let context = Context::create();
+/// let module = context.create_module("live");
+/// let i64_type = context.i64_type();
+/// let function = module.add_function("answer", i64_type.fn_type(&[], false), None);
+///
+/// // ... build the first version of `answer` ...
+///
+/// let mut answer = HotFunction::prepare(&module, function).unwrap();
+/// let execution_engine = module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
+///
+/// // ... call `answer`, and later build its next version in a module of its own ...
+/// let next_module = context.create_module("live.1");
+/// let next = next_module.add_function("answer", i64_type.fn_type(&[], false), None);
+///
+/// answer.replace(&execution_engine, next_module, next).unwrap();
+/// answer.collect(&execution_engine);
+/// ```
+#[derive(Debug)]
+pub struct HotFunction<'ctx> {
+    name: String,
+    stub: FunctionValue<'ctx>,
+    current: Version<'ctx>,
+    retired: Vec<Version<'ctx>>,
+    version: u32,
+}
+
+impl<'ctx> HotFunction<'ctx> {
+    /// Makes `function` of `module` replaceable. This must be done before anything in `module`
+    /// is compiled, since callers compiled beforehand call the body directly.
+    pub fn prepare(module: &Module<'ctx>, function: FunctionValue<'ctx>) -> Result<Self, HotReloadError> {
+        let name = function.get_name().to_string_lossy().into_owned();
+
+        if module.get_function(&name) != Some(function) {
+            return Err(HotReloadError::NotInModule);
+        }
+
+        check_forwardable(function)?;
+
+        let context = module.get_context();
+        let global = function.as_global_value();
+        let body_name = versioned_name(&name, 0);
+
+        global.set_name(&body_name);
+
+        let stub = module.add_function(&name, function.get_type(), Some(function.get_linkage()));
+
+        stub.as_global_value().set_visibility(global.get_visibility());
+        stub.set_call_conventions(function.get_call_conventions());
+        function.replace_all_uses_with(stub);
+        function.set_linkage(Linkage::External);
+
+        let address_type = context.custom_width_int_type(usize::BITS);
+        let slot = module.add_global(address_type, None, &slot_name(&name));
+        let active_calls = module.add_global(address_type, None, &active_calls_name(&name));
+        let builder = context.create_builder();
+
+        slot.set_initializer(&function.as_global_value().as_pointer_value().const_to_int(address_type));
+        active_calls.set_initializer(&address_type.const_zero());
+        builder.position_at_end(context.append_basic_block(stub, "entry"));
+
+        let one = address_type.const_int(1, false);
+        let zero = address_type.const_zero();
+        let seq_cst = AtomicOrdering::SequentiallyConsistent;
+        let fn_ptr_type = stub.as_global_value().as_pointer_value().get_type();
+        let args: Vec<BasicMetadataValueEnum<'ctx>> = stub.get_params().into_iter().map(Into::into).collect();
+
+        // Counting the call before loading the body means that once no calls are counted, every
+        // later call loads the current body
+        builder
+            .build_atomicrmw(AtomicRMWBinOp::Add, active_calls.as_pointer_value(), one, seq_cst)
+            .unwrap();
+
+        let target = builder
+            .build_atomicrmw(AtomicRMWBinOp::Or, slot.as_pointer_value(), zero, seq_cst)
+            .unwrap();
+        let target = builder.build_int_to_ptr(target, fn_ptr_type, "target").unwrap();
+        let call = build_indirect_call(&builder, function.get_type(), target, &args);
+
+        call.set_call_convention(function.get_call_conventions());
+        builder
+            .build_atomicrmw(AtomicRMWBinOp::Sub, active_calls.as_pointer_value(), one, seq_cst)
+            .unwrap();
+
+        match call.try_as_basic_value().left() {
+            Some(value) => builder.build_return(Some(&value)).unwrap(),
+            None => builder.build_return(None).unwrap(),
+        };
+
+        Ok(HotFunction {
+            name,
+            stub,
+            current: Version {
+                body: function,
+                module: None,
+            },
+            retired: Vec::new(),
+            version: 0,
+        })
+    }
+
+    /// Gets the stub every caller calls, which has the function's original name.
+    pub fn get_stub(&self) -> FunctionValue<'ctx> {
+        self.stub
+    }
+
+    /// Gets the current body, named after the function followed by `.hot` and its version.
+    pub fn get_body(&self) -> FunctionValue<'ctx> {
+        self.current.body
+    }
+
+    /// Gets how many times the body has been replaced.
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Gets how many replaced bodies haven't been freed by `collect` yet.
+    pub fn count_retired(&self) -> usize {
+        self.retired.len()
+    }
+
+    /// Replaces the body of the function with `function` of `module`, which is compiled by
+    /// `execution_engine` first. `module` may declare the function under its original name, to
+    /// call it through the stub; so do calls of `function` to itself.
+    ///
+    /// The previous body keeps running in calls which already entered it, and is retired until
+    /// `collect` finds that no calls are running anymore.
+    pub fn replace(
+        &mut self,
+        execution_engine: &ExecutionEngine<'ctx>,
+        module: Module<'ctx>,
+        function: FunctionValue<'ctx>,
+    ) -> Result<(), HotReloadError> {
+        let function_name = function.get_name().to_string_lossy().into_owned();
+
+        if module.get_function(&function_name) != Some(function) {
+            return Err(HotReloadError::NotInModule);
+        }
+
+        if function.get_type() != self.stub.get_type() {
+            return Err(HotReloadError::SignatureMismatch);
+        }
+
+        check_forwardable(function)?;
+
+        if module.owned_by_ee.borrow().is_some() {
+            return Err(HotReloadError::ModuleAlreadyOwned);
+        }
+
+        let body_name = versioned_name(&self.name, self.version + 1);
+
+        function.as_global_value().set_name(&body_name);
+        function.set_linkage(Linkage::External);
+
+        if function_name == self.name {
+            let declaration = module.add_function(&self.name, function.get_type(), None);
+
+            declaration.set_call_conventions(function.get_call_conventions());
+            function.replace_all_uses_with(declaration);
+        }
+
+        execution_engine
+            .add_module(&module)
+            .map_err(|()| HotReloadError::ModuleAlreadyOwned)?;
+
+        let address = execution_engine.get_function_address(&body_name)?;
+        let slot = global_address(execution_engine, &slot_name(&self.name))?;
+
+        unsafe { (*(slot as *const AtomicUsize)).store(address, Ordering::SeqCst) };
+
+        let previous = std::mem::replace(
+            &mut self.current,
+            Version {
+                body: function,
+                module: Some(module),
+            },
+        );
+
+        self.retired.push(previous);
+        self.version += 1;
+
+        Ok(())
+    }
+
+    /// Gets how many calls are running through the stub, in any body. Calls which entered the
+    /// current body recursively are counted as many times.
+    pub fn count_active_calls(&self, execution_engine: &ExecutionEngine<'ctx>) -> Result<usize, HotReloadError> {
+        let active_calls = global_address(execution_engine, &active_calls_name(&self.name))?;
+
+        Ok(unsafe { (*(active_calls as *const AtomicUsize)).load(Ordering::SeqCst) })
+    }
+
+    /// Frees the machine code of the retired bodies with `ExecutionEngine::free_fn_machine_code`
+    /// and removes their modules from `execution_engine`, if no calls are running through the stub.
+    /// Returns how many bodies were freed.
+    ///
+    /// Note that MCJIT keeps the memory of everything it compiled until it's dropped, so this
+    /// mostly frees the IR of the retired bodies.
+    pub fn collect(&mut self, execution_engine: &ExecutionEngine<'ctx>) -> usize {
+        if self.retired.is_empty() || self.count_active_calls(execution_engine) != Ok(0) {
+            return 0;
+        }
+
+        let freed = self.retired.len();
+
+        for version in self.retired.drain(..) {
+            execution_engine.free_fn_machine_code(version.body);
+
+            if let Some(module) = version.module {
+                // The module is still disposed when dropped if it somehow isn't owned anymore
+                let _ = execution_engine.remove_module(&module);
+            }
+        }
+
+        freed
+    }
+}
+
+fn versioned_name(name: &str, version: u32) -> String {
+    format!("{}.hot{}", name, version)
+}
+
+fn slot_name(name: &str) -> String {
+    format!("{}.hot_slot", name)
+}
+
+fn active_calls_name(name: &str) -> String {
+    format!("{}.hot_active_calls", name)
+}
+
+fn check_forwardable(function: FunctionValue<'_>) -> Result<(), HotReloadError> {
+    if function.as_global_value().is_declaration() {
+        return Err(HotReloadError::Declaration);
+    }
+
+    if function.get_type().is_var_arg() {
+        return Err(HotReloadError::VarArg);
+    }
+
+    for idx in 0..function.count_params() {
+        let unforwardable = UNFORWARDABLE_ATTRIBUTES.iter().any(|name| {
+            let kind_id = Attribute::get_named_enum_kind_id(name);
+
+            kind_id != 0 && function.get_enum_attribute(AttributeLoc::Param(idx), kind_id).is_some()
+        });
+
+        if unforwardable {
+            return Err(HotReloadError::UnforwardableParameter(idx));
+        }
+    }
+
+    Ok(())
+}
+
+fn global_address(execution_engine: &ExecutionEngine<'_>, name: &str) -> Result<usize, FunctionLookupError> {
+    let c_string = to_c_str(name);
+    let address = unsafe { LLVMGetGlobalValueAddress(execution_engine.as_mut_ptr(), c_string.as_ptr()) };
+
+    if address == 0 {
+        return Err(FunctionLookupError::FunctionNotFound);
+    }
+
+    Ok(address as usize)
+}
+
+#[llvm_versions(..=14)]
+fn build_indirect_call<'ctx>(
+    builder: &Builder<'ctx>,
+    _fn_type: FunctionType<'ctx>,
+    target: PointerValue<'ctx>,
+    args: &[BasicMetadataValueEnum<'ctx>],
+) -> CallSiteValue<'ctx> {
+    let callable = CallableValue::try_from(target).expect("Stub calls a function pointer");
+
+    builder.build_call(callable, args, "result").unwrap()
+}
+
+#[llvm_versions(15..)]
+fn build_indirect_call<'ctx>(
+    builder: &Builder<'ctx>,
+    fn_type: FunctionType<'ctx>,
+    target: PointerValue<'ctx>,
+    args: &[BasicMetadataValueEnum<'ctx>],
+) -> CallSiteValue<'ctx> {
+    builder.build_indirect_call(fn_type, target, args, "result").unwrap()
+}
@@ -0,0 +1,122 @@
+//! Listeners told about the code a JIT emits, so that debuggers and profilers can make sense of
+//! JIT-compiled frames.
+//!
+//! `JITEventListener`s are the ones built into LLVM. They're given to an `LLJIT` through
+//! `LLJIT::create_with_event_listeners`, and to an MCJIT `ExecutionEngine` through
+//! `ExecutionEngine::register_event_listener`. Rust code can follow what an `LLJIT` emits through
+//! `LLJIT::set_event_callback`, which a `PerfMap` can be fed from.
+
+use llvm_sys::execution_engine::{
+    LLVMCreateGDBRegistrationListener, LLVMCreateIntelJITEventListener, LLVMCreateOProfileJITEventListener,
+    LLVMCreatePerfJITEventListener,
+};
+use llvm_sys::prelude::LLVMJITEventListenerRef;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// One of the listeners built into LLVM. They live as long as the process does, and can be
+/// shared between any number of JITs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JITEventListener {
+    listener: LLVMJITEventListenerRef,
+}
+
+impl JITEventListener {
+    unsafe fn new(listener: LLVMJITEventListenerRef) -> Option<Self> {
+        if listener.is_null() {
+            return None;
+        }
+
+        Some(JITEventListener { listener })
+    }
+
+    /// Gets the listener which registers emitted objects through GDB's JIT interface, which GDB
+    /// and LLDB read debug info and symbols of JIT-compiled code from.
+    pub fn create_gdb_registration_listener() -> Self {
+        unsafe { JITEventListener::new(LLVMCreateGDBRegistrationListener()).expect("GDB listener is always built") }
+    }
+
+    /// Gets the listener which writes a jitdump file for `perf inject --jit`, if LLVM was built
+    /// with `LLVM_USE_PERF`.
+    pub fn create_perf_listener() -> Option<Self> {
+        unsafe { JITEventListener::new(LLVMCreatePerfJITEventListener()) }
+    }
+
+    /// Gets the listener which reports to Intel VTune, if LLVM was built with
+    /// `LLVM_USE_INTEL_JITEVENTS`.
+    pub fn create_intel_listener() -> Option<Self> {
+        unsafe { JITEventListener::new(LLVMCreateIntelJITEventListener()) }
+    }
+
+    /// Gets the listener which reports to OProfile, if LLVM was built with `LLVM_USE_OPROFILE`.
+    pub fn create_oprofile_listener() -> Option<Self> {
+        unsafe { JITEventListener::new(LLVMCreateOProfileJITEventListener()) }
+    }
+
+    /// Acquires the underlying raw pointer belonging to this `JITEventListener` type.
+    pub fn as_mut_ptr(&self) -> LLVMJITEventListenerRef {
+        self.listener
+    }
+}
+
+/// Something a JIT did with the code it compiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitEvent<'a> {
+    /// A relocatable object was compiled, and is about to be linked into memory.
+    ObjectEmitted { object: &'a [u8] },
+    /// A symbol of an emitted object was linked at `address`, and spans `size` bytes.
+    SymbolLoaded { name: &'a str, address: usize, size: u64 },
+}
+
+/// Writes a perf map, the file `perf report` looks JIT-compiled symbols up in when it can't
+/// symbolize an address. Unlike the jitdump written by `JITEventListener::create_perf_listener`,
+/// it needs no support built into LLVM, but only holds symbol names.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::jit_event_listener::{JitEvent, PerfMap};
+///
+/// let mut perf_map = PerfMap::create().unwrap();
+///
+/// // Usually fed from `LLJIT::set_event_callback`
+/// perf_map.record(&JitEvent::SymbolLoaded { name: "sum", address: 0x1000, size: 16 }).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct PerfMap {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl PerfMap {
+    /// Creates `/tmp/perf-<pid>.map`, where perf expects the map of the current process.
+    pub fn create() -> io::Result<Self> {
+        PerfMap::create_at(format!("/tmp/perf-{}.map", std::process::id()))
+    }
+
+    /// Creates a perf map at `path`, truncating any file already there.
+    pub fn create_at<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = BufWriter::new(File::create(&path)?);
+
+        Ok(PerfMap { path, file })
+    }
+
+    /// Gets where this perf map is written.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds the symbol of a `JitEvent::SymbolLoaded` to the map, ignoring other events. The map
+    /// is flushed after every symbol, as perf reads it whenever it sees fit.
+    pub fn record(&mut self, event: &JitEvent<'_>) -> io::Result<()> {
+        if let JitEvent::SymbolLoaded { name, address, size } = *event {
+            writeln!(self.file, "{:x} {:x} {}", address, size, name)?;
+            self.file.flush()?;
+        }
+
+        Ok(())
+    }
+}
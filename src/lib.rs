@@ -31,12 +31,18 @@ pub mod data_layout;
 #[cfg(not(any(feature = "llvm4-0", feature = "llvm5-0", feature = "llvm6-0")))]
 pub mod debug_info;
 pub mod execution_engine;
+pub mod hot_reload;
 pub mod intrinsics;
+#[llvm_versions(7..)]
+pub mod jit_event_listener;
 pub mod memory_buffer;
 #[deny(missing_docs)]
 pub mod module;
 pub mod object_file;
+#[llvm_versions(12..)]
+pub mod orc;
 pub mod passes;
+pub mod symbol_map;
 pub mod targets;
 pub mod types;
 pub mod values;
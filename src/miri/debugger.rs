@@ -0,0 +1,216 @@
+use llvm_sys::miri::MiriPointer;
+
+use crate::basic_block::BasicBlock;
+use crate::miri::probes;
+use crate::values::{FunctionValue, InstructionValue};
+
+/// Identifies a breakpoint or watchpoint set on an `ExecutionEngine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BreakpointId(u32);
+
+/// Where a breakpoint is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointLocation<'ctx> {
+    /// Fires when the function is called, directly or through a function pointer.
+    Function(FunctionValue<'ctx>),
+    /// Fires when a thread enters the block, before executing its first instruction.
+    BasicBlock(BasicBlock<'ctx>),
+    /// Fires before a thread executes the instruction.
+    Instruction(InstructionValue<'ctx>),
+}
+
+impl<'ctx> From<FunctionValue<'ctx>> for BreakpointLocation<'ctx> {
+    fn from(function: FunctionValue<'ctx>) -> Self {
+        BreakpointLocation::Function(function)
+    }
+}
+
+impl<'ctx> From<BasicBlock<'ctx>> for BreakpointLocation<'ctx> {
+    fn from(block: BasicBlock<'ctx>) -> Self {
+        BreakpointLocation::BasicBlock(block)
+    }
+}
+
+impl<'ctx> From<InstructionValue<'ctx>> for BreakpointLocation<'ctx> {
+    fn from(instruction: InstructionValue<'ctx>) -> Self {
+        BreakpointLocation::Instruction(instruction)
+    }
+}
+
+/// Which memory accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Whether memory was read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read,
+    Write,
+}
+
+impl WatchKind {
+    fn matches(self, access: MemoryAccess) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::ReadWrite, _) | (WatchKind::Read, MemoryAccess::Read) | (WatchKind::Write, MemoryAccess::Write)
+        )
+    }
+}
+
+/// A breakpoint or watchpoint which fired while stepping an `InterpreterThread`.
+#[derive(Debug, Clone, Copy)]
+pub enum Trigger<'ctx> {
+    /// The thread reached the location of a breakpoint.
+    Breakpoint {
+        id: BreakpointId,
+        location: BreakpointLocation<'ctx>,
+    },
+    /// The thread accessed `size` bytes at `ptr`, overlapping a watched range of the same allocation.
+    Watchpoint {
+        id: BreakpointId,
+        ptr: MiriPointer,
+        size: u64,
+        access: MemoryAccess,
+    },
+}
+
+/// The triggers which stopped `Scheduler::run`, along with the thread which hit them.
+#[derive(Debug)]
+pub struct BreakpointHit<'ctx> {
+    pub thread_id: u64,
+    pub triggers: Vec<Trigger<'ctx>>,
+}
+
+#[derive(Debug)]
+struct Watchpoint {
+    id: BreakpointId,
+    alloc_id: u64,
+    addr: u64,
+    len: u64,
+    kind: WatchKind,
+}
+
+/// The breakpoints and watchpoints of an `ExecutionEngine`, along with those which fired
+/// since the last time they were taken.
+#[derive(Debug, Default)]
+pub(crate) struct Breakpoints<'ctx> {
+    next_id: u32,
+    locations: Vec<(BreakpointId, BreakpointLocation<'ctx>)>,
+    watchpoints: Vec<Watchpoint>,
+    triggered: Vec<Trigger<'ctx>>,
+    /// Every access made since they were last taken, if they are being recorded.
+    accesses: Option<Vec<(MiriPointer, u64, MemoryAccess)>>,
+}
+
+impl<'ctx> Breakpoints<'ctx> {
+    fn next_id(&mut self) -> BreakpointId {
+        let id = BreakpointId(self.next_id);
+
+        self.next_id += 1;
+
+        id
+    }
+
+    pub(crate) fn add_location(&mut self, location: BreakpointLocation<'ctx>) -> BreakpointId {
+        let id = self.next_id();
+
+        self.locations.push((id, location));
+
+        id
+    }
+
+    pub(crate) fn add_watchpoint(&mut self, ptr: MiriPointer, len: u64, kind: WatchKind) -> BreakpointId {
+        let id = self.next_id();
+
+        self.watchpoints.push(Watchpoint {
+            id,
+            alloc_id: ptr.prov.alloc_id,
+            addr: ptr.addr,
+            len,
+            kind,
+        });
+
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: BreakpointId) -> bool {
+        let count = self.locations.len() + self.watchpoints.len();
+
+        self.locations.retain(|(location_id, _)| *location_id != id);
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+
+        count != self.locations.len() + self.watchpoints.len()
+    }
+
+    fn trigger_where(&mut self, matches: impl Fn(BreakpointLocation<'ctx>) -> bool) {
+        for &(id, location) in &self.locations {
+            if matches(location) {
+                self.triggered.push(Trigger::Breakpoint { id, location });
+            }
+        }
+    }
+
+    /// Called for calls to functions the module only declares, which never run any instructions.
+    pub(crate) fn on_call(&mut self, name: &str) {
+        self.trigger_where(|location| match location {
+            BreakpointLocation::Function(function) => function.get_name().to_bytes() == name.as_bytes(),
+            _ => false,
+        });
+    }
+
+    /// Called when a thread enters `function`, however it was called.
+    pub(crate) fn on_entry(&mut self, function: FunctionValue<'ctx>) {
+        self.trigger_where(|location| location == BreakpointLocation::Function(function));
+    }
+
+    /// Called when a thread is about to execute `instruction`.
+    pub(crate) fn on_instruction(&mut self, instruction: InstructionValue<'ctx>) {
+        self.trigger_where(|location| match location {
+            BreakpointLocation::Instruction(breakpoint) => breakpoint == instruction,
+            BreakpointLocation::BasicBlock(block) => {
+                instruction.get_parent() == Some(block) && probes::is_first_instruction(instruction)
+            },
+            BreakpointLocation::Function(_) => false,
+        });
+    }
+
+    pub(crate) fn on_access(&mut self, ptr: MiriPointer, size: u64, access: MemoryAccess) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push((ptr, size, access));
+        }
+
+        let start = ptr.addr;
+        let end = start.saturating_add(size);
+
+        // Accesses through pointers to other allocations don't touch the watched memory, even at the
+        // same address
+        for watchpoint in &self.watchpoints {
+            let overlaps = start < watchpoint.addr.saturating_add(watchpoint.len) && watchpoint.addr < end;
+
+            if ptr.prov.alloc_id == watchpoint.alloc_id && overlaps && watchpoint.kind.matches(access) {
+                self.triggered.push(Trigger::Watchpoint {
+                    id: watchpoint.id,
+                    ptr,
+                    size,
+                    access,
+                });
+            }
+        }
+    }
+
+    pub(crate) fn take_triggered(&mut self) -> Vec<Trigger<'ctx>> {
+        std::mem::take(&mut self.triggered)
+    }
+
+    pub(crate) fn set_recording_accesses(&mut self, recording: bool) {
+        self.accesses = if recording { Some(Vec::new()) } else { None };
+    }
+
+    pub(crate) fn take_accesses(&mut self) -> Vec<(MiriPointer, u64, MemoryAccess)> {
+        self.accesses.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+}
@@ -0,0 +1,855 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use llvm_sys::miri::{MiriPointer, MiriProvenance};
+
+use crate::miri::limits;
+use crate::support::search_for_address_of_symbol;
+use crate::symbol_map::{SymbolMap, SymbolMapError};
+use crate::types::{BasicTypeEnum, FunctionType};
+use crate::values::{FromGenericValue, GenericValue, GenericValueError, GenericValueRef, ToGenericValue};
+
+/// The memory the functions of a `ForeignFunctionRegistry` read arguments from and write results to.
+pub trait ForeignMemory {
+    /// Allocates `size` zeroed bytes on the heap.
+    fn allocate(&self, size: u64, align: u64) -> MiriPointer;
+
+    /// Frees a heap allocation. Returns `false` if `ptr` isn't the start of a live allocation.
+    fn deallocate(&self, ptr: MiriPointer) -> bool;
+
+    /// Reads `len` bytes starting at `ptr`, if they all lie inside the allocation `ptr` belongs to.
+    fn read_bytes(&self, ptr: MiriPointer, len: u64) -> Option<Vec<u8>>;
+
+    /// Writes `bytes` starting at `ptr`. Returns `false` unless they all lie inside the allocation
+    /// `ptr` belongs to.
+    fn write_bytes(&self, ptr: MiriPointer, bytes: &[u8]) -> bool;
+}
+
+/// An error calling a function through a `ForeignFunctionRegistry`.
+#[derive(Debug, Clone)]
+pub enum ForeignCallError {
+    /// Nothing is registered under this name, and the fallback policy is `ForeignFallback::Error`.
+    Unresolved(String),
+    /// The argument at this index wasn't passed, or is a variadic argument without a declared type.
+    MissingArgument(usize),
+    /// An argument or the return value doesn't match the Rust type it was converted to or from.
+    Conversion(GenericValueError),
+    /// A function accessed memory through a pointer which doesn't point to enough live memory.
+    InvalidPointer(MiriPointer),
+    /// A function of this type can't be called, such as a host symbol taking a pointer.
+    UnsupportedSignature(String),
+    /// A registered function reported an error of its own.
+    Failed(String),
+}
+
+impl Error for ForeignCallError {}
+
+impl Display for ForeignCallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ForeignCallError::Unresolved(name) => write!(f, "no foreign function is registered for `{}`", name),
+            ForeignCallError::MissingArgument(idx) => write!(f, "argument {} is missing or has no declared type", idx),
+            ForeignCallError::Conversion(err) => write!(f, "{}", err),
+            ForeignCallError::InvalidPointer(ptr) => {
+                write!(f, "invalid pointer {:#x} (allocation {})", ptr.addr, ptr.prov.alloc_id)
+            },
+            ForeignCallError::UnsupportedSignature(fn_type) => {
+                write!(f, "foreign functions of type `{}` can't be called", fn_type)
+            },
+            ForeignCallError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<GenericValueError> for ForeignCallError {
+    fn from(err: GenericValueError) -> Self {
+        ForeignCallError::Conversion(err)
+    }
+}
+
+/// What a `ForeignFunctionRegistry` does with calls to names nothing is registered under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForeignFallback {
+    /// Fails the call with `ForeignCallError::Unresolved`.
+    Error,
+    /// Returns zero, or nothing for `void` functions. Calls returning aggregates still fail.
+    Stub,
+    /// Calls the symbol of the same name found by `support::search_for_address_of_symbol`. Only
+    /// functions taking and returning integers of up to 64 bits, with up to six parameters, can be
+    /// called this way, since pointers into the interpreter's memory mean nothing to native code.
+    HostSymbol,
+}
+
+/// A call to a foreign function, as handed to the functions of a `ForeignFunctionRegistry`.
+pub struct ForeignCall<'a, 'ctx> {
+    name: &'a str,
+    args: &'a [GenericValueRef<'a>],
+    fn_type: FunctionType<'ctx>,
+    memory: &'a dyn ForeignMemory,
+    stdout: &'a mut dyn Write,
+}
+
+impl<'a, 'ctx> ForeignCall<'a, 'ctx> {
+    /// Gets the name the function was called by.
+    pub fn get_name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Gets every argument, including variadic ones.
+    pub fn get_args(&self) -> &'a [GenericValueRef<'a>] {
+        self.args
+    }
+
+    /// Gets the type the function was called as.
+    pub fn get_fn_type(&self) -> FunctionType<'ctx> {
+        self.fn_type
+    }
+
+    /// Gets the memory pointer arguments point into.
+    pub fn get_memory(&self) -> &'a dyn ForeignMemory {
+        self.memory
+    }
+
+    /// Gets where output to `stdout` should be written.
+    pub fn get_stdout(&mut self) -> &mut dyn Write {
+        &mut *self.stdout
+    }
+
+    /// Reads the argument at `idx` as a `T`, according to its declared parameter type.
+    pub fn get_arg<T: FromGenericValue<'ctx>>(&self, idx: usize) -> Result<T, ForeignCallError> {
+        let ty = self
+            .fn_type
+            .get_param_types()
+            .get(idx)
+            .copied()
+            .ok_or(ForeignCallError::MissingArgument(idx))?;
+        let arg = self.args.get(idx).ok_or(ForeignCallError::MissingArgument(idx))?;
+
+        Ok(T::from_generic_value(arg, ty)?)
+    }
+
+    /// Reads `len` bytes starting at `ptr`.
+    pub fn read_bytes(&self, ptr: MiriPointer, len: u64) -> Result<Vec<u8>, ForeignCallError> {
+        self.memory
+            .read_bytes(ptr, len)
+            .ok_or(ForeignCallError::InvalidPointer(ptr))
+    }
+
+    /// Writes `bytes` starting at `ptr`.
+    pub fn write_bytes(&self, ptr: MiriPointer, bytes: &[u8]) -> Result<(), ForeignCallError> {
+        if self.memory.write_bytes(ptr, bytes) {
+            Ok(())
+        } else {
+            Err(ForeignCallError::InvalidPointer(ptr))
+        }
+    }
+
+    /// Reads the nul-terminated string starting at `ptr`, without the terminator.
+    pub fn read_c_string(&self, ptr: MiriPointer) -> Result<Vec<u8>, ForeignCallError> {
+        let mut bytes = Vec::new();
+
+        loop {
+            let next = offset_pointer(ptr, bytes.len() as u64);
+
+            match self.read_bytes(next, 1)?[0] {
+                0 => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    /// Creates a return value of the function's integer return type holding `value`.
+    pub fn int_return(&self, value: u64) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+        match self.fn_type.get_return_type() {
+            Some(BasicTypeEnum::IntType(int_type)) => Ok(Some(GenericValue::new_int(value, &int_type, false))),
+            _ => Err(ForeignCallError::UnsupportedSignature(
+                self.fn_type.print_to_string().to_string(),
+            )),
+        }
+    }
+
+    /// Creates a return value of the function's return type holding `value`.
+    pub fn value_return<T: ToGenericValue<'ctx> + ?Sized>(
+        &self,
+        value: &T,
+    ) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+        let ty = self
+            .fn_type
+            .get_return_type()
+            .ok_or_else(|| ForeignCallError::UnsupportedSignature(self.fn_type.print_to_string().to_string()))?;
+
+        Ok(Some(value.to_generic_value(ty)?))
+    }
+
+    /// Allocates `size` bytes on the heap, as long as the engine running the module allows it.
+    fn allocate_heap(&self, size: u64) -> Result<MiriPointer, ForeignCallError> {
+        limits::allocate_heap(size, || self.memory.allocate(size, MALLOC_ALIGN))
+            .ok_or_else(|| ForeignCallError::Failed(format!("allocating {} bytes exceeds the heap size limit", size)))
+    }
+
+    fn get_int_arg(&self, idx: usize) -> Result<u64, ForeignCallError> {
+        self.args
+            .get(idx)
+            .map(|arg| arg.as_int() as u64)
+            .ok_or(ForeignCallError::MissingArgument(idx))
+    }
+
+    fn get_pointer_arg(&self, idx: usize) -> Result<MiriPointer, ForeignCallError> {
+        self.args
+            .get(idx)
+            .map(|arg| arg.as_miri_pointer())
+            .ok_or(ForeignCallError::MissingArgument(idx))
+    }
+}
+
+/// Arguments a typed function registered with `ForeignFunctionRegistry::register_fn` takes, as a tuple.
+pub trait ForeignArgs<'ctx>: Sized {
+    /// Reads the arguments of `call` according to their declared parameter types.
+    fn from_call(call: &ForeignCall<'_, 'ctx>) -> Result<Self, ForeignCallError>;
+}
+
+impl<'ctx> ForeignArgs<'ctx> for () {
+    fn from_call(_call: &ForeignCall<'_, 'ctx>) -> Result<Self, ForeignCallError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_foreign_args {
+    ($(($($name:ident: $idx:tt),+);)*) => {$(
+        impl<'ctx, $($name: FromGenericValue<'ctx>),+> ForeignArgs<'ctx> for ($($name,)+) {
+            fn from_call(call: &ForeignCall<'_, 'ctx>) -> Result<Self, ForeignCallError> {
+                Ok(($(call.get_arg::<$name>($idx)?,)+))
+            }
+        }
+    )*};
+}
+
+impl_foreign_args! {
+    (A: 0);
+    (A: 0, B: 1);
+    (A: 0, B: 1, C: 2);
+    (A: 0, B: 1, C: 2, D: 3);
+    (A: 0, B: 1, C: 2, D: 3, E: 4);
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+}
+
+/// What a typed function registered with `ForeignFunctionRegistry::register_fn` returns.
+pub trait ForeignReturn<'ctx> {
+    /// Converts this into a return value of the function's return type.
+    fn into_return(self, call: &ForeignCall<'_, 'ctx>) -> Result<Option<GenericValue<'ctx>>, ForeignCallError>;
+}
+
+impl<'ctx> ForeignReturn<'ctx> for () {
+    fn into_return(self, _call: &ForeignCall<'_, 'ctx>) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+        Ok(None)
+    }
+}
+
+impl<'ctx, T: ToGenericValue<'ctx>> ForeignReturn<'ctx> for T {
+    fn into_return(self, call: &ForeignCall<'_, 'ctx>) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+        call.value_return(&self)
+    }
+}
+
+type ForeignFunction<'ctx> =
+    Box<dyn FnMut(&mut ForeignCall<'_, 'ctx>) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> + 'ctx>;
+
+/// Dispatches calls to functions a module only declares to Rust closures, by name.
+///
+/// Functions are registered under either an exact name or a glob pattern, in which `*` matches
+/// any run of characters and `?` matches any single one. Exact names take precedence over
+/// patterns, and later patterns over earlier ones.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::context::Context;
+/// use inkwell::miri::{ForeignFunctionRegistry, SimpleMiriHost};
+///
+/// let context = Context::create();
+/// let module = context.create_module("miri");
+/// let execution_engine = module.create_interpreter_execution_engine().unwrap();
+/// let host = SimpleMiriHost::new(execution_engine.get_target_data());
+/// let mut foreign_functions = ForeignFunctionRegistry::with_libc_shims();
+///
+/// foreign_functions.register_fn("abs_*", |(value,): (i64,)| value.abs());
+/// host.set_foreign_functions(foreign_functions);
+///
+/// execution_engine.install_miri_hooks(Box::new(host.clone()));
+/// ```
+pub struct ForeignFunctionRegistry<'ctx> {
+    exact: HashMap<String, ForeignFunction<'ctx>>,
+    patterns: Vec<(String, ForeignFunction<'ctx>)>,
+    fallback: ForeignFallback,
+    stdout: Box<dyn Write + 'ctx>,
+}
+
+impl<'ctx> ForeignFunctionRegistry<'ctx> {
+    /// Creates a registry without any functions, which writes to the process' `stdout`.
+    pub fn new() -> Self {
+        ForeignFunctionRegistry {
+            exact: HashMap::new(),
+            patterns: Vec::new(),
+            fallback: ForeignFallback::Error,
+            stdout: Box::new(io::stdout()),
+        }
+    }
+
+    /// Creates a registry with the shims added by `add_libc_shims`.
+    pub fn with_libc_shims() -> Self {
+        let mut registry = Self::new();
+
+        registry.add_libc_shims();
+        registry
+    }
+
+    /// Registers `function` under `pattern`, replacing whatever was registered under it before.
+    pub fn register<F>(&mut self, pattern: &str, function: F)
+    where
+        F: FnMut(&mut ForeignCall<'_, 'ctx>) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> + 'ctx,
+    {
+        if pattern.contains(['*', '?']) {
+            self.patterns.retain(|(existing, _)| existing != pattern);
+            self.patterns.push((pattern.to_owned(), Box::new(function)));
+        } else {
+            self.exact.insert(pattern.to_owned(), Box::new(function));
+        }
+    }
+
+    /// Registers a function taking a tuple of its arguments, which are converted from their declared
+    /// parameter types, and returning a value of the declared return type (or `()` for `void`).
+    pub fn register_fn<A, R, F>(&mut self, pattern: &str, mut function: F)
+    where
+        A: ForeignArgs<'ctx>,
+        R: ForeignReturn<'ctx>,
+        F: FnMut(A) -> R + 'ctx,
+    {
+        self.register(pattern, move |call| function(A::from_call(call)?).into_return(call));
+    }
+
+    /// Returns whether a function is registered for `name`, ignoring the fallback policy.
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.exact.contains_key(name) || self.patterns.iter().any(|(pattern, _)| glob_matches(pattern, name))
+    }
+
+    /// Gets what happens to calls nothing is registered for.
+    pub fn get_fallback(&self) -> ForeignFallback {
+        self.fallback
+    }
+
+    /// Sets what happens to calls nothing is registered for. Defaults to `ForeignFallback::Error`.
+    ///
+    /// # Safety
+    ///
+    /// With `ForeignFallback::HostSymbol`, every host symbol the interpreter calls must actually
+    /// have the type it's called as.
+    pub unsafe fn set_fallback(&mut self, fallback: ForeignFallback) {
+        self.fallback = fallback;
+    }
+
+    /// Redirects everything functions write to `stdout`, such as the output of the `printf` shim.
+    pub fn set_stdout(&mut self, stdout: Box<dyn Write + 'ctx>) {
+        self.stdout = stdout;
+    }
+
+    /// Registers the functions of `symbols`, which are called on the host when their declared type
+    /// is one they can be called as, and only takes and returns integers of up to 64 bits. Statics
+    /// are skipped, as host memory can't be accessed from the interpreter, and so is the current
+    /// process, which is searched with `ForeignFallback::HostSymbol` instead.
+    pub fn add_symbol_map(&mut self, symbols: &SymbolMap) {
+        let symbols = Rc::new(symbols.clone());
+
+        for (name, _, is_function) in symbols.symbols() {
+            if !is_function {
+                continue;
+            }
+
+            let symbols = symbols.clone();
+            let owned_name = name.to_owned();
+
+            self.register(name, move |call| {
+                let fn_type = call.get_fn_type();
+                let address = symbols.get_function(&owned_name, fn_type).ok_or_else(|| {
+                    let name = owned_name.clone();
+                    let fn_type = fn_type.print_to_string().to_string();
+
+                    ForeignCallError::Failed(SymbolMapError::SignatureMismatch { name, fn_type }.to_string())
+                })?;
+
+                unsafe { call_host_function(address, call.get_args(), fn_type) }
+            });
+        }
+    }
+
+    /// Registers shims for `strlen`, `memcmp`, `malloc`, `calloc`, `free`, `putchar`, `puts` and `printf`,
+    /// operating on the registry's `ForeignMemory`.
+    ///
+    /// `printf` supports the `d`, `i`, `u`, `x`, `X`, `o`, `c`, `s`, `p`, `f` and `F` conversions,
+    /// along with flags, widths, precisions and length modifiers.
+    pub fn add_libc_shims(&mut self) {
+        self.register("strlen", |call| {
+            let len = call.read_c_string(call.get_pointer_arg(0)?)?.len();
+
+            call.int_return(len as u64)
+        });
+        self.register("memcmp", |call| {
+            let len = call.get_int_arg(2)?;
+            let lhs = call.read_bytes(call.get_pointer_arg(0)?, len)?;
+            let rhs = call.read_bytes(call.get_pointer_arg(1)?, len)?;
+            let ordering = lhs
+                .iter()
+                .zip(&rhs)
+                .find(|(lhs, rhs)| lhs != rhs)
+                .map_or(0, |(&lhs, &rhs)| i64::from(lhs) - i64::from(rhs));
+
+            call.int_return(ordering as u64)
+        });
+        self.register("malloc", |call| {
+            let ptr = call.allocate_heap(call.get_int_arg(0)?)?;
+
+            call.value_return(&ptr)
+        });
+        self.register("calloc", |call| {
+            let size = call.get_int_arg(0)?.saturating_mul(call.get_int_arg(1)?);
+            let ptr = call.allocate_heap(size)?;
+
+            call.value_return(&ptr)
+        });
+        self.register("free", |call| {
+            let ptr = call.get_pointer_arg(0)?;
+
+            if is_null(ptr) {
+                return Ok(None);
+            }
+
+            if !call.get_memory().deallocate(ptr) {
+                return Err(ForeignCallError::InvalidPointer(ptr));
+            }
+
+            limits::free_heap(ptr);
+
+            Ok(None)
+        });
+        self.register("putchar", |call| {
+            let c = call.get_int_arg(0)?;
+
+            write_stdout(call, &[c as u8])?;
+            call.int_return(c & 0xff)
+        });
+        self.register("puts", |call| {
+            let mut line = call.read_c_string(call.get_pointer_arg(0)?)?;
+
+            line.push(b'\n');
+            write_stdout(call, &line)?;
+            call.int_return(line.len() as u64)
+        });
+        self.register("printf", |call| {
+            let format = call.read_c_string(call.get_pointer_arg(0)?)?;
+            let out = format_printf(call, &format, 1)?;
+
+            write_stdout(call, &out)?;
+            call.int_return(out.len() as u64)
+        });
+    }
+
+    /// Calls the function registered for `name`, or applies the fallback policy if there is none.
+    ///
+    /// Returns the call's return value, or `None` for `void` functions. This is meant to be called from
+    /// `MiriHooks::call_by_name`, which is how `SimpleMiriHost` uses it.
+    pub fn call(
+        &mut self,
+        memory: &dyn ForeignMemory,
+        name: &str,
+        args: &[GenericValueRef<'_>],
+        fn_type: FunctionType<'ctx>,
+    ) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+        let function = match self.exact.get_mut(name) {
+            Some(function) => Some(function),
+            None => self
+                .patterns
+                .iter_mut()
+                .rev()
+                .find(|(pattern, _)| glob_matches(pattern, name))
+                .map(|(_, function)| function),
+        };
+        let function = match function {
+            Some(function) => function,
+            None => return call_fallback(self.fallback, name, args, fn_type),
+        };
+        let mut call = ForeignCall {
+            name,
+            args,
+            fn_type,
+            memory,
+            stdout: &mut *self.stdout,
+        };
+
+        function(&mut call)
+    }
+}
+
+impl Default for ForeignFunctionRegistry<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ForeignFunctionRegistry<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&str> = self.exact.keys().map(String::as_str).collect();
+
+        names.sort_unstable();
+
+        f.debug_struct("ForeignFunctionRegistry")
+            .field("names", &names)
+            .field(
+                "patterns",
+                &self.patterns.iter().map(|(pattern, _)| pattern).collect::<Vec<_>>(),
+            )
+            .field("fallback", &self.fallback)
+            .finish()
+    }
+}
+
+/// The alignment of memory returned by the `malloc` and `calloc` shims, as guaranteed by glibc on 64 bit targets.
+const MALLOC_ALIGN: u64 = 16;
+
+fn offset_pointer(ptr: MiriPointer, offset: u64) -> MiriPointer {
+    MiriPointer {
+        addr: ptr.addr.wrapping_add(offset),
+        prov: ptr.prov,
+    }
+}
+
+fn is_null(ptr: MiriPointer) -> bool {
+    ptr.addr == 0 && ptr.prov.alloc_id == 0
+}
+
+fn write_stdout(call: &mut ForeignCall<'_, '_>, bytes: &[u8]) -> Result<(), ForeignCallError> {
+    call.get_stdout()
+        .write_all(bytes)
+        .map_err(|err| ForeignCallError::Failed(err.to_string()))
+}
+
+fn call_fallback<'ctx>(
+    fallback: ForeignFallback,
+    name: &str,
+    args: &[GenericValueRef<'_>],
+    fn_type: FunctionType<'ctx>,
+) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+    let unsupported = || ForeignCallError::UnsupportedSignature(fn_type.print_to_string().to_string());
+
+    match fallback {
+        ForeignFallback::Error => Err(ForeignCallError::Unresolved(name.to_owned())),
+        ForeignFallback::Stub => match fn_type.get_return_type() {
+            None => Ok(None),
+            Some(BasicTypeEnum::IntType(int_type)) => Ok(Some(GenericValue::new_int(0, &int_type, false))),
+            Some(BasicTypeEnum::FloatType(float_type)) => Ok(Some(GenericValue::new_float(0.0, &float_type))),
+            Some(BasicTypeEnum::PointerType(_)) => {
+                let null = MiriPointer {
+                    addr: 0,
+                    prov: MiriProvenance { alloc_id: 0, tag: 0 },
+                };
+
+                Ok(Some(unsafe {
+                    GenericValue::create_generic_value_of_miri_pointer(null)
+                }))
+            },
+            Some(_) => Err(unsupported()),
+        },
+        ForeignFallback::HostSymbol => {
+            let address =
+                search_for_address_of_symbol(name).ok_or_else(|| ForeignCallError::Unresolved(name.to_owned()))?;
+
+            unsafe { call_host_function(address, args, fn_type) }
+        },
+    }
+}
+
+/// Calls the host function at `address` as a function of type `fn_type`, if it only takes and returns integers.
+unsafe fn call_host_function<'ctx>(
+    address: usize,
+    args: &[GenericValueRef<'_>],
+    fn_type: FunctionType<'ctx>,
+) -> Result<Option<GenericValue<'ctx>>, ForeignCallError> {
+    let unsupported = || ForeignCallError::UnsupportedSignature(fn_type.print_to_string().to_string());
+    let is_word =
+        |ty: &BasicTypeEnum<'_>| matches!(ty, BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() <= 64);
+    let return_type = fn_type.get_return_type();
+
+    if fn_type.is_var_arg()
+        || !fn_type.get_param_types().iter().all(is_word)
+        || !return_type.as_ref().map_or(true, is_word)
+        || args.len() > 6
+    {
+        return Err(unsupported());
+    }
+
+    let words: Vec<u64> = args.iter().map(|arg| arg.as_int() as u64).collect();
+    let result = call_host_symbol(address, &words);
+
+    Ok(match return_type {
+        Some(BasicTypeEnum::IntType(int_type)) => Some(GenericValue::new_int(result, &int_type, false)),
+        _ => None,
+    })
+}
+
+/// Calls the native function at `address` with up to six integer arguments. Narrower integers are passed in
+/// full registers, whose upper bits the C calling conventions of supported targets leave unspecified anyway.
+unsafe fn call_host_symbol(address: usize, args: &[u64]) -> u64 {
+    use std::mem::transmute;
+
+    match *args {
+        [] => transmute::<usize, extern "C" fn() -> u64>(address)(),
+        [a] => transmute::<usize, extern "C" fn(u64) -> u64>(address)(a),
+        [a, b] => transmute::<usize, extern "C" fn(u64, u64) -> u64>(address)(a, b),
+        [a, b, c] => transmute::<usize, extern "C" fn(u64, u64, u64) -> u64>(address)(a, b, c),
+        [a, b, c, d] => transmute::<usize, extern "C" fn(u64, u64, u64, u64) -> u64>(address)(a, b, c, d),
+        [a, b, c, d, e] => transmute::<usize, extern "C" fn(u64, u64, u64, u64, u64) -> u64>(address)(a, b, c, d, e),
+        [a, b, c, d, e, f] => {
+            transmute::<usize, extern "C" fn(u64, u64, u64, u64, u64, u64) -> u64>(address)(a, b, c, d, e, f)
+        },
+        _ => unreachable!("host symbols are called with at most six arguments"),
+    }
+}
+
+/// Matches `name` against a glob `pattern`, in which `*` matches any run of characters and `?` any single one.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it currently matches up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Formats `format` the way `printf` does, taking variadic arguments of `call` starting at `first_arg`.
+fn format_printf(call: &ForeignCall<'_, '_>, format: &[u8], first_arg: usize) -> Result<Vec<u8>, ForeignCallError> {
+    let mut out = Vec::with_capacity(format.len());
+    let mut next_arg = first_arg;
+    let mut idx = 0;
+    let mut take_arg = || {
+        let arg = call
+            .get_args()
+            .get(next_arg)
+            .ok_or(ForeignCallError::MissingArgument(next_arg));
+
+        next_arg += 1;
+        arg
+    };
+
+    while idx < format.len() {
+        if format[idx] != b'%' {
+            out.push(format[idx]);
+            idx += 1;
+            continue;
+        }
+
+        idx += 1;
+
+        let (mut left, mut zero, mut plus, mut space, mut alternate) = (false, false, false, false, false);
+
+        while let Some(&flag) = format.get(idx) {
+            match flag {
+                b'-' => left = true,
+                b'0' => zero = true,
+                b'+' => plus = true,
+                b' ' => space = true,
+                b'#' => alternate = true,
+                _ => break,
+            }
+
+            idx += 1;
+        }
+
+        let width = if format.get(idx) == Some(&b'*') {
+            let arg = take_arg()?.as_int() as u32 as i32;
+
+            // A negative width taken from an argument is a `-` flag followed by a positive one
+            left |= arg < 0;
+            idx += 1;
+            Some(arg.unsigned_abs() as usize)
+        } else {
+            parse_number(format, &mut idx)
+        };
+
+        let mut precision = None;
+
+        if format.get(idx) == Some(&b'.') {
+            idx += 1;
+
+            if format.get(idx) == Some(&b'*') {
+                let arg = take_arg()?.as_int() as u32 as i32;
+
+                precision = usize::try_from(arg).ok();
+                idx += 1;
+            } else {
+                precision = Some(parse_number(format, &mut idx).unwrap_or(0));
+            }
+        }
+
+        let mut long = false;
+        let mut short_bits = None;
+
+        while let Some(&modifier) = format.get(idx) {
+            match modifier {
+                b'l' | b'z' | b'j' | b't' | b'L' | b'q' => long = true,
+                b'h' => short_bits = Some(if short_bits.is_some() { 8 } else { 16 }),
+                _ => break,
+            }
+
+            idx += 1;
+        }
+
+        let conversion = match format.get(idx) {
+            Some(&conversion) => conversion,
+            None => return Err(ForeignCallError::Failed("printf format ends in `%`".to_owned())),
+        };
+
+        idx += 1;
+
+        let bits = if long { 64 } else { short_bits.unwrap_or(32) };
+        let (prefix, body, numeric) = match conversion {
+            b'%' => {
+                out.push(b'%');
+                continue;
+            },
+            b'd' | b'i' => {
+                let value = take_arg()?.as_int() as u64;
+                let value = ((value << (64 - bits)) as i64) >> (64 - bits);
+                let sign = if value < 0 {
+                    "-"
+                } else if plus {
+                    "+"
+                } else if space {
+                    " "
+                } else {
+                    ""
+                };
+
+                (sign.to_owned(), value.unsigned_abs().to_string().into_bytes(), true)
+            },
+            b'u' | b'x' | b'X' | b'o' => {
+                let value = take_arg()?.as_int() as u64;
+                let value = if bits == 64 { value } else { value & ((1 << bits) - 1) };
+                let (prefix, body) = match conversion {
+                    b'x' => ("0x", format!("{:x}", value)),
+                    b'X' => ("0X", format!("{:X}", value)),
+                    b'o' => ("0", format!("{:o}", value)),
+                    _ => ("", value.to_string()),
+                };
+                let prefix = if alternate && value != 0 { prefix } else { "" };
+
+                (prefix.to_owned(), body.into_bytes(), true)
+            },
+            b'c' => (String::new(), vec![take_arg()?.as_int() as u8], false),
+            b's' => {
+                let mut string = call.read_c_string(take_arg()?.as_miri_pointer())?;
+
+                if let Some(precision) = precision {
+                    string.truncate(precision);
+                }
+
+                (String::new(), string, false)
+            },
+            b'p' => (
+                "0x".to_owned(),
+                format!("{:x}", take_arg()?.as_miri_pointer().addr).into_bytes(),
+                false,
+            ),
+            b'f' | b'F' => {
+                let value = take_arg()?.as_f64();
+                let sign = if value.is_sign_negative() {
+                    "-"
+                } else if plus {
+                    "+"
+                } else if space {
+                    " "
+                } else {
+                    ""
+                };
+                let mut body = format!("{:.*}", precision.unwrap_or(6), value.abs());
+
+                if conversion == b'F' {
+                    body.make_ascii_uppercase();
+                }
+
+                (sign.to_owned(), body.into_bytes(), value.is_finite())
+            },
+            conversion => {
+                return Err(ForeignCallError::Failed(format!(
+                    "unsupported printf conversion `%{}`",
+                    conversion as char
+                )))
+            },
+        };
+
+        let len = prefix.len() + body.len();
+        let padding = width.unwrap_or(0).saturating_sub(len);
+
+        if left {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(&body);
+            out.extend(std::iter::repeat(b' ').take(padding));
+        } else if zero && numeric {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend(std::iter::repeat(b'0').take(padding));
+            out.extend_from_slice(&body);
+        } else {
+            out.extend(std::iter::repeat(b' ').take(padding));
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(&body);
+        }
+    }
+
+    Ok(out)
+}
+
+fn parse_number(format: &[u8], idx: &mut usize) -> Option<usize> {
+    let start = *idx;
+
+    while format.get(*idx).map_or(false, u8::is_ascii_digit) {
+        *idx += 1;
+    }
+
+    std::str::from_utf8(&format[start..*idx]).ok()?.parse().ok()
+}
+
+#[test]
+fn test_glob_matches() {
+    assert!(glob_matches("strlen", "strlen"));
+    assert!(!glob_matches("strlen", "strnlen"));
+    assert!(glob_matches("str*", "strnlen"));
+    assert!(glob_matches("*len", "strnlen"));
+    assert!(glob_matches("s*n*n", "strnlen"));
+    assert!(glob_matches("str?len", "strnlen"));
+    assert!(!glob_matches("str?len", "strlen"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("?", ""));
+    assert!(glob_matches("__*_chk", "__printf_chk"));
+}
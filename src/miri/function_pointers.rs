@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use llvm_sys::miri::MiriPointer;
+
+use crate::types::FunctionType;
+use crate::values::{FunctionValue, GenericValue, GenericValueRef};
+
+/// A Rust closure which indirect calls can be made to, as registered with
+/// `ExecutionEngine::register_host_function`.
+pub(crate) type HostFunction<'ctx> = dyn FnMut(&[GenericValueRef<'_>]) -> Option<GenericValue<'ctx>> + 'ctx;
+
+/// What a function pointer known to an `ExecutionEngine` points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionPointerTarget<'ctx> {
+    /// A function of one of the engine's modules.
+    Function(FunctionValue<'ctx>),
+    /// A Rust closure registered with `ExecutionEngine::register_host_function`, of this type.
+    Host(FunctionType<'ctx>),
+}
+
+impl<'ctx> FunctionPointerTarget<'ctx> {
+    /// Gets the type calls through the pointer must have.
+    pub fn get_type(self) -> FunctionType<'ctx> {
+        match self {
+            FunctionPointerTarget::Function(function) => function.get_type(),
+            FunctionPointerTarget::Host(fn_type) => fn_type,
+        }
+    }
+}
+
+struct Target<'ctx> {
+    target: FunctionPointerTarget<'ctx>,
+    host_function: Option<Rc<RefCell<Box<HostFunction<'ctx>>>>>,
+}
+
+/// The function pointers of an `ExecutionEngine`, which map both ways between the pointers the
+/// installed `MiriHooks` allocated for them and the functions they point to.
+#[derive(Default)]
+pub(crate) struct FunctionPointers<'ctx> {
+    pointers: HashMap<FunctionValue<'ctx>, MiriPointer>,
+    /// Targets by the allocation id and address of their pointer.
+    targets: HashMap<(u64, u64), Target<'ctx>>,
+}
+
+impl<'ctx> FunctionPointers<'ctx> {
+    pub(crate) fn get_pointer(&self, function: FunctionValue<'ctx>) -> Option<MiriPointer> {
+        self.pointers.get(&function).copied()
+    }
+
+    pub(crate) fn add_function(&mut self, function: FunctionValue<'ctx>, ptr: MiriPointer) {
+        self.pointers.insert(function, ptr);
+        self.targets.insert(
+            key(ptr),
+            Target {
+                target: FunctionPointerTarget::Function(function),
+                host_function: None,
+            },
+        );
+    }
+
+    pub(crate) fn add_host_function(
+        &mut self,
+        fn_type: FunctionType<'ctx>,
+        function: Box<HostFunction<'ctx>>,
+        ptr: MiriPointer,
+    ) {
+        self.targets.insert(
+            key(ptr),
+            Target {
+                target: FunctionPointerTarget::Host(fn_type),
+                host_function: Some(Rc::new(RefCell::new(function))),
+            },
+        );
+    }
+
+    pub(crate) fn resolve(&self, ptr: MiriPointer) -> Option<FunctionPointerTarget<'ctx>> {
+        self.targets.get(&key(ptr)).map(|target| target.target)
+    }
+
+    /// Gets the closure a host function pointer points to. It's shared so that it can be called
+    /// without keeping the table borrowed, since it may register function pointers of its own.
+    pub(crate) fn get_host_function(&self, ptr: MiriPointer) -> Option<Rc<RefCell<Box<HostFunction<'ctx>>>>> {
+        self.targets.get(&key(ptr))?.host_function.clone()
+    }
+
+    /// Forgets every function pointer, since they belong to the hooks which allocated them.
+    pub(crate) fn clear(&mut self) {
+        self.pointers.clear();
+        self.targets.clear();
+    }
+}
+
+impl fmt::Debug for FunctionPointers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FunctionPointers")
+            .field("functions", &self.pointers.len())
+            .field("targets", &self.targets.len())
+            .finish()
+    }
+}
+
+fn key(ptr: MiriPointer) -> (u64, u64) {
+    (ptr.prov.alloc_id, ptr.addr)
+}
+
+/// A call through a function pointer which the engine resolved itself, rather than handing it to
+/// `MiriHooks::call_by_pointer`.
+pub(crate) enum ResolvedCall<'ctx> {
+    /// The callee was a host function, which already returned this.
+    Returned(Option<GenericValue<'ctx>>),
+    /// The callee is a function of the module, which still has to be run with these arguments.
+    Function(FunctionValue<'ctx>, Vec<GenericValue<'ctx>>),
+}
@@ -0,0 +1,354 @@
+use llvm_sys::core::{
+    LLVMAliasGetAliasee, LLVMConstIntGetZExtValue, LLVMConstRealGetDouble, LLVMGetConstOpcode,
+    LLVMGetElementAsConstant, LLVMGetOperand, LLVMGetTypeKind, LLVMGetValueKind, LLVMIsNull, LLVMIsUndef,
+    LLVMPrintValueToString, LLVMTypeOf,
+};
+use llvm_sys::prelude::LLVMValueRef;
+use llvm_sys::{LLVMOpcode, LLVMTypeKind, LLVMValueKind};
+
+use crate::module::Module;
+use crate::support::LLVMString;
+use crate::targets::{ByteOrdering, TargetData};
+use crate::types::{AsTypeRef, BasicTypeEnum, PointerType};
+use crate::values::{AsValueRef, FunctionValue, GlobalValue};
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// What a pointer stored in the initializer of a global variable points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationTarget<'ctx> {
+    Global(GlobalValue<'ctx>),
+    Function(FunctionValue<'ctx>),
+}
+
+/// A pointer in a `GlobalImage`, which can only be written once its target has been given an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation<'ctx> {
+    /// Where the pointer is stored, in bytes from the start of the global.
+    pub offset: u64,
+    /// The type of the stored pointer.
+    pub ptr_type: PointerType<'ctx>,
+    pub target: RelocationTarget<'ctx>,
+    /// How many bytes past the start of `target` the pointer points, as computed from constant
+    /// `getelementptr` expressions.
+    pub addend: i64,
+}
+
+/// The in-memory contents of a global variable, laid out according to a `TargetData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalImage<'ctx> {
+    pub global: GlobalValue<'ctx>,
+    /// The allocation size of the global's type.
+    pub size: u64,
+    /// The alignment of the global, either as set on it or as preferred by the target.
+    pub align: u32,
+    /// The serialized initializer. Padding, `undef` and `poison` are zeroed, as are the bytes of
+    /// every pointer listed in `relocations`.
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation<'ctx>>,
+}
+
+impl<'ctx> GlobalImage<'ctx> {
+    /// Lays out `global` and serializes its initializer.
+    pub fn new(global: GlobalValue<'ctx>, target_data: &TargetData) -> Result<Self, GlobalLayoutError> {
+        let initializer = match global.get_initializer() {
+            Some(initializer) => initializer,
+            None => return Err(GlobalLayoutError::NoInitializer(global_name(global))),
+        };
+        let value_type = global.get_value_type();
+        let size = target_data.get_abi_size(&value_type);
+        let align = global
+            .get_alignment()
+            .max(target_data.get_preferred_alignment_of_global(&global));
+
+        let mut serializer = Serializer {
+            global,
+            target_data,
+            little_endian: target_data.get_byte_ordering() == ByteOrdering::LittleEndian,
+            bytes: vec![0; size as usize],
+            relocations: Vec::new(),
+        };
+
+        serializer.write(initializer.as_value_ref(), 0)?;
+
+        Ok(GlobalImage {
+            global,
+            size,
+            align,
+            bytes: serializer.bytes,
+            relocations: serializer.relocations,
+        })
+    }
+
+    /// Lays out every global variable `module` defines, skipping the ones it only declares.
+    pub fn from_module(module: &Module<'ctx>, target_data: &TargetData) -> Result<Vec<Self>, GlobalLayoutError> {
+        module
+            .get_globals()
+            .filter(|global| !global.is_declaration())
+            .map(|global| GlobalImage::new(global, target_data))
+            .collect()
+    }
+}
+
+/// Why the globals of a module couldn't be laid out or materialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlobalLayoutError {
+    /// The global is only declared, so there's nothing to lay out.
+    NoInitializer(String),
+    /// The initializer of `global` contains `constant`, which can't be serialized to bytes.
+    UnsupportedConstant { global: String, constant: String },
+    /// The initializer of `global` points to `target`, which wasn't materialized along with it.
+    UnresolvedTarget { global: String, target: String },
+    /// No `MiriHooks` are installed, or they are currently running.
+    NoHooks,
+    /// The installed `MiriHooks` rejected the write of the initializer of this global.
+    Rejected(String),
+}
+
+impl Error for GlobalLayoutError {}
+
+impl Display for GlobalLayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GlobalLayoutError::NoInitializer(global) => write!(f, "global `{}` has no initializer", global),
+            GlobalLayoutError::UnsupportedConstant { global, constant } => {
+                write!(
+                    f,
+                    "initializer of global `{}` contains unsupported constant `{}`",
+                    global, constant
+                )
+            },
+            GlobalLayoutError::UnresolvedTarget { global, target } => write!(
+                f,
+                "initializer of global `{}` points to `{}`, which has no address",
+                global, target
+            ),
+            GlobalLayoutError::NoHooks => write!(f, "no Miri hooks are available"),
+            GlobalLayoutError::Rejected(global) => {
+                write!(f, "the Miri hooks rejected the initializer of global `{}`", global)
+            },
+        }
+    }
+}
+
+pub(crate) fn global_name(global: GlobalValue<'_>) -> String {
+    global.get_name().to_string_lossy().into_owned()
+}
+
+pub(crate) fn target_name(target: RelocationTarget<'_>) -> String {
+    match target {
+        RelocationTarget::Global(global) => global_name(global),
+        RelocationTarget::Function(function) => function.get_name().to_string_lossy().into_owned(),
+    }
+}
+
+struct Serializer<'a, 'ctx> {
+    global: GlobalValue<'ctx>,
+    target_data: &'a TargetData,
+    little_endian: bool,
+    bytes: Vec<u8>,
+    relocations: Vec<Relocation<'ctx>>,
+}
+
+impl<'a, 'ctx> Serializer<'a, 'ctx> {
+    fn write(&mut self, value: LLVMValueRef, offset: u64) -> Result<(), GlobalLayoutError> {
+        // Zeroes of every type, as well as undef and poison, are already covered by the zeroed buffer
+        if unsafe { LLVMIsNull(value) != 0 || LLVMIsUndef(value) != 0 } {
+            return Ok(());
+        }
+
+        let ty = unsafe { BasicTypeEnum::new(LLVMTypeOf(value)) };
+
+        match ty {
+            BasicTypeEnum::IntType(int_type) => {
+                let kind = unsafe { LLVMGetValueKind(value) };
+
+                if kind != LLVMValueKind::LLVMConstantIntValueKind || int_type.get_bit_width() > 64 {
+                    return Err(self.unsupported(value));
+                }
+
+                let int = unsafe { LLVMConstIntGetZExtValue(value) };
+
+                self.write_int(int, self.target_data.get_store_size(&int_type), offset);
+            },
+            BasicTypeEnum::FloatType(float_type) => {
+                if unsafe { LLVMGetValueKind(value) } != LLVMValueKind::LLVMConstantFPValueKind {
+                    return Err(self.unsupported(value));
+                }
+
+                let mut lossy = 0;
+                let float = unsafe { LLVMConstRealGetDouble(value, &mut lossy) };
+
+                match unsafe { LLVMGetTypeKind(float_type.as_type_ref()) } {
+                    LLVMTypeKind::LLVMFloatTypeKind => self.write_int((float as f32).to_bits() as u64, 4, offset),
+                    LLVMTypeKind::LLVMDoubleTypeKind => self.write_int(float.to_bits(), 8, offset),
+                    _ => return Err(self.unsupported(value)),
+                }
+            },
+            BasicTypeEnum::PointerType(ptr_type) => {
+                let (target, addend) = self.resolve_pointer(value)?;
+
+                self.relocations.push(Relocation {
+                    offset,
+                    ptr_type,
+                    target,
+                    addend,
+                });
+            },
+            BasicTypeEnum::StructType(struct_type) => {
+                for index in 0..struct_type.count_fields() {
+                    let field_offset = self
+                        .target_data
+                        .offset_of_element(&struct_type, index)
+                        .expect("field index should be in bounds");
+
+                    self.write(self.element(value, index)?, offset + field_offset)?;
+                }
+            },
+            BasicTypeEnum::ArrayType(array_type) => {
+                let stride = self.target_data.get_abi_size(&array_type.get_element_type());
+
+                for index in 0..array_type.len() {
+                    self.write(self.element(value, index)?, offset + stride * index as u64)?;
+                }
+            },
+            BasicTypeEnum::VectorType(vector_type) => {
+                let element_type = vector_type.get_element_type();
+                let stride = self.target_data.get_store_size(&element_type);
+
+                // Vectors of elements which aren't a whole number of bytes are packed into bits
+                if self.target_data.get_bit_size(&element_type) != stride * 8 {
+                    return Err(self.unsupported(value));
+                }
+
+                for index in 0..vector_type.get_size() {
+                    self.write(self.element(value, index)?, offset + stride * index as u64)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn write_int(&mut self, int: u64, size: u64, offset: u64) {
+        let bytes = if self.little_endian {
+            int.to_le_bytes()
+        } else {
+            int.to_be_bytes()
+        };
+        let size = size.min(8) as usize;
+        let bytes = if self.little_endian {
+            &bytes[..size]
+        } else {
+            &bytes[8 - size..]
+        };
+        let offset = offset as usize;
+
+        self.bytes[offset..offset + size].copy_from_slice(bytes);
+    }
+
+    fn element(&self, aggregate: LLVMValueRef, index: u32) -> Result<LLVMValueRef, GlobalLayoutError> {
+        unsafe {
+            match LLVMGetValueKind(aggregate) {
+                LLVMValueKind::LLVMConstantDataArrayValueKind | LLVMValueKind::LLVMConstantDataVectorValueKind => {
+                    Ok(LLVMGetElementAsConstant(aggregate, index))
+                },
+                LLVMValueKind::LLVMConstantStructValueKind
+                | LLVMValueKind::LLVMConstantArrayValueKind
+                | LLVMValueKind::LLVMConstantVectorValueKind => Ok(LLVMGetOperand(aggregate, index)),
+                _ => Err(self.unsupported(aggregate)),
+            }
+        }
+    }
+
+    fn resolve_pointer(&self, value: LLVMValueRef) -> Result<(RelocationTarget<'ctx>, i64), GlobalLayoutError> {
+        unsafe {
+            match LLVMGetValueKind(value) {
+                LLVMValueKind::LLVMFunctionValueKind => {
+                    let function = FunctionValue::new(value).expect("value should be a function");
+
+                    Ok((RelocationTarget::Function(function), 0))
+                },
+                LLVMValueKind::LLVMGlobalVariableValueKind => {
+                    Ok((RelocationTarget::Global(GlobalValue::new(value)), 0))
+                },
+                LLVMValueKind::LLVMGlobalAliasValueKind => self.resolve_pointer(LLVMAliasGetAliasee(value)),
+                LLVMValueKind::LLVMConstantExprValueKind => match LLVMGetConstOpcode(value) {
+                    LLVMOpcode::LLVMBitCast | LLVMOpcode::LLVMAddrSpaceCast => {
+                        self.resolve_pointer(LLVMGetOperand(value, 0))
+                    },
+                    LLVMOpcode::LLVMGetElementPtr => {
+                        let (target, addend) = self.resolve_pointer(LLVMGetOperand(value, 0))?;
+                        let offset = self.gep_offset(value).ok_or_else(|| self.unsupported(value))?;
+
+                        Ok((target, addend.wrapping_add(offset)))
+                    },
+                    _ => Err(self.unsupported(value)),
+                },
+                _ => Err(self.unsupported(value)),
+            }
+        }
+    }
+
+    /// Computes the byte offset of a constant `getelementptr` expression from its base pointer.
+    #[llvm_versions(14..)]
+    fn gep_offset(&self, gep: LLVMValueRef) -> Option<i64> {
+        use llvm_sys::core::{LLVMConstIntGetSExtValue, LLVMGetGEPSourceElementType, LLVMGetNumOperands};
+
+        let num_operands = unsafe { LLVMGetNumOperands(gep) } as u32;
+        let mut ty = unsafe { BasicTypeEnum::new(LLVMGetGEPSourceElementType(gep)) };
+        let mut offset = 0i64;
+
+        for operand in 1..num_operands {
+            let index = unsafe { LLVMGetOperand(gep, operand) };
+
+            if unsafe { LLVMGetValueKind(index) } != LLVMValueKind::LLVMConstantIntValueKind {
+                return None;
+            }
+
+            let index = unsafe { LLVMConstIntGetSExtValue(index) };
+
+            // The first index steps over whole source elements, the rest into the current type
+            if operand == 1 {
+                offset = offset.wrapping_add(index.wrapping_mul(self.target_data.get_abi_size(&ty) as i64));
+                continue;
+            }
+
+            match ty {
+                BasicTypeEnum::StructType(struct_type) => {
+                    offset =
+                        offset.wrapping_add(self.target_data.offset_of_element(&struct_type, index as u32)? as i64);
+                    ty = struct_type.get_field_type_at_index(index as u32)?;
+                },
+                BasicTypeEnum::ArrayType(array_type) => {
+                    ty = array_type.get_element_type();
+                    offset = offset.wrapping_add(index.wrapping_mul(self.target_data.get_abi_size(&ty) as i64));
+                },
+                BasicTypeEnum::VectorType(vector_type) => {
+                    ty = vector_type.get_element_type();
+                    offset = offset.wrapping_add(index.wrapping_mul(self.target_data.get_store_size(&ty) as i64));
+                },
+                _ => return None,
+            }
+        }
+
+        Some(offset)
+    }
+
+    /// Before LLVM 14, the C API has no way of getting the source element type of a
+    /// `getelementptr` expression, so its offset can't be computed.
+    #[llvm_versions(..=13)]
+    fn gep_offset(&self, _gep: LLVMValueRef) -> Option<i64> {
+        None
+    }
+
+    fn unsupported(&self, constant: LLVMValueRef) -> GlobalLayoutError {
+        let constant = unsafe { LLVMString::new(LLVMPrintValueToString(constant)) };
+
+        GlobalLayoutError::UnsupportedConstant {
+            global: global_name(self.global),
+            constant: constant.to_string(),
+        }
+    }
+}
@@ -0,0 +1,733 @@
+use libc::{c_char, c_int};
+use llvm_sys::execution_engine::{LLVMGenericValueArrayRef, LLVMGenericValueRef};
+use llvm_sys::miri::{MiriErrorTrace, MiriInterpCxOpaque, MiriPointer};
+use llvm_sys::prelude::LLVMTypeRef;
+use llvm_sys::target::{LLVMStoreSizeOfType, LLVMTargetDataRef};
+
+use crate::execution_engine::{ExecutionEngine, InterpreterError};
+use crate::miri::debugger::{Breakpoints, MemoryAccess};
+use crate::miri::function_pointers::{FunctionPointers, ResolvedCall};
+use crate::miri::limits;
+use crate::miri::probes::Probes;
+use crate::miri::snapshot::Journal;
+use crate::miri::{FunctionPointerTarget, InterpreterLimit, Limits, StackTrace};
+use crate::types::{BasicTypeEnum, FunctionType};
+use crate::values::{
+    FromGenericValue, FunctionValue, GenericValue, GenericValueArrayRef, GenericValueRef, InstructionValue,
+};
+
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// The callbacks LLVM's interpreter makes into a Miri-style host while executing a module.
+///
+/// Each method corresponds to one of the raw `ExecutionEngine::set_miri_*` setters. Implementors
+/// are handed typed values rather than the raw pointers LLVM passes across the C API, and
+/// `ExecutionEngine::install_miri_hooks` takes care of generating the trampolines and keeping the
+/// implementor alive for as long as the engine may call into it.
+///
+/// Methods returning `bool` report whether the operation succeeded; returning `false` makes the
+/// interpreter stop the current thread with an error, which `ExecutionEngine::step_thread` returns
+/// as `InterpreterError::HookRejected`.
+///
+/// The `GenericValueRef`s passed to a hook are owned by the interpreter and are only valid for
+/// the duration of that call.
+pub trait MiriHooks<'ctx> {
+    /// Allocates `size` bytes aligned to `align`. `is_heap` is `false` for stack allocations.
+    fn malloc(&mut self, size: u64, align: u64, is_heap: bool) -> MiriPointer;
+
+    /// Deallocates memory previously returned by `malloc`.
+    fn free(&mut self, ptr: MiriPointer) -> bool;
+
+    /// Reads a value of type `ty` from `src` into `dest`.
+    fn load(&mut self, dest: GenericValueRef<'_>, src: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool;
+
+    /// Writes `value`, which is of type `ty`, to `dest`.
+    fn store(&mut self, value: GenericValueRef<'_>, dest: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool;
+
+    /// Offsets `base` by `offset` bytes, preserving its provenance.
+    fn get_element_pointer(&mut self, base: MiriPointer, offset: u64) -> MiriPointer;
+
+    /// Fills `len` bytes starting at `dest` with `value`.
+    fn memset(&mut self, dest: MiriPointer, value: u8, len: u64) -> bool;
+
+    /// Copies `src`, which lives in the interpreter's own memory, to `dest`.
+    fn memcpy(&mut self, dest: MiriPointer, src: &[u8]) -> bool;
+
+    /// Converts an integer into a pointer, recovering its provenance if possible.
+    fn int_to_ptr(&mut self, addr: u64) -> MiriPointer;
+
+    /// Converts a pointer into an integer, exposing its provenance.
+    fn ptr_to_int(&mut self, ptr: MiriPointer) -> u64;
+
+    /// Called for every call to a function the module only declares. The result of the call is
+    /// handed back to the interpreter through `ExecutionEngine::step_thread`.
+    fn call_by_name(&mut self, name: &str, args: &[GenericValueRef<'_>], fn_type: FunctionType<'ctx>) -> bool;
+
+    /// Called for every indirect call through a pointer the interpreter can't resolve itself, unless
+    /// it's one of the engine's function pointers, as returned by `ExecutionEngine::get_function_pointer`
+    /// and `ExecutionEngine::register_host_function`.
+    fn call_by_pointer(
+        &mut self,
+        callee: MiriPointer,
+        args: &[GenericValueRef<'_>],
+        fn_type: FunctionType<'ctx>,
+    ) -> bool;
+
+    /// Called once for each global variable after it has been allocated and initialized.
+    fn register_global(&mut self, name: &str, ptr: MiriPointer) -> bool {
+        let _ = (name, ptr);
+        true
+    }
+
+    /// Called when the interpreter reports an error, with the stack of the failing thread.
+    fn record_stack_trace(&mut self, trace: StackTrace<'ctx>) {
+        let _ = trace;
+    }
+
+    /// Called when an `InterpreterThread` enters `function`, a function the module defines, however
+    /// it was called. This and the two hooks below are only called for functions instrumented with
+    /// `ExecutionEngine::instrument_module`.
+    fn enter_function(&mut self, function: FunctionValue<'ctx>) {
+        let _ = function;
+    }
+
+    /// Called before an `InterpreterThread` executes `instruction`.
+    fn execute_instruction(&mut self, instruction: InstructionValue<'ctx>) {
+        let _ = instruction;
+    }
+
+    /// Called when `function` returns to its caller, or ends the `InterpreterThread` running it.
+    /// `value` is `None` for `void` functions.
+    fn return_from_function(&mut self, function: FunctionValue<'ctx>, value: Option<GenericValueRef<'_>>) {
+        let _ = (function, value);
+    }
+
+    /// Takes the return value of the call most recently handed to `call_by_name` or `call_by_pointer`.
+    /// `ExecutionEngine::interpret_function` uses this to complete calls, since it steps the function
+    /// itself.
+    fn take_return_value(&mut self) -> Option<GenericValue<'ctx>> {
+        None
+    }
+
+    /// Completes the call `thread_id` is blocked on, for a `ModelChecker` exploring the thread. It's
+    /// first asked right after the thread made the call, so that it's the call most recently handed
+    /// to `call_by_name` or `call_by_pointer`, and asked again after each step of another thread for
+    /// as long as this returns `CallCompletion::Waiting`.
+    ///
+    /// Completes every call right away with `take_return_value` by default.
+    fn complete_call(&mut self, thread_id: u64) -> CallCompletion<'ctx> {
+        let _ = thread_id;
+
+        CallCompletion::Returned(self.take_return_value())
+    }
+
+    /// Copies the memory these hooks manage, for `ExecutionEngine::take_interpreter_snapshot`. Returns
+    /// `None` if they can't, which is the default, in which case threads can't be snapshotted.
+    fn snapshot_memory(&mut self) -> Option<Box<dyn Any>> {
+        None
+    }
+
+    /// Puts the memory back the way it was when `snapshot`, which was returned by `snapshot_memory`,
+    /// was taken.
+    fn restore_memory(&mut self, snapshot: &dyn Any) {
+        let _ = snapshot;
+    }
+}
+
+/// Whether a call handed to the `MiriHooks` can return yet, as told by `MiriHooks::complete_call`.
+#[derive(Debug)]
+pub enum CallCompletion<'ctx> {
+    /// The call returned, with `None` for `void` functions.
+    Returned(Option<GenericValue<'ctx>>),
+    /// The call has to wait on another thread, e.g. for a lock it holds to be released.
+    Waiting,
+}
+
+/// A panic which occurred inside one of the installed `MiriHooks`.
+///
+/// Panics are never allowed to unwind into LLVM. Instead, they are caught at the hook boundary
+/// and returned by the next call to `ExecutionEngine::step_thread` or `ExecutionEngine::interpret_function`,
+/// which terminate the thread the hook was called from.
+pub struct MiriHookPanic {
+    hook: &'static str,
+    payload: Box<dyn Any + Send>,
+}
+
+impl MiriHookPanic {
+    /// Gets the name of the hook which panicked, such as `"load"` or `"call_by_name"`.
+    pub fn get_hook(&self) -> &'static str {
+        self.hook
+    }
+
+    /// Gets the panic message, if the payload was a string.
+    pub fn get_message(&self) -> Option<&str> {
+        if let Some(message) = self.payload.downcast_ref::<&str>() {
+            return Some(message);
+        }
+
+        self.payload.downcast_ref::<String>().map(|message| message.as_str())
+    }
+
+    /// Consumes the panic, returning its original payload.
+    pub fn into_payload(self) -> Box<dyn Any + Send> {
+        self.payload
+    }
+
+    /// Resumes unwinding with the original payload, now that LLVM is no longer on the stack.
+    pub fn resume_unwind(self) -> ! {
+        resume_unwind(self.payload)
+    }
+}
+
+impl fmt::Debug for MiriHookPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MiriHookPanic")
+            .field("hook", &self.hook)
+            .field("message", &self.get_message())
+            .finish()
+    }
+}
+
+impl fmt::Display for MiriHookPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get_message() {
+            Some(message) => write!(f, "Miri hook `{}` panicked: {}", self.hook, message),
+            None => write!(f, "Miri hook `{}` panicked", self.hook),
+        }
+    }
+}
+
+/// The boxed state whose address is handed to LLVM as the interpcx wrapper.
+pub(crate) struct MiriHookState<'ctx> {
+    hooks: RefCell<Box<dyn MiriHooks<'ctx> + 'ctx>>,
+    panic: RefCell<Option<MiriHookPanic>>,
+    pending_call: Cell<bool>,
+    resolved_call: RefCell<Option<ResolvedCall<'ctx>>>,
+    call_error: RefCell<Option<InterpreterError>>,
+    breakpoints: Rc<RefCell<Breakpoints<'ctx>>>,
+    function_pointers: Rc<RefCell<FunctionPointers<'ctx>>>,
+    limits: Rc<RefCell<Limits>>,
+    probes: Rc<RefCell<Probes<'ctx>>>,
+    target_data: LLVMTargetDataRef,
+}
+
+/// Owns the hooks installed on an `ExecutionEngine`. It is shared between clones of
+/// the engine so that the hooks live until the last of them is dropped.
+#[derive(Default)]
+pub(crate) struct MiriHookSlot<'ctx> {
+    state: RefCell<Option<Box<MiriHookState<'ctx>>>>,
+    breakpoints: Rc<RefCell<Breakpoints<'ctx>>>,
+    function_pointers: Rc<RefCell<FunctionPointers<'ctx>>>,
+    limits: Rc<RefCell<Limits>>,
+    probes: Rc<RefCell<Probes<'ctx>>>,
+    journal: RefCell<Journal<'ctx>>,
+}
+
+impl<'ctx> MiriHookSlot<'ctx> {
+    pub(crate) fn install(&self, execution_engine: &ExecutionEngine<'ctx>, hooks: Box<dyn MiriHooks<'ctx> + 'ctx>) {
+        let state = Box::new(MiriHookState {
+            hooks: RefCell::new(hooks),
+            panic: RefCell::new(None),
+            pending_call: Cell::new(false),
+            resolved_call: RefCell::new(None),
+            call_error: RefCell::new(None),
+            breakpoints: self.breakpoints.clone(),
+            function_pointers: self.function_pointers.clone(),
+            limits: self.limits.clone(),
+            probes: self.probes.clone(),
+            target_data: execution_engine.get_target_data().as_mut_ptr(),
+        });
+
+        execution_engine.set_miri_interpcx_wrapper(&*state as *const MiriHookState<'ctx> as *mut MiriInterpCxOpaque);
+        execution_engine.set_miri_malloc(Some(miri_malloc));
+        execution_engine.set_miri_free(Some(miri_free));
+        execution_engine.set_miri_load(Some(miri_load));
+        execution_engine.set_miri_store(Some(miri_store));
+        execution_engine.set_miri_get_element_pointer(Some(miri_get_element_pointer));
+        execution_engine.set_miri_memset(Some(miri_memset));
+        execution_engine.set_miri_memcpy(Some(miri_memcpy));
+        execution_engine.set_miri_inttoptr(Some(miri_int_to_ptr));
+        execution_engine.set_miri_ptrtoint(Some(miri_ptr_to_int));
+        execution_engine.set_miri_call_by_name(Some(miri_call_by_name));
+        execution_engine.set_miri_call_by_pointer(Some(miri_call_by_pointer));
+        execution_engine.set_miri_register_global(Some(miri_register_global));
+        execution_engine.set_miri_stack_trace_recorder(Some(miri_stack_trace_recorder));
+
+        // The previous hooks (if any) are only dropped once LLVM no longer points at them
+        *self.state.borrow_mut() = Some(state);
+        self.function_pointers.borrow_mut().clear();
+        self.limits.borrow_mut().clear_allocations();
+        self.journal.borrow_mut().clear();
+    }
+
+    /// Takes the panic recorded by the most recent hook to panic, if any. Once taken, the hooks
+    /// will be called again.
+    pub(crate) fn take_panic(&self) -> Option<MiriHookPanic> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.panic.borrow_mut().take())
+    }
+
+    /// Returns whether a call was handed to the hooks since this was last called, in which case
+    /// the thread which made it is waiting on its return value.
+    pub(crate) fn take_pending_call(&self) -> bool {
+        self.state
+            .borrow()
+            .as_ref()
+            .map_or(false, |state| state.pending_call.replace(false))
+    }
+
+    /// Takes the call through one of the engine's function pointers which was made since this was
+    /// last called, if any.
+    pub(crate) fn take_resolved_call(&self) -> Option<ResolvedCall<'ctx>> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.resolved_call.borrow_mut().take())
+    }
+
+    /// Takes the error the trampolines most recently recorded, if any, such as that of a failed call
+    /// through one of the engine's function pointers or of an exceeded limit.
+    pub(crate) fn take_call_error(&self) -> Option<InterpreterError> {
+        self.state
+            .borrow()
+            .as_ref()
+            .and_then(|state| state.call_error.borrow_mut().take())
+    }
+
+    pub(crate) fn is_installed(&self) -> bool {
+        self.state.borrow().is_some()
+    }
+
+    pub(crate) fn breakpoints(&self) -> &RefCell<Breakpoints<'ctx>> {
+        &self.breakpoints
+    }
+
+    pub(crate) fn function_pointers(&self) -> &RefCell<FunctionPointers<'ctx>> {
+        &self.function_pointers
+    }
+
+    pub(crate) fn limits(&self) -> &RefCell<Limits> {
+        &self.limits
+    }
+
+    pub(crate) fn probes(&self) -> &RefCell<Probes<'ctx>> {
+        &self.probes
+    }
+
+    pub(crate) fn journal(&self) -> &RefCell<Journal<'ctx>> {
+        &self.journal
+    }
+
+    /// Allocates the pointer for a new function pointer through the installed hooks. Returns `None`
+    /// if no hooks are installed, or if they are currently running.
+    pub(crate) fn allocate_function_pointer(&self) -> Option<MiriPointer> {
+        // Each function gets a (never initialized) byte of its own, so that its pointer has a unique
+        // address and provenance, but can't be read from or written to like data
+        self.with_hooks(|hooks| hooks.malloc(1, 1, false))
+    }
+
+    /// Calls `f` with the installed hooks. Returns `None` if no hooks are installed, or if they are
+    /// currently running.
+    pub(crate) fn with_hooks<R>(&self, f: impl FnOnce(&mut dyn MiriHooks<'ctx>) -> R) -> Option<R> {
+        let state = self.state.borrow();
+        let mut hooks = state.as_ref()?.hooks.try_borrow_mut().ok()?;
+
+        Some(f(&mut **hooks))
+    }
+}
+
+impl fmt::Debug for MiriHookSlot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MiriHookSlot")
+            .field("installed", &self.state.borrow().is_some())
+            .field("breakpoints", &self.breakpoints)
+            .field("function_pointers", &self.function_pointers)
+            .field("limits", &self.limits)
+            .field("probes", &self.probes)
+            .field("journal", &self.journal)
+            .finish()
+    }
+}
+
+// Two slots are only equal if they are the same slot, which is the case for clones of an engine
+impl PartialEq for MiriHookSlot<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for MiriHookSlot<'_> {}
+
+unsafe fn state_from_raw<'a>(ctx: *mut MiriInterpCxOpaque) -> &'a MiriHookState<'a> {
+    &*(ctx as *const MiriHookState<'a>)
+}
+
+/// Calls into the installed hooks, making sure a panic never unwinds into LLVM. If the hooks
+/// panic (or already did and the panic hasn't been reported yet), `on_panic` is returned instead.
+unsafe fn with_hooks<'a, R>(
+    ctx: *mut MiriInterpCxOpaque,
+    hook: &'static str,
+    on_panic: R,
+    f: impl FnOnce(&mut (dyn MiriHooks<'a> + 'a)) -> R,
+) -> R {
+    let state = state_from_raw(ctx);
+
+    if state.panic.borrow().is_some() {
+        return on_panic;
+    }
+
+    // The hooks are never called again after a panic until it has been reported,
+    // so there is no way to observe them in a broken state
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let mut hooks = state.hooks.borrow_mut();
+
+        f(&mut **hooks)
+    }));
+
+    match result {
+        Ok(value) => value,
+        Err(payload) => {
+            *state.panic.borrow_mut() = Some(MiriHookPanic { hook, payload });
+
+            on_panic
+        },
+    }
+}
+
+/// Records an access to `size` bytes at `ptr` against the watchpoints of the engine.
+unsafe fn record_access(ctx: *mut MiriInterpCxOpaque, ptr: MiriPointer, size: u64, access: MemoryAccess) {
+    state_from_raw(ctx)
+        .breakpoints
+        .borrow_mut()
+        .on_access(ptr, size, access);
+}
+
+unsafe fn store_size(ctx: *mut MiriInterpCxOpaque, ty: LLVMTypeRef) -> u64 {
+    LLVMStoreSizeOfType(state_from_raw(ctx).target_data, ty)
+}
+
+/// Records that `hook` rejected the operation it was handed, so that stepping the thread which made it
+/// errors, unless the hooks panicked or the trampolines already recorded a more specific error.
+/// Returns `accepted`, for handing back to the interpreter.
+fn check_accepted(state: &MiriHookState<'_>, hook: &'static str, accepted: bool) -> bool {
+    if !accepted && state.panic.borrow().is_none() {
+        state
+            .call_error
+            .borrow_mut()
+            .get_or_insert(InterpreterError::HookRejected { hook });
+    }
+
+    accepted
+}
+
+/// Records the limit the hooks exceeded while allocating on the module's behalf, if any, as the
+/// error of the call they were handling.
+fn record_exceeded_limit(state: &MiriHookState<'_>) {
+    if let Some(limit) = state.limits.borrow_mut().take_exceeded() {
+        *state.call_error.borrow_mut() = Some(InterpreterError::LimitExceeded(limit));
+    }
+}
+
+/// The pointer handed back to LLVM in place of a real one when a hook panics.
+fn null_pointer() -> MiriPointer {
+    // MiriPointer is a plain C struct of integers, for which all zeroes is valid
+    unsafe { std::mem::zeroed() }
+}
+
+unsafe fn str_from_raw<'a>(ptr: *const c_char, len: u64) -> Cow<'a, str> {
+    if ptr.is_null() {
+        return Cow::Borrowed("");
+    }
+
+    String::from_utf8_lossy(std::slice::from_raw_parts(ptr as *const u8, len as usize))
+}
+
+unsafe fn args_from_raw<'a>(args: LLVMGenericValueArrayRef) -> Vec<GenericValueRef<'a>> {
+    let args = GenericValueArrayRef::new(args);
+
+    (0..args.len()).filter_map(|idx| args.get_element_at(idx)).collect()
+}
+
+extern "C" fn miri_malloc(ctx: *mut MiriInterpCxOpaque, size: u64, align: u64, is_heap: bool) -> MiriPointer {
+    unsafe {
+        let state = state_from_raw(ctx);
+
+        if is_heap && !state.limits.borrow().allows_allocation(size) {
+            *state.call_error.borrow_mut() = Some(InterpreterError::LimitExceeded(InterpreterLimit::HeapSize));
+
+            return null_pointer();
+        }
+
+        let ptr = with_hooks(ctx, "malloc", null_pointer(), |hooks| {
+            hooks.malloc(size, align, is_heap)
+        });
+
+        if is_heap && state.panic.borrow().is_none() {
+            state.limits.borrow_mut().on_allocate(ptr, size);
+        }
+
+        ptr
+    }
+}
+
+extern "C" fn miri_free(ctx: *mut MiriInterpCxOpaque, ptr: MiriPointer) -> bool {
+    unsafe {
+        let freed = with_hooks(ctx, "free", false, |hooks| hooks.free(ptr));
+
+        if check_accepted(state_from_raw(ctx), "free", freed) {
+            state_from_raw(ctx).limits.borrow_mut().on_free(ptr);
+        }
+
+        freed
+    }
+}
+
+extern "C" fn miri_load(
+    ctx: *mut MiriInterpCxOpaque,
+    dest: LLVMGenericValueRef,
+    src: MiriPointer,
+    ty: LLVMTypeRef,
+    align: u64,
+) -> bool {
+    unsafe {
+        record_access(ctx, src, store_size(ctx, ty), MemoryAccess::Read);
+
+        let dest = GenericValueRef::new(dest);
+        let ty = BasicTypeEnum::new(ty);
+
+        let loaded = with_hooks(ctx, "load", false, |hooks| hooks.load(dest, src, ty, align));
+
+        check_accepted(state_from_raw(ctx), "load", loaded)
+    }
+}
+
+extern "C" fn miri_store(
+    ctx: *mut MiriInterpCxOpaque,
+    value: LLVMGenericValueRef,
+    dest: MiriPointer,
+    ty: LLVMTypeRef,
+    align: u64,
+) -> bool {
+    unsafe {
+        record_access(ctx, dest, store_size(ctx, ty), MemoryAccess::Write);
+
+        let value = GenericValueRef::new(value);
+        let ty = BasicTypeEnum::new(ty);
+
+        let stored = with_hooks(ctx, "store", false, |hooks| hooks.store(value, dest, ty, align));
+
+        check_accepted(state_from_raw(ctx), "store", stored)
+    }
+}
+
+extern "C" fn miri_get_element_pointer(ctx: *mut MiriInterpCxOpaque, base: MiriPointer, offset: u64) -> MiriPointer {
+    unsafe {
+        with_hooks(ctx, "get_element_pointer", null_pointer(), |hooks| {
+            hooks.get_element_pointer(base, offset)
+        })
+    }
+}
+
+extern "C" fn miri_memset(ctx: *mut MiriInterpCxOpaque, dest: MiriPointer, value: c_int, len: u64) -> bool {
+    unsafe {
+        record_access(ctx, dest, len, MemoryAccess::Write);
+
+        let set = with_hooks(ctx, "memset", false, |hooks| hooks.memset(dest, value as u8, len));
+
+        check_accepted(state_from_raw(ctx), "memset", set)
+    }
+}
+
+extern "C" fn miri_memcpy(ctx: *mut MiriInterpCxOpaque, dest: MiriPointer, src: *const u8, len: u64) -> bool {
+    unsafe {
+        let src = if src.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(src, len as usize)
+        };
+
+        record_access(ctx, dest, src.len() as u64, MemoryAccess::Write);
+
+        let copied = with_hooks(ctx, "memcpy", false, |hooks| hooks.memcpy(dest, src));
+
+        check_accepted(state_from_raw(ctx), "memcpy", copied)
+    }
+}
+
+extern "C" fn miri_int_to_ptr(ctx: *mut MiriInterpCxOpaque, addr: u64) -> MiriPointer {
+    unsafe { with_hooks(ctx, "int_to_ptr", null_pointer(), |hooks| hooks.int_to_ptr(addr)) }
+}
+
+extern "C" fn miri_ptr_to_int(ctx: *mut MiriInterpCxOpaque, ptr: MiriPointer) -> u64 {
+    unsafe { with_hooks(ctx, "ptr_to_int", 0, |hooks| hooks.ptr_to_int(ptr)) }
+}
+
+extern "C" fn miri_call_by_name(
+    ctx: *mut MiriInterpCxOpaque,
+    args: LLVMGenericValueArrayRef,
+    name: *const c_char,
+    name_len: u64,
+    fn_type: LLVMTypeRef,
+) -> bool {
+    unsafe {
+        let args = args_from_raw(args);
+        let name = str_from_raw(name, name_len);
+        let fn_type = FunctionType::new(fn_type);
+        let state = state_from_raw(ctx);
+
+        // Probes are stepped over by the thread, without the hooks ever seeing them
+        if state.probes.borrow_mut().on_call(&name, &args, fn_type) {
+            *state.resolved_call.borrow_mut() = Some(ResolvedCall::Returned(None));
+            state.pending_call.set(true);
+
+            return true;
+        }
+
+        state.breakpoints.borrow_mut().on_call(&name);
+
+        let called = limits::with_call_limits(&state.limits, || {
+            with_hooks(ctx, "call_by_name", false, |hooks| {
+                hooks.call_by_name(&name, &args, fn_type)
+            })
+        });
+
+        record_exceeded_limit(state);
+        state.pending_call.set(called);
+
+        check_accepted(state, "call_by_name", called)
+    }
+}
+
+extern "C" fn miri_call_by_pointer(
+    ctx: *mut MiriInterpCxOpaque,
+    callee: MiriPointer,
+    args: LLVMGenericValueArrayRef,
+    fn_type: LLVMTypeRef,
+) -> bool {
+    unsafe {
+        let state = state_from_raw(ctx);
+        let args = args_from_raw(args);
+        let fn_type = FunctionType::new(fn_type);
+        let target = state.function_pointers.borrow().resolve(callee);
+
+        let called = match target {
+            Some(target) => call_function_pointer(state, callee, target, &args, fn_type),
+            None => limits::with_call_limits(&state.limits, || {
+                with_hooks(ctx, "call_by_pointer", false, |hooks| {
+                    hooks.call_by_pointer(callee, &args, fn_type)
+                })
+            }),
+        };
+
+        record_exceeded_limit(state);
+        state.pending_call.set(called);
+
+        check_accepted(state, "call_by_pointer", called)
+    }
+}
+
+/// Makes a call through one of the engine's function pointers, after checking it has the type of
+/// the function it points to. Calls to host functions are made right away, while calls to functions
+/// of the module are left for the `InterpreterThread` which made them to run.
+fn call_function_pointer<'a>(
+    state: &MiriHookState<'a>,
+    callee: MiriPointer,
+    target: FunctionPointerTarget<'a>,
+    args: &[GenericValueRef<'_>],
+    fn_type: FunctionType<'a>,
+) -> bool {
+    if target.get_type() != fn_type || args.len() != fn_type.count_param_types() as usize {
+        *state.call_error.borrow_mut() = Some(InterpreterError::SignatureMismatch {
+            expected: target.get_type().print_to_string().to_string(),
+            found: fn_type.print_to_string().to_string(),
+        });
+
+        return false;
+    }
+
+    let resolved_call = match target {
+        FunctionPointerTarget::Function(function) => {
+            // The arguments are owned by the interpreter, and have to outlive this call
+            let args = fn_type
+                .get_param_types()
+                .into_iter()
+                .zip(args)
+                .map(|(ty, arg)| GenericValue::from_generic_value(arg, ty))
+                .collect::<Result<Vec<_>, _>>();
+
+            match args {
+                Ok(args) => ResolvedCall::Function(function, args),
+                Err(err) => {
+                    *state.call_error.borrow_mut() = Some(InterpreterError::InvalidArgument(err));
+
+                    return false;
+                },
+            }
+        },
+        FunctionPointerTarget::Host(_) => {
+            let host_function = match state.function_pointers.borrow().get_host_function(callee) {
+                Some(host_function) => host_function,
+                None => return false,
+            };
+
+            match catch_unwind(AssertUnwindSafe(|| (host_function.borrow_mut())(args))) {
+                Ok(return_value) => ResolvedCall::Returned(return_value),
+                Err(payload) => {
+                    *state.panic.borrow_mut() = Some(MiriHookPanic {
+                        hook: "call_by_pointer",
+                        payload,
+                    });
+
+                    return false;
+                },
+            }
+        },
+    };
+
+    *state.resolved_call.borrow_mut() = Some(resolved_call);
+
+    true
+}
+
+extern "C" fn miri_register_global(
+    ctx: *mut MiriInterpCxOpaque,
+    name: *const c_char,
+    name_len: u64,
+    ptr: MiriPointer,
+) -> bool {
+    unsafe {
+        let name = str_from_raw(name, name_len);
+
+        let registered = with_hooks(ctx, "register_global", false, |hooks| hooks.register_global(&name, ptr));
+
+        check_accepted(state_from_raw(ctx), "register_global", registered)
+    }
+}
+
+extern "C" fn miri_stack_trace_recorder(
+    ctx: *mut MiriInterpCxOpaque,
+    traces: *const MiriErrorTrace,
+    traces_len: u64,
+    inst: *const c_char,
+    inst_len: u64,
+) {
+    unsafe {
+        let traces = if traces.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(traces, traces_len as usize)
+        };
+        let inst = (!inst.is_null()).then(|| str_from_raw(inst, inst_len).into_owned());
+
+        with_hooks(ctx, "record_stack_trace", (), |hooks| {
+            hooks.record_stack_trace(StackTrace::new(inst, traces))
+        })
+    }
+}
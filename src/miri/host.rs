@@ -0,0 +1,774 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use llvm_sys::miri::{MiriPointer, MiriProvenance};
+
+use crate::miri::{
+    ForeignCallError, ForeignFunctionRegistry, ForeignMemory, MemoryAccess, MemoryError, MemoryErrorKind, MiriHooks,
+    StackTrace,
+};
+use crate::targets::{ByteOrdering, TargetData};
+use crate::types::{BasicTypeEnum, FunctionType};
+use crate::values::{GenericValue, GenericValueRef};
+
+/// Unused bytes left between allocations, so that small out of bounds accesses never
+/// land inside a neighbouring allocation.
+const ALLOCATION_GAP: u64 = 16;
+
+/// Where allocations start. Keeps every valid address well clear of null.
+const BASE_ADDRESS: u64 = 0x1000;
+
+#[derive(Debug, Clone)]
+struct Allocation {
+    base: u64,
+    size: u64,
+    /// The contents of the allocation, which are dropped once it is freed.
+    bytes: Vec<u8>,
+    /// Whether each byte of `bytes` has been written since the allocation was made.
+    init: Vec<bool>,
+    is_heap: bool,
+    live: bool,
+    exposed: bool,
+    /// The provenance of pointers stored in this allocation, by the offset they were stored at.
+    provenance: BTreeMap<u64, MiriProvenance>,
+}
+
+impl Allocation {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size.max(1)
+    }
+
+    fn clear_provenance(&mut self, offset: u64, len: u64, pointer_size: u64) {
+        let start = offset.saturating_sub(pointer_size - 1);
+        let end = offset.saturating_add(len);
+        let overlapping: Vec<u64> = self.provenance.range(start..end).map(|(&offset, _)| offset).collect();
+
+        for offset in overlapping {
+            self.provenance.remove(&offset);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HostState<'ctx> {
+    target_data: TargetData,
+    pointer_size: u64,
+    little_endian: bool,
+    next_addr: u64,
+    next_alloc_id: u64,
+    allocations: BTreeMap<u64, Allocation>,
+    /// Allocation ids by base address, for recovering provenance in `int_to_ptr`.
+    addresses: BTreeMap<u64, u64>,
+    errors: Vec<MemoryError<'ctx>>,
+    /// The result of the last call handled by the `ForeignFunctionRegistry`.
+    return_value: Option<GenericValue<'ctx>>,
+    call_errors: Vec<(String, ForeignCallError)>,
+}
+
+impl<'ctx> HostState<'ctx> {
+    /// Makes a new allocation of `size` bytes. If the host can't hold that many, this records a
+    /// `MemoryError` and returns a null pointer, like `malloc` does.
+    fn allocate(&mut self, size: u64, align: u64, is_heap: bool, initialized: bool) -> MiriPointer {
+        let (bytes, init) = match (alloc_bytes(size, 0), alloc_bytes(size, initialized)) {
+            (Some(bytes), Some(init)) => (bytes, init),
+            _ => {
+                let null = MiriPointer {
+                    addr: 0,
+                    prov: null_provenance(),
+                };
+
+                self.fail(null, MemoryErrorKind::AllocationFailed { size });
+
+                return null;
+            },
+        };
+        let align = align.max(1);
+        let base = self.next_addr.saturating_add(align - 1) / align * align;
+        let alloc_id = self.next_alloc_id;
+
+        self.next_addr = base.saturating_add(size.max(1)).saturating_add(ALLOCATION_GAP);
+        self.next_alloc_id += 1;
+        self.addresses.insert(base, alloc_id);
+        self.allocations.insert(
+            alloc_id,
+            Allocation {
+                base,
+                size,
+                bytes,
+                init,
+                is_heap,
+                live: true,
+                exposed: false,
+                provenance: BTreeMap::new(),
+            },
+        );
+
+        pointer(base, alloc_id)
+    }
+
+    /// Gets the live allocation `ptr` points into, along with the offset of `ptr` into it, if all
+    /// `len` bytes starting at `ptr` lie inside the allocation.
+    fn access(
+        &mut self,
+        ptr: MiriPointer,
+        len: u64,
+        access: MemoryAccess,
+    ) -> Result<(&mut Allocation, u64), MemoryErrorKind> {
+        let allocation = match self.allocations.get_mut(&ptr.prov.alloc_id) {
+            Some(allocation) => allocation,
+            None => return Err(MemoryErrorKind::NoProvenance { access, size: len }),
+        };
+
+        if !allocation.live {
+            return Err(MemoryErrorKind::UseAfterFree { access, size: len });
+        }
+
+        let offset = ptr.addr.wrapping_sub(allocation.base);
+        let in_bounds =
+            ptr.addr >= allocation.base && matches!(offset.checked_add(len), Some(end) if end <= allocation.size);
+
+        if !in_bounds {
+            return Err(MemoryErrorKind::OutOfBounds {
+                access,
+                offset: offset as i64,
+                size: len,
+                alloc_size: allocation.size,
+            });
+        }
+
+        Ok((allocation, offset))
+    }
+
+    fn read(&mut self, ptr: MiriPointer, len: u64) -> Result<Vec<u8>, MemoryErrorKind> {
+        let (allocation, offset) = self.access(ptr, len, MemoryAccess::Read)?;
+
+        Ok(allocation.bytes[offset as usize..(offset + len) as usize].to_vec())
+    }
+
+    fn write(&mut self, ptr: MiriPointer, bytes: &[u8]) -> Result<(), MemoryErrorKind> {
+        let pointer_size = self.pointer_size;
+        let (allocation, offset) = self.access(ptr, bytes.len() as u64, MemoryAccess::Write)?;
+
+        allocation.bytes[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+        allocation.init[offset as usize..offset as usize + bytes.len()].fill(true);
+        allocation.clear_provenance(offset, bytes.len() as u64, pointer_size);
+
+        Ok(())
+    }
+
+    fn free(&mut self, ptr: MiriPointer) -> Result<(), MemoryErrorKind> {
+        let allocation = match self.allocations.get_mut(&ptr.prov.alloc_id) {
+            Some(allocation) if allocation.base == ptr.addr => allocation,
+            _ => return Err(MemoryErrorKind::InvalidFree),
+        };
+
+        if !allocation.live {
+            return Err(MemoryErrorKind::DoubleFree);
+        }
+
+        allocation.live = false;
+        allocation.bytes = Vec::new();
+        allocation.init = Vec::new();
+        allocation.provenance.clear();
+
+        Ok(())
+    }
+
+    /// Records an operation the host rejected. Returns `false`, for hooks to hand back to the interpreter.
+    fn fail(&mut self, ptr: MiriPointer, kind: MemoryErrorKind) -> bool {
+        self.errors.push(MemoryError {
+            kind,
+            ptr,
+            stack_trace: None,
+        });
+
+        false
+    }
+
+    fn check(&mut self, ptr: MiriPointer, result: Result<(), MemoryErrorKind>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(kind) => self.fail(ptr, kind),
+        }
+    }
+
+    fn int_bytes(&self, words: &[u64], len: usize) -> Vec<u8> {
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        bytes.resize(len, 0);
+
+        if !self.little_endian {
+            bytes.reverse();
+        }
+
+        bytes
+    }
+
+    fn int_words(&self, bytes: &[u8]) -> Vec<u64> {
+        let mut bytes = bytes.to_vec();
+
+        if !self.little_endian {
+            bytes.reverse();
+        }
+
+        bytes.resize((bytes.len() + 7) / 8 * 8, 0);
+        bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Lays `value` out as a `ty` at `offset` into `out`, noting the offsets pointers were written to.
+    /// Fails for types this host can't represent.
+    fn encode(
+        &self,
+        value: &GenericValueRef<'_>,
+        ty: BasicTypeEnum<'_>,
+        out: &mut [u8],
+        pointers: &mut Vec<(u64, MiriProvenance)>,
+        offset: u64,
+    ) -> Result<(), MemoryErrorKind> {
+        let size = self.target_data.get_store_size(&ty) as usize;
+        let slot = match out.get_mut(offset as usize..offset as usize + size) {
+            Some(slot) => slot,
+            None => return Err(unsupported(MemoryAccess::Write, ty)),
+        };
+
+        match ty {
+            BasicTypeEnum::IntType(_) => slot.copy_from_slice(&self.int_bytes(&value.as_int_words(), size)),
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                slot.copy_from_slice(&self.int_bytes(&[u64::from(value.as_f32().to_bits())], size))
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                slot.copy_from_slice(&self.int_bytes(&[value.as_f64().to_bits()], size))
+            },
+            BasicTypeEnum::PointerType(_) => {
+                let ptr = value.as_miri_pointer();
+
+                slot.copy_from_slice(&self.int_bytes(&[ptr.addr], size));
+                pointers.push((offset, ptr.prov));
+            },
+            BasicTypeEnum::ArrayType(_) | BasicTypeEnum::StructType(_) | BasicTypeEnum::VectorType(_) => {
+                value.set_type_tag(&ty);
+
+                let fields = value.get_fields().ok_or_else(|| unsupported(MemoryAccess::Write, ty))?;
+
+                for (idx, field) in fields.iter().enumerate() {
+                    let (field_type, field_offset) = self
+                        .field_layout(ty, idx as u32)
+                        .ok_or_else(|| unsupported(MemoryAccess::Write, ty))?;
+
+                    self.encode(field, field_type, out, pointers, offset + field_offset)?;
+                }
+            },
+            _ => return Err(unsupported(MemoryAccess::Write, ty)),
+        }
+
+        Ok(())
+    }
+
+    /// Reads a `ty` laid out at `offset` into `bytes` into `dest`, using `provenance` to find the
+    /// provenance of pointers by their offset. Fails for types this host can't represent, and if any
+    /// byte of a scalar isn't marked as written in `init`. Padding between fields may be uninitialized.
+    fn decode(
+        &self,
+        dest: &mut GenericValueRef<'_>,
+        ty: BasicTypeEnum<'_>,
+        bytes: &[u8],
+        init: &[bool],
+        provenance: &dyn Fn(u64) -> Option<MiriProvenance>,
+        offset: u64,
+    ) -> Result<(), MemoryErrorKind> {
+        let size = self.target_data.get_store_size(&ty) as usize;
+        let range = offset as usize..offset as usize + size;
+        let slot = match bytes.get(range.clone()) {
+            Some(slot) => slot,
+            None => return Err(unsupported(MemoryAccess::Read, ty)),
+        };
+        let is_aggregate = matches!(
+            ty,
+            BasicTypeEnum::ArrayType(_) | BasicTypeEnum::StructType(_) | BasicTypeEnum::VectorType(_)
+        );
+
+        if !is_aggregate && init[range].contains(&false) {
+            return Err(MemoryErrorKind::Uninitialized {
+                offset,
+                size: size as u64,
+            });
+        }
+
+        match ty {
+            BasicTypeEnum::IntType(int_type) => {
+                let words = self.int_words(slot);
+
+                if int_type.get_bit_width() <= 128 {
+                    let low = words.first().copied().unwrap_or(0);
+                    let high = words.get(1).copied().unwrap_or(0);
+
+                    dest.set_int_value((u128::from(high) << 64) | u128::from(low), size as u64);
+                } else {
+                    dest.set_int_words(&words);
+                }
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                dest.set_float_value(f32::from_bits(self.int_words(slot)[0] as u32))
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                dest.set_double_value(f64::from_bits(self.int_words(slot)[0]))
+            },
+            BasicTypeEnum::PointerType(_) => {
+                let addr = self.int_words(slot)[0];
+                let prov = provenance(offset).unwrap_or_else(null_provenance);
+
+                dest.set_miri_pointer_value(MiriPointer { addr, prov });
+            },
+            BasicTypeEnum::ArrayType(_) | BasicTypeEnum::StructType(_) | BasicTypeEnum::VectorType(_) => {
+                let len = self.field_count(ty);
+
+                while dest.get_aggregate_size() < len {
+                    dest.append_aggregate_value(GenericValue::new_void());
+                }
+
+                dest.set_type_tag(&ty);
+
+                for idx in 0..len {
+                    let (field_type, field_offset) = self
+                        .field_layout(ty, idx as u32)
+                        .ok_or_else(|| unsupported(MemoryAccess::Read, ty))?;
+                    let mut field = dest.get_field(idx).ok_or_else(|| unsupported(MemoryAccess::Read, ty))?;
+
+                    self.decode(&mut field, field_type, bytes, init, provenance, offset + field_offset)?;
+                }
+            },
+            _ => return Err(unsupported(MemoryAccess::Read, ty)),
+        }
+
+        Ok(())
+    }
+
+    fn field_count(&self, ty: BasicTypeEnum<'_>) -> u64 {
+        match ty {
+            BasicTypeEnum::ArrayType(array_type) => array_type.len().into(),
+            BasicTypeEnum::StructType(struct_type) => struct_type.count_fields().into(),
+            BasicTypeEnum::VectorType(vector_type) => vector_type.get_size().into(),
+            _ => 0,
+        }
+    }
+
+    /// Gets the type and byte offset of field `idx` of the aggregate `ty`.
+    fn field_layout<'a>(&self, ty: BasicTypeEnum<'a>, idx: u32) -> Option<(BasicTypeEnum<'a>, u64)> {
+        match ty {
+            BasicTypeEnum::StructType(struct_type) => Some((
+                struct_type.get_field_type_at_index(idx)?,
+                self.target_data.offset_of_element(&struct_type, idx)?,
+            )),
+            BasicTypeEnum::ArrayType(array_type) => {
+                let element_type = array_type.get_element_type();
+
+                Some((
+                    element_type,
+                    u64::from(idx) * self.target_data.get_abi_size(&element_type),
+                ))
+            },
+            BasicTypeEnum::VectorType(vector_type) => {
+                let element_type = vector_type.get_element_type();
+
+                // Vectors are packed, which only matches byte addressing for whole-byte elements
+                if self.target_data.get_bit_size(&element_type) % 8 != 0 {
+                    return None;
+                }
+
+                Some((
+                    element_type,
+                    u64::from(idx) * self.target_data.get_store_size(&element_type),
+                ))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A self-contained Miri host, keeping all memory in a byte-addressed heap owned by Rust.
+///
+/// Every allocation gets a fresh allocation id, which pointers into it carry as their provenance.
+/// Pointers stored to memory keep their provenance, and integers cast back to pointers regain the
+/// provenance of whichever allocation they point into, as long as that allocation was exposed by
+/// casting a pointer into it to an integer.
+///
+/// Calls to functions outside the module are handled by a `ForeignFunctionRegistry`, set with
+/// `set_foreign_functions`, which has access to the host's memory. The thread making the call is
+/// blocked until its result, taken with `take_return_value`, is passed back with
+/// `InterpreterThread::set_pending_return`. By default, no functions are registered.
+///
+/// Out-of-bounds accesses, accesses to freed memory, double frees and frees of pointers which
+/// don't point to the start of an allocation are rejected, which errors the interpreter thread.
+/// Each rejected operation is recorded as a `MemoryError`, along with the stack trace the
+/// interpreter reports for it, and can be retrieved with `get_errors`. Allocations of more memory
+/// than the host can hold return a null pointer, and are recorded as a `MemoryError` as well.
+///
+/// Memory allocated by the interpreter starts out uninitialized, and loading a scalar which
+/// includes any byte that hasn't been written since is rejected as well. Since `memcpy` only
+/// hands the host the copied bytes, its destination is always considered initialized. Values
+/// derived from `undef` or `poison` never reach the host, but branching on them is caught by the
+/// interpreter thread instead, as described by `InterpreterError::UndefinedBranch`.
+///
+/// Cloning a `SimpleMiriHost` creates another handle to the same heap, so memory can still be
+/// inspected after a clone was installed with `ExecutionEngine::install_miri_hooks`.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::context::Context;
+/// use inkwell::miri::SimpleMiriHost;
+///
+/// let context = Context::create();
+/// let module = context.create_module("miri");
+/// let execution_engine = module.create_interpreter_execution_engine().unwrap();
+/// let host = SimpleMiriHost::new(execution_engine.get_target_data());
+///
+/// execution_engine.install_miri_hooks(Box::new(host.clone()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SimpleMiriHost<'ctx> {
+    state: Rc<RefCell<HostState<'ctx>>>,
+    foreign_functions: Rc<RefCell<ForeignFunctionRegistry<'ctx>>>,
+}
+
+/// The memory of a `SimpleMiriHost` at one point in time, as taken by `SimpleMiriHost::snapshot`.
+#[derive(Debug, Clone)]
+pub struct MemorySnapshot {
+    next_addr: u64,
+    next_alloc_id: u64,
+    allocations: BTreeMap<u64, Allocation>,
+    addresses: BTreeMap<u64, u64>,
+}
+
+impl<'ctx> SimpleMiriHost<'ctx> {
+    /// Creates an empty heap laid out according to `target_data`.
+    pub fn new(target_data: &TargetData) -> Self {
+        let data_layout = target_data.get_data_layout();
+        let target_data = TargetData::create(&data_layout.as_str().to_string_lossy());
+
+        SimpleMiriHost {
+            state: Rc::new(RefCell::new(HostState {
+                pointer_size: target_data.get_pointer_byte_size(None).into(),
+                little_endian: target_data.get_byte_ordering() == ByteOrdering::LittleEndian,
+                target_data,
+                next_addr: BASE_ADDRESS,
+                next_alloc_id: 1,
+                allocations: BTreeMap::new(),
+                addresses: BTreeMap::new(),
+                errors: Vec::new(),
+                return_value: None,
+                call_errors: Vec::new(),
+            })),
+            foreign_functions: Rc::new(RefCell::new(ForeignFunctionRegistry::new())),
+        }
+    }
+
+    /// Allocates `size` zeroed bytes on the heap, for passing memory to the interpreter. Returns a
+    /// null pointer and records a `MemoryError` if the host can't hold that many bytes.
+    pub fn allocate(&self, size: u64, align: u64) -> MiriPointer {
+        self.state.borrow_mut().allocate(size, align, true, true)
+    }
+
+    /// Reads `len` bytes starting at `ptr`. Returns `None` unless they all lie inside
+    /// the live allocation `ptr` belongs to.
+    pub fn read_bytes(&self, ptr: MiriPointer, len: u64) -> Option<Vec<u8>> {
+        self.state.borrow_mut().read(ptr, len).ok()
+    }
+
+    /// Writes `bytes` starting at `ptr`. Returns `false` unless they all lie inside
+    /// the live allocation `ptr` belongs to.
+    pub fn write_bytes(&self, ptr: MiriPointer, bytes: &[u8]) -> bool {
+        self.state.borrow_mut().write(ptr, bytes).is_ok()
+    }
+
+    /// Gets a pointer to the start and the size of every live heap allocation, in the order they were made.
+    pub fn get_live_heap_allocations(&self) -> Vec<(MiriPointer, u64)> {
+        self.state
+            .borrow()
+            .allocations
+            .iter()
+            .filter(|(_, allocation)| allocation.live && allocation.is_heap)
+            .map(|(&alloc_id, allocation)| (pointer(allocation.base, alloc_id), allocation.size))
+            .collect()
+    }
+
+    /// Copies every allocation, live or freed, along with its contents.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let state = self.state.borrow();
+
+        MemorySnapshot {
+            next_addr: state.next_addr,
+            next_alloc_id: state.next_alloc_id,
+            allocations: state.allocations.clone(),
+            addresses: state.addresses.clone(),
+        }
+    }
+
+    /// Puts memory back the way it was when `snapshot` was taken. Allocations made since then are
+    /// forgotten, so pointers into them lose their provenance, and the addresses and ids they used
+    /// are handed out again. Recorded errors and the pending return value are kept.
+    pub fn restore(&self, snapshot: &MemorySnapshot) {
+        let mut state = self.state.borrow_mut();
+
+        state.next_addr = snapshot.next_addr;
+        state.next_alloc_id = snapshot.next_alloc_id;
+        state.allocations = snapshot.allocations.clone();
+        state.addresses = snapshot.addresses.clone();
+    }
+
+    /// Gets every invalid memory operation the host rejected so far, oldest first.
+    pub fn get_errors(&self) -> Vec<MemoryError<'ctx>> {
+        self.state.borrow().errors.clone()
+    }
+
+    /// Removes and returns every invalid memory operation the host rejected so far, oldest first.
+    pub fn take_errors(&self) -> Vec<MemoryError<'ctx>> {
+        std::mem::take(&mut self.state.borrow_mut().errors)
+    }
+
+    /// Replaces the functions calls to functions outside the module are dispatched to.
+    pub fn set_foreign_functions(&self, foreign_functions: ForeignFunctionRegistry<'ctx>) {
+        *self.foreign_functions.borrow_mut() = foreign_functions;
+    }
+
+    /// Takes the return value of the last call to a function outside the module, for handing to
+    /// `InterpreterThread::set_pending_return`. This is `None` for `void` functions.
+    pub fn take_return_value(&self) -> Option<GenericValue<'ctx>> {
+        self.state.borrow_mut().return_value.take()
+    }
+
+    /// Removes and returns the name and error of every failed call to a function outside the module, oldest first.
+    pub fn take_call_errors(&self) -> Vec<(String, ForeignCallError)> {
+        std::mem::take(&mut self.state.borrow_mut().call_errors)
+    }
+}
+
+fn unsupported(access: MemoryAccess, ty: BasicTypeEnum<'_>) -> MemoryErrorKind {
+    MemoryErrorKind::UnsupportedType {
+        access,
+        llvm_type: ty.print_to_string().to_string(),
+    }
+}
+
+/// Allocates `size` copies of `value`, or returns `None` if that many don't fit in memory, rather
+/// than aborting like `vec!` does.
+fn alloc_bytes<T: Clone>(size: u64, value: T) -> Option<Vec<T>> {
+    let size = usize::try_from(size).ok()?;
+    let mut bytes = Vec::new();
+
+    bytes.try_reserve_exact(size).ok()?;
+    bytes.resize(size, value);
+
+    Some(bytes)
+}
+
+fn null_provenance() -> MiriProvenance {
+    MiriProvenance { alloc_id: 0, tag: 0 }
+}
+
+fn pointer(addr: u64, alloc_id: u64) -> MiriPointer {
+    MiriPointer {
+        addr,
+        prov: MiriProvenance { alloc_id, tag: 0 },
+    }
+}
+
+impl<'ctx> MiriHooks<'ctx> for SimpleMiriHost<'ctx> {
+    fn malloc(&mut self, size: u64, align: u64, is_heap: bool) -> MiriPointer {
+        self.state.borrow_mut().allocate(size, align, is_heap, false)
+    }
+
+    fn free(&mut self, ptr: MiriPointer) -> bool {
+        let mut state = self.state.borrow_mut();
+        let result = state.free(ptr);
+
+        state.check(ptr, result)
+    }
+
+    fn load(&mut self, mut dest: GenericValueRef<'_>, src: MiriPointer, ty: BasicTypeEnum<'ctx>, _align: u64) -> bool {
+        let mut state = self.state.borrow_mut();
+        let size = state.target_data.get_store_size(&ty);
+        let (allocation, offset) = match state.access(src, size, MemoryAccess::Read) {
+            Ok(access) => access,
+            Err(kind) => return state.fail(src, kind),
+        };
+        let bytes = allocation.bytes[offset as usize..(offset + size) as usize].to_vec();
+        let init = allocation.init[offset as usize..(offset + size) as usize].to_vec();
+        let provenance: BTreeMap<u64, MiriProvenance> = allocation
+            .provenance
+            .range(offset..offset + size)
+            .map(|(&ptr_offset, &prov)| (ptr_offset - offset, prov))
+            .collect();
+
+        let result = state.decode(
+            &mut dest,
+            ty,
+            &bytes,
+            &init,
+            &|offset| provenance.get(&offset).copied(),
+            0,
+        );
+
+        state.check(src, result)
+    }
+
+    fn store(&mut self, value: GenericValueRef<'_>, dest: MiriPointer, ty: BasicTypeEnum<'ctx>, _align: u64) -> bool {
+        let mut state = self.state.borrow_mut();
+        let mut bytes = vec![0; state.target_data.get_store_size(&ty) as usize];
+        let mut pointers = Vec::new();
+
+        if let Err(kind) = state.encode(&value, ty, &mut bytes, &mut pointers, 0) {
+            return state.fail(dest, kind);
+        }
+
+        if let Err(kind) = state.write(dest, &bytes) {
+            return state.fail(dest, kind);
+        }
+
+        let (allocation, offset) = state
+            .access(dest, bytes.len() as u64, MemoryAccess::Write)
+            .expect("store was just checked");
+
+        for (ptr_offset, prov) in pointers {
+            allocation.provenance.insert(offset + ptr_offset, prov);
+        }
+
+        true
+    }
+
+    fn get_element_pointer(&mut self, base: MiriPointer, offset: u64) -> MiriPointer {
+        MiriPointer {
+            addr: base.addr.wrapping_add(offset),
+            prov: base.prov,
+        }
+    }
+
+    fn memset(&mut self, dest: MiriPointer, value: u8, len: u64) -> bool {
+        let mut state = self.state.borrow_mut();
+        let pointer_size = state.pointer_size;
+
+        // Filled in place, since `len` is only known to be reasonable once it's been bounds checked
+        let (allocation, offset) = match state.access(dest, len, MemoryAccess::Write) {
+            Ok(access) => access,
+            Err(kind) => return state.fail(dest, kind),
+        };
+
+        allocation.bytes[offset as usize..(offset + len) as usize].fill(value);
+        allocation.init[offset as usize..(offset + len) as usize].fill(true);
+        allocation.clear_provenance(offset, len, pointer_size);
+
+        true
+    }
+
+    fn memcpy(&mut self, dest: MiriPointer, src: &[u8]) -> bool {
+        let mut state = self.state.borrow_mut();
+        let result = state.write(dest, src);
+
+        state.check(dest, result)
+    }
+
+    fn int_to_ptr(&mut self, addr: u64) -> MiriPointer {
+        let state = self.state.borrow();
+        let prov = state
+            .addresses
+            .range(..=addr)
+            .next_back()
+            .and_then(|(_, alloc_id)| {
+                let allocation = &state.allocations[alloc_id];
+
+                (allocation.live && allocation.exposed && allocation.contains(addr)).then_some(MiriProvenance {
+                    alloc_id: *alloc_id,
+                    tag: 0,
+                })
+            })
+            .unwrap_or_else(null_provenance);
+
+        MiriPointer { addr, prov }
+    }
+
+    fn ptr_to_int(&mut self, ptr: MiriPointer) -> u64 {
+        if let Some(allocation) = self.state.borrow_mut().allocations.get_mut(&ptr.prov.alloc_id) {
+            allocation.exposed = true;
+        }
+
+        ptr.addr
+    }
+
+    fn call_by_name(&mut self, name: &str, args: &[GenericValueRef<'_>], fn_type: FunctionType<'ctx>) -> bool {
+        let host: &Self = self;
+        let result = host.foreign_functions.borrow_mut().call(host, name, args, fn_type);
+        let mut state = self.state.borrow_mut();
+
+        match result {
+            Ok(return_value) => {
+                state.return_value = return_value;
+
+                true
+            },
+            Err(err) => {
+                state.call_errors.push((name.to_owned(), err));
+
+                false
+            },
+        }
+    }
+
+    fn call_by_pointer(
+        &mut self,
+        _callee: MiriPointer,
+        _args: &[GenericValueRef<'_>],
+        _fn_type: FunctionType<'ctx>,
+    ) -> bool {
+        false
+    }
+
+    fn record_stack_trace(&mut self, trace: StackTrace<'ctx>) {
+        let mut state = self.state.borrow_mut();
+
+        // The interpreter reports the stack right after a hook fails, so it belongs to the latest error
+        if let Some(error) = state.errors.last_mut().filter(|error| error.stack_trace.is_none()) {
+            error.stack_trace = Some(trace);
+        }
+    }
+
+    fn take_return_value(&mut self) -> Option<GenericValue<'ctx>> {
+        SimpleMiriHost::take_return_value(self)
+    }
+
+    fn snapshot_memory(&mut self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.snapshot()))
+    }
+
+    fn restore_memory(&mut self, snapshot: &dyn Any) {
+        if let Some(snapshot) = snapshot.downcast_ref() {
+            self.restore(snapshot);
+        }
+    }
+}
+
+impl ForeignMemory for SimpleMiriHost<'_> {
+    fn allocate(&self, size: u64, align: u64) -> MiriPointer {
+        SimpleMiriHost::allocate(self, size, align)
+    }
+
+    /// Frees a heap allocation, recording a `MemoryError` if it can't be freed.
+    fn deallocate(&self, ptr: MiriPointer) -> bool {
+        let mut state = self.state.borrow_mut();
+        let result = state.free(ptr);
+
+        state.check(ptr, result)
+    }
+
+    fn read_bytes(&self, ptr: MiriPointer, len: u64) -> Option<Vec<u8>> {
+        SimpleMiriHost::read_bytes(self, ptr, len)
+    }
+
+    fn write_bytes(&self, ptr: MiriPointer, bytes: &[u8]) -> bool {
+        SimpleMiriHost::write_bytes(self, ptr, bytes)
+    }
+}
@@ -0,0 +1,184 @@
+use llvm_sys::miri::MiriPointer;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+/// Bounds on how much work LLVM's interpreter may do, as set with
+/// `ExecutionEngine::set_interpreter_limits`. `None` leaves a resource unbounded.
+///
+/// A thread which hits a limit is terminated, and the call which was stepping or running it
+/// returns `InterpreterError::LimitExceeded`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterpreterLimits {
+    /// How many instructions may be executed in total, across every thread.
+    pub fuel: Option<u64>,
+    /// How many frames a thread may have, counting the function it was spawned with as the first
+    /// and including those of the calls it made through the engine's function pointers. Frames are
+    /// only tracked in functions instrumented with `ExecutionEngine::instrument_module`.
+    pub max_call_depth: Option<u32>,
+    /// How many bytes may be live at once in heap allocations made through `MiriHooks::malloc`, or
+    /// by the `malloc` and `calloc` shims of a `ForeignFunctionRegistry` on behalf of the module.
+    pub max_heap_size: Option<u64>,
+}
+
+impl InterpreterLimits {
+    /// Returns whether any resource is bounded.
+    pub fn is_limited(&self) -> bool {
+        self.fuel.is_some() || self.max_call_depth.is_some() || self.max_heap_size.is_some()
+    }
+}
+
+/// Which of the `InterpreterLimits` was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpreterLimit {
+    Fuel,
+    CallDepth,
+    HeapSize,
+}
+
+impl Display for InterpreterLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpreterLimit::Fuel => write!(f, "fuel"),
+            InterpreterLimit::CallDepth => write!(f, "call depth"),
+            InterpreterLimit::HeapSize => write!(f, "heap size"),
+        }
+    }
+}
+
+/// The limits of an `ExecutionEngine`, along with how much of each resource is in use.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Limits {
+    limits: InterpreterLimits,
+    fuel_used: u64,
+    heap_size: u64,
+    /// The sizes of the live heap allocations, by allocation id. They are tracked even when the
+    /// heap size isn't limited, so that a limit set later accounts for them.
+    heap_allocations: HashMap<u64, u64>,
+    /// The limit an allocation made by the hooks on the module's behalf exceeded, if any.
+    exceeded: Option<InterpreterLimit>,
+}
+
+thread_local! {
+    /// The limits of the engines whose hooks are handling a call, innermost last. Allocations the
+    /// `ForeignFunctionRegistry` shims make on the module's behalf count towards the innermost.
+    static CALL_LIMITS: RefCell<Vec<Rc<RefCell<Limits>>>> = RefCell::new(Vec::new());
+}
+
+impl Limits {
+    pub(crate) fn get_limits(&self) -> InterpreterLimits {
+        self.limits
+    }
+
+    /// Replaces the limits, refilling the fuel.
+    pub(crate) fn set_limits(&mut self, limits: InterpreterLimits) {
+        self.limits = limits;
+        self.fuel_used = 0;
+    }
+
+    pub(crate) fn get_remaining_fuel(&self) -> Option<u64> {
+        self.limits.fuel.map(|fuel| fuel.saturating_sub(self.fuel_used))
+    }
+
+    /// Uses up the fuel for one instruction. Returns `false` if there is none left.
+    pub(crate) fn consume_fuel(&mut self) -> bool {
+        if self.get_remaining_fuel() == Some(0) {
+            return false;
+        }
+
+        self.fuel_used += 1;
+
+        true
+    }
+
+    pub(crate) fn allows_call_depth(&self, depth: u32) -> bool {
+        self.limits.max_call_depth.map_or(true, |max| depth <= max)
+    }
+
+    pub(crate) fn allows_allocation(&self, size: u64) -> bool {
+        match self.limits.max_heap_size {
+            Some(max) => matches!(self.heap_size.checked_add(size), Some(total) if total <= max),
+            None => true,
+        }
+    }
+
+    pub(crate) fn on_allocate(&mut self, ptr: MiriPointer, size: u64) {
+        self.heap_size += size;
+
+        if let Some(old_size) = self.heap_allocations.insert(ptr.prov.alloc_id, size) {
+            self.heap_size -= old_size;
+        }
+    }
+
+    pub(crate) fn on_free(&mut self, ptr: MiriPointer) {
+        if let Some(size) = self.heap_allocations.remove(&ptr.prov.alloc_id) {
+            self.heap_size -= size;
+        }
+    }
+
+    /// Takes the limit an allocation made through `allocate_heap` exceeded since this was last called.
+    pub(crate) fn take_exceeded(&mut self) -> Option<InterpreterLimit> {
+        self.exceeded.take()
+    }
+
+    /// Puts back how much fuel and heap were in use when `usage` was cloned, keeping the limits.
+    pub(crate) fn restore_usage(&mut self, usage: &Limits) {
+        self.fuel_used = usage.fuel_used;
+        self.heap_size = usage.heap_size;
+        self.heap_allocations = usage.heap_allocations.clone();
+        self.exceeded = None;
+    }
+
+    /// Forgets every heap allocation, since they belong to the hooks which made them.
+    pub(crate) fn clear_allocations(&mut self) {
+        self.heap_allocations.clear();
+        self.heap_size = 0;
+    }
+}
+
+/// Calls `f`, which hands a call to the hooks of the engine `limits` belong to, with the heap
+/// allocations made through `allocate_heap` meanwhile counting towards them.
+pub(crate) fn with_call_limits<R>(limits: &Rc<RefCell<Limits>>, f: impl FnOnce() -> R) -> R {
+    CALL_LIMITS.with(|stack| stack.borrow_mut().push(limits.clone()));
+
+    let result = f();
+
+    CALL_LIMITS.with(|stack| stack.borrow_mut().pop());
+
+    result
+}
+
+/// Makes a heap allocation of `size` bytes on the module's behalf with `allocate`, unless it would
+/// exceed the `max_heap_size` of the engine whose hooks are handling the call. Returns `None` if it
+/// would, in which case the thread which made the call errors once it's stepped.
+pub(crate) fn allocate_heap(size: u64, allocate: impl FnOnce() -> MiriPointer) -> Option<MiriPointer> {
+    let limits = match CALL_LIMITS.with(|stack| stack.borrow().last().cloned()) {
+        Some(limits) => limits,
+        None => return Some(allocate()),
+    };
+
+    if !limits.borrow().allows_allocation(size) {
+        limits.borrow_mut().exceeded = Some(InterpreterLimit::HeapSize);
+
+        return None;
+    }
+
+    let ptr = allocate();
+
+    // A null pointer means the allocation failed
+    if ptr.addr != 0 {
+        limits.borrow_mut().on_allocate(ptr, size);
+    }
+
+    Some(ptr)
+}
+
+/// Forgets a heap allocation freed on the module's behalf, so it no longer counts towards the
+/// `max_heap_size` of the engine whose hooks are handling the call.
+pub(crate) fn free_heap(ptr: MiriPointer) {
+    if let Some(limits) = CALL_LIMITS.with(|stack| stack.borrow().last().cloned()) {
+        limits.borrow_mut().on_free(ptr);
+    }
+}
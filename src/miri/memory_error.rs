@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use llvm_sys::miri::MiriPointer;
+
+use crate::miri::{MemoryAccess, StackTrace};
+
+/// What kind of invalid memory operation a `SimpleMiriHost` caught.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryErrorKind {
+    /// An access of `size` bytes, starting `offset` bytes into an allocation of `alloc_size` bytes,
+    /// which doesn't lie entirely inside it.
+    OutOfBounds {
+        access: MemoryAccess,
+        offset: i64,
+        size: u64,
+        alloc_size: u64,
+    },
+    /// An access through a pointer to an allocation which has already been freed.
+    UseAfterFree { access: MemoryAccess, size: u64 },
+    /// A pointer to an allocation which has already been freed was freed again.
+    DoubleFree,
+    /// A pointer which doesn't point to the start of a live allocation was freed.
+    InvalidFree,
+    /// An access through a pointer without provenance, such as null or an integer cast to a
+    /// pointer which doesn't point into an exposed allocation.
+    NoProvenance { access: MemoryAccess, size: u64 },
+    /// A load of a scalar of `size` bytes, starting `offset` bytes past the pointer loaded from,
+    /// which read bytes that were never written.
+    Uninitialized { offset: u64, size: u64 },
+    /// A value of a type the host doesn't know how to lay out in memory was loaded or stored.
+    UnsupportedType { access: MemoryAccess, llvm_type: String },
+    /// An allocation of `size` bytes, more than the host could hold. The allocation returned a null
+    /// pointer instead.
+    AllocationFailed { size: u64 },
+}
+
+impl Display for MemoryErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryErrorKind::OutOfBounds {
+                access,
+                offset,
+                size,
+                alloc_size,
+            } => write!(
+                f,
+                "out-of-bounds {} of {} bytes at offset {} of an allocation of {} bytes",
+                access_name(*access),
+                size,
+                offset,
+                alloc_size
+            ),
+            MemoryErrorKind::UseAfterFree { access, size } => {
+                write!(f, "use-after-free {} of {} bytes", access_name(*access), size)
+            },
+            MemoryErrorKind::DoubleFree => write!(f, "double free"),
+            MemoryErrorKind::InvalidFree => write!(f, "free of a pointer which isn't the start of an allocation"),
+            MemoryErrorKind::NoProvenance { access, size } => write!(
+                f,
+                "{} of {} bytes through a pointer without provenance",
+                access_name(*access),
+                size
+            ),
+            MemoryErrorKind::Uninitialized { offset, size } => write!(
+                f,
+                "read of {} bytes at offset {} past the pointer, some of which are uninitialized",
+                size, offset
+            ),
+            MemoryErrorKind::UnsupportedType { access, llvm_type } => {
+                write!(f, "{} of unsupported type `{}`", access_name(*access), llvm_type)
+            },
+            MemoryErrorKind::AllocationFailed { size } => write!(f, "allocation of {} bytes failed", size),
+        }
+    }
+}
+
+fn access_name(access: MemoryAccess) -> &'static str {
+    match access {
+        MemoryAccess::Read => "read",
+        MemoryAccess::Write => "write",
+    }
+}
+
+/// An invalid memory operation caught by a `SimpleMiriHost`, along with the stack of the
+/// interpreter thread which performed it.
+#[derive(Debug, Clone)]
+pub struct MemoryError<'ctx> {
+    pub kind: MemoryErrorKind,
+    /// The pointer which was accessed or freed.
+    pub ptr: MiriPointer,
+    /// The stack the interpreter reported after the operation was rejected. This is `None` if
+    /// the interpreter didn't report one, e.g. because the host was called directly. Resolving it
+    /// with `StackTrace::resolve` finds the instruction which performed the operation.
+    pub stack_trace: Option<StackTrace<'ctx>>,
+}
+
+impl Error for MemoryError<'_> {}
+
+impl Display for MemoryError<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (pointer {:#x}, allocation {})",
+            self.kind, self.ptr.addr, self.ptr.prov.alloc_id
+        )?;
+
+        if let Some(stack_trace) = &self.stack_trace {
+            write!(f, "\n{}", stack_trace)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! Support for driving LLVM's interpreter from a Miri-style host.
+
+mod debugger;
+mod foreign;
+mod function_pointers;
+mod globals;
+mod hooks;
+mod host;
+mod limits;
+mod memory_error;
+mod model_checker;
+mod probes;
+mod snapshot;
+mod stack_trace;
+mod thread;
+pub mod trace;
+
+pub use crate::miri::debugger::{BreakpointHit, BreakpointId, BreakpointLocation, MemoryAccess, Trigger, WatchKind};
+pub use crate::miri::foreign::{
+    ForeignArgs, ForeignCall, ForeignCallError, ForeignFallback, ForeignFunctionRegistry, ForeignMemory, ForeignReturn,
+};
+pub use crate::miri::function_pointers::FunctionPointerTarget;
+pub(crate) use crate::miri::globals::{global_name, target_name};
+pub use crate::miri::globals::{GlobalImage, GlobalLayoutError, Relocation, RelocationTarget};
+pub(crate) use crate::miri::hooks::MiriHookSlot;
+pub use crate::miri::hooks::{CallCompletion, MiriHookPanic, MiriHooks};
+pub use crate::miri::host::{MemorySnapshot, SimpleMiriHost};
+pub(crate) use crate::miri::limits::Limits;
+pub use crate::miri::limits::{InterpreterLimit, InterpreterLimits};
+pub use crate::miri::memory_error::{MemoryError, MemoryErrorKind};
+pub use crate::miri::model_checker::{DataRace, Exploration, Finding, ModelChecker, RacingAccess};
+pub use crate::miri::snapshot::InterpreterSnapshot;
+pub use crate::miri::stack_trace::{InlinedFrame, StackTrace, StackTraceFormat, StackTraceItem};
+pub(crate) use crate::miri::thread::unused_thread_id;
+pub use crate::miri::thread::{Frame, InterpreterThread, RandomScheduler, RoundRobinScheduler, Scheduler, StepResult};
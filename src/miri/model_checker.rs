@@ -0,0 +1,653 @@
+use llvm_sys::core::{LLVMGetCmpXchgSuccessOrdering, LLVMGetOrdering};
+use llvm_sys::miri::MiriPointer;
+
+use crate::execution_engine::{ExecutionEngine, InterpreterError};
+use crate::miri::{CallCompletion, InterpreterThread, MemoryAccess, StackTrace};
+use crate::values::{AsValueRef, FunctionValue, InstructionOpcode, InstructionValue};
+use crate::AtomicOrdering;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// One side of a `DataRace`.
+#[derive(Debug, Clone)]
+pub struct RacingAccess<'ctx> {
+    pub thread_id: u64,
+    pub ptr: MiriPointer,
+    pub size: u64,
+    pub access: MemoryAccess,
+    /// The frames of the thread when it made the access, innermost last, located by the debug
+    /// info of the instructions they were at.
+    pub stack_trace: StackTrace<'ctx>,
+}
+
+/// Two accesses to overlapping memory by different threads, at least one of them a write and at
+/// least one of them not atomic, which aren't ordered by synchronization.
+#[derive(Debug, Clone)]
+pub struct DataRace<'ctx> {
+    pub first: RacingAccess<'ctx>,
+    pub second: RacingAccess<'ctx>,
+    /// The thread id of each transition up to and including the second access.
+    pub schedule: Vec<u64>,
+}
+
+/// A problem found by a `ModelChecker`. Each is only reported for the first interleaving it was
+/// found in.
+#[derive(Debug)]
+pub enum Finding<'ctx> {
+    DataRace(DataRace<'ctx>),
+    /// Every thread which hadn't exited was waiting on a call which could never complete, after
+    /// the transitions in `schedule`. Each thread is listed with its frames, innermost last.
+    Deadlock {
+        threads: Vec<(u64, StackTrace<'ctx>)>,
+        schedule: Vec<u64>,
+    },
+    /// Stepping `thread_id` failed, after the transitions in `schedule`.
+    Error {
+        thread_id: u64,
+        error: InterpreterError,
+        schedule: Vec<u64>,
+    },
+}
+
+/// The outcome of `ModelChecker::explore`.
+#[derive(Debug)]
+pub struct Exploration<'ctx> {
+    /// How many interleavings were run.
+    pub executions: usize,
+    /// Whether every interleaving which could behave differently was run to the end, rather than
+    /// stopping at `ModelChecker::set_max_executions` or being cut off by `ModelChecker::set_max_steps`.
+    pub complete: bool,
+    pub findings: Vec<Finding<'ctx>>,
+}
+
+/// Systematically explores the interleavings of a set of `InterpreterThread`s, looking for data
+/// races, deadlocks and errors.
+///
+/// Threads are only switched between at their memory accesses, since everything else a thread
+/// does can't be observed by the others. On top of that, dynamic partial-order reduction skips
+/// interleavings which only reorder accesses which don't conflict, i.e. which don't overlap or
+/// are both reads.
+///
+/// Each interleaving is run from the start, since restoring an `InterpreterSnapshot` would replay
+/// the steps leading up to it anyway: the closure passed to `explore` has to put the host's memory
+/// back, e.g. with `SimpleMiriHost::restore`, and spawn the threads anew. Runs have to be
+/// deterministic for the exploration to be sound.
+///
+/// Accesses made by atomic instructions, that is `atomicrmw`, `cmpxchg` and loads and stores with an
+/// atomic ordering, never race with each other, only with accesses which aren't atomic. They order
+/// the accesses around them by their ordering: release stores and read-modify-writes publish what
+/// their thread has seen, and acquire loads and read-modify-writes see what the release they read
+/// from published. `seq_cst` accesses do both, while `monotonic` ones do neither. Which instruction
+/// made an access, and so whether it's atomic, is only known in functions instrumented with
+/// `ExecutionEngine::instrument_module`.
+///
+/// Calls the threads hand to the installed `MiriHooks` are completed through
+/// `MiriHooks::complete_call`. A thread whose call has to wait isn't scheduled until another thread
+/// has stepped, and once every thread which hasn't exited is waiting, they're deadlocked.
+#[derive(Debug, Clone)]
+pub struct ModelChecker {
+    max_steps: u64,
+    max_executions: Option<usize>,
+}
+
+impl Default for ModelChecker {
+    fn default() -> Self {
+        ModelChecker {
+            max_steps: 10_000,
+            max_executions: None,
+        }
+    }
+}
+
+/// The accesses made by the step which ended a transition, along with where the thread was.
+struct Transition<'ctx> {
+    accesses: Vec<(MiriPointer, u64, MemoryAccess)>,
+    sync: Synchronization,
+    stack_trace: StackTrace<'ctx>,
+}
+
+/// How the instruction which made a transition's accesses synchronizes with other threads.
+#[derive(Debug, Clone, Copy, Default)]
+struct Synchronization {
+    atomic: bool,
+    acquire: bool,
+    release: bool,
+    /// Whether the instruction both reads and writes, and so continues the release sequence of the
+    /// store it reads from.
+    read_modify_write: bool,
+}
+
+/// A thread and the accesses made by its step which ended a transition.
+struct Event<'ctx> {
+    thread_id: u64,
+    transition: Transition<'ctx>,
+    /// Which node of the exploration stack chose this transition.
+    depth: usize,
+    /// The transition's clock, ordered by program order and conflicting accesses.
+    causal_clock: VectorClock,
+    /// The transition's clock, ordered by program order and synchronizing atomic accesses.
+    sync_clock: VectorClock,
+}
+
+/// A scheduling decision, along with the alternatives which still have to be explored.
+struct Node {
+    enabled: Vec<u64>,
+    chosen: u64,
+    backtrack: BTreeSet<u64>,
+    done: BTreeSet<u64>,
+}
+
+#[derive(Clone, Default)]
+struct VectorClock(HashMap<u64, u64>);
+
+impl VectorClock {
+    fn get(&self, thread_id: u64) -> u64 {
+        self.0.get(&thread_id).copied().unwrap_or(0)
+    }
+
+    fn tick(&mut self, thread_id: u64) {
+        *self.0.entry(thread_id).or_insert(0) += 1;
+    }
+
+    fn join(&mut self, other: &VectorClock) {
+        for (&thread_id, &time) in &other.0 {
+            let entry = self.0.entry(thread_id).or_insert(0);
+
+            *entry = (*entry).max(time);
+        }
+    }
+
+    /// Returns whether `event`, made by `thread_id`, happened before the transition with this clock.
+    fn has_seen(&self, thread_id: u64, event: &VectorClock) -> bool {
+        self.get(thread_id) >= event.get(thread_id)
+    }
+}
+
+enum Stop {
+    Error(InterpreterError),
+    StepLimit,
+}
+
+impl ModelChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many steps, across all threads, a single interleaving may take before it's cut off,
+    /// which leaves the exploration incomplete. Defaults to 10,000.
+    pub fn set_max_steps(&mut self, max_steps: u64) {
+        self.max_steps = max_steps;
+    }
+
+    /// Stops exploring after `max_executions` interleavings. Unbounded by default.
+    pub fn set_max_executions(&mut self, max_executions: usize) {
+        self.max_executions = Some(max_executions);
+    }
+
+    /// Runs the threads returned by `setup` in every interleaving which could behave differently,
+    /// calling `setup` again before each one. The threads must have the same ids every time.
+    ///
+    /// Returns an error if `setup` does.
+    pub unsafe fn explore<'ctx, F>(
+        &self,
+        execution_engine: &ExecutionEngine<'ctx>,
+        mut setup: F,
+    ) -> Result<Exploration<'ctx>, InterpreterError>
+    where
+        F: FnMut() -> Result<Vec<InterpreterThread<'ctx>>, InterpreterError>,
+    {
+        let breakpoints = execution_engine.miri_hooks().breakpoints();
+        let mut stack = Vec::new();
+        let mut exploration = Exploration {
+            executions: 0,
+            complete: false,
+            findings: Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        let mut cut_off = false;
+
+        breakpoints.borrow_mut().set_recording_accesses(true);
+
+        loop {
+            let threads = match setup() {
+                Ok(threads) => threads,
+                Err(err) => {
+                    breakpoints.borrow_mut().set_recording_accesses(false);
+
+                    return Err(err);
+                },
+            };
+
+            if !self.execute(
+                execution_engine,
+                threads,
+                &mut stack,
+                &mut exploration.findings,
+                &mut seen,
+            ) {
+                cut_off = true;
+            }
+
+            exploration.executions += 1;
+
+            // Continue from the deepest decision which still has alternatives left
+            while let Some(node) = stack.last_mut() {
+                let next = node.backtrack.difference(&node.done).next().copied();
+
+                if let Some(next) = next {
+                    node.chosen = next;
+                    node.done.insert(next);
+                    break;
+                }
+
+                stack.pop();
+            }
+
+            if stack.is_empty() {
+                exploration.complete = !cut_off;
+                break;
+            }
+
+            if self.max_executions.map_or(false, |max| exploration.executions >= max) {
+                break;
+            }
+        }
+
+        breakpoints.borrow_mut().set_recording_accesses(false);
+
+        Ok(exploration)
+    }
+
+    /// Runs one interleaving, replaying the choices on `stack` and extending it with new ones.
+    /// Returns `false` if it was cut off by the step limit.
+    unsafe fn execute<'ctx>(
+        &self,
+        execution_engine: &ExecutionEngine<'ctx>,
+        mut threads: Vec<InterpreterThread<'ctx>>,
+        stack: &mut Vec<Node>,
+        findings: &mut Vec<Finding<'ctx>>,
+        seen: &mut HashSet<String>,
+    ) -> bool {
+        let mut events: Vec<Event<'ctx>> = Vec::new();
+        let mut causal_clocks: HashMap<u64, VectorClock> = HashMap::new();
+        let mut sync_clocks: HashMap<u64, VectorClock> = HashMap::new();
+        // What the last release to each address published, along with the read-modify-writes after it
+        let mut released: HashMap<u64, VectorClock> = HashMap::new();
+        // The threads whose calls had to wait since the last transition
+        let mut waiting: BTreeSet<u64> = BTreeSet::new();
+        let mut schedule = Vec::new();
+        let mut steps = 0;
+
+        // Clear out anything recorded while setting up
+        execution_engine.miri_hooks().breakpoints().borrow_mut().take_accesses();
+
+        loop {
+            for thread in &mut threads {
+                let thread_id = thread.get_id();
+
+                if !thread.is_blocked() || waiting.contains(&thread_id) {
+                    continue;
+                }
+
+                match complete_call(execution_engine, thread) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        waiting.insert(thread_id);
+                    },
+                    Err(error) => {
+                        report_error(thread_id, error, &schedule, findings, seen);
+
+                        return true;
+                    },
+                }
+            }
+
+            let enabled: Vec<u64> = threads
+                .iter()
+                .filter(|thread| !thread.has_exited() && !thread.is_blocked())
+                .map(|thread| thread.get_id())
+                .collect();
+
+            if enabled.is_empty() {
+                if !waiting.is_empty() {
+                    report_deadlock(&threads, &schedule, findings, seen);
+                }
+
+                return true;
+            }
+
+            let depth = schedule.len();
+            let replayed = stack.get(depth).map(|node| node.chosen);
+            let thread_id = match replayed {
+                // A replayed choice may no longer be possible if the threads aren't deterministic
+                Some(chosen) if enabled.contains(&chosen) => chosen,
+                Some(_) => {
+                    stack.truncate(depth);
+                    return true;
+                },
+                None => {
+                    // Keep running the thread which ran last, so as to preempt as little as possible
+                    let chosen = schedule
+                        .last()
+                        .copied()
+                        .filter(|thread_id| enabled.contains(thread_id))
+                        .unwrap_or(enabled[0]);
+
+                    stack.push(Node {
+                        enabled: enabled.clone(),
+                        chosen,
+                        backtrack: std::iter::once(chosen).collect(),
+                        done: std::iter::once(chosen).collect(),
+                    });
+
+                    chosen
+                },
+            };
+            let thread = threads
+                .iter_mut()
+                .find(|thread| thread.get_id() == thread_id)
+                .expect("enabled threads should exist");
+
+            schedule.push(thread_id);
+
+            let transition = match self.run_transition(execution_engine, thread, &mut steps) {
+                Ok(transition) => transition,
+                Err(Stop::Error(error)) => {
+                    report_error(thread_id, error, &schedule, findings, seen);
+
+                    return true;
+                },
+                // Running out of steps says nothing about the threads, which may just be slow
+                Err(Stop::StepLimit) => return false,
+            };
+
+            // Whatever the waiting calls wait on may have happened
+            waiting.clear();
+
+            let accesses = &transition.accesses;
+            let sync = transition.sync;
+            let mut causal_clock = causal_clocks.remove(&thread_id).unwrap_or_default();
+            let mut sync_clock = sync_clocks.remove(&thread_id).unwrap_or_default();
+
+            causal_clock.tick(thread_id);
+            sync_clock.tick(thread_id);
+
+            // Acquires see everything the release they read from published
+            if sync.acquire {
+                for &(ptr, _, access) in accesses {
+                    if let (MemoryAccess::Read, Some(clock)) = (access, released.get(&ptr.addr)) {
+                        sync_clock.join(clock);
+                    }
+                }
+            }
+
+            let mut last_unordered: HashMap<u64, usize> = HashMap::new();
+
+            for (idx, event) in events.iter().enumerate() {
+                if event.thread_id == thread_id || !conflicting(&event.transition.accesses, accesses, true) {
+                    continue;
+                }
+
+                if !causal_clock.has_seen(event.thread_id, &event.causal_clock) {
+                    last_unordered.insert(event.thread_id, idx);
+                }
+
+                if !(sync.atomic && event.transition.sync.atomic)
+                    && !sync_clock.has_seen(event.thread_id, &event.sync_clock)
+                {
+                    report_race(event, thread_id, &transition, &schedule, findings, seen);
+                }
+            }
+
+            // Running this transition before the last unordered conflicting one of each other thread
+            // could behave differently, so that has to be explored as well
+            for &idx in last_unordered.values() {
+                let node = &mut stack[events[idx].depth];
+
+                if node.enabled.contains(&thread_id) {
+                    node.backtrack.insert(thread_id);
+                } else {
+                    node.backtrack.extend(node.enabled.iter().copied());
+                }
+            }
+
+            for event in &events {
+                if event.thread_id != thread_id && conflicting(&event.transition.accesses, accesses, true) {
+                    causal_clock.join(&event.causal_clock);
+                }
+            }
+
+            for &(ptr, _, access) in accesses {
+                if access != MemoryAccess::Write {
+                    continue;
+                }
+
+                match (sync.release, sync.read_modify_write) {
+                    (true, true) => released.entry(ptr.addr).or_default().join(&sync_clock),
+                    (true, false) => {
+                        released.insert(ptr.addr, sync_clock.clone());
+                    },
+                    // Read-modify-writes continue the release sequence they read from, other stores end it
+                    (false, true) => {},
+                    (false, false) => {
+                        released.remove(&ptr.addr);
+                    },
+                }
+            }
+
+            causal_clocks.insert(thread_id, causal_clock.clone());
+            sync_clocks.insert(thread_id, sync_clock.clone());
+            events.push(Event {
+                thread_id,
+                transition,
+                depth,
+                causal_clock,
+                sync_clock,
+            });
+        }
+    }
+
+    /// Steps `thread` up to and including its next step which accesses memory or makes a call to
+    /// the hooks, or until it exits.
+    unsafe fn run_transition<'ctx>(
+        &self,
+        execution_engine: &ExecutionEngine<'ctx>,
+        thread: &mut InterpreterThread<'ctx>,
+        steps: &mut u64,
+    ) -> Result<Transition<'ctx>, Stop> {
+        loop {
+            if *steps >= self.max_steps {
+                return Err(Stop::StepLimit);
+            }
+
+            *steps += 1;
+
+            // The step executes the instruction each frame is at
+            let frames = current_frames(thread);
+            let result = thread.step();
+            let accesses = execution_engine.miri_hooks().breakpoints().borrow_mut().take_accesses();
+
+            result.map_err(Stop::Error)?;
+
+            // The call is completed before the next transition is chosen, if it can be
+            if !accesses.is_empty() || thread.has_exited() || thread.is_blocked() {
+                let sync = frames.last().map(|&(_, instruction)| synchronization(instruction));
+
+                return Ok(Transition {
+                    accesses,
+                    sync: sync.unwrap_or_default(),
+                    stack_trace: StackTrace::from_frames(&frames),
+                });
+            }
+        }
+    }
+}
+
+/// Asks the hooks to complete the call `thread` is blocked on, returning whether they could.
+fn complete_call<'ctx>(
+    execution_engine: &ExecutionEngine<'ctx>,
+    thread: &mut InterpreterThread<'ctx>,
+) -> Result<bool, InterpreterError> {
+    let thread_id = thread.get_id();
+    let completion = execution_engine
+        .miri_hooks()
+        .with_hooks(|hooks| hooks.complete_call(thread_id));
+    let return_value = match completion {
+        Some(CallCompletion::Waiting) => return Ok(false),
+        Some(CallCompletion::Returned(return_value)) => return_value,
+        None => None,
+    };
+
+    thread.set_pending_return(return_value)?;
+
+    Ok(true)
+}
+
+/// Gets the function and instruction of each frame of `thread`, innermost last.
+fn current_frames<'ctx>(thread: &InterpreterThread<'ctx>) -> Vec<(FunctionValue<'ctx>, InstructionValue<'ctx>)> {
+    thread
+        .get_frames()
+        .iter()
+        .filter_map(|frame| Some((frame.get_function(), frame.get_instruction()?)))
+        .collect()
+}
+
+fn report_error<'ctx>(
+    thread_id: u64,
+    error: InterpreterError,
+    schedule: &[u64],
+    findings: &mut Vec<Finding<'ctx>>,
+    seen: &mut HashSet<String>,
+) {
+    if seen.insert(format!("error {} {}", thread_id, error)) {
+        findings.push(Finding::Error {
+            thread_id,
+            error,
+            schedule: schedule.to_vec(),
+        });
+    }
+}
+
+/// Reports the threads which are blocked on calls, all of which had to wait.
+fn report_deadlock<'ctx>(
+    threads: &[InterpreterThread<'ctx>],
+    schedule: &[u64],
+    findings: &mut Vec<Finding<'ctx>>,
+    seen: &mut HashSet<String>,
+) {
+    let blocked: Vec<_> = threads.iter().filter(|thread| thread.is_blocked()).collect();
+    // The same deadlock is usually reached in many interleavings
+    let calls: Vec<_> = blocked
+        .iter()
+        .map(|thread| {
+            let call = thread.get_current_instruction().map(|call| call.as_value_ref());
+
+            (thread.get_id(), call)
+        })
+        .collect();
+
+    if seen.insert(format!("deadlock {:?}", calls)) {
+        findings.push(Finding::Deadlock {
+            threads: blocked
+                .iter()
+                .map(|thread| (thread.get_id(), StackTrace::from_frames(&current_frames(thread))))
+                .collect(),
+            schedule: schedule.to_vec(),
+        });
+    }
+}
+
+fn report_race<'ctx>(
+    event: &Event<'ctx>,
+    thread_id: u64,
+    transition: &Transition<'ctx>,
+    schedule: &[u64],
+    findings: &mut Vec<Finding<'ctx>>,
+    seen: &mut HashSet<String>,
+) {
+    for &(first_ptr, first_size, first_access) in &event.transition.accesses {
+        for &(second_ptr, second_size, second_access) in &transition.accesses {
+            let conflict = overlaps(first_ptr.addr, first_size, second_ptr.addr, second_size)
+                && (first_access == MemoryAccess::Write || second_access == MemoryAccess::Write);
+
+            if !conflict {
+                continue;
+            }
+
+            // The same pair of accesses is usually found in many interleavings, in either order
+            let mut sides = [
+                format!("{} {:#x} {:?}", event.thread_id, first_ptr.addr, first_access),
+                format!("{} {:#x} {:?}", thread_id, second_ptr.addr, second_access),
+            ];
+
+            sides.sort();
+
+            if seen.insert(format!("race {} {}", sides[0], sides[1])) {
+                findings.push(Finding::DataRace(DataRace {
+                    first: RacingAccess {
+                        thread_id: event.thread_id,
+                        ptr: first_ptr,
+                        size: first_size,
+                        access: first_access,
+                        stack_trace: event.transition.stack_trace.clone(),
+                    },
+                    second: RacingAccess {
+                        thread_id,
+                        ptr: second_ptr,
+                        size: second_size,
+                        access: second_access,
+                        stack_trace: transition.stack_trace.clone(),
+                    },
+                    schedule: schedule.to_vec(),
+                }));
+            }
+        }
+    }
+}
+
+/// Gets how `instruction` synchronizes with other threads, by its atomic ordering.
+fn synchronization(instruction: InstructionValue<'_>) -> Synchronization {
+    let opcode = instruction.get_opcode();
+    let ordering: AtomicOrdering = match opcode {
+        // The success ordering, which is at least as strong as the failure one
+        InstructionOpcode::AtomicCmpXchg => unsafe { LLVMGetCmpXchgSuccessOrdering(instruction.as_value_ref()) }.into(),
+        InstructionOpcode::AtomicRMW => unsafe { LLVMGetOrdering(instruction.as_value_ref()) }.into(),
+        _ => match instruction.get_atomic_ordering() {
+            Ok(ordering) => ordering,
+            Err(_) => return Synchronization::default(),
+        },
+    };
+    let acquires = matches!(
+        ordering,
+        AtomicOrdering::Acquire | AtomicOrdering::AcquireRelease | AtomicOrdering::SequentiallyConsistent
+    );
+    let releases = matches!(
+        ordering,
+        AtomicOrdering::Release | AtomicOrdering::AcquireRelease | AtomicOrdering::SequentiallyConsistent
+    );
+
+    Synchronization {
+        atomic: ordering != AtomicOrdering::NotAtomic,
+        acquire: acquires && opcode != InstructionOpcode::Store,
+        release: releases && opcode != InstructionOpcode::Load,
+        read_modify_write: matches!(opcode, InstructionOpcode::AtomicRMW | InstructionOpcode::AtomicCmpXchg),
+    }
+}
+
+fn overlaps(start: u64, len: u64, other_start: u64, other_len: u64) -> bool {
+    start < other_start.saturating_add(other_len) && other_start < start.saturating_add(len)
+}
+
+/// Returns whether any of `first` overlaps any of `second`, and, if `needs_write`, either of them is a write.
+fn conflicting(
+    first: &[(MiriPointer, u64, MemoryAccess)],
+    second: &[(MiriPointer, u64, MemoryAccess)],
+    needs_write: bool,
+) -> bool {
+    first.iter().any(|&(ptr, size, access)| {
+        second.iter().any(|&(other_ptr, other_size, other_access)| {
+            overlaps(ptr.addr, size, other_ptr.addr, other_size)
+                && (!needs_write || access == MemoryAccess::Write || other_access == MemoryAccess::Write)
+        })
+    })
+}
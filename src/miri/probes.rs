@@ -0,0 +1,313 @@
+use either::Either;
+use llvm_sys::core::{LLVMAddFunction, LLVMGetFirstFunction, LLVMGetNamedFunction};
+use llvm_sys::prelude::{LLVMModuleRef, LLVMValueRef};
+
+use crate::support::to_c_str;
+use crate::types::{AsTypeRef, BasicTypeEnum, FunctionType};
+use crate::values::{
+    AsValueRef, BasicMetadataValueEnum, BasicValueEnum, FromGenericValue, FunctionValue, GenericValue, GenericValueRef,
+    InstructionOpcode, InstructionValue,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The prefix of the functions probes call, which are handled by the engine rather than the `MiriHooks`.
+pub(crate) const PROBE_PREFIX: &str = "inkwell.miri.";
+const ENTRY_PROBE: &str = "inkwell.miri.entry";
+const INSTRUCTION_PROBE: &str = "inkwell.miri.instruction";
+const VALUE_PROBE: &str = "inkwell.miri.value.";
+
+/// Probe ids are unique across modules and engines, so that a module instrumented by one engine
+/// can be run by another.
+static NEXT_PROBE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a probe reports when the interpreter calls it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ProbeSite<'ctx> {
+    /// `function` was entered. The values of its arguments follow.
+    Entry(FunctionValue<'ctx>),
+    /// An argument or instruction was bound to the value passed to the probe.
+    Value(BasicValueEnum<'ctx>),
+    /// The instruction is about to be executed.
+    Instruction(InstructionValue<'ctx>),
+}
+
+#[derive(Debug)]
+pub(crate) struct ProbeHit<'ctx> {
+    pub(crate) site: ProbeSite<'ctx>,
+    /// The value passed to a `ProbeSite::Value` probe.
+    pub(crate) value: Option<GenericValue<'ctx>>,
+}
+
+/// Tracks where an `InterpreterThread` is by instrumenting the modules it runs with calls to
+/// declared `inkwell.miri.*` functions, since the interpreter doesn't report which instruction it
+/// executed or what its frames hold.
+///
+/// Every function gets a probe on entry, followed by one for each of its arguments, a probe before
+/// each instruction and one after each instruction producing a value. Nothing may come between a
+/// `musttail` call and the `ret` after it, so the rest of its block goes without probes, and the
+/// frame making it returns along with its callee. The call by name trampoline hands the calls to
+/// `on_call` instead of the hooks, and the thread steps over them.
+#[derive(Debug, Default)]
+pub(crate) struct Probes<'ctx> {
+    sites: HashMap<u64, ProbeSite<'ctx>>,
+    /// The functions whose probes are in `sites`.
+    functions: HashSet<LLVMValueRef>,
+    hits: Vec<ProbeHit<'ctx>>,
+}
+
+impl<'ctx> Probes<'ctx> {
+    /// Instruments every function defined in `module` which isn't yet. This has to happen before a
+    /// thread starts running them, or it misses the probes it's already past.
+    pub(crate) fn instrument(&mut self, module: LLVMModuleRef) {
+        let mut next = unsafe { FunctionValue::new(LLVMGetFirstFunction(module)) };
+
+        while let Some(function) = next {
+            // Probes are declared while instrumenting, but they're never defined
+            next = function.get_next_function();
+
+            if function.count_basic_blocks() == 0 || !self.functions.insert(function.as_value_ref()) {
+                continue;
+            }
+
+            if !is_instrumented(function) {
+                instrument_function(module, function);
+            }
+
+            self.register(function);
+        }
+    }
+
+    /// Returns whether the probes of `function` are known, i.e. whether a thread running it can be
+    /// followed through them.
+    pub(crate) fn is_registered(&self, function: FunctionValue<'ctx>) -> bool {
+        self.functions.contains(&function.as_value_ref())
+    }
+
+    /// Finds the probes of `function` by the calls to them, which makes no difference between
+    /// functions this engine instrumented and those instrumented before.
+    fn register(&mut self, function: FunctionValue<'ctx>) {
+        for block in function.get_basic_block_iter() {
+            for instruction in block.get_instructions() {
+                let (name, id) = match probe_call(instruction) {
+                    Some(probe) => probe,
+                    None => continue,
+                };
+                let site = if name == ENTRY_PROBE {
+                    ProbeSite::Entry(function)
+                } else if name == INSTRUCTION_PROBE {
+                    match instruction.get_next_instruction() {
+                        Some(next) => ProbeSite::Instruction(next),
+                        None => continue,
+                    }
+                } else {
+                    match instruction.get_operand(1) {
+                        Some(Either::Left(value)) => ProbeSite::Value(value),
+                        _ => continue,
+                    }
+                };
+
+                self.sites.insert(id, site);
+            }
+        }
+    }
+
+    /// Records a call to the function `name` if it's one of the probes, returning whether it was.
+    pub(crate) fn on_call(&mut self, name: &str, args: &[GenericValueRef<'_>], fn_type: FunctionType<'ctx>) -> bool {
+        if !name.starts_with(PROBE_PREFIX) {
+            return false;
+        }
+
+        // Probes of functions which were never registered, e.g. because they were added after the
+        // module was instrumented, have nothing to report
+        let site = match args.first().and_then(|id| self.sites.get(&(id.as_int() as u64))) {
+            Some(&site) => site,
+            None => return true,
+        };
+        let value = match (args.get(1), fn_type.get_param_types().get(1)) {
+            (Some(value), Some(&ty)) => GenericValue::from_generic_value(value, ty).ok(),
+            _ => None,
+        };
+
+        self.hits.push(ProbeHit { site, value });
+
+        true
+    }
+
+    /// Takes the probes hit since this was last called, oldest first.
+    pub(crate) fn take_hits(&mut self) -> Vec<ProbeHit<'ctx>> {
+        std::mem::take(&mut self.hits)
+    }
+}
+
+/// Gets the name and id of the probe `instruction` calls, if it calls one.
+fn probe_call(instruction: InstructionValue<'_>) -> Option<(String, u64)> {
+    if instruction.get_opcode() != InstructionOpcode::Call {
+        return None;
+    }
+
+    // The callee is the last operand of a call
+    let callee = match instruction.get_operand(instruction.get_num_operands().checked_sub(1)?)? {
+        Either::Left(BasicValueEnum::PointerValue(callee)) => callee,
+        _ => return None,
+    };
+    let name = callee.get_name().to_str().ok()?;
+
+    if !name.starts_with(PROBE_PREFIX) {
+        return None;
+    }
+
+    let id = match instruction.get_operand(0)? {
+        Either::Left(BasicValueEnum::IntValue(id)) => id.get_zero_extended_constant()?,
+        _ => return None,
+    };
+
+    Some((name.to_owned(), id))
+}
+
+/// Returns whether `instruction` is a call to one of the probes rather than part of the module itself.
+pub(crate) fn is_probe(instruction: InstructionValue<'_>) -> bool {
+    probe_call(instruction).is_some()
+}
+
+/// Returns whether `instruction` is a `musttail` call, which has to be followed by a `ret`.
+pub(crate) fn is_must_tail_call(instruction: InstructionValue<'_>) -> bool {
+    // There's no way to tell `musttail` from `tail` through the C API before LLVM 18
+    instruction.is_tail_call()
+        && instruction
+            .print_to_string()
+            .to_string()
+            .split_whitespace()
+            .any(|word| word == "musttail")
+}
+
+/// Returns whether `instruction` is the first instruction of its block a thread stops before, that
+/// is whether only probes and instructions which have to start the block come before it.
+pub(crate) fn is_first_instruction(instruction: InstructionValue<'_>) -> bool {
+    let mut previous = instruction.get_previous_instruction();
+
+    while let Some(instruction) = previous {
+        if !is_block_start(instruction) && !is_probe(instruction) {
+            return false;
+        }
+
+        previous = instruction.get_previous_instruction();
+    }
+
+    true
+}
+
+fn is_instrumented(function: FunctionValue<'_>) -> bool {
+    function
+        .get_first_basic_block()
+        .and_then(|block| {
+            block
+                .get_instructions()
+                .find(|instruction| !is_block_start(*instruction))
+        })
+        .and_then(probe_call)
+        .map_or(false, |(name, _)| name == ENTRY_PROBE)
+}
+
+/// Returns whether `instruction` has to stay at the start of its block, ahead of any probes.
+fn is_block_start(instruction: InstructionValue<'_>) -> bool {
+    matches!(
+        instruction.get_opcode(),
+        InstructionOpcode::Phi
+            | InstructionOpcode::LandingPad
+            | InstructionOpcode::CatchPad
+            | InstructionOpcode::CleanupPad
+            | InstructionOpcode::CatchSwitch
+    )
+}
+
+/// Gets the value `instruction` produces, if it's one a probe can be passed right after it.
+fn result_value(instruction: InstructionValue<'_>) -> Option<BasicValueEnum<'_>> {
+    if instruction.is_terminator() {
+        return None;
+    }
+
+    BasicTypeEnum::try_from(instruction.get_type()).ok()?;
+
+    Some(unsafe { BasicValueEnum::new(instruction.as_value_ref()) })
+}
+
+fn declare<'ctx>(module: LLVMModuleRef, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+    let c_string = to_c_str(name);
+
+    unsafe {
+        let mut function = LLVMGetNamedFunction(module, c_string.as_ptr());
+
+        if function.is_null() {
+            function = LLVMAddFunction(module, c_string.as_ptr(), fn_type.as_type_ref());
+        }
+
+        FunctionValue::new(function).expect("probes are functions")
+    }
+}
+
+fn instrument_function(module: LLVMModuleRef, function: FunctionValue<'_>) {
+    let context = function.get_type().get_context();
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    let probe_type = context.void_type().fn_type(&[i64_type.into()], false);
+    let entry_probe = declare(module, ENTRY_PROBE, probe_type);
+    let instruction_probe = declare(module, INSTRUCTION_PROBE, probe_type);
+    let call = |probe: FunctionValue<'_>, value: Option<BasicValueEnum<'_>>| {
+        let id = i64_type.const_int(NEXT_PROBE_ID.fetch_add(1, Ordering::Relaxed), false);
+        let args: Vec<BasicMetadataValueEnum> = std::iter::once(id.into()).chain(value.map(Into::into)).collect();
+
+        builder.build_call(probe, &args, "").expect("builder is positioned");
+    };
+    let call_value = |value: BasicValueEnum<'_>| {
+        let ty = value.get_type();
+        let probe_type = context.void_type().fn_type(&[i64_type.into(), ty.into()], false);
+        let name = format!("{}{}", VALUE_PROBE, ty.print_to_string());
+
+        call(declare(module, &name, probe_type), Some(value));
+    };
+
+    for (idx, block) in function.get_basic_blocks().into_iter().enumerate() {
+        let instructions: Vec<_> = block.get_instructions().collect();
+        let first = match instructions
+            .iter()
+            .position(|instruction| !is_block_start(*instruction))
+        {
+            Some(first) => first,
+            None => continue,
+        };
+
+        builder.position_before(&instructions[first]);
+
+        if idx == 0 {
+            call(entry_probe, None);
+
+            for param in function.get_param_iter() {
+                call_value(param);
+            }
+        }
+
+        for &instruction in &instructions[..first] {
+            if let Some(value) = result_value(instruction) {
+                call_value(value);
+            }
+        }
+
+        for (pos, &instruction) in instructions.iter().enumerate().skip(first) {
+            builder.position_before(&instruction);
+            call(instruction_probe, None);
+
+            // Only a `ret`, or a cast of the call it returns, may follow a `musttail` call
+            if is_must_tail_call(instruction) {
+                break;
+            }
+
+            // The probe before the next instruction is inserted after this one, once it's reached
+            if let (Some(value), Some(next)) = (result_value(instruction), instructions.get(pos + 1)) {
+                builder.position_before(next);
+                call_value(value);
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::execution_engine::{ExecutionEngine, InterpreterError};
+use crate::miri::thread::ThreadImage;
+use crate::miri::{InterpreterThread, Limits, MiriHookSlot};
+use crate::values::{FromGenericValue, FunctionValue, GenericValue, GenericValueError};
+
+/// Something an `InterpreterThread` did to the interpreter, which is done again in the same order to
+/// restore an `InterpreterSnapshot`.
+#[derive(Debug)]
+enum JournalEntry<'ctx> {
+    Spawn {
+        thread_id: u64,
+        function: FunctionValue<'ctx>,
+        args: Vec<GenericValue<'ctx>>,
+    },
+    /// A step which succeeded. Steps which failed terminated the thread instead.
+    Step {
+        thread_id: u64,
+        pending_return: Option<Rc<GenericValue<'ctx>>>,
+    },
+    Terminate {
+        thread_id: u64,
+    },
+}
+
+/// The memory of the hooks and the resources in use when a journal started.
+struct Baseline {
+    memory: Rc<dyn Any>,
+    limits: Limits,
+}
+
+/// Everything the `InterpreterThread`s of an engine did to the interpreter since the oldest of
+/// those still alive was spawned, once `ExecutionEngine::enable_interpreter_snapshots` was called.
+#[derive(Default)]
+pub(crate) struct Journal<'ctx> {
+    enabled: bool,
+    /// `None` if the hooks couldn't snapshot their memory, or were replaced since.
+    baseline: Option<Rc<Baseline>>,
+    entries: Vec<Rc<JournalEntry<'ctx>>>,
+    /// The threads which were spawned and haven't been terminated.
+    live: HashSet<u64>,
+}
+
+impl<'ctx> Journal<'ctx> {
+    pub(crate) fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Forgets the threads journaled so far, since the memory they ran on belonged to the hooks
+    /// which were replaced.
+    pub(crate) fn clear(&mut self) {
+        self.baseline = None;
+        self.entries.clear();
+    }
+
+    /// Records that the thread `thread_id` is being spawned to run `function` with `args`, starting a
+    /// new journal if no journaled thread is still alive.
+    pub(crate) fn on_spawn(
+        &mut self,
+        miri_hooks: &MiriHookSlot<'ctx>,
+        thread_id: u64,
+        function: FunctionValue<'ctx>,
+        args: &[GenericValue<'ctx>],
+    ) -> Result<(), GenericValueError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let fn_type = function.get_type();
+        let param_types = fn_type.get_param_types();
+
+        if param_types.len() != args.len() {
+            return Err(GenericValueError::LengthMismatch {
+                llvm_type: fn_type.print_to_string().to_string(),
+                expected: param_types.len() as u64,
+                found: args.len() as u64,
+            });
+        }
+
+        // The interpreter may free or change the arguments along with the thread
+        let args = param_types
+            .into_iter()
+            .zip(args)
+            .map(|(ty, arg)| GenericValue::from_generic_value(arg.as_ref(), ty))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if self.live.is_empty() {
+            let memory = miri_hooks.with_hooks(|hooks| hooks.snapshot_memory()).flatten();
+
+            self.entries.clear();
+            self.baseline = memory.map(|memory| {
+                Rc::new(Baseline {
+                    memory: memory.into(),
+                    limits: miri_hooks.limits().borrow().clone(),
+                })
+            });
+        }
+
+        self.push(Rc::new(JournalEntry::Spawn {
+            thread_id,
+            function,
+            args,
+        }));
+
+        Ok(())
+    }
+
+    /// Records a step of the thread `thread_id` which was handed `pending_return`.
+    pub(crate) fn on_step(&mut self, thread_id: u64, pending_return: Option<Rc<GenericValue<'ctx>>>) {
+        if self.live.contains(&thread_id) {
+            self.push(Rc::new(JournalEntry::Step {
+                thread_id,
+                pending_return,
+            }));
+        }
+    }
+
+    pub(crate) fn on_terminate(&mut self, thread_id: u64) {
+        if self.live.contains(&thread_id) {
+            self.push(Rc::new(JournalEntry::Terminate { thread_id }));
+        }
+    }
+
+    fn push(&mut self, entry: Rc<JournalEntry<'ctx>>) {
+        match *entry {
+            JournalEntry::Spawn { thread_id, .. } => {
+                self.live.insert(thread_id);
+            },
+            JournalEntry::Step { .. } => {},
+            JournalEntry::Terminate { thread_id } => {
+                self.live.remove(&thread_id);
+            },
+        }
+
+        self.entries.push(entry);
+    }
+}
+
+impl fmt::Debug for Journal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("enabled", &self.enabled)
+            .field("entries", &self.entries.len())
+            .field("live", &self.live)
+            .finish()
+    }
+}
+
+/// The state of some `InterpreterThread`s, their frames and the values bound in them, along with the
+/// memory of the installed `MiriHooks`, as taken by `ExecutionEngine::take_interpreter_snapshot`.
+///
+/// The interpreter's side of the threads can't be copied, so restoring the snapshot spawns them
+/// again and replays every step they took, before putting the memory back the way it was.
+pub struct InterpreterSnapshot<'ctx> {
+    baseline: Rc<Baseline>,
+    entries: Vec<Rc<JournalEntry<'ctx>>>,
+    threads: Vec<ThreadImage<'ctx>>,
+    memory: Rc<dyn Any>,
+    limits: Limits,
+}
+
+impl<'ctx> InterpreterSnapshot<'ctx> {
+    pub(crate) fn take(execution_engine: &ExecutionEngine<'ctx>, threads: &[InterpreterThread<'ctx>]) -> Option<Self> {
+        let miri_hooks = execution_engine.miri_hooks();
+        let journal = miri_hooks.journal().borrow();
+        let baseline = journal.baseline.clone()?;
+        let threads: Vec<_> = threads.iter().map(InterpreterThread::image).collect();
+
+        // Threads spawned before the journal started can't be replayed
+        let journaled = threads
+            .iter()
+            .flat_map(ThreadImage::get_thread_ids)
+            .all(|thread_id| journal.live.contains(&thread_id));
+
+        if !journaled {
+            return None;
+        }
+
+        let memory = miri_hooks.with_hooks(|hooks| hooks.snapshot_memory()).flatten()?;
+
+        Some(InterpreterSnapshot {
+            baseline,
+            entries: journal.entries.clone(),
+            threads,
+            memory: memory.into(),
+            limits: miri_hooks.limits().borrow().clone(),
+        })
+    }
+
+    /// Gets the ids of the threads in this snapshot, in the order they were passed to
+    /// `ExecutionEngine::take_interpreter_snapshot`.
+    pub fn get_thread_ids(&self) -> Vec<u64> {
+        self.threads.iter().map(ThreadImage::get_id).collect()
+    }
+
+    pub(crate) unsafe fn restore(
+        &self,
+        execution_engine: &ExecutionEngine<'ctx>,
+    ) -> Result<Vec<InterpreterThread<'ctx>>, InterpreterError> {
+        let miri_hooks = execution_engine.miri_hooks();
+
+        // Every thread is spawned again with the id it had
+        for entry in &self.entries {
+            if let JournalEntry::Spawn { thread_id, .. } = **entry {
+                if execution_engine.has_thread(thread_id) {
+                    return Err(InterpreterError::ThreadIdInUse(thread_id));
+                }
+            }
+        }
+
+        miri_hooks.with_hooks(|hooks| hooks.restore_memory(&*self.baseline.memory));
+        miri_hooks.limits().borrow_mut().restore_usage(&self.baseline.limits);
+
+        let mut journal = Journal {
+            enabled: true,
+            baseline: Some(self.baseline.clone()),
+            entries: Vec::new(),
+            live: HashSet::new(),
+        };
+
+        for entry in &self.entries {
+            if let Err(err) = replay(execution_engine, entry) {
+                for &thread_id in &journal.live {
+                    if execution_engine.has_thread(thread_id) {
+                        execution_engine.terminate_thread(thread_id);
+                    }
+                }
+
+                *miri_hooks.journal().borrow_mut() = Journal {
+                    enabled: true,
+                    ..Journal::default()
+                };
+
+                return Err(err);
+            }
+
+            journal.push(entry.clone());
+        }
+
+        // The threads which weren't snapshotted are left terminated, as their handles would have
+        let restored: HashSet<_> = self.threads.iter().flat_map(ThreadImage::get_thread_ids).collect();
+        let stale: Vec<_> = journal.live.difference(&restored).copied().collect();
+
+        for thread_id in stale {
+            execution_engine.terminate_thread(thread_id);
+            journal.push(Rc::new(JournalEntry::Terminate { thread_id }));
+        }
+
+        miri_hooks.with_hooks(|hooks| hooks.restore_memory(&*self.memory));
+        miri_hooks.limits().borrow_mut().restore_usage(&self.limits);
+        *miri_hooks.journal().borrow_mut() = journal;
+
+        Ok(self
+            .threads
+            .iter()
+            .map(|image| InterpreterThread::from_image(execution_engine, image.clone()))
+            .collect())
+    }
+}
+
+impl fmt::Debug for InterpreterSnapshot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterpreterSnapshot")
+            .field("threads", &self.threads)
+            .field("entries", &self.entries.len())
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+/// Does what `entry` records to the interpreter again, discarding whatever the step reports, since
+/// that was already handled when it was first taken.
+unsafe fn replay<'ctx>(
+    execution_engine: &ExecutionEngine<'ctx>,
+    entry: &JournalEntry<'ctx>,
+) -> Result<(), InterpreterError> {
+    match entry {
+        JournalEntry::Spawn {
+            thread_id,
+            function,
+            args,
+        } => execution_engine.create_thread(*thread_id, *function, args),
+        JournalEntry::Step {
+            thread_id,
+            pending_return,
+        } => {
+            let pending_return = pending_return.as_deref().map(|value| *value.as_ref());
+            let stepped = execution_engine.step_thread_unmetered(*thread_id, pending_return);
+            let miri_hooks = execution_engine.miri_hooks();
+            let mut breakpoints = miri_hooks.breakpoints().borrow_mut();
+
+            miri_hooks.take_pending_call();
+            miri_hooks.take_resolved_call();
+            miri_hooks.probes().borrow_mut().take_hits();
+            breakpoints.take_triggered();
+            breakpoints.take_accesses();
+            stepped?;
+        },
+        JournalEntry::Terminate { thread_id } => execution_engine.terminate_thread(*thread_id),
+    }
+
+    Ok(())
+}
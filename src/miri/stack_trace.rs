@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt::{self, Display, Formatter, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use llvm_sys::miri::MiriErrorTrace;
+#[llvm_versions(9..)]
+use llvm_sys::prelude::LLVMMetadataRef;
+
+#[llvm_versions(9..)]
+use crate::debug_info::DILocation;
+#[llvm_versions(9..)]
+use crate::module::Module;
+use crate::values::{AnyValue, FunctionValue, InstructionValue};
+
+/// How a `StackTrace` is rendered by `StackTrace::render`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackTraceFormat {
+    /// One frame per line, as used by `StackTrace`'s `Display` impl.
+    Plain,
+    /// The same layout as `Plain`, highlighted with ANSI escape codes for terminals.
+    Colored,
+    /// A single JSON object with an `instruction` and an array of `frames`.
+    Json,
+}
+
+/// A call site which an inlined frame of a `StackTraceItem` was inlined into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlinedFrame {
+    pub line: u32,
+    pub column: u32,
+    pub file: PathBuf,
+    pub function_name: Option<String>,
+    pub source_line: Option<String>,
+}
+
+/// A frame of a `StackTrace`.
+///
+/// Only the location is known when the trace is recorded; the remaining fields are filled in by
+/// `StackTrace::resolve` and `StackTrace::load_source_lines`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTraceItem<'ctx> {
+    pub line: u32,
+    pub column: u32,
+    pub file: PathBuf,
+    /// The function the interpreter was executing in this frame.
+    pub function: Option<FunctionValue<'ctx>>,
+    /// The instruction the interpreter was executing in this frame.
+    pub instruction: Option<InstructionValue<'ctx>>,
+    /// The name of the source function this location belongs to. If the location was inlined,
+    /// this is the inlined function rather than `function`.
+    pub function_name: Option<String>,
+    /// The call sites this location was inlined into, innermost first.
+    pub inlined_at: Vec<InlinedFrame>,
+    pub source_line: Option<String>,
+}
+
+impl Display for StackTraceItem<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(StackTraceFormat::Plain))
+    }
+}
+
+impl From<&MiriErrorTrace> for StackTraceItem<'_> {
+    fn from(error_trace: &MiriErrorTrace) -> Self {
+        let file_slice =
+            unsafe { std::slice::from_raw_parts(error_trace.file as *const u8, error_trace.file_len as usize) };
+        let dir_slice = unsafe {
+            std::slice::from_raw_parts(error_trace.directory as *const u8, error_trace.directory_len as usize)
+        };
+        let file_string = unsafe { CString::from_vec_unchecked(file_slice.to_vec().clone()) };
+        let dir_string = unsafe { CString::from_vec_unchecked(dir_slice.to_vec().clone()) };
+        let mut dir = PathBuf::new();
+        dir.push(dir_string.to_string_lossy().to_string());
+        dir.push(file_string.to_string_lossy().to_string());
+        Self {
+            line: error_trace.line,
+            column: error_trace.column,
+            file: dir,
+            function: None,
+            instruction: None,
+            function_name: None,
+            inlined_at: Vec::new(),
+            source_line: None,
+        }
+    }
+}
+
+impl StackTraceItem<'_> {
+    /// Renders this frame along with the call sites it was inlined into.
+    pub fn render(&self, format: StackTraceFormat) -> String {
+        let mut out = String::new();
+
+        match format {
+            StackTraceFormat::Plain | StackTraceFormat::Colored => self.render_text(&mut out, format),
+            StackTraceFormat::Json => self.render_json(&mut out),
+        }
+
+        out
+    }
+
+    fn render_text(&self, out: &mut String, format: StackTraceFormat) {
+        let frames = std::iter::once((
+            &self.file,
+            self.line,
+            self.column,
+            self.function_name.as_deref(),
+            self.source_line.as_deref(),
+        ))
+        .chain(self.inlined_at.iter().map(|frame| {
+            (
+                &frame.file,
+                frame.line,
+                frame.column,
+                frame.function_name.as_deref(),
+                frame.source_line.as_deref(),
+            )
+        }));
+        let last = self.inlined_at.len();
+
+        for (idx, (file, line, column, function_name, source_line)) in frames.enumerate() {
+            if idx > 0 {
+                out.push('\n');
+            }
+
+            render_location(out, format, file, line, column, function_name, idx < last);
+
+            if let Some(source_line) = source_line {
+                let _ = write!(out, "\n    {}", source_line.trim());
+            }
+        }
+    }
+
+    fn render_json(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "{{\"file\":{},\"line\":{},\"column\":{},\"function\":{},\"source\":{},\"inlined_at\":[",
+            json_string(&self.file.display().to_string()),
+            self.line,
+            self.column,
+            json_option(self.function_name.as_deref()),
+            json_option(self.source_line.as_deref()),
+        );
+
+        for (idx, frame) in self.inlined_at.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+
+            let _ = write!(
+                out,
+                "{{\"file\":{},\"line\":{},\"column\":{},\"function\":{},\"source\":{}}}",
+                json_string(&frame.file.display().to_string()),
+                frame.line,
+                frame.column,
+                json_option(frame.function_name.as_deref()),
+                json_option(frame.source_line.as_deref()),
+            );
+        }
+
+        out.push_str("]}");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace<'ctx> {
+    pub inst: Option<String>,
+    pub traces: Vec<StackTraceItem<'ctx>>,
+}
+
+impl<'ctx> StackTrace<'ctx> {
+    pub fn new(inst: Option<String>, traces: &[MiriErrorTrace]) -> Self {
+        Self {
+            inst,
+            traces: traces.iter().map(|t| t.into()).collect(),
+        }
+    }
+
+    /// Builds a trace of the frames of an `InterpreterThread`, outermost first, each given by its
+    /// function and the instruction it's at, from the debug locations of those instructions.
+    pub(crate) fn from_frames(frames: &[(FunctionValue<'ctx>, InstructionValue<'ctx>)]) -> Self {
+        let traces = frames
+            .iter()
+            .map(|&(function, instruction)| {
+                let (file, line, column) = instruction_location(instruction);
+
+                StackTraceItem {
+                    line,
+                    column,
+                    file,
+                    function: Some(function),
+                    instruction: Some(instruction),
+                    function_name: Some(source_name(function)),
+                    inlined_at: Vec::new(),
+                    source_line: None,
+                }
+            })
+            .collect();
+
+        Self {
+            inst: frames
+                .last()
+                .map(|(_, instruction)| instruction.print_to_string().to_string()),
+            traces,
+        }
+    }
+
+    /// Fills in the function, instruction, function name and inlined-at chain of each frame from the debug info
+    /// of `module`.
+    ///
+    /// Frames are matched to the first instruction with the same debug location, so a location which was
+    /// inlined into several functions may be attributed to any one of them. Frames with no match are left as is.
+    #[llvm_versions(9..)]
+    pub fn resolve(&mut self, module: &Module<'ctx>) {
+        let mut locations: HashMap<
+            (PathBuf, u32, u32),
+            (FunctionValue<'ctx>, InstructionValue<'ctx>, DILocation<'ctx>),
+        > = HashMap::new();
+        let mut scope_names: HashMap<LLVMMetadataRef, String> = HashMap::new();
+
+        for function in module.get_functions() {
+            let name = source_name(function);
+
+            if let Some(subprogram) = function.get_subprogram() {
+                scope_names.insert(subprogram.metadata_ref, name.clone());
+            }
+
+            for block in function.get_basic_block_iter() {
+                for instruction in block.get_instructions() {
+                    let location = match instruction.get_debug_location() {
+                        Some(location) => location,
+                        None => continue,
+                    };
+
+                    // Lexical blocks have no parent in the C API, so their function is only
+                    // known from the non-inlined instructions which use them
+                    if location.get_inlined_at().is_none() {
+                        scope_names
+                            .entry(location.get_scope().as_mut_ptr())
+                            .or_insert_with(|| name.clone());
+                    }
+
+                    locations
+                        .entry(location_key(&location))
+                        .or_insert((function, instruction, location));
+                }
+            }
+        }
+
+        for item in &mut self.traces {
+            let key = (item.file.clone(), item.line, item.column);
+            let (function, instruction, location) = match locations.get(&key) {
+                Some(&found) => found,
+                None => continue,
+            };
+
+            item.function = Some(function);
+            item.instruction = Some(instruction);
+            item.function_name = scope_names.get(&location.get_scope().as_mut_ptr()).cloned();
+            item.inlined_at.clear();
+
+            let mut inlined_at = location.get_inlined_at();
+
+            while let Some(call_site) = inlined_at {
+                let (file, line, column) = location_key(&call_site);
+
+                item.inlined_at.push(InlinedFrame {
+                    line,
+                    column,
+                    file,
+                    function_name: scope_names.get(&call_site.get_scope().as_mut_ptr()).cloned(),
+                    source_line: None,
+                });
+
+                inlined_at = call_site.get_inlined_at();
+            }
+
+            // The outermost frame is always the function being executed, even when its scope is unknown
+            let outermost = match item.inlined_at.last_mut() {
+                Some(frame) => &mut frame.function_name,
+                None => &mut item.function_name,
+            };
+
+            if outermost.is_none() {
+                *outermost = Some(source_name(function));
+            }
+        }
+    }
+
+    /// Reads the source line of each frame from disk. Files which can't be read are skipped.
+    pub fn load_source_lines(&mut self) {
+        let mut files: HashMap<PathBuf, Option<Vec<String>>> = HashMap::new();
+        let mut source_line = |file: &Path, line: u32| -> Option<String> {
+            let lines = files.entry(file.to_path_buf()).or_insert_with(|| {
+                fs::read(file)
+                    .ok()
+                    .map(|bytes| String::from_utf8_lossy(&bytes).lines().map(str::to_owned).collect())
+            });
+
+            lines.as_ref()?.get((line as usize).checked_sub(1)?).cloned()
+        };
+
+        for item in &mut self.traces {
+            item.source_line = source_line(&item.file, item.line);
+
+            for frame in &mut item.inlined_at {
+                frame.source_line = source_line(&frame.file, frame.line);
+            }
+        }
+    }
+
+    /// Renders this trace, innermost frame first. Never panics, even if a path isn't valid UTF-8.
+    pub fn render(&self, format: StackTraceFormat) -> String {
+        let mut out = String::new();
+
+        match format {
+            StackTraceFormat::Plain | StackTraceFormat::Colored => {
+                if let Some(inst) = &self.inst {
+                    let _ = write!(out, "\n@ {}\n\n", inst.trim());
+                }
+
+                for (idx, item) in self.traces.iter().rev().enumerate() {
+                    if idx > 0 {
+                        out.push('\n');
+                    }
+
+                    item.render_text(&mut out, format);
+                }
+            },
+            StackTraceFormat::Json => {
+                let _ = write!(
+                    out,
+                    "{{\"instruction\":{},\"frames\":[",
+                    json_option(self.inst.as_deref())
+                );
+
+                for (idx, item) in self.traces.iter().rev().enumerate() {
+                    if idx > 0 {
+                        out.push(',');
+                    }
+
+                    item.render_json(&mut out);
+                }
+
+                out.push_str("]}");
+            },
+        }
+
+        out
+    }
+}
+
+impl Display for StackTrace<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(StackTraceFormat::Plain))
+    }
+}
+
+#[llvm_versions(9..)]
+fn location_key(location: &DILocation<'_>) -> (PathBuf, u32, u32) {
+    let mut file = PathBuf::new();
+
+    if let Some(scope_file) = location.get_scope().get_file() {
+        file.push(String::from_utf8_lossy(scope_file.get_directory()).into_owned());
+        file.push(String::from_utf8_lossy(scope_file.get_filename()).into_owned());
+    }
+
+    (file, location.get_line(), location.get_column())
+}
+
+/// Gets the location of `instruction` in the source, or an empty path and zeroes if it has none.
+#[llvm_versions(9..)]
+fn instruction_location(instruction: InstructionValue<'_>) -> (PathBuf, u32, u32) {
+    match instruction.get_debug_location() {
+        Some(location) => location_key(&location),
+        None => (PathBuf::new(), 0, 0),
+    }
+}
+
+#[llvm_versions(..=8)]
+fn instruction_location(_instruction: InstructionValue<'_>) -> (PathBuf, u32, u32) {
+    (PathBuf::new(), 0, 0)
+}
+
+/// Gets the name `function` has in the source, which unlike its own name isn't mangled, falling
+/// back on its own name if it has no debug info.
+#[llvm_versions(9..)]
+fn source_name(function: FunctionValue<'_>) -> String {
+    let context = function.get_type().get_context();
+    let name = function
+        .get_subprogram()
+        .and_then(|subprogram| subprogram.get_name(context));
+
+    match name {
+        Some(name) => String::from_utf8_lossy(name).into_owned(),
+        None => function.get_name().to_string_lossy().into_owned(),
+    }
+}
+
+#[llvm_versions(..=8)]
+fn source_name(function: FunctionValue<'_>) -> String {
+    function.get_name().to_string_lossy().into_owned()
+}
+
+fn render_location(
+    out: &mut String,
+    format: StackTraceFormat,
+    file: &Path,
+    line: u32,
+    column: u32,
+    function_name: Option<&str>,
+    inlined: bool,
+) {
+    let colored = format == StackTraceFormat::Colored;
+    let path = file.display();
+
+    if colored {
+        let _ = write!(out, "\x1b[36m{}:{}:{}\x1b[0m", path, line, column);
+    } else {
+        let _ = write!(out, "{}:{}:{}", path, line, column);
+    }
+
+    if let Some(function_name) = function_name {
+        if colored {
+            let _ = write!(out, " in \x1b[1m{}\x1b[0m", function_name);
+        } else {
+            let _ = write!(out, " in {}", function_name);
+        }
+    }
+
+    if inlined {
+        out.push_str(" [inlined]");
+    }
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_owned(), json_string)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_stack_trace_render() {
+    let trace = StackTrace {
+        inst: Some("  %x = load i32, ptr %p  ".into()),
+        traces: vec![
+            StackTraceItem {
+                line: 10,
+                column: 3,
+                file: PathBuf::from("/nonexistent/main.c"),
+                function: None,
+                instruction: None,
+                function_name: Some("main".into()),
+                inlined_at: Vec::new(),
+                source_line: None,
+            },
+            StackTraceItem {
+                line: 4,
+                column: 12,
+                file: PathBuf::from("/nonexistent/util.h"),
+                function: None,
+                instruction: None,
+                function_name: Some("get".into()),
+                inlined_at: vec![InlinedFrame {
+                    line: 7,
+                    column: 5,
+                    file: PathBuf::from("/nonexistent/util.c"),
+                    function_name: Some("helper".into()),
+                    source_line: Some("    return get(p);".into()),
+                }],
+                source_line: None,
+            },
+        ],
+    };
+
+    assert_eq!(
+        trace.to_string(),
+        "\n@ %x = load i32, ptr %p\n\n\
+         /nonexistent/util.h:4:12 in get [inlined]\n\
+         /nonexistent/util.c:7:5 in helper\n    return get(p);\n\
+         /nonexistent/main.c:10:3 in main"
+    );
+    assert_eq!(
+        trace.render(StackTraceFormat::Json),
+        "{\"instruction\":\"  %x = load i32, ptr %p  \",\"frames\":[\
+         {\"file\":\"/nonexistent/util.h\",\"line\":4,\"column\":12,\"function\":\"get\",\"source\":null,\"inlined_at\":[\
+         {\"file\":\"/nonexistent/util.c\",\"line\":7,\"column\":5,\"function\":\"helper\",\"source\":\"    return get(p);\"}]},\
+         {\"file\":\"/nonexistent/main.c\",\"line\":10,\"column\":3,\"function\":\"main\",\"source\":null,\"inlined_at\":[]}]}"
+    );
+}
@@ -0,0 +1,844 @@
+use either::Either;
+use llvm_sys::core::LLVMIsUndef;
+use llvm_sys::prelude::LLVMValueRef;
+
+use crate::execution_engine::{ExecutionEngine, InterpreterError};
+use crate::miri::function_pointers::ResolvedCall;
+use crate::miri::probes::{self, ProbeSite};
+use crate::miri::{BreakpointHit, InterpreterLimit, Trigger};
+use crate::types::BasicTypeEnum;
+use crate::values::{
+    AnyValue, AsValueRef, BasicValue, BasicValueEnum, FromGenericValue, FunctionValue, GenericValue, GenericValueRef,
+    InstructionOpcode, InstructionValue,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// The outcome of stepping an `InterpreterThread`.
+#[derive(Debug)]
+pub enum StepResult<'ctx> {
+    /// The thread executed an instruction and can be stepped again.
+    Running,
+    /// The thread handed a call to the installed `MiriHooks` and is waiting on its return value,
+    /// which should be provided through `InterpreterThread::set_pending_return` before stepping it again.
+    Blocked,
+    /// The thread returned from its entry function, with the value it returned, which is a void
+    /// `GenericValue` if the function returns `void`.
+    Exited(GenericValue<'ctx>),
+    /// The thread executed an instruction which hit breakpoints or watchpoints. If it also made a
+    /// call, it is now blocked on it, just as if `Blocked` had been returned.
+    Triggered(Vec<Trigger<'ctx>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Runnable,
+    Blocked,
+    Exited,
+}
+
+/// A function being run by an `InterpreterThread`, as seen through the probes the module was
+/// instrumented with.
+#[derive(Debug, Clone)]
+pub struct Frame<'ctx> {
+    function: FunctionValue<'ctx>,
+    instruction: Option<InstructionValue<'ctx>>,
+    /// The values are shared with the `InterpreterSnapshot`s the frame was copied into.
+    values: HashMap<LLVMValueRef, Rc<GenericValue<'ctx>>>,
+}
+
+impl<'ctx> Frame<'ctx> {
+    /// Gets the function this frame is running.
+    pub fn get_function(&self) -> FunctionValue<'ctx> {
+        self.function
+    }
+
+    /// Gets the instruction this frame executes next. For a frame which made a call, this is the call.
+    pub fn get_instruction(&self) -> Option<InstructionValue<'ctx>> {
+        self.instruction
+    }
+
+    /// Gets the value most recently bound to `value`, which is one of the arguments of this frame's
+    /// function or one of its instructions, or `None` if it hasn't been computed yet.
+    pub fn get_value<V: AsValueRef>(&self, value: V) -> Option<&GenericValue<'ctx>> {
+        self.values.get(&value.as_value_ref()).map(|value| &**value)
+    }
+
+    /// Gets the value of the `nth` argument this frame's function was called with.
+    pub fn get_argument(&self, nth: u32) -> Option<&GenericValue<'ctx>> {
+        self.get_value(self.function.get_nth_param(nth)?)
+    }
+}
+
+/// What a single step of the interpreter, and the probes it hit, amounted to.
+enum Progress<'ctx> {
+    /// The thread executed an instruction of the module, or probes before reaching the next one.
+    Probing {
+        hit: bool,
+    },
+    /// The thread is about to execute the next instruction of the module.
+    Reached,
+    Stopped(StepResult<'ctx>),
+}
+
+/// A thread of LLVM's interpreter, identified by the id it was spawned with.
+///
+/// Calls the thread makes through the engine's function pointers to functions of the module are
+/// run on a thread of their own, which this thread steps in its place until the call returns.
+///
+/// The thread is terminated when its handle is dropped, unless it has already exited.
+#[derive(Debug)]
+pub struct InterpreterThread<'ctx> {
+    execution_engine: ExecutionEngine<'ctx>,
+    id: u64,
+    state: ThreadState,
+    pending_return: Option<Rc<GenericValue<'ctx>>>,
+    /// The thread running the function this thread called through a function pointer, if any.
+    callee: Option<Box<InterpreterThread<'ctx>>>,
+    /// How many frames the threads this thread is running a function pointer call for have.
+    depth: u32,
+    /// Whether the functions this thread runs are instrumented with probes.
+    probed: bool,
+    /// The frames of the functions this thread is running, outermost first.
+    frames: Vec<Frame<'ctx>>,
+    /// The return type of the entry function, or `None` if it returns `void`.
+    return_type: Option<BasicTypeEnum<'ctx>>,
+    /// A copy of what the entry function returned, which outlives the interpreter's thread.
+    exit_value: Option<Rc<GenericValue<'ctx>>>,
+}
+
+/// An owned copy of an `InterpreterThread` without the interpreter's side of it, which is kept by
+/// an `InterpreterSnapshot`.
+#[derive(Debug, Clone)]
+pub(crate) struct ThreadImage<'ctx> {
+    id: u64,
+    state: ThreadState,
+    pending_return: Option<Rc<GenericValue<'ctx>>>,
+    callee: Option<Box<ThreadImage<'ctx>>>,
+    depth: u32,
+    probed: bool,
+    frames: Vec<Frame<'ctx>>,
+    return_type: Option<BasicTypeEnum<'ctx>>,
+    exit_value: Option<Rc<GenericValue<'ctx>>>,
+}
+
+impl ThreadImage<'_> {
+    pub(crate) fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Gets the ids of the interpreter's threads this thread needs, which are its own and that of
+    /// its callee unless it has exited.
+    pub(crate) fn get_thread_ids(&self) -> Vec<u64> {
+        if self.state == ThreadState::Exited {
+            return Vec::new();
+        }
+
+        let mut thread_ids = vec![self.id];
+
+        if let Some(callee) = &self.callee {
+            thread_ids.extend(callee.get_thread_ids());
+        }
+
+        thread_ids
+    }
+}
+
+impl<'ctx> InterpreterThread<'ctx> {
+    /// Spawns a new thread with the id `id` which will run `function` with `args`. Nothing is executed
+    /// until the thread is stepped.
+    ///
+    /// The thread only knows which instruction it's at and what its frames hold if `function` was
+    /// instrumented with `ExecutionEngine::instrument_module` and `MiriHooks` are installed.
+    ///
+    /// Returns an error if `execution_engine` already has a thread with this id, or if snapshots are
+    /// enabled and `args` don't match the parameters of `function`.
+    pub fn spawn(
+        execution_engine: &ExecutionEngine<'ctx>,
+        id: u64,
+        function: FunctionValue<'ctx>,
+        args: &[GenericValue<'ctx>],
+    ) -> Result<Self, InterpreterError> {
+        unsafe {
+            if execution_engine.has_thread(id) {
+                return Err(InterpreterError::ThreadIdInUse(id));
+            }
+        }
+
+        let miri_hooks = execution_engine.miri_hooks();
+        let probed = miri_hooks.is_installed() && miri_hooks.probes().borrow().is_registered(function);
+
+        miri_hooks
+            .journal()
+            .borrow_mut()
+            .on_spawn(miri_hooks, id, function, args)
+            .map_err(InterpreterError::InvalidArgument)?;
+
+        unsafe {
+            execution_engine.create_thread(id, function, args);
+        }
+
+        Ok(InterpreterThread {
+            execution_engine: execution_engine.clone(),
+            id,
+            state: ThreadState::Runnable,
+            pending_return: None,
+            callee: None,
+            depth: 0,
+            probed,
+            frames: Vec::new(),
+            return_type: function.get_type().get_return_type(),
+            exit_value: None,
+        })
+    }
+
+    /// Gets the id this thread was spawned with.
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns whether this thread is waiting on the return value of a call.
+    pub fn is_blocked(&self) -> bool {
+        self.state == ThreadState::Blocked
+    }
+
+    /// Returns whether this thread has returned from its entry function.
+    pub fn has_exited(&self) -> bool {
+        self.state == ThreadState::Exited
+    }
+
+    /// Provides the return value of the call this thread is blocked on, unblocking it. Calls to `void`
+    /// functions are completed by passing `None`.
+    ///
+    /// Returns an error if this thread isn't blocked on a call.
+    pub fn set_pending_return(&mut self, value: Option<GenericValue<'ctx>>) -> Result<(), InterpreterError> {
+        if self.state != ThreadState::Blocked {
+            return Err(InterpreterError::ThreadNotBlocked(self.id));
+        }
+
+        // The call is blocked on by the function this thread called through a function pointer
+        match &mut self.callee {
+            Some(callee) => callee.set_pending_return(value)?,
+            None => self.pending_return = value.map(Rc::new),
+        }
+
+        self.state = ThreadState::Runnable;
+
+        Ok(())
+    }
+
+    /// Gets the frames of the functions this thread is running, outermost first, including those of
+    /// the functions it called through function pointers.
+    ///
+    /// Only threads running functions instrumented with `ExecutionEngine::instrument_module` have frames.
+    pub fn get_frames(&self) -> Vec<&Frame<'ctx>> {
+        let mut frames: Vec<_> = self.frames.iter().collect();
+
+        if let Some(callee) = &self.callee {
+            frames.extend(callee.get_frames());
+        }
+
+        frames
+    }
+
+    /// Gets the instruction this thread executes next, if it is running an instrumented function.
+    pub fn get_current_instruction(&self) -> Option<InstructionValue<'ctx>> {
+        self.get_frames().last().and_then(|frame| frame.instruction)
+    }
+
+    /// Executes a single instruction of this thread. A thread which was just spawned stops before the
+    /// first instruction of its function instead.
+    ///
+    /// Stepping a blocked thread completes its pending call with whatever was last passed to
+    /// `set_pending_return`. Stepping a thread which has exited just returns `Exited` again, with
+    /// another copy of its value.
+    pub unsafe fn step(&mut self) -> Result<StepResult<'ctx>, InterpreterError> {
+        if self.state == ThreadState::Exited {
+            return Ok(self.exited());
+        }
+
+        if let Some(callee) = &mut self.callee {
+            let result = match callee.step() {
+                Ok(result) => result,
+                Err(err) => {
+                    self.terminate();
+                    self.exit();
+
+                    return Err(err);
+                },
+            };
+
+            // Once the callee returns, its return value completes this thread's call
+            if let StepResult::Exited(_) = result {
+                let mut callee = self.callee.take().expect("callee was just stepped");
+
+                return self.advance(callee.exit_value.take());
+            }
+
+            self.state = callee.state;
+
+            return Ok(result);
+        }
+
+        let pending_return = self.pending_return.take();
+
+        self.advance(pending_return)
+    }
+
+    /// Steps the interpreter until this thread reaches the next instruction of the module, stepping
+    /// over the probes on the way.
+    unsafe fn advance(
+        &mut self,
+        pending_return: Option<Rc<GenericValue<'ctx>>>,
+    ) -> Result<StepResult<'ctx>, InterpreterError> {
+        let mut triggers = Vec::new();
+        let mut progress = self.step_with(pending_return, true, &mut triggers)?;
+        let mut steps_without_probes = 0;
+
+        let result = loop {
+            match progress {
+                Progress::Probing { hit } if self.probed => {
+                    // Code which isn't instrumented still has to run out of fuel eventually
+                    if !hit {
+                        steps_without_probes += 1;
+                    }
+
+                    let pending_return = self.pending_return.take();
+
+                    progress = self.step_with(pending_return, steps_without_probes > 2, &mut triggers)?;
+                },
+                Progress::Probing { .. } | Progress::Reached => break StepResult::Running,
+                Progress::Stopped(result) => break result,
+            }
+        };
+
+        match result {
+            StepResult::Exited(_) => Ok(result),
+            _ if !triggers.is_empty() => Ok(StepResult::Triggered(triggers)),
+            _ => Ok(result),
+        }
+    }
+
+    unsafe fn step_with(
+        &mut self,
+        pending_return: Option<Rc<GenericValue<'ctx>>>,
+        metered: bool,
+        triggers: &mut Vec<Trigger<'ctx>>,
+    ) -> Result<Progress<'ctx>, InterpreterError> {
+        let value = pending_return.as_deref().map(|value| *value.as_ref());
+        let stepped = if metered {
+            self.execution_engine.step_thread(self.id, value)
+        } else {
+            self.execution_engine.step_thread_unmetered(self.id, value)
+        };
+        let mut called = self.execution_engine.miri_hooks().take_pending_call();
+        let mut journal = self.execution_engine.miri_hooks().journal().borrow_mut();
+
+        // A thread is terminated when one of the hooks panics while stepping it
+        let stepped = match stepped {
+            Ok(stepped) => stepped,
+            Err(err) => {
+                journal.on_terminate(self.id);
+                drop(journal);
+                self.exit();
+
+                return Err(err);
+            },
+        };
+
+        journal.on_step(self.id, pending_return);
+        drop(journal);
+
+        if !stepped {
+            // The entry function returns without any probe being hit in a caller, along with the
+            // functions it made `musttail` calls to
+            let value = self.execution_engine.get_thread_exit_value(self.id);
+
+            for frame in self.frames.iter().rev() {
+                self.execution_engine
+                    .miri_hooks()
+                    .with_hooks(|hooks| hooks.return_from_function(frame.function, value));
+            }
+
+            // The interpreter frees the value along with its thread
+            let exit_value = match (value, self.return_type) {
+                (Some(value), Some(ty)) => Some(GenericValue::from_generic_value(&value, ty)),
+                _ => None,
+            };
+
+            self.exit();
+            self.exit_value = exit_value
+                .transpose()
+                .map_err(InterpreterError::InvalidArgument)?
+                .map(Rc::new);
+
+            return Ok(Progress::Stopped(self.exited()));
+        }
+
+        let (hit, reached) = match self.apply_probe_hits() {
+            Ok(progress) => progress,
+            Err(err) => {
+                self.terminate();
+                self.exit();
+
+                return Err(err);
+            },
+        };
+
+        triggers.extend(
+            self.execution_engine
+                .miri_hooks()
+                .breakpoints()
+                .borrow_mut()
+                .take_triggered(),
+        );
+
+        // Calls through the engine's function pointers don't block the thread on the caller of `step`
+        match self.execution_engine.miri_hooks().take_resolved_call() {
+            Some(ResolvedCall::Returned(return_value)) => {
+                self.pending_return = return_value.map(Rc::new);
+                called = false;
+            },
+            Some(ResolvedCall::Function(function, args)) => {
+                let id = unused_thread_id(&self.execution_engine);
+                let mut callee = InterpreterThread::spawn(&self.execution_engine, id, function, &args)?;
+
+                callee.depth = self.depth + self.frames.len() as u32;
+                self.callee = Some(Box::new(callee));
+                self.state = ThreadState::Runnable;
+
+                return Ok(Progress::Stopped(StepResult::Running));
+            },
+            None => {},
+        }
+
+        if called {
+            self.state = ThreadState::Blocked;
+
+            return Ok(Progress::Stopped(StepResult::Blocked));
+        }
+
+        self.state = ThreadState::Runnable;
+
+        if reached {
+            return Ok(Progress::Reached);
+        }
+
+        Ok(Progress::Probing { hit })
+    }
+
+    /// Updates the frames of this thread with the probes it hit in the last step, firing the
+    /// breakpoints they reached and reporting them to the hooks. Returns whether it hit any, and
+    /// whether it reached the next instruction of the module.
+    ///
+    /// Returns an error if the thread entered a function beyond the `max_call_depth`, or is about to
+    /// branch on an undefined value.
+    fn apply_probe_hits(&mut self) -> Result<(bool, bool), InterpreterError> {
+        let miri_hooks = self.execution_engine.miri_hooks();
+        let hits = miri_hooks.probes().borrow_mut().take_hits();
+        let hit_any = !hits.is_empty();
+        let mut reached = false;
+
+        for hit in hits {
+            let site = match hit.site {
+                ProbeSite::Entry(function) => {
+                    let depth = self.depth + self.frames.len() as u32 + 1;
+
+                    if !miri_hooks.limits().borrow().allows_call_depth(depth) {
+                        return Err(InterpreterError::LimitExceeded(InterpreterLimit::CallDepth));
+                    }
+
+                    miri_hooks.breakpoints().borrow_mut().on_entry(function);
+                    miri_hooks.with_hooks(|hooks| hooks.enter_function(function));
+                    self.frames.push(Frame {
+                        function,
+                        instruction: None,
+                        values: HashMap::new(),
+                    });
+
+                    continue;
+                },
+                site => site,
+            };
+
+            // The first probe hit after a `ret` belongs to the caller
+            let returned = self
+                .frames
+                .last()
+                .and_then(|frame| frame.instruction)
+                .map_or(false, |instruction| {
+                    instruction.get_opcode() == InstructionOpcode::Return
+                });
+
+            if returned {
+                // Frames which made a `musttail` call return along with their callee
+                let must_tail_calls = self.frames[..self.frames.len() - 1]
+                    .iter()
+                    .rev()
+                    .take_while(|frame| frame.instruction.map_or(false, probes::is_must_tail_call))
+                    .count();
+                let callees = self.frames.split_off(self.frames.len() - 1 - must_tail_calls);
+                let caller = self.frames.last().and_then(|frame| frame.instruction);
+
+                // What the call returned is what the caller's probe binds the call to
+                let value = match site {
+                    ProbeSite::Value(value) if caller.map(|call| call.as_value_ref()) == Some(value.as_value_ref()) => {
+                        hit.value.as_ref().map(|value| *value.as_ref())
+                    },
+                    _ => None,
+                };
+
+                for callee in callees.iter().rev() {
+                    miri_hooks.with_hooks(|hooks| hooks.return_from_function(callee.function, value));
+                }
+            }
+
+            let frame = match self.frames.last_mut() {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            match site {
+                ProbeSite::Value(value) => match hit.value {
+                    Some(generic_value) => {
+                        frame.values.insert(value.as_value_ref(), Rc::new(generic_value));
+                    },
+                    None => {
+                        frame.values.remove(&value.as_value_ref());
+                    },
+                },
+                ProbeSite::Instruction(instruction) => {
+                    if branches_on_undefined(instruction) {
+                        return Err(InterpreterError::UndefinedBranch {
+                            instruction: instruction.print_to_string().to_string(),
+                        });
+                    }
+
+                    miri_hooks.breakpoints().borrow_mut().on_instruction(instruction);
+                    miri_hooks.with_hooks(|hooks| hooks.execute_instruction(instruction));
+                    frame.instruction = Some(instruction);
+                    reached = true;
+                },
+                ProbeSite::Entry(_) => unreachable!("entries were handled above"),
+            }
+        }
+
+        Ok((hit_any, reached))
+    }
+
+    fn exit(&mut self) {
+        self.state = ThreadState::Exited;
+        self.callee = None;
+        self.frames.clear();
+    }
+
+    /// Terminates the interpreter's side of this thread.
+    unsafe fn terminate(&self) {
+        self.execution_engine.terminate_thread(self.id);
+        self.execution_engine
+            .miri_hooks()
+            .journal()
+            .borrow_mut()
+            .on_terminate(self.id);
+    }
+
+    /// Gets the value this thread returned from its entry function, if it has exited and wasn't `void`.
+    /// The value is freed along with this handle.
+    pub fn get_exit_value(&self) -> Option<GenericValueRef<'_>> {
+        self.exit_value.as_deref().map(|value| *value.as_ref())
+    }
+
+    /// Copies this thread for an `InterpreterSnapshot`.
+    pub(crate) fn image(&self) -> ThreadImage<'ctx> {
+        ThreadImage {
+            id: self.id,
+            state: self.state,
+            pending_return: self.pending_return.clone(),
+            callee: self.callee.as_ref().map(|callee| Box::new(callee.image())),
+            depth: self.depth,
+            probed: self.probed,
+            frames: self.frames.clone(),
+            return_type: self.return_type,
+            exit_value: self.exit_value.clone(),
+        }
+    }
+
+    /// Makes a handle for a thread the interpreter has been put back into the state `image` was
+    /// copied in.
+    pub(crate) fn from_image(execution_engine: &ExecutionEngine<'ctx>, image: ThreadImage<'ctx>) -> Self {
+        InterpreterThread {
+            execution_engine: execution_engine.clone(),
+            id: image.id,
+            state: image.state,
+            pending_return: image.pending_return,
+            callee: image
+                .callee
+                .map(|callee| Box::new(InterpreterThread::from_image(execution_engine, *callee))),
+            depth: image.depth,
+            probed: image.probed,
+            frames: image.frames,
+            return_type: image.return_type,
+            exit_value: image.exit_value,
+        }
+    }
+
+    /// Makes the result of stepping this thread once it has exited.
+    fn exited(&self) -> StepResult<'ctx> {
+        let value = match (self.exit_value.as_deref(), self.return_type) {
+            (Some(value), Some(ty)) => {
+                GenericValue::from_generic_value(value.as_ref(), ty).expect("exit value was copied with its type")
+            },
+            _ => GenericValue::new_void(),
+        };
+
+        StepResult::Exited(value)
+    }
+}
+
+/// Returns whether `instruction` is a conditional branch or a switch on a value computed from `undef`
+/// or `poison`.
+fn branches_on_undefined(instruction: InstructionValue<'_>) -> bool {
+    let is_conditional = match instruction.get_opcode() {
+        InstructionOpcode::Br => instruction.get_num_operands() == 3,
+        InstructionOpcode::Switch => true,
+        _ => false,
+    };
+
+    match instruction.get_operand(0) {
+        Some(Either::Left(condition)) if is_conditional => is_undefined(condition, &mut HashSet::new()),
+        _ => false,
+    }
+}
+
+/// Returns whether `value` is computed from `undef` or `poison`, as far as can be told from the module
+/// alone. Values which went through memory, a call or a `phi` aren't followed, `freeze` always yields
+/// a defined value, and only the condition of a `select` is.
+fn is_undefined(value: BasicValueEnum<'_>, visited: &mut HashSet<LLVMValueRef>) -> bool {
+    // `poison` counts as `undef` too
+    if unsafe { LLVMIsUndef(value.as_value_ref()) } != 0 {
+        return true;
+    }
+
+    let instruction = match value.as_instruction_value() {
+        Some(instruction) if visited.insert(instruction.as_value_ref()) => instruction,
+        _ => return false,
+    };
+    let operands = match instruction.get_opcode() {
+        InstructionOpcode::Select => 1,
+        InstructionOpcode::Add
+        | InstructionOpcode::FAdd
+        | InstructionOpcode::Sub
+        | InstructionOpcode::FSub
+        | InstructionOpcode::Mul
+        | InstructionOpcode::FMul
+        | InstructionOpcode::UDiv
+        | InstructionOpcode::SDiv
+        | InstructionOpcode::FDiv
+        | InstructionOpcode::URem
+        | InstructionOpcode::SRem
+        | InstructionOpcode::FRem
+        | InstructionOpcode::Shl
+        | InstructionOpcode::LShr
+        | InstructionOpcode::AShr
+        | InstructionOpcode::And
+        | InstructionOpcode::Or
+        | InstructionOpcode::Xor
+        | InstructionOpcode::Trunc
+        | InstructionOpcode::ZExt
+        | InstructionOpcode::SExt
+        | InstructionOpcode::FPToUI
+        | InstructionOpcode::FPToSI
+        | InstructionOpcode::UIToFP
+        | InstructionOpcode::SIToFP
+        | InstructionOpcode::FPTrunc
+        | InstructionOpcode::FPExt
+        | InstructionOpcode::PtrToInt
+        | InstructionOpcode::IntToPtr
+        | InstructionOpcode::BitCast
+        | InstructionOpcode::AddrSpaceCast
+        | InstructionOpcode::ICmp
+        | InstructionOpcode::FCmp
+        | InstructionOpcode::GetElementPtr
+        | InstructionOpcode::ExtractValue
+        | InstructionOpcode::InsertValue
+        | InstructionOpcode::ExtractElement
+        | InstructionOpcode::InsertElement
+        | InstructionOpcode::ShuffleVector => instruction.get_num_operands(),
+        _ => return false,
+    };
+
+    (0..operands).any(|idx| match instruction.get_operand(idx) {
+        Some(Either::Left(operand)) => is_undefined(operand, visited),
+        _ => false,
+    })
+}
+
+/// Finds an id for a thread the interpreter spawns itself, counting down from the largest id so as
+/// not to collide with the ids of threads spawned by the caller.
+pub(crate) unsafe fn unused_thread_id(execution_engine: &ExecutionEngine<'_>) -> u64 {
+    let mut id = u64::MAX;
+
+    while execution_engine.has_thread(id) {
+        id -= 1;
+    }
+
+    id
+}
+
+impl Drop for InterpreterThread<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.execution_engine.has_thread(self.id) {
+                self.terminate();
+            }
+        }
+    }
+}
+
+/// Decides the order in which interpreter threads are stepped.
+///
+/// Schedulers only ever see threads which can make progress, and must be deterministic
+/// given the same sequence of choices for an interleaving to be replayable.
+pub trait Scheduler {
+    /// Chooses which of `runnable`, a non-empty list of thread ids in the order they were
+    /// passed to `run`, to step next. Returns an index into `runnable`.
+    fn choose(&mut self, runnable: &[u64]) -> usize;
+
+    /// Steps `threads` one instruction at a time in the order chosen by this scheduler, until every
+    /// thread has either exited or is blocked on a pending call, or until a thread hits a breakpoint
+    /// or watchpoint.
+    unsafe fn run<'ctx>(
+        &mut self,
+        threads: &mut [InterpreterThread<'ctx>],
+    ) -> Result<Option<BreakpointHit<'ctx>>, InterpreterError>
+    where
+        Self: Sized,
+    {
+        loop {
+            let runnable: Vec<usize> = threads
+                .iter()
+                .enumerate()
+                .filter(|(_, thread)| thread.state == ThreadState::Runnable)
+                .map(|(idx, _)| idx)
+                .collect();
+
+            if runnable.is_empty() {
+                return Ok(None);
+            }
+
+            let ids: Vec<u64> = runnable.iter().map(|&idx| threads[idx].id).collect();
+            let choice = self.choose(&ids);
+
+            if let StepResult::Triggered(triggers) = threads[runnable[choice]].step()? {
+                return Ok(Some(BreakpointHit {
+                    thread_id: ids[choice],
+                    triggers,
+                }));
+            }
+        }
+    }
+}
+
+/// Steps threads in turn, one instruction each.
+#[derive(Debug, Default, Clone)]
+pub struct RoundRobinScheduler {
+    /// Every thread id seen so far, in the order the threads take turns.
+    order: Vec<u64>,
+    /// The position in `order` of the thread stepped last.
+    last: Option<usize>,
+}
+
+impl RoundRobinScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn choose(&mut self, runnable: &[u64]) -> usize {
+        for &id in runnable {
+            if !self.order.contains(&id) {
+                self.order.push(id);
+            }
+        }
+
+        // Pick the first runnable thread after the last one stepped, skipping those which can't run
+        let start = self.last.map_or(0, |last| last + 1);
+        let pos = (0..self.order.len())
+            .map(|offset| (start + offset) % self.order.len())
+            .find(|&pos| runnable.contains(&self.order[pos]))
+            .expect("runnable threads have been added to the order");
+
+        self.last = Some(pos);
+
+        runnable
+            .iter()
+            .position(|&id| id == self.order[pos])
+            .expect("chosen thread is runnable")
+    }
+}
+
+/// Steps a randomly chosen thread each time. Two schedulers created with the same
+/// seed make the same choices, so any interleaving can be replayed from its seed.
+#[derive(Debug, Clone)]
+pub struct RandomScheduler {
+    state: u64,
+}
+
+impl RandomScheduler {
+    pub fn new(seed: u64) -> Self {
+        RandomScheduler { state: seed }
+    }
+
+    // splitmix64, which is good enough for picking threads and has no bad seeds
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+
+        z ^ (z >> 31)
+    }
+}
+
+impl Scheduler for RandomScheduler {
+    fn choose(&mut self, runnable: &[u64]) -> usize {
+        (self.next_u64() % runnable.len() as u64) as usize
+    }
+}
+
+#[test]
+fn test_round_robin_scheduler() {
+    let mut scheduler = RoundRobinScheduler::new();
+
+    assert_eq!(scheduler.choose(&[1, 2, 3]), 0);
+    assert_eq!(scheduler.choose(&[1, 2, 3]), 1);
+    assert_eq!(scheduler.choose(&[1, 3]), 1);
+    assert_eq!(scheduler.choose(&[1, 2, 3]), 0);
+
+    // Threads don't have to be passed in the order of their ids
+    let mut scheduler = RoundRobinScheduler::new();
+
+    assert_eq!(scheduler.choose(&[5, 2, 9]), 0);
+    assert_eq!(scheduler.choose(&[5, 2, 9]), 1);
+    assert_eq!(scheduler.choose(&[5, 9]), 1);
+    assert_eq!(scheduler.choose(&[5, 2]), 0);
+    assert_eq!(scheduler.choose(&[5, 2, 9]), 1);
+}
+
+#[test]
+fn test_random_scheduler_is_replayable() {
+    let mut first = RandomScheduler::new(42);
+    let mut second = RandomScheduler::new(42);
+    let runnable = [0, 1, 2, 3, 4];
+
+    for _ in 0..100 {
+        let choice = first.choose(&runnable);
+
+        assert!(choice < runnable.len());
+        assert_eq!(choice, second.choose(&runnable));
+    }
+}
@@ -0,0 +1,1021 @@
+//! A compact binary format for recording what an interpreter did through its `MiriHooks`.
+//!
+//! A trace starts with an 8 byte magic number and a version byte, followed by a sequence of events.
+//! Each event is a tag byte followed by its fields, where integers are LEB128 encoded, floats are
+//! little endian and strings and byte buffers are prefixed with their length.
+//!
+//! Instructions, along with the functions they are in being entered and returned from, are only
+//! reported to the hooks by `InterpreterThread`s, through the probes `ExecutionEngine::instrument_module`
+//! instruments the module with. Instructions are recorded by their position in the module rather than
+//! by their text.
+
+use llvm_sys::execution_engine::LLVMGetPointerToAggregateGenericValue;
+use llvm_sys::miri::MiriPointer;
+
+use crate::miri::probes;
+use crate::miri::{CallCompletion, MiriHooks, StackTrace};
+use crate::types::{BasicTypeEnum, FunctionType};
+use crate::values::{__aggregate_field_types, FunctionValue, GenericValue, GenericValueRef, InstructionValue};
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"INKTRACE";
+const VERSION: u8 = 2;
+
+/// A `MiriPointer` as it was recorded in a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TracePointer {
+    pub addr: u64,
+    pub alloc_id: u64,
+    pub tag: u64,
+}
+
+impl From<MiriPointer> for TracePointer {
+    fn from(ptr: MiriPointer) -> Self {
+        TracePointer {
+            addr: ptr.addr,
+            alloc_id: ptr.prov.alloc_id,
+            tag: ptr.prov.tag,
+        }
+    }
+}
+
+/// A value loaded, stored or passed to a call, as it was recorded in a trace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceValue {
+    Int {
+        bits: u32,
+        value: u128,
+    },
+    /// An integer wider than 128 bits, as `u64` words, least significant first.
+    WideInt {
+        bits: u32,
+        words: Vec<u64>,
+    },
+    F32(f32),
+    F64(f64),
+    /// A floating point value of any other type, such as `half`, `x86_fp80` or `fp128`, as the bits
+    /// of its representation in `u64` words, least significant first.
+    Float {
+        ty: String,
+        words: Vec<u64>,
+    },
+    Pointer(TracePointer),
+    Aggregate(Vec<TraceValue>),
+    /// A value whose type wasn't known when it was recorded.
+    Unknown,
+}
+
+impl TraceValue {
+    /// Records `value`, interpreting it as a `ty`. The value itself is left untouched.
+    pub fn new(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'_>) -> Self {
+        match ty {
+            BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() > 128 => TraceValue::WideInt {
+                bits: int_type.get_bit_width(),
+                words: value.as_int_words(),
+            },
+            BasicTypeEnum::IntType(int_type) => TraceValue::Int {
+                bits: int_type.get_bit_width(),
+                value: value.as_int(),
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                TraceValue::F32(value.as_f32())
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                TraceValue::F64(value.as_f64())
+            },
+            // The interpreter keeps other floating point types as the bits of their representation
+            BasicTypeEnum::FloatType(float_type) => TraceValue::Float {
+                ty: float_type.print_to_string().to_string(),
+                words: value.as_int_words(),
+            },
+            BasicTypeEnum::PointerType(_) => TraceValue::Pointer(value.as_miri_pointer().into()),
+            BasicTypeEnum::ArrayType(_) | BasicTypeEnum::StructType(_) | BasicTypeEnum::VectorType(_) => {
+                // Getting the fields through `GenericValueRef::get_fields` would tag them with their types
+                let len = value.get_aggregate_size();
+                let field_types = match __aggregate_field_types::<Self>(ty, len) {
+                    Ok(field_types) => field_types,
+                    Err(_) => return TraceValue::Unknown,
+                };
+
+                TraceValue::Aggregate(
+                    field_types
+                        .into_iter()
+                        .enumerate()
+                        .map(|(idx, field_type)| {
+                            let field = unsafe {
+                                GenericValueRef::new(LLVMGetPointerToAggregateGenericValue(
+                                    value.generic_value,
+                                    idx as u64,
+                                ))
+                            };
+
+                            TraceValue::new(&field, field_type)
+                        })
+                        .collect(),
+                )
+            },
+        }
+    }
+
+    fn from_args(args: &[GenericValueRef<'_>], fn_type: FunctionType<'_>) -> Vec<Self> {
+        let param_types = fn_type.get_param_types();
+
+        // Variadic arguments have no declared type, so fall back on their type tag
+        args.iter()
+            .enumerate()
+            .map(
+                |(idx, arg)| match param_types.get(idx).copied().or_else(|| arg.get_type_tag()) {
+                    Some(ty) => TraceValue::new(arg, ty),
+                    None => TraceValue::Unknown,
+                },
+            )
+            .collect()
+    }
+}
+
+/// Where an instruction is in the module, as it was recorded in a trace. Probes don't count towards
+/// the position of an instruction in its block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceInstruction {
+    pub function: String,
+    pub block: u32,
+    pub index: u32,
+}
+
+impl From<InstructionValue<'_>> for TraceInstruction {
+    fn from(instruction: InstructionValue<'_>) -> Self {
+        let block = instruction.get_parent().expect("executed instructions are in a block");
+        let function = block.get_parent().expect("executed blocks are in a function");
+        let mut block_idx = 0;
+        let mut previous_block = block.get_previous_basic_block();
+
+        while let Some(block) = previous_block {
+            block_idx += 1;
+            previous_block = block.get_previous_basic_block();
+        }
+
+        let mut idx = 0;
+        let mut previous = instruction.get_previous_instruction();
+
+        while let Some(instruction) = previous {
+            if !probes::is_probe(instruction) {
+                idx += 1;
+            }
+
+            previous = instruction.get_previous_instruction();
+        }
+
+        TraceInstruction {
+            function: function_name(function),
+            block: block_idx,
+            index: idx,
+        }
+    }
+}
+
+fn function_name(function: FunctionValue<'_>) -> String {
+    function.get_name().to_string_lossy().into_owned()
+}
+
+/// A frame of a `StackTrace`, as it was recorded in a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Something the interpreter did, as it was recorded in a trace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    Malloc {
+        size: u64,
+        align: u64,
+        is_heap: bool,
+        ptr: TracePointer,
+    },
+    Free {
+        ptr: TracePointer,
+    },
+    Load {
+        ptr: TracePointer,
+        value: TraceValue,
+    },
+    Store {
+        ptr: TracePointer,
+        value: TraceValue,
+    },
+    Memset {
+        ptr: TracePointer,
+        value: u8,
+        len: u64,
+    },
+    Memcpy {
+        ptr: TracePointer,
+        bytes: Vec<u8>,
+    },
+    CallByName {
+        name: String,
+        args: Vec<TraceValue>,
+    },
+    CallByPointer {
+        callee: TracePointer,
+        args: Vec<TraceValue>,
+    },
+    /// A thread entered a function the module defines.
+    Enter {
+        function: String,
+    },
+    /// A thread was about to execute an instruction.
+    Instruction(TraceInstruction),
+    /// A function the module defines returned `value`, which is `None` for `void` functions.
+    Return {
+        function: String,
+        value: Option<TraceValue>,
+    },
+    /// A thread returned from its entry function. Hooks never see this, so it has to be
+    /// recorded with `TraceWriter::write_event`.
+    Exit {
+        thread_id: u64,
+        value: Option<TraceValue>,
+    },
+    Error {
+        inst: Option<String>,
+        frames: Vec<TraceFrame>,
+    },
+}
+
+impl From<&StackTrace<'_>> for TraceEvent {
+    fn from(trace: &StackTrace<'_>) -> Self {
+        TraceEvent::Error {
+            inst: trace.inst.clone(),
+            frames: trace
+                .traces
+                .iter()
+                .map(|item| TraceFrame {
+                    file: item.file.to_string_lossy().into_owned(),
+                    line: item.line,
+                    column: item.column,
+                })
+                .collect(),
+        }
+    }
+}
+
+mod tag {
+    pub const MALLOC: u8 = 0;
+    pub const FREE: u8 = 1;
+    pub const LOAD: u8 = 2;
+    pub const STORE: u8 = 3;
+    pub const MEMSET: u8 = 4;
+    pub const MEMCPY: u8 = 5;
+    pub const CALL_BY_NAME: u8 = 6;
+    pub const CALL_BY_POINTER: u8 = 7;
+    pub const EXIT: u8 = 8;
+    pub const ERROR: u8 = 9;
+    pub const ENTER: u8 = 10;
+    pub const INSTRUCTION: u8 = 11;
+    pub const RETURN: u8 = 12;
+
+    pub const INT: u8 = 0;
+    pub const F32: u8 = 1;
+    pub const F64: u8 = 2;
+    pub const POINTER: u8 = 3;
+    pub const AGGREGATE: u8 = 4;
+    pub const UNKNOWN: u8 = 5;
+    pub const WIDE_INT: u8 = 6;
+    pub const FLOAT: u8 = 7;
+}
+
+/// Writes `TraceEvent`s to `W` in the trace format.
+#[derive(Debug)]
+pub struct TraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a new trace by writing its header to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(TraceWriter { writer })
+    }
+
+    pub fn write_event(&mut self, event: &TraceEvent) -> io::Result<()> {
+        match event {
+            TraceEvent::Malloc {
+                size,
+                align,
+                is_heap,
+                ptr,
+            } => {
+                self.write_u8(tag::MALLOC)?;
+                self.write_uint(*size)?;
+                self.write_uint(*align)?;
+                self.write_u8(*is_heap as u8)?;
+                self.write_pointer(ptr)
+            },
+            TraceEvent::Free { ptr } => {
+                self.write_u8(tag::FREE)?;
+                self.write_pointer(ptr)
+            },
+            TraceEvent::Load { ptr, value } => {
+                self.write_u8(tag::LOAD)?;
+                self.write_pointer(ptr)?;
+                self.write_value(value)
+            },
+            TraceEvent::Store { ptr, value } => {
+                self.write_u8(tag::STORE)?;
+                self.write_pointer(ptr)?;
+                self.write_value(value)
+            },
+            TraceEvent::Memset { ptr, value, len } => {
+                self.write_u8(tag::MEMSET)?;
+                self.write_pointer(ptr)?;
+                self.write_u8(*value)?;
+                self.write_uint(*len)
+            },
+            TraceEvent::Memcpy { ptr, bytes } => {
+                self.write_u8(tag::MEMCPY)?;
+                self.write_pointer(ptr)?;
+                self.write_bytes(bytes)
+            },
+            TraceEvent::CallByName { name, args } => {
+                self.write_u8(tag::CALL_BY_NAME)?;
+                self.write_bytes(name.as_bytes())?;
+                self.write_values(args)
+            },
+            TraceEvent::CallByPointer { callee, args } => {
+                self.write_u8(tag::CALL_BY_POINTER)?;
+                self.write_pointer(callee)?;
+                self.write_values(args)
+            },
+            TraceEvent::Enter { function } => {
+                self.write_u8(tag::ENTER)?;
+                self.write_bytes(function.as_bytes())
+            },
+            TraceEvent::Instruction(instruction) => {
+                self.write_u8(tag::INSTRUCTION)?;
+                self.write_bytes(instruction.function.as_bytes())?;
+                self.write_uint(instruction.block.into())?;
+                self.write_uint(instruction.index.into())
+            },
+            TraceEvent::Return { function, value } => {
+                self.write_u8(tag::RETURN)?;
+                self.write_bytes(function.as_bytes())?;
+                self.write_optional_value(value.as_ref())
+            },
+            TraceEvent::Exit { thread_id, value } => {
+                self.write_u8(tag::EXIT)?;
+                self.write_uint(*thread_id)?;
+                self.write_optional_value(value.as_ref())
+            },
+            TraceEvent::Error { inst, frames } => {
+                self.write_u8(tag::ERROR)?;
+
+                match inst {
+                    Some(inst) => {
+                        self.write_u8(1)?;
+                        self.write_bytes(inst.as_bytes())?;
+                    },
+                    None => self.write_u8(0)?,
+                }
+
+                self.write_uint(frames.len() as u64)?;
+
+                for frame in frames {
+                    self.write_bytes(frame.file.as_bytes())?;
+                    self.write_uint(frame.line.into())?;
+                    self.write_uint(frame.column.into())?;
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    /// Flushes any buffered events and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_u8(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn write_uint(&mut self, value: u64) -> io::Result<()> {
+        self.write_u128(value.into())
+    }
+
+    fn write_u128(&mut self, mut value: u128) -> io::Result<()> {
+        loop {
+            let byte = (value & 0x7f) as u8;
+
+            value >>= 7;
+
+            if value == 0 {
+                return self.write_u8(byte);
+            }
+
+            self.write_u8(byte | 0x80)?;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_uint(bytes.len() as u64)?;
+        self.writer.write_all(bytes)
+    }
+
+    fn write_words(&mut self, words: &[u64]) -> io::Result<()> {
+        self.write_uint(words.len() as u64)?;
+
+        for word in words {
+            self.write_uint(*word)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pointer(&mut self, ptr: &TracePointer) -> io::Result<()> {
+        self.write_uint(ptr.addr)?;
+        self.write_uint(ptr.alloc_id)?;
+        self.write_uint(ptr.tag)
+    }
+
+    fn write_values(&mut self, values: &[TraceValue]) -> io::Result<()> {
+        self.write_uint(values.len() as u64)?;
+
+        for value in values {
+            self.write_value(value)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_optional_value(&mut self, value: Option<&TraceValue>) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.write_u8(1)?;
+                self.write_value(value)
+            },
+            None => self.write_u8(0),
+        }
+    }
+
+    fn write_value(&mut self, value: &TraceValue) -> io::Result<()> {
+        match value {
+            TraceValue::Int { bits, value } => {
+                self.write_u8(tag::INT)?;
+                self.write_uint((*bits).into())?;
+                self.write_u128(*value)
+            },
+            TraceValue::WideInt { bits, words } => {
+                self.write_u8(tag::WIDE_INT)?;
+                self.write_uint((*bits).into())?;
+                self.write_words(words)
+            },
+            TraceValue::F32(value) => {
+                self.write_u8(tag::F32)?;
+                self.writer.write_all(&value.to_le_bytes())
+            },
+            TraceValue::F64(value) => {
+                self.write_u8(tag::F64)?;
+                self.writer.write_all(&value.to_le_bytes())
+            },
+            TraceValue::Float { ty, words } => {
+                self.write_u8(tag::FLOAT)?;
+                self.write_bytes(ty.as_bytes())?;
+                self.write_words(words)
+            },
+            TraceValue::Pointer(ptr) => {
+                self.write_u8(tag::POINTER)?;
+                self.write_pointer(ptr)
+            },
+            TraceValue::Aggregate(fields) => {
+                self.write_u8(tag::AGGREGATE)?;
+                self.write_values(fields)
+            },
+            TraceValue::Unknown => self.write_u8(tag::UNKNOWN),
+        }
+    }
+}
+
+/// Reads the `TraceEvent`s written by a `TraceWriter` back from `R`.
+#[derive(Debug)]
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Starts reading a trace, checking that `reader` begins with a trace header.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; MAGIC.len() + 1];
+
+        reader.read_exact(&mut header)?;
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not an execution trace"));
+        }
+
+        if header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("unsupported execution trace version"));
+        }
+
+        Ok(TraceReader { reader })
+    }
+
+    /// Reads the next event, or `None` once the end of the trace is reached.
+    pub fn read_event(&mut self) -> io::Result<Option<TraceEvent>> {
+        let mut tag = [0];
+
+        if self.reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+
+        let event = match tag[0] {
+            tag::MALLOC => TraceEvent::Malloc {
+                size: self.read_uint()?,
+                align: self.read_uint()?,
+                is_heap: self.read_u8()? != 0,
+                ptr: self.read_pointer()?,
+            },
+            tag::FREE => TraceEvent::Free {
+                ptr: self.read_pointer()?,
+            },
+            tag::LOAD => TraceEvent::Load {
+                ptr: self.read_pointer()?,
+                value: self.read_value()?,
+            },
+            tag::STORE => TraceEvent::Store {
+                ptr: self.read_pointer()?,
+                value: self.read_value()?,
+            },
+            tag::MEMSET => TraceEvent::Memset {
+                ptr: self.read_pointer()?,
+                value: self.read_u8()?,
+                len: self.read_uint()?,
+            },
+            tag::MEMCPY => TraceEvent::Memcpy {
+                ptr: self.read_pointer()?,
+                bytes: self.read_bytes()?,
+            },
+            tag::CALL_BY_NAME => TraceEvent::CallByName {
+                name: self.read_string()?,
+                args: self.read_values()?,
+            },
+            tag::CALL_BY_POINTER => TraceEvent::CallByPointer {
+                callee: self.read_pointer()?,
+                args: self.read_values()?,
+            },
+            tag::ENTER => TraceEvent::Enter {
+                function: self.read_string()?,
+            },
+            tag::INSTRUCTION => TraceEvent::Instruction(TraceInstruction {
+                function: self.read_string()?,
+                block: self.read_u32()?,
+                index: self.read_u32()?,
+            }),
+            tag::RETURN => TraceEvent::Return {
+                function: self.read_string()?,
+                value: self.read_optional_value()?,
+            },
+            tag::EXIT => TraceEvent::Exit {
+                thread_id: self.read_uint()?,
+                value: self.read_optional_value()?,
+            },
+            tag::ERROR => {
+                let inst = match self.read_u8()? {
+                    0 => None,
+                    _ => Some(self.read_string()?),
+                };
+                let frames = (0..self.read_uint()?)
+                    .map(|_| {
+                        Ok(TraceFrame {
+                            file: self.read_string()?,
+                            line: self.read_u32()?,
+                            column: self.read_u32()?,
+                        })
+                    })
+                    .collect::<io::Result<_>>()?;
+
+                TraceEvent::Error { inst, frames }
+            },
+            _ => return Err(invalid_data("unknown event in execution trace")),
+        };
+
+        Ok(Some(event))
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+
+        self.reader.read_exact(&mut byte)?;
+
+        Ok(byte[0])
+    }
+
+    fn read_u128(&mut self) -> io::Result<u128> {
+        let mut value = 0u128;
+
+        for shift in (0..128).step_by(7) {
+            let byte = self.read_u8()?;
+
+            value |= u128::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(invalid_data("integer in execution trace is too large"))
+    }
+
+    fn read_uint(&mut self) -> io::Result<u64> {
+        u64::try_from(self.read_u128()?).map_err(|_| invalid_data("integer in execution trace is too large"))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        u32::try_from(self.read_uint()?).map_err(|_| invalid_data("integer in execution trace is too large"))
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_uint()?;
+        let mut bytes = Vec::new();
+
+        (&mut self.reader).take(len).read_to_end(&mut bytes)?;
+
+        if bytes.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.read_bytes()?).map_err(|_| invalid_data("string in execution trace isn't valid UTF-8"))
+    }
+
+    fn read_pointer(&mut self) -> io::Result<TracePointer> {
+        Ok(TracePointer {
+            addr: self.read_uint()?,
+            alloc_id: self.read_uint()?,
+            tag: self.read_uint()?,
+        })
+    }
+
+    fn read_values(&mut self) -> io::Result<Vec<TraceValue>> {
+        (0..self.read_uint()?).map(|_| self.read_value()).collect()
+    }
+
+    fn read_words(&mut self) -> io::Result<Vec<u64>> {
+        (0..self.read_uint()?).map(|_| self.read_uint()).collect()
+    }
+
+    fn read_optional_value(&mut self) -> io::Result<Option<TraceValue>> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            _ => self.read_value().map(Some),
+        }
+    }
+
+    fn read_value(&mut self) -> io::Result<TraceValue> {
+        let value = match self.read_u8()? {
+            tag::INT => TraceValue::Int {
+                bits: self.read_u32()?,
+                value: self.read_u128()?,
+            },
+            tag::WIDE_INT => TraceValue::WideInt {
+                bits: self.read_u32()?,
+                words: self.read_words()?,
+            },
+            tag::F32 => {
+                let mut bytes = [0; 4];
+
+                self.reader.read_exact(&mut bytes)?;
+
+                TraceValue::F32(f32::from_le_bytes(bytes))
+            },
+            tag::F64 => {
+                let mut bytes = [0; 8];
+
+                self.reader.read_exact(&mut bytes)?;
+
+                TraceValue::F64(f64::from_le_bytes(bytes))
+            },
+            tag::FLOAT => TraceValue::Float {
+                ty: self.read_string()?,
+                words: self.read_words()?,
+            },
+            tag::POINTER => TraceValue::Pointer(self.read_pointer()?),
+            tag::AGGREGATE => TraceValue::Aggregate(self.read_values()?),
+            tag::UNKNOWN => TraceValue::Unknown,
+            _ => return Err(invalid_data("unknown value in execution trace")),
+        };
+
+        Ok(value)
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The error which stopped a `TracingHooks` from writing its trace. It is shared with the hooks, so
+/// that it can still be taken once they are installed.
+#[derive(Debug, Clone, Default)]
+pub struct TraceError(Rc<RefCell<Option<io::Error>>>);
+
+impl TraceError {
+    /// Takes the error, if writing the trace failed.
+    pub fn take(&self) -> Option<io::Error> {
+        self.0.borrow_mut().take()
+    }
+
+    /// Returns whether writing the trace failed.
+    pub fn is_set(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+}
+
+/// Wraps another set of `MiriHooks`, recording every call made into them, along with the functions,
+/// instructions and returns reported by `InterpreterThread`s, to a trace.
+///
+/// Once writing to the trace fails, nothing more is recorded and every hook which can fail does,
+/// stopping the interpreter rather than letting it run on with an incomplete trace. The error is
+/// kept in the `TraceError` returned by `get_error`.
+#[derive(Debug)]
+pub struct TracingHooks<H, W: Write> {
+    hooks: H,
+    writer: TraceWriter<W>,
+    error: TraceError,
+}
+
+impl<H, W: Write> TracingHooks<H, W> {
+    pub fn new(hooks: H, writer: TraceWriter<W>) -> Self {
+        TracingHooks {
+            hooks,
+            writer,
+            error: TraceError::default(),
+        }
+    }
+
+    /// Gets the error which stopped these hooks from writing the trace, which is shared with them.
+    pub fn get_error(&self) -> TraceError {
+        self.error.clone()
+    }
+
+    /// Records `event`, returning whether the trace is still being written.
+    fn record(&mut self, event: TraceEvent) -> bool {
+        if self.error.is_set() {
+            return false;
+        }
+
+        if let Err(err) = self.writer.write_event(&event) {
+            *self.error.0.borrow_mut() = Some(err);
+
+            return false;
+        }
+
+        true
+    }
+}
+
+impl<'ctx, H: MiriHooks<'ctx>, W: Write> MiriHooks<'ctx> for TracingHooks<H, W> {
+    fn malloc(&mut self, size: u64, align: u64, is_heap: bool) -> MiriPointer {
+        let ptr = self.hooks.malloc(size, align, is_heap);
+
+        self.record(TraceEvent::Malloc {
+            size,
+            align,
+            is_heap,
+            ptr: ptr.into(),
+        });
+
+        ptr
+    }
+
+    fn free(&mut self, ptr: MiriPointer) -> bool {
+        self.record(TraceEvent::Free { ptr: ptr.into() }) && self.hooks.free(ptr)
+    }
+
+    fn load(&mut self, dest: GenericValueRef<'_>, src: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool {
+        if !self.hooks.load(dest, src, ty, align) {
+            return false;
+        }
+
+        self.record(TraceEvent::Load {
+            ptr: src.into(),
+            value: TraceValue::new(&dest, ty),
+        })
+    }
+
+    fn store(&mut self, value: GenericValueRef<'_>, dest: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool {
+        let event = TraceEvent::Store {
+            ptr: dest.into(),
+            value: TraceValue::new(&value, ty),
+        };
+
+        self.record(event) && self.hooks.store(value, dest, ty, align)
+    }
+
+    fn get_element_pointer(&mut self, base: MiriPointer, offset: u64) -> MiriPointer {
+        self.hooks.get_element_pointer(base, offset)
+    }
+
+    fn memset(&mut self, dest: MiriPointer, value: u8, len: u64) -> bool {
+        let event = TraceEvent::Memset {
+            ptr: dest.into(),
+            value,
+            len,
+        };
+
+        self.record(event) && self.hooks.memset(dest, value, len)
+    }
+
+    fn memcpy(&mut self, dest: MiriPointer, src: &[u8]) -> bool {
+        let event = TraceEvent::Memcpy {
+            ptr: dest.into(),
+            bytes: src.to_vec(),
+        };
+
+        self.record(event) && self.hooks.memcpy(dest, src)
+    }
+
+    fn int_to_ptr(&mut self, addr: u64) -> MiriPointer {
+        self.hooks.int_to_ptr(addr)
+    }
+
+    fn ptr_to_int(&mut self, ptr: MiriPointer) -> u64 {
+        self.hooks.ptr_to_int(ptr)
+    }
+
+    fn call_by_name(&mut self, name: &str, args: &[GenericValueRef<'_>], fn_type: FunctionType<'ctx>) -> bool {
+        let event = TraceEvent::CallByName {
+            name: name.to_owned(),
+            args: TraceValue::from_args(args, fn_type),
+        };
+
+        self.record(event) && self.hooks.call_by_name(name, args, fn_type)
+    }
+
+    fn call_by_pointer(
+        &mut self,
+        callee: MiriPointer,
+        args: &[GenericValueRef<'_>],
+        fn_type: FunctionType<'ctx>,
+    ) -> bool {
+        let event = TraceEvent::CallByPointer {
+            callee: callee.into(),
+            args: TraceValue::from_args(args, fn_type),
+        };
+
+        self.record(event) && self.hooks.call_by_pointer(callee, args, fn_type)
+    }
+
+    fn register_global(&mut self, name: &str, ptr: MiriPointer) -> bool {
+        self.hooks.register_global(name, ptr)
+    }
+
+    fn record_stack_trace(&mut self, trace: StackTrace<'ctx>) {
+        self.record((&trace).into());
+        self.hooks.record_stack_trace(trace)
+    }
+
+    fn take_return_value(&mut self) -> Option<GenericValue<'ctx>> {
+        self.hooks.take_return_value()
+    }
+
+    fn complete_call(&mut self, thread_id: u64) -> CallCompletion<'ctx> {
+        self.hooks.complete_call(thread_id)
+    }
+
+    fn snapshot_memory(&mut self) -> Option<Box<dyn Any>> {
+        self.hooks.snapshot_memory()
+    }
+
+    fn restore_memory(&mut self, snapshot: &dyn Any) {
+        self.hooks.restore_memory(snapshot)
+    }
+
+    fn enter_function(&mut self, function: FunctionValue<'ctx>) {
+        self.record(TraceEvent::Enter {
+            function: function_name(function),
+        });
+        self.hooks.enter_function(function)
+    }
+
+    fn execute_instruction(&mut self, instruction: InstructionValue<'ctx>) {
+        self.record(TraceEvent::Instruction(instruction.into()));
+        self.hooks.execute_instruction(instruction)
+    }
+
+    fn return_from_function(&mut self, function: FunctionValue<'ctx>, value: Option<GenericValueRef<'_>>) {
+        let return_type = function.get_type().get_return_type();
+
+        self.record(TraceEvent::Return {
+            function: function_name(function),
+            value: value.map(|value| match return_type {
+                Some(ty) => TraceValue::new(&value, ty),
+                None => TraceValue::Unknown,
+            }),
+        });
+        self.hooks.return_from_function(function, value)
+    }
+}
+
+#[test]
+fn test_trace_round_trip() {
+    let ptr = TracePointer {
+        addr: 0x1000,
+        alloc_id: 3,
+        tag: 7,
+    };
+    let events = vec![
+        TraceEvent::Malloc {
+            size: 16,
+            align: 8,
+            is_heap: true,
+            ptr,
+        },
+        TraceEvent::Store {
+            ptr,
+            value: TraceValue::Aggregate(vec![
+                TraceValue::Int {
+                    bits: 128,
+                    value: u128::MAX,
+                },
+                TraceValue::WideInt {
+                    bits: 256,
+                    words: vec![u64::MAX, 1, 0, 1 << 63],
+                },
+                TraceValue::F32(1.5),
+                TraceValue::F64(-2.25),
+                TraceValue::Float {
+                    ty: "x86_fp80".into(),
+                    words: vec![1 << 63, 0x3fff],
+                },
+                TraceValue::Pointer(ptr),
+                TraceValue::Unknown,
+            ]),
+        },
+        TraceEvent::CallByName {
+            name: "printf".into(),
+            args: vec![TraceValue::Pointer(ptr)],
+        },
+        TraceEvent::Memcpy {
+            ptr,
+            bytes: vec![1, 2, 3],
+        },
+        TraceEvent::Enter {
+            function: "main".into(),
+        },
+        TraceEvent::Instruction(TraceInstruction {
+            function: "main".into(),
+            block: 2,
+            index: 5,
+        }),
+        TraceEvent::Return {
+            function: "main".into(),
+            value: Some(TraceValue::Int { bits: 32, value: 0 }),
+        },
+        TraceEvent::Exit {
+            thread_id: 1,
+            value: None,
+        },
+        TraceEvent::Error {
+            inst: Some("store i32 0, ptr null".into()),
+            frames: vec![TraceFrame {
+                file: "main.c".into(),
+                line: 4,
+                column: 2,
+            }],
+        },
+    ];
+
+    let mut writer = TraceWriter::new(Vec::new()).unwrap();
+
+    for event in &events {
+        writer.write_event(event).unwrap();
+    }
+
+    let bytes = writer.finish().unwrap();
+    let reader = TraceReader::new(bytes.as_slice()).unwrap();
+
+    assert_eq!(reader.collect::<io::Result<Vec<_>>>().unwrap(), events);
+    assert!(TraceReader::new(&b"NOTATRACE"[..]).is_err());
+}
@@ -0,0 +1,1419 @@
+//! A wrapper around LLVM's ORCv2 JIT, `LLJIT`, which succeeds MCJIT.
+//!
+//! Code is added to an `LLJIT` as `ThreadSafeModule`s, whose context is shared between threads
+//! through a `ThreadSafeContext`. Modules are grouped into `JITDylib`s, which act like dynamic
+//! libraries, and the code of a module can be unloaded again through the `ResourceTracker` it was
+//! added with.
+
+#[llvm_versions(13..)]
+use libc::{c_char, c_void};
+#[llvm_versions(13..)]
+use llvm_sys::core::{
+    LLVMAddFunction, LLVMAddGlobalInAddressSpace, LLVMAddModuleFlag, LLVMBasicBlockAsValue, LLVMConstAddrSpaceCast,
+    LLVMConstBitCast, LLVMConstIntToPtr, LLVMConstPtrToInt, LLVMCopyModuleFlagsMetadata,
+    LLVMDisposeModuleFlagsMetadata, LLVMDisposeValueMetadataEntries, LLVMGetConstOpcode, LLVMGetFirstFunction,
+    LLVMGetFirstUse, LLVMGetFunctionCallConv, LLVMGetGC, LLVMGetNextFunction, LLVMGetNextUse, LLVMGetNumOperands,
+    LLVMGetOperand, LLVMGetPointerAddressSpace, LLVMGetThreadLocalMode, LLVMGetTypeKind, LLVMGetUser, LLVMGetValueKind,
+    LLVMGetValueName2, LLVMGetVisibility, LLVMGlobalCopyAllMetadata, LLVMGlobalGetValueType, LLVMGlobalSetMetadata,
+    LLVMIsAConstant, LLVMIsDeclaration, LLVMIsGlobalConstant, LLVMIsInBounds, LLVMModuleFlagEntriesGetFlagBehavior,
+    LLVMModuleFlagEntriesGetKey, LLVMModuleFlagEntriesGetMetadata, LLVMReplaceAllUsesWith, LLVMSetFunctionCallConv,
+    LLVMSetGC, LLVMSetGlobalConstant, LLVMSetOperand, LLVMSetPersonalityFn, LLVMSetThreadLocalMode, LLVMSetValueName2,
+    LLVMSetVisibility, LLVMTypeOf, LLVMValueMetadataEntriesGetKind, LLVMValueMetadataEntriesGetMetadata,
+};
+#[llvm_versions(13..=14)]
+use llvm_sys::core::{LLVMConstGEP, LLVMConstInBoundsGEP};
+#[llvm_versions(15..)]
+use llvm_sys::core::{LLVMConstGEP2, LLVMConstInBoundsGEP2, LLVMGetGEPSourceElementType};
+#[llvm_versions(13..)]
+use llvm_sys::error::LLVMCreateStringError;
+use llvm_sys::error::{LLVMConsumeError, LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage};
+#[llvm_versions(13..)]
+use llvm_sys::execution_engine::{LLVMCreateSymbolLoadedJITEventListener, LLVMDisposeJITEventListener};
+#[llvm_versions(13..)]
+use llvm_sys::orc2::ee::{
+    LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager,
+    LLVMOrcRTDyldObjectLinkingLayerRegisterJITEventListener,
+};
+use llvm_sys::orc2::lljit::{
+    LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcDisposeLLJIT, LLVMOrcLLJITAddLLVMIRModule,
+    LLVMOrcLLJITAddLLVMIRModuleWithRT, LLVMOrcLLJITBuilderRef, LLVMOrcLLJITBuilderSetJITTargetMachineBuilder,
+    LLVMOrcLLJITGetExecutionSession, LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITGetMainJITDylib,
+    LLVMOrcLLJITGetTripleString, LLVMOrcLLJITLookup, LLVMOrcLLJITMangleAndIntern, LLVMOrcLLJITRef,
+};
+#[llvm_versions(13..)]
+use llvm_sys::orc2::lljit::{
+    LLVMOrcLLJITBuilderSetObjectLinkingLayerCreator, LLVMOrcLLJITGetIRTransformLayer, LLVMOrcLLJITGetObjTransformLayer,
+};
+use llvm_sys::orc2::{
+    LLVMJITEvaluatedSymbol, LLVMJITSymbolFlags, LLVMJITSymbolGenericFlags, LLVMOrcAbsoluteSymbols,
+    LLVMOrcCSymbolMapPair, LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess, LLVMOrcCreateNewThreadSafeContext,
+    LLVMOrcCreateNewThreadSafeModule, LLVMOrcDisposeMaterializationUnit, LLVMOrcDisposeThreadSafeContext,
+    LLVMOrcDisposeThreadSafeModule, LLVMOrcExecutionSessionCreateJITDylib, LLVMOrcExecutionSessionGetJITDylibByName,
+    LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibClear, LLVMOrcJITDylibCreateResourceTracker, LLVMOrcJITDylibDefine,
+    LLVMOrcJITDylibRef, LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine, LLVMOrcMaterializationUnitRef,
+    LLVMOrcReleaseResourceTracker, LLVMOrcResourceTrackerRef, LLVMOrcResourceTrackerRemove,
+    LLVMOrcSymbolStringPoolEntryRef, LLVMOrcThreadSafeContextGetContext, LLVMOrcThreadSafeContextRef,
+    LLVMOrcThreadSafeModuleRef,
+};
+#[llvm_versions(13..)]
+use llvm_sys::orc2::{
+    LLVMOrcCSymbolAliasMapEntry, LLVMOrcCSymbolAliasMapPair, LLVMOrcCreateLocalIndirectStubsManager,
+    LLVMOrcCreateLocalLazyCallThroughManager, LLVMOrcDisposeIndirectStubsManager, LLVMOrcDisposeLazyCallThroughManager,
+    LLVMOrcIRTransformLayerSetTransform, LLVMOrcIndirectStubsManagerRef, LLVMOrcLazyCallThroughManagerRef,
+    LLVMOrcLazyReexports, LLVMOrcMaterializationResponsibilityRef, LLVMOrcThreadSafeModuleWithModuleDo,
+};
+#[llvm_versions(13..)]
+use llvm_sys::orc2::{LLVMOrcExecutionSessionRef, LLVMOrcObjectLayerRef, LLVMOrcObjectTransformLayerSetTransform};
+#[llvm_versions(13..)]
+use llvm_sys::prelude::{LLVMJITEventListenerRef, LLVMMemoryBufferRef, LLVMModuleRef, LLVMValueRef};
+#[llvm_versions(13..)]
+use llvm_sys::{LLVMOpcode, LLVMTypeKind, LLVMValueKind};
+
+#[llvm_versions(13..)]
+use crate::attributes::AttributeLoc;
+#[llvm_versions(13..)]
+use crate::basic_block::BasicBlock;
+use crate::context::ContextRef;
+use crate::execution_engine::{JitFunction, UnsafeFunctionPointer};
+#[llvm_versions(13..)]
+use crate::jit_event_listener::{JITEventListener, JitEvent};
+#[llvm_versions(13..)]
+use crate::memory_buffer::MemoryBuffer;
+#[llvm_versions(13..)]
+use crate::module::Linkage;
+use crate::module::Module;
+use crate::support::{load_visible_symbols, to_c_str, LLVMString};
+use crate::symbol_map::SymbolMap;
+use crate::targets::{TargetMachine, TargetTriple};
+#[llvm_versions(13..)]
+use crate::values::{AsValueRef, FunctionValue};
+#[llvm_versions(13..)]
+use crate::GlobalVisibility;
+
+#[llvm_versions(13..)]
+use std::cell::{Cell, Ref, RefCell};
+#[llvm_versions(13..)]
+use std::collections::HashMap;
+use std::ffi::CStr;
+#[llvm_versions(13..)]
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem::forget;
+#[llvm_versions(13..)]
+use std::mem::ManuallyDrop;
+#[llvm_versions(13..)]
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+
+/// Turns an `LLVMErrorRef`, which is null on success, into a `Result`, consuming the error.
+unsafe fn error_to_result(error: LLVMErrorRef) -> Result<(), LLVMString> {
+    if error.is_null() {
+        return Ok(());
+    }
+
+    let message = LLVMGetErrorMessage(error);
+    let string = LLVMString::create_from_c_str(CStr::from_ptr(message));
+
+    LLVMDisposeErrorMessage(message);
+
+    Err(string)
+}
+
+/// A `Context` which can be shared by modules compiled on different threads. It stays alive until
+/// both it and every `ThreadSafeModule` created in it are dropped.
+#[derive(Debug)]
+pub struct ThreadSafeContext {
+    thread_safe_context: LLVMOrcThreadSafeContextRef,
+}
+
+impl ThreadSafeContext {
+    /// Creates a new `ThreadSafeContext` owning a new `Context`.
+    pub fn create() -> Self {
+        ThreadSafeContext {
+            thread_safe_context: unsafe { LLVMOrcCreateNewThreadSafeContext() },
+        }
+    }
+
+    /// Acquires the underlying raw pointer belonging to this `ThreadSafeContext` type.
+    pub fn as_mut_ptr(&self) -> LLVMOrcThreadSafeContextRef {
+        self.thread_safe_context
+    }
+
+    /// Gets the `Context` in which to build modules for a `ThreadSafeModule`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::orc::{ThreadSafeContext, ThreadSafeModule};
+    ///
+    /// let thread_safe_context = ThreadSafeContext::create();
+    /// let module = thread_safe_context.context().create_module("my_module");
+    /// let thread_safe_module = ThreadSafeModule::create(&thread_safe_context, module).unwrap();
+    /// ```
+    pub fn context(&self) -> ContextRef<'_> {
+        unsafe { ContextRef::new(LLVMOrcThreadSafeContextGetContext(self.thread_safe_context)) }
+    }
+}
+
+impl Drop for ThreadSafeContext {
+    fn drop(&mut self) {
+        unsafe { LLVMOrcDisposeThreadSafeContext(self.thread_safe_context) }
+    }
+}
+
+/// A `Module` paired with the `ThreadSafeContext` it was built in, ready to be added to an `LLJIT`.
+#[derive(Debug)]
+pub struct ThreadSafeModule {
+    thread_safe_module: LLVMOrcThreadSafeModuleRef,
+}
+
+impl ThreadSafeModule {
+    /// Takes ownership of `module`, which must have been built in `context`.
+    pub fn create<'ctx>(context: &'ctx ThreadSafeContext, module: Module<'ctx>) -> Result<Self, LLVMString> {
+        Self::check(context, &module)?;
+
+        let thread_safe_module =
+            unsafe { LLVMOrcCreateNewThreadSafeModule(module.as_mut_ptr(), context.thread_safe_context) };
+
+        forget(module);
+
+        Ok(ThreadSafeModule { thread_safe_module })
+    }
+
+    /// Acquires the underlying raw pointer belonging to this `ThreadSafeModule` type.
+    pub fn as_mut_ptr(&self) -> LLVMOrcThreadSafeModuleRef {
+        self.thread_safe_module
+    }
+
+    /// Checks whether `create` would accept `module`.
+    fn check<'ctx>(context: &'ctx ThreadSafeContext, module: &Module<'ctx>) -> Result<(), LLVMString> {
+        if module.owned_by_ee.borrow().is_some() {
+            let string = "Cannot add a module which is already owned by an ExecutionEngine.\0";
+            return Err(LLVMString::create_from_str(string));
+        }
+
+        if module.get_context() != context.context() {
+            let string = "Module was not built in the ThreadSafeContext.\0";
+            return Err(LLVMString::create_from_str(string));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ThreadSafeModule {
+    fn drop(&mut self) {
+        unsafe { LLVMOrcDisposeThreadSafeModule(self.thread_safe_module) }
+    }
+}
+
+/// A JIT built on LLVM's ORCv2 APIs, which compiles every module it's given for the host.
+///
+/// Clones share the same JIT, which is disposed along with the last clone or `JitFunction` taken
+/// from it.
+#[derive(Debug, Clone)]
+pub struct LLJIT {
+    jit: Rc<LLJITInner>,
+}
+
+/// A smart pointer which wraps the `Drop` logic for `LLVMOrcLLJITRef`, along with the state the
+/// JIT calls back into.
+#[llvm_versioned_item]
+#[derive(Debug)]
+struct LLJITInner {
+    jit: LLVMOrcLLJITRef,
+    /// The stubs functions added with `add_module_lazily` are called through, created on first use.
+    #[llvm_versions(13..)]
+    lazy_compilation: RefCell<Option<LazyCompilation>>,
+    /// Numbers the modules added with `add_module_lazily`, to give their private symbols unique names.
+    #[llvm_versions(13..)]
+    lazy_module_count: Cell<u64>,
+    /// Boxed so that its address, which the JIT holds on to, stays the same.
+    #[llvm_versions(13..)]
+    materialization_hook: Box<RefCell<Option<MaterializationHook>>>,
+    /// Boxed for the same reason.
+    #[llvm_versions(13..)]
+    event_reporting: Box<EventReporting>,
+    /// Reports the symbols of every object the JIT loads to `event_reporting`. Disposed after the JIT.
+    #[llvm_versions(13..)]
+    symbol_listener: SymbolListener,
+}
+
+impl LLJITInner {
+    #[llvm_versions(12..=12)]
+    fn new(jit: LLVMOrcLLJITRef) -> Self {
+        LLJITInner { jit }
+    }
+
+    #[llvm_versions(13..)]
+    fn new(jit: LLVMOrcLLJITRef, event_reporting: Box<EventReporting>, symbol_listener: SymbolListener) -> Self {
+        LLJITInner {
+            jit,
+            lazy_compilation: RefCell::new(None),
+            lazy_module_count: Cell::new(0),
+            materialization_hook: Box::new(RefCell::new(None)),
+            event_reporting,
+            symbol_listener,
+        }
+    }
+}
+
+impl Drop for LLJITInner {
+    fn drop(&mut self) {
+        // The lazy call-through machinery is only disposed along with the fields, after the JIT
+        unsafe {
+            let error = LLVMOrcDisposeLLJIT(self.jit);
+
+            if !error.is_null() {
+                LLVMConsumeError(error);
+            }
+        }
+    }
+}
+
+impl LLJIT {
+    /// Creates an `LLJIT` for the host. The native target must have been initialized beforehand.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::orc::LLJIT;
+    /// use inkwell::targets::{InitializationConfig, Target};
+    ///
+    /// Target::initialize_native(&InitializationConfig::default()).unwrap();
+    ///
+    /// let jit = LLJIT::create().unwrap();
+    /// ```
+    pub fn create() -> Result<Self, LLVMString> {
+        unsafe { LLJIT::create_with_builder(ptr::null_mut()) }
+    }
+
+    /// Creates an `LLJIT` which compiles with `target_machine` rather than one for the host.
+    pub fn create_with_target_machine(target_machine: TargetMachine) -> Result<Self, LLVMString> {
+        unsafe {
+            let builder = LLVMOrcCreateLLJITBuilder();
+            let target_machine_builder =
+                LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine(target_machine.target_machine);
+
+            forget(target_machine);
+            LLVMOrcLLJITBuilderSetJITTargetMachineBuilder(builder, target_machine_builder);
+
+            LLJIT::create_with_builder(builder)
+        }
+    }
+
+    /// Creates an `LLJIT` for the host which tells `listeners` about every object it links, such
+    /// as GDB or perf. The native target must have been initialized beforehand.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::jit_event_listener::JITEventListener;
+    /// use inkwell::orc::LLJIT;
+    /// use inkwell::targets::{InitializationConfig, Target};
+    ///
+    /// Target::initialize_native(&InitializationConfig::default()).unwrap();
+    ///
+    /// let mut listeners = vec![JITEventListener::create_gdb_registration_listener()];
+    ///
+    /// listeners.extend(JITEventListener::create_perf_listener());
+    ///
+    /// let jit = LLJIT::create_with_event_listeners(&listeners).unwrap();
+    /// ```
+    #[llvm_versions(13..)]
+    pub fn create_with_event_listeners(listeners: &[JITEventListener]) -> Result<Self, LLVMString> {
+        unsafe { LLJIT::create_with_listeners(LLVMOrcCreateLLJITBuilder(), listeners) }
+    }
+
+    #[llvm_versions(12..=12)]
+    unsafe fn create_with_builder(builder: LLVMOrcLLJITBuilderRef) -> Result<Self, LLVMString> {
+        let mut jit = ptr::null_mut();
+
+        error_to_result(LLVMOrcCreateLLJIT(&mut jit, builder))?;
+
+        Ok(LLJIT {
+            jit: Rc::new(LLJITInner::new(jit)),
+        })
+    }
+
+    #[llvm_versions(13..)]
+    unsafe fn create_with_builder(builder: LLVMOrcLLJITBuilderRef) -> Result<Self, LLVMString> {
+        let builder = if builder.is_null() {
+            LLVMOrcCreateLLJITBuilder()
+        } else {
+            builder
+        };
+
+        LLJIT::create_with_listeners(builder, &[])
+    }
+
+    /// Creates an `LLJIT` whose linking layer tells `listeners`, and the event callback, about the
+    /// objects it loads.
+    #[llvm_versions(13..)]
+    unsafe fn create_with_listeners(
+        builder: LLVMOrcLLJITBuilderRef,
+        listeners: &[JITEventListener],
+    ) -> Result<Self, LLVMString> {
+        let event_reporting = Box::<EventReporting>::default();
+        let reporting: *const EventReporting = &*event_reporting;
+        let symbol_listener = SymbolListener(LLVMCreateSymbolLoadedJITEventListener(
+            report_loaded_symbol,
+            reporting as *mut c_void,
+        ));
+        // The linking layer is created before `LLVMOrcCreateLLJIT` returns, so this outlives its use
+        let mut linking = ObjectLinking {
+            listeners,
+            symbol_listener: symbol_listener.0,
+        };
+        let mut jit = ptr::null_mut();
+
+        LLVMOrcLLJITBuilderSetObjectLinkingLayerCreator(
+            builder,
+            create_object_linking_layer,
+            &mut linking as *mut ObjectLinking as *mut c_void,
+        );
+        error_to_result(LLVMOrcCreateLLJIT(&mut jit, builder))?;
+        event_reporting
+            .global_prefix
+            .set(LLVMOrcLLJITGetGlobalPrefix(jit) as u8);
+
+        Ok(LLJIT {
+            jit: Rc::new(LLJITInner::new(jit, event_reporting, symbol_listener)),
+        })
+    }
+
+    /// Acquires the underlying raw pointer belonging to this `LLJIT` type.
+    pub fn as_mut_ptr(&self) -> LLVMOrcLLJITRef {
+        self.jit.jit
+    }
+
+    /// Gets the triple of the target code is compiled for.
+    pub fn get_triple(&self) -> TargetTriple {
+        unsafe {
+            let triple = CStr::from_ptr(LLVMOrcLLJITGetTripleString(self.jit.jit));
+
+            TargetTriple::new(LLVMString::create_from_c_str(triple))
+        }
+    }
+
+    /// Gets the `JITDylib` whose symbols are looked up by `get_function`.
+    pub fn get_main_jit_dylib(&self) -> JITDylib<'_> {
+        unsafe { JITDylib::new(LLVMOrcLLJITGetMainJITDylib(self.jit.jit)) }
+    }
+
+    /// Creates an empty `JITDylib` named `name`. Fails if there already is one by that name.
+    pub fn create_jit_dylib(&self, name: &str) -> Result<JITDylib<'_>, LLVMString> {
+        let c_string = to_c_str(name);
+        let mut dylib = ptr::null_mut();
+
+        unsafe {
+            let execution_session = LLVMOrcLLJITGetExecutionSession(self.jit.jit);
+
+            error_to_result(LLVMOrcExecutionSessionCreateJITDylib(
+                execution_session,
+                &mut dylib,
+                c_string.as_ptr(),
+            ))?;
+
+            Ok(JITDylib::new(dylib))
+        }
+    }
+
+    /// Gets the `JITDylib` named `name`, if there is one.
+    pub fn get_jit_dylib(&self, name: &str) -> Option<JITDylib<'_>> {
+        let c_string = to_c_str(name);
+
+        unsafe {
+            let execution_session = LLVMOrcLLJITGetExecutionSession(self.jit.jit);
+            let dylib = LLVMOrcExecutionSessionGetJITDylibByName(execution_session, c_string.as_ptr());
+
+            if dylib.is_null() {
+                return None;
+            }
+
+            Some(JITDylib::new(dylib))
+        }
+    }
+
+    /// Adds `module` to `dylib`. Its code is compiled when one of its symbols is first looked up,
+    /// and stays loaded until `dylib` is cleared.
+    pub fn add_module(&self, dylib: &JITDylib<'_>, module: ThreadSafeModule) -> Result<(), LLVMString> {
+        let thread_safe_module = module.thread_safe_module;
+
+        // The JIT takes ownership of the module even if adding it fails
+        forget(module);
+
+        unsafe {
+            error_to_result(LLVMOrcLLJITAddLLVMIRModule(
+                self.jit.jit,
+                dylib.dylib,
+                thread_safe_module,
+            ))
+        }
+    }
+
+    /// Adds `module` to the `JITDylib` of `tracker`, so that its code is unloaded when `tracker` is
+    /// removed.
+    pub fn add_module_with_tracker(
+        &self,
+        tracker: &ResourceTracker<'_>,
+        module: ThreadSafeModule,
+    ) -> Result<(), LLVMString> {
+        let thread_safe_module = module.thread_safe_module;
+
+        // The JIT takes ownership of the module even if adding it fails
+        forget(module);
+
+        unsafe {
+            error_to_result(LLVMOrcLLJITAddLLVMIRModuleWithRT(
+                self.jit.jit,
+                tracker.tracker,
+                thread_safe_module,
+            ))
+        }
+    }
+
+    /// Looks up the address of the symbol `name` in the main `JITDylib`, compiling it if need be.
+    pub fn get_function_address(&self, name: &str) -> Result<usize, LLVMString> {
+        let c_string = to_c_str(name);
+        let mut address = 0;
+
+        unsafe {
+            error_to_result(LLVMOrcLLJITLookup(self.jit.jit, &mut address, c_string.as_ptr()))?;
+        }
+
+        Ok(address as usize)
+    }
+
+    /// Looks up the function `name` in the main `JITDylib`, compiling it if need be.
+    ///
+    /// # Safety
+    ///
+    /// It is the caller's responsibility to ensure they call the function with the correct
+    /// signature and calling convention.
+    ///
+    /// The `JitFunction` keeps the JIT alive, but not the code of the function: removing the
+    /// `ResourceTracker` it was added with or clearing its `JITDylib` leaves the function
+    /// pointer dangling.
+    pub unsafe fn get_function<F>(&self, name: &str) -> Result<JitFunction<'static, F>, LLVMString>
+    where
+        F: UnsafeFunctionPointer,
+    {
+        let address = self.get_function_address(name)?;
+
+        Ok(JitFunction::from_lljit(self.clone(), address))
+    }
+
+    /// Defines the symbols of `symbols` in `dylib`, and makes `dylib` search the current process
+    /// for other symbols if `symbols` does. Fails if any of them is already defined in `dylib`.
+    ///
+    /// As there are no declarations to check the symbols against, modules using them should be
+    /// checked with `SymbolMap::check` before being added.
+    pub fn add_symbol_map(&self, dylib: &JITDylib<'_>, symbols: &SymbolMap) -> Result<(), LLVMString> {
+        let mut definitions: Vec<_> = symbols
+            .symbols()
+            .map(|(name, address, is_function)| {
+                let mut flags = LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8;
+
+                if is_function {
+                    flags |= LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8;
+                }
+
+                LLVMOrcCSymbolMapPair {
+                    Name: self.mangle_and_intern(name),
+                    Sym: LLVMJITEvaluatedSymbol {
+                        Address: address as u64,
+                        Flags: LLVMJITSymbolFlags {
+                            GenericFlags: flags,
+                            TargetFlags: 0,
+                        },
+                    },
+                }
+            })
+            .collect();
+
+        unsafe {
+            if !definitions.is_empty() {
+                // Takes over the references to the names
+                dylib.define(LLVMOrcAbsoluteSymbols(definitions.as_mut_ptr(), definitions.len()))?;
+            }
+
+            if symbols.get_search_process() {
+                let mut generator = ptr::null_mut();
+
+                load_visible_symbols();
+                error_to_result(LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+                    &mut generator,
+                    LLVMOrcLLJITGetGlobalPrefix(self.jit.jit),
+                    None,
+                    ptr::null_mut(),
+                ))?;
+                LLVMOrcJITDylibAddGenerator(dylib.dylib, generator);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds `module` to `dylib` such that each of its functions is only compiled when it's first
+    /// called, rather than along with the whole module when any of its symbols is first looked up.
+    ///
+    /// The body of every function defined in `module` is moved into a module of its own, where
+    /// it's renamed by appending `LAZY_BODY_SUFFIX`, and is replaced in `dylib` by a stub which
+    /// compiles it on first call. The global variables stay behind in a module which is compiled
+    /// when one of them is first needed. To be shared between these modules, the module's private
+    /// and internal symbols are made hidden and given names unique to this `LLJIT`.
+    ///
+    /// A body which takes the address of a basic block, or uses a global in a constant other
+    /// than a cast or `getelementptr`, can't be moved. Such functions stay behind with the global
+    /// variables and are compiled along with them.
+    ///
+    /// Functions of different modules added to the same `JITDylib` mustn't share a name, even if
+    /// their linkage is `linkonce` or `weak`. If adding any part of `module` fails, none of it is
+    /// left in `dylib`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::orc::{ThreadSafeContext, LLJIT};
+    /// use inkwell::targets::{InitializationConfig, Target};
+    ///
+    /// Target::initialize_native(&InitializationConfig::default()).unwrap();
+    ///
+    /// let jit = LLJIT::create().unwrap();
+    /// let context = ThreadSafeContext::create();
+    /// let module = context.context().create_module("prelude");
+    ///
+    /// // ... build the module ...
+    ///
+    /// jit.set_materialization_hook(|function| println!("compiling {:?}", function.get_name()));
+    /// jit.add_module_lazily(&jit.get_main_jit_dylib(), &context, module).unwrap();
+    /// ```
+    #[llvm_versions(13..)]
+    pub fn add_module_lazily<'ctx>(
+        &self,
+        dylib: &JITDylib<'_>,
+        context: &'ctx ThreadSafeContext,
+        module: Module<'ctx>,
+    ) -> Result<(), LLVMString> {
+        module.verify()?;
+        ThreadSafeModule::check(context, &module)?;
+
+        // Whatever can fail is done before the module is split
+        let lazy_compilation = if module.get_functions().any(has_lazy_body) {
+            let lazy_compilation = unsafe { self.get_lazy_compilation()? };
+
+            Some((lazy_compilation.call_through_manager, lazy_compilation.stubs_manager))
+        } else {
+            None
+        };
+        let module_id = self.jit.lazy_module_count.get();
+
+        self.jit.lazy_module_count.set(module_id + 1);
+
+        let mut anonymous_count = 0;
+        let globals = module
+            .get_functions()
+            .map(|function| function.as_global_value())
+            .chain(module.get_globals());
+
+        for global in globals {
+            if !matches!(global.get_linkage(), Linkage::Private | Linkage::Internal) {
+                continue;
+            }
+
+            let name = global.get_name().to_string_lossy().into_owned();
+
+            if name.is_empty() {
+                global.set_name(&format!("lazy{}.anonymous{}", module_id, anonymous_count));
+                anonymous_count += 1;
+            } else {
+                global.set_name(&format!("{}.lazy{}", name, module_id));
+            }
+
+            global.set_linkage(Linkage::External);
+            global.set_visibility(GlobalVisibility::Hidden);
+        }
+
+        // Bodies are moved out one at a time, so the module is only walked once
+        let functions: Vec<_> = module
+            .get_functions()
+            .filter(|function| has_lazy_body(*function))
+            .collect();
+        let mut aliases = Vec::with_capacity(functions.len());
+        let mut modules = Vec::with_capacity(functions.len() + 1);
+
+        for function in functions {
+            let name = function.get_name().to_string_lossy().into_owned();
+            let hidden = function.as_global_value().get_visibility() == GlobalVisibility::Hidden;
+            let body_name = format!("{}{}", name, LAZY_BODY_SUFFIX);
+
+            if let Some(body_module) = unsafe { move_body(&module, function, &body_name) } {
+                modules.push(body_module);
+                aliases.push((name, body_name, hidden));
+            }
+        }
+
+        modules.push(module);
+
+        // Everything is added through a tracker of its own, so that it can be removed again
+        let tracker = dylib.create_resource_tracker();
+
+        for module in modules {
+            let added = ThreadSafeModule::create(context, module)
+                .and_then(|module| self.add_module_with_tracker(&tracker, module));
+
+            if let Err(err) = added {
+                let _ = tracker.remove();
+
+                return Err(err);
+            }
+        }
+
+        let (call_through_manager, stubs_manager) = match lazy_compilation {
+            Some(lazy_compilation) if !aliases.is_empty() => lazy_compilation,
+            _ => return Ok(()),
+        };
+
+        // The names are only interned once nothing but defining the stubs can fail
+        let mut aliases: Vec<_> = aliases
+            .into_iter()
+            .map(|(name, body_name, hidden)| {
+                let mut flags = LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8;
+
+                if !hidden {
+                    flags |= LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8;
+                }
+
+                LLVMOrcCSymbolAliasMapPair {
+                    Name: self.mangle_and_intern(&name),
+                    Entry: LLVMOrcCSymbolAliasMapEntry {
+                        Name: self.mangle_and_intern(&body_name),
+                        Flags: LLVMJITSymbolFlags {
+                            GenericFlags: flags,
+                            TargetFlags: 0,
+                        },
+                    },
+                }
+            })
+            .collect();
+
+        // Takes over the references to the names, which are released along with it if defining fails
+        let defined = unsafe {
+            let reexports = LLVMOrcLazyReexports(
+                call_through_manager,
+                stubs_manager,
+                dylib.dylib,
+                aliases.as_mut_ptr(),
+                aliases.len(),
+            );
+
+            dylib.define(reexports)
+        };
+
+        if defined.is_err() {
+            let _ = tracker.remove();
+        }
+
+        defined
+    }
+
+    /// Sets a hook which is called with each function defined in a module just before the module
+    /// is compiled, replacing any previous one. Functions added with `add_module_lazily` are
+    /// seen under their name followed by `LAZY_BODY_SUFFIX`.
+    ///
+    /// Compilation happens on the thread whose lookup or call needs the code. If the hook panics,
+    /// that compilation fails.
+    #[llvm_versions(13..)]
+    pub fn set_materialization_hook<F>(&self, hook: F)
+    where
+        F: for<'a> FnMut(FunctionValue<'a>) + 'static,
+    {
+        let installed = self
+            .jit
+            .materialization_hook
+            .borrow_mut()
+            .replace(MaterializationHook(Box::new(hook)));
+
+        if installed.is_none() {
+            let hook: *const RefCell<Option<MaterializationHook>> = &*self.jit.materialization_hook;
+
+            unsafe {
+                LLVMOrcIRTransformLayerSetTransform(
+                    LLVMOrcLLJITGetIRTransformLayer(self.jit.jit),
+                    materialization_transform,
+                    hook as *mut c_void,
+                );
+            }
+        }
+    }
+
+    /// Sets a callback which is told about every object this `LLJIT` emits, and the symbols
+    /// it defines, replacing any previous one.
+    ///
+    /// Objects are reported just before they're linked, and their symbols as soon as they're
+    /// loaded, both on the thread which compiled them. If the callback panics while an object is
+    /// reported, the object fails to link. A panic while a symbol is reported is swallowed, as
+    /// the object has been loaded by then.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use inkwell::jit_event_listener::{JitEvent, PerfMap};
+    /// use inkwell::orc::LLJIT;
+    /// use inkwell::targets::{InitializationConfig, Target};
+    ///
+    /// Target::initialize_native(&InitializationConfig::default()).unwrap();
+    ///
+    /// let jit = LLJIT::create().unwrap();
+    /// let mut perf_map = PerfMap::create().unwrap();
+    ///
+    /// jit.set_event_callback(move |event| {
+    ///     if let JitEvent::ObjectEmitted { object } = event {
+    ///         println!("linking {} bytes", object.len());
+    ///     }
+    ///
+    ///     perf_map.record(event).unwrap();
+    /// });
+    /// ```
+    #[llvm_versions(13..)]
+    pub fn set_event_callback<F>(&self, callback: F)
+    where
+        F: for<'a> FnMut(&JitEvent<'a>) + 'static,
+    {
+        let installed = self
+            .jit
+            .event_reporting
+            .callback
+            .borrow_mut()
+            .replace(EventCallback(Box::new(callback)));
+
+        if installed.is_none() {
+            let reporting: *const EventReporting = &*self.jit.event_reporting;
+
+            unsafe {
+                LLVMOrcObjectTransformLayerSetTransform(
+                    LLVMOrcLLJITGetObjTransformLayer(self.jit.jit),
+                    report_emitted_object,
+                    reporting as *mut c_void,
+                );
+            }
+        }
+    }
+
+    fn mangle_and_intern(&self, name: &str) -> LLVMOrcSymbolStringPoolEntryRef {
+        let c_string = to_c_str(name);
+
+        unsafe { LLVMOrcLLJITMangleAndIntern(self.jit.jit, c_string.as_ptr()) }
+    }
+
+    #[llvm_versions(13..)]
+    unsafe fn get_lazy_compilation(&self) -> Result<Ref<'_, LazyCompilation>, LLVMString> {
+        if self.jit.lazy_compilation.borrow().is_none() {
+            let triple = LLVMOrcLLJITGetTripleString(self.jit.jit);
+            let execution_session = LLVMOrcLLJITGetExecutionSession(self.jit.jit);
+            let mut call_through_manager = ptr::null_mut();
+
+            error_to_result(LLVMOrcCreateLocalLazyCallThroughManager(
+                triple,
+                execution_session,
+                lazy_compilation_failed as usize as _,
+                &mut call_through_manager,
+            ))?;
+
+            *self.jit.lazy_compilation.borrow_mut() = Some(LazyCompilation {
+                call_through_manager,
+                stubs_manager: LLVMOrcCreateLocalIndirectStubsManager(triple),
+            });
+        }
+
+        Ok(Ref::map(self.jit.lazy_compilation.borrow(), |lazy_compilation| {
+            lazy_compilation.as_ref().unwrap()
+        }))
+    }
+}
+
+/// A namespace of symbols in an `LLJIT`, like a dynamic library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JITDylib<'jit> {
+    dylib: LLVMOrcJITDylibRef,
+    _marker: PhantomData<&'jit LLJIT>,
+}
+
+impl<'jit> JITDylib<'jit> {
+    unsafe fn new(dylib: LLVMOrcJITDylibRef) -> Self {
+        assert!(!dylib.is_null());
+
+        JITDylib {
+            dylib,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Acquires the underlying raw pointer belonging to this `JITDylib` type.
+    pub fn as_mut_ptr(&self) -> LLVMOrcJITDylibRef {
+        self.dylib
+    }
+
+    /// Creates a `ResourceTracker` through which modules can be added to this `JITDylib` and later
+    /// unloaded together.
+    pub fn create_resource_tracker(&self) -> ResourceTracker<'jit> {
+        ResourceTracker {
+            tracker: unsafe { LLVMOrcJITDylibCreateResourceTracker(self.dylib) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Defines the symbols of `unit`, taking ownership of it if that succeeds.
+    unsafe fn define(&self, unit: LLVMOrcMaterializationUnitRef) -> Result<(), LLVMString> {
+        let result = error_to_result(LLVMOrcJITDylibDefine(self.dylib, unit));
+
+        if result.is_err() {
+            LLVMOrcDisposeMaterializationUnit(unit);
+        }
+
+        result
+    }
+
+    /// Unloads everything in this `JITDylib`.
+    pub fn clear(&self) -> Result<(), LLVMString> {
+        unsafe { error_to_result(LLVMOrcJITDylibClear(self.dylib)) }
+    }
+}
+
+/// Tracks the code added to a `JITDylib` through it, so that it can be unloaded with `remove`.
+///
+/// Dropping a tracker without removing it hands its code over to the `JITDylib`, where it stays
+/// loaded until the `JITDylib` is cleared.
+#[derive(Debug)]
+pub struct ResourceTracker<'jit> {
+    tracker: LLVMOrcResourceTrackerRef,
+    _marker: PhantomData<&'jit LLJIT>,
+}
+
+impl ResourceTracker<'_> {
+    /// Acquires the underlying raw pointer belonging to this `ResourceTracker` type.
+    pub fn as_mut_ptr(&self) -> LLVMOrcResourceTrackerRef {
+        self.tracker
+    }
+
+    /// Unloads the code added through this tracker, and removes its symbols from the `JITDylib`.
+    pub fn remove(self) -> Result<(), LLVMString> {
+        unsafe { error_to_result(LLVMOrcResourceTrackerRemove(self.tracker)) }
+    }
+}
+
+impl Drop for ResourceTracker<'_> {
+    fn drop(&mut self) {
+        unsafe { LLVMOrcReleaseResourceTracker(self.tracker) }
+    }
+}
+
+/// What `LLJIT::add_module_lazily` appends to the names of the functions it splits off.
+#[llvm_versions(13..)]
+pub const LAZY_BODY_SUFFIX: &str = ".lazy_body";
+
+/// The stubs through which `LLJIT::add_module_lazily` compiles functions on first call.
+#[llvm_versions(13..)]
+#[derive(Debug)]
+struct LazyCompilation {
+    call_through_manager: LLVMOrcLazyCallThroughManagerRef,
+    stubs_manager: LLVMOrcIndirectStubsManagerRef,
+}
+
+#[llvm_versions(13..)]
+impl Drop for LazyCompilation {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMOrcDisposeIndirectStubsManager(self.stubs_manager);
+            LLVMOrcDisposeLazyCallThroughManager(self.call_through_manager);
+        }
+    }
+}
+
+/// Where a stub jumps if compiling its function fails, as there is nothing to return to. Why it
+/// failed has already been passed to the error reporter of the `ExecutionSession`.
+#[llvm_versions(13..)]
+extern "C" fn lazy_compilation_failed() {
+    std::process::abort();
+}
+
+#[llvm_versions(13..)]
+fn has_lazy_body(function: FunctionValue<'_>) -> bool {
+    !function.as_global_value().is_declaration() && function.get_linkage() != Linkage::AvailableExternally
+}
+
+/// Replaces `function` with a declaration of the same name.
+#[llvm_versions(13..)]
+fn strip_body<'ctx>(module: &Module<'ctx>, function: FunctionValue<'ctx>) {
+    let global = function.as_global_value();
+    let name = global.get_name().to_string_lossy().into_owned();
+
+    global.set_name("");
+
+    let declaration = module.add_function(&name, function.get_type(), None);
+
+    declaration.set_call_conventions(function.get_call_conventions());
+    declaration.as_global_value().set_visibility(global.get_visibility());
+    function.replace_all_uses_with(declaration);
+
+    unsafe { function.delete() }
+}
+
+/// Moves the body of `function` into a new module, as a function named `body_name`, and leaves a
+/// declaration behind. The globals the body uses are declared in the new module, except for
+/// `function` itself, whose recursive calls go straight to the moved body.
+///
+/// Returns `None`, without changing anything, if the body can't be moved.
+#[llvm_versions(13..)]
+unsafe fn move_body<'ctx>(
+    module: &Module<'ctx>,
+    function: FunctionValue<'ctx>,
+    body_name: &str,
+) -> Option<Module<'ctx>> {
+    let context = module.get_context();
+    let body_module = context.create_module(body_name);
+
+    body_module.set_data_layout(&module.get_data_layout());
+    body_module.set_triple(&module.get_triple());
+    copy_debug_info_metadata(module, &body_module);
+
+    let body = body_module.add_function(body_name, function.get_type(), Some(Linkage::External));
+    let mut declarations = Declarations {
+        module: body_module.as_mut_ptr(),
+        declared: HashMap::new(),
+    };
+
+    declarations
+        .declared
+        .insert(function.as_value_ref(), body.as_value_ref());
+
+    // Everything that needs redirecting is worked out first, so that nothing has changed if a
+    // use can't be
+    let blocks = function.get_basic_blocks();
+    let mut replacements = Vec::new();
+
+    for block in &blocks {
+        if is_address_taken(*block) {
+            return None;
+        }
+
+        for instruction in block.get_instructions() {
+            let instruction = instruction.as_value_ref();
+
+            for index in 0..LLVMGetNumOperands(instruction) as u32 {
+                let operand = LLVMGetOperand(instruction, index);
+                let remapped = declarations.remap(operand)?;
+
+                if remapped != operand {
+                    replacements.push((instruction, index, remapped));
+                }
+            }
+        }
+    }
+
+    let personality = match function.get_personality_function() {
+        Some(personality) => Some(declarations.remap(personality.as_value_ref())?),
+        None => None,
+    };
+
+    copy_function_properties(function, body);
+
+    if let Some(personality) = personality {
+        LLVMSetPersonalityFn(body.as_value_ref(), personality);
+    }
+
+    let placeholder = context.append_basic_block(body, "");
+    let mut previous = placeholder;
+
+    for block in blocks {
+        block.move_after(previous).expect("Both blocks have a parent");
+        previous = block;
+    }
+
+    placeholder.delete().expect("Placeholder has a parent");
+
+    for (parameter, moved) in function.get_param_iter().zip(body.get_param_iter()) {
+        let mut len = 0;
+        let name = LLVMGetValueName2(parameter.as_value_ref(), &mut len);
+
+        LLVMSetValueName2(moved.as_value_ref(), name, len);
+        LLVMReplaceAllUsesWith(parameter.as_value_ref(), moved.as_value_ref());
+    }
+
+    for (instruction, index, remapped) in replacements {
+        LLVMSetOperand(instruction, index, remapped);
+    }
+
+    strip_body(module, function);
+
+    Some(body_module)
+}
+
+/// Copies the attributes, calling convention and other properties of `function` over to `body`.
+#[llvm_versions(13..)]
+unsafe fn copy_function_properties<'ctx>(function: FunctionValue<'ctx>, body: FunctionValue<'ctx>) {
+    let global = function.as_global_value();
+    let body_global = body.as_global_value();
+    let locations = [AttributeLoc::Function, AttributeLoc::Return]
+        .into_iter()
+        .chain((0..function.count_params()).map(AttributeLoc::Param));
+
+    for location in locations {
+        for attribute in function.attributes(location) {
+            body.add_attribute(location, attribute);
+        }
+    }
+
+    body.set_call_conventions(function.get_call_conventions());
+    body_global.set_visibility(global.get_visibility());
+    body_global.set_unnamed_address(global.get_unnamed_address());
+    body_global.set_alignment(global.get_alignment());
+
+    if let Some(section) = global.get_section() {
+        body_global.set_section(Some(&*section.to_string_lossy()));
+    }
+
+    let gc = LLVMGetGC(function.as_value_ref());
+
+    if !gc.is_null() {
+        LLVMSetGC(body.as_value_ref(), gc);
+    }
+
+    let mut len = 0;
+    let entries = LLVMGlobalCopyAllMetadata(function.as_value_ref(), &mut len);
+
+    for index in 0..len as u32 {
+        LLVMGlobalSetMetadata(
+            body.as_value_ref(),
+            LLVMValueMetadataEntriesGetKind(entries, index),
+            LLVMValueMetadataEntriesGetMetadata(entries, index),
+        );
+    }
+
+    LLVMDisposeValueMetadataEntries(entries);
+}
+
+/// Copies the module flags and compile units of `from`, which the debug info of its functions
+/// refers to, to `to`.
+#[llvm_versions(13..)]
+unsafe fn copy_debug_info_metadata<'ctx>(from: &Module<'ctx>, to: &Module<'ctx>) {
+    let mut len = 0;
+    let flags = LLVMCopyModuleFlagsMetadata(from.as_mut_ptr(), &mut len);
+
+    for index in 0..len as u32 {
+        let mut key_len = 0;
+        let key = LLVMModuleFlagEntriesGetKey(flags, index, &mut key_len);
+
+        LLVMAddModuleFlag(
+            to.as_mut_ptr(),
+            LLVMModuleFlagEntriesGetFlagBehavior(flags, index),
+            key,
+            key_len,
+            LLVMModuleFlagEntriesGetMetadata(flags, index),
+        );
+    }
+
+    LLVMDisposeModuleFlagsMetadata(flags);
+
+    for compile_unit in from.get_global_metadata("llvm.dbg.cu") {
+        to.add_global_metadata("llvm.dbg.cu", &compile_unit)
+            .expect("Compile units are metadata nodes");
+    }
+}
+
+/// Whether a constant, such as a `blockaddress`, refers to `block`.
+#[llvm_versions(13..)]
+unsafe fn is_address_taken(block: BasicBlock<'_>) -> bool {
+    let mut block_use = LLVMGetFirstUse(LLVMBasicBlockAsValue(block.as_mut_ptr()));
+
+    while !block_use.is_null() {
+        if !LLVMIsAConstant(LLVMGetUser(block_use)).is_null() {
+            return true;
+        }
+
+        block_use = LLVMGetNextUse(block_use);
+    }
+
+    false
+}
+
+/// The declarations of the globals used by a body which is moved into `module`.
+#[llvm_versions(13..)]
+struct Declarations {
+    module: LLVMModuleRef,
+    declared: HashMap<LLVMValueRef, LLVMValueRef>,
+}
+
+#[llvm_versions(13..)]
+impl Declarations {
+    /// Returns `value` with the globals it refers to replaced by their declarations, or `None`
+    /// if they can't be.
+    unsafe fn remap(&mut self, value: LLVMValueRef) -> Option<LLVMValueRef> {
+        match LLVMGetValueKind(value) {
+            LLVMValueKind::LLVMFunctionValueKind
+            | LLVMValueKind::LLVMGlobalVariableValueKind
+            | LLVMValueKind::LLVMGlobalAliasValueKind
+            | LLVMValueKind::LLVMGlobalIFuncValueKind => Some(self.declare(value)),
+            LLVMValueKind::LLVMConstantExprValueKind => self.remap_expression(value),
+            LLVMValueKind::LLVMBlockAddressValueKind => None,
+            LLVMValueKind::LLVMConstantStructValueKind
+            | LLVMValueKind::LLVMConstantArrayValueKind
+            | LLVMValueKind::LLVMConstantVectorValueKind => {
+                for index in 0..LLVMGetNumOperands(value) as u32 {
+                    let operand = LLVMGetOperand(value, index);
+
+                    if self.remap(operand)? != operand {
+                        return None;
+                    }
+                }
+
+                Some(value)
+            },
+            _ => Some(value),
+        }
+    }
+
+    unsafe fn remap_expression(&mut self, expression: LLVMValueRef) -> Option<LLVMValueRef> {
+        let operands: Vec<_> = (0..LLVMGetNumOperands(expression) as u32)
+            .map(|index| LLVMGetOperand(expression, index))
+            .collect();
+        let mut remapped = operands
+            .iter()
+            .map(|operand| self.remap(*operand))
+            .collect::<Option<Vec<_>>>()?;
+
+        if remapped == operands {
+            return Some(expression);
+        }
+
+        let ty = LLVMTypeOf(expression);
+
+        match LLVMGetConstOpcode(expression) {
+            LLVMOpcode::LLVMBitCast => Some(LLVMConstBitCast(remapped[0], ty)),
+            LLVMOpcode::LLVMAddrSpaceCast => Some(LLVMConstAddrSpaceCast(remapped[0], ty)),
+            LLVMOpcode::LLVMPtrToInt => Some(LLVMConstPtrToInt(remapped[0], ty)),
+            LLVMOpcode::LLVMIntToPtr => Some(LLVMConstIntToPtr(remapped[0], ty)),
+            LLVMOpcode::LLVMGetElementPtr => {
+                let (pointer, indices) = remapped.split_first_mut().expect("GEPs have a pointer operand");
+
+                Some(const_gep(expression, *pointer, indices))
+            },
+            _ => None,
+        }
+    }
+
+    unsafe fn declare(&mut self, global: LLVMValueRef) -> LLVMValueRef {
+        if let Some(declaration) = self.declared.get(&global) {
+            return *declaration;
+        }
+
+        let mut len = 0;
+        let name = LLVMGetValueName2(global, &mut len);
+        let value_type = LLVMGlobalGetValueType(global);
+        let kind = LLVMGetValueKind(global);
+        let declaration = if LLVMGetTypeKind(value_type) == LLVMTypeKind::LLVMFunctionTypeKind {
+            let declaration = LLVMAddFunction(self.module, name, value_type);
+
+            if kind == LLVMValueKind::LLVMFunctionValueKind {
+                LLVMSetFunctionCallConv(declaration, LLVMGetFunctionCallConv(global));
+            }
+
+            declaration
+        } else {
+            let address_space = LLVMGetPointerAddressSpace(LLVMTypeOf(global));
+            let declaration = LLVMAddGlobalInAddressSpace(self.module, value_type, name, address_space);
+
+            if kind == LLVMValueKind::LLVMGlobalVariableValueKind {
+                LLVMSetThreadLocalMode(declaration, LLVMGetThreadLocalMode(global));
+                LLVMSetGlobalConstant(declaration, LLVMIsGlobalConstant(global));
+            }
+
+            declaration
+        };
+
+        LLVMSetVisibility(declaration, LLVMGetVisibility(global));
+        self.declared.insert(global, declaration);
+
+        declaration
+    }
+}
+
+/// Rebuilds the constant `getelementptr` expression `gep` with a new pointer operand.
+#[llvm_versions(13..=14)]
+unsafe fn const_gep(gep: LLVMValueRef, pointer: LLVMValueRef, indices: &mut [LLVMValueRef]) -> LLVMValueRef {
+    if LLVMIsInBounds(gep) != 0 {
+        LLVMConstInBoundsGEP(pointer, indices.as_mut_ptr(), indices.len() as u32)
+    } else {
+        LLVMConstGEP(pointer, indices.as_mut_ptr(), indices.len() as u32)
+    }
+}
+
+/// Rebuilds the constant `getelementptr` expression `gep` with a new pointer operand.
+#[llvm_versions(15..)]
+unsafe fn const_gep(gep: LLVMValueRef, pointer: LLVMValueRef, indices: &mut [LLVMValueRef]) -> LLVMValueRef {
+    let ty = LLVMGetGEPSourceElementType(gep);
+
+    if LLVMIsInBounds(gep) != 0 {
+        LLVMConstInBoundsGEP2(ty, pointer, indices.as_mut_ptr(), indices.len() as u32)
+    } else {
+        LLVMConstGEP2(ty, pointer, indices.as_mut_ptr(), indices.len() as u32)
+    }
+}
+
+#[llvm_versions(13..)]
+struct MaterializationHook(Box<dyn for<'a> FnMut(FunctionValue<'a>)>);
+
+#[llvm_versions(13..)]
+impl Debug for MaterializationHook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MaterializationHook").finish()
+    }
+}
+
+#[llvm_versions(13..)]
+extern "C" fn materialization_transform(
+    hook: *mut c_void,
+    module: *mut LLVMOrcThreadSafeModuleRef,
+    _responsibility: LLVMOrcMaterializationResponsibilityRef,
+) -> LLVMErrorRef {
+    unsafe { LLVMOrcThreadSafeModuleWithModuleDo(*module, call_materialization_hook, hook) }
+}
+
+#[llvm_versions(13..)]
+extern "C" fn call_materialization_hook(hook: *mut c_void, module: LLVMModuleRef) -> LLVMErrorRef {
+    let hook = unsafe { &*(hook as *const RefCell<Option<MaterializationHook>>) };
+    // The hook may itself cause code to be compiled, which it then doesn't see
+    let mut hook = match hook.try_borrow_mut() {
+        Ok(hook) => hook,
+        Err(_) => return ptr::null_mut(),
+    };
+    let hook = match hook.as_mut() {
+        Some(hook) => hook,
+        None => return ptr::null_mut(),
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        let mut function = LLVMGetFirstFunction(module);
+
+        while !function.is_null() {
+            if LLVMIsDeclaration(function) == 0 {
+                (hook.0)(FunctionValue::new(function).unwrap());
+            }
+
+            function = LLVMGetNextFunction(function);
+        }
+    }));
+
+    match result {
+        Ok(()) => ptr::null_mut(),
+        Err(_) => unsafe { LLVMCreateStringError(b"materialization hook panicked\0".as_ptr() as *const _) },
+    }
+}
+
+#[llvm_versions(13..)]
+extern "C" fn create_object_linking_layer(
+    linking: *mut c_void,
+    execution_session: LLVMOrcExecutionSessionRef,
+    _triple: *const c_char,
+) -> LLVMOrcObjectLayerRef {
+    unsafe {
+        let linking = &*(linking as *const ObjectLinking);
+        let layer = LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager(execution_session);
+
+        for listener in linking.listeners.iter() {
+            LLVMOrcRTDyldObjectLinkingLayerRegisterJITEventListener(layer, listener.as_mut_ptr());
+        }
+
+        LLVMOrcRTDyldObjectLinkingLayerRegisterJITEventListener(layer, linking.symbol_listener);
+
+        layer
+    }
+}
+
+/// The listeners `create_object_linking_layer` registers with the linking layer it creates.
+#[llvm_versions(13..)]
+struct ObjectLinking<'a> {
+    listeners: &'a [JITEventListener],
+    symbol_listener: LLVMJITEventListenerRef,
+}
+
+/// A listener which calls `report_loaded_symbol` for every symbol of the objects it's told about.
+#[llvm_versions(13..)]
+#[derive(Debug)]
+struct SymbolListener(LLVMJITEventListenerRef);
+
+#[llvm_versions(13..)]
+impl Drop for SymbolListener {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeJITEventListener(self.0) }
+    }
+}
+
+/// What `LLJIT::set_event_callback` reports emitted objects and their symbols to.
+#[llvm_versions(13..)]
+#[derive(Debug, Default)]
+struct EventReporting {
+    callback: RefCell<Option<EventCallback>>,
+    /// The prefix the JIT mangles global symbols with, or 0 if it has none.
+    global_prefix: Cell<u8>,
+}
+
+#[llvm_versions(13..)]
+struct EventCallback(Box<dyn for<'a> FnMut(&JitEvent<'a>)>);
+
+#[llvm_versions(13..)]
+impl Debug for EventCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EventCallback").finish()
+    }
+}
+
+#[llvm_versions(13..)]
+extern "C" fn report_emitted_object(reporting: *mut c_void, object: *mut LLVMMemoryBufferRef) -> LLVMErrorRef {
+    let reporting = unsafe { &*(reporting as *const EventReporting) };
+    // The object is linked as is, so it stays the JIT's
+    let object = ManuallyDrop::new(unsafe { MemoryBuffer::new(*object) });
+    let bytes = object.as_slice();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Ok(mut callback) = reporting.callback.try_borrow_mut() {
+            if let Some(callback) = callback.as_mut() {
+                (callback.0)(&JitEvent::ObjectEmitted { object: bytes });
+            }
+        }
+    }));
+
+    if result.is_err() {
+        return unsafe { LLVMCreateStringError(b"event callback panicked\0".as_ptr() as *const _) };
+    }
+
+    ptr::null_mut()
+}
+
+#[llvm_versions(13..)]
+extern "C" fn report_loaded_symbol(reporting: *mut c_void, name: *const c_char, address: u64, size: u64) {
+    let reporting = unsafe { &*(reporting as *const EventReporting) };
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let name = match (reporting.global_prefix.get(), name.as_bytes().first()) {
+        (prefix, Some(&first)) if prefix != 0 && first == prefix => &name[1..],
+        _ => &name[..],
+    };
+
+    // Symbols of sections and files have no size
+    if size == 0 || name.is_empty() {
+        return;
+    }
+
+    // The object is already loaded, so there's nothing left for a panic to fail
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Ok(mut callback) = reporting.callback.try_borrow_mut() {
+            if let Some(callback) = callback.as_mut() {
+                (callback.0)(&JitEvent::SymbolLoaded {
+                    name,
+                    address: address as usize,
+                    size,
+                });
+            }
+        }
+    }));
+}
@@ -0,0 +1,411 @@
+//! Definitions of host functions and statics by name, for code run by an `ExecutionEngine` or an
+//! ORC `JITDylib`.
+
+use crate::execution_engine::UnsafeFunctionPointer;
+use crate::module::Module;
+use crate::support::{load_visible_symbols, search_for_address_of_symbol};
+use crate::types::{BasicTypeEnum, FunctionType};
+use crate::values::GlobalValue;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::mem::{size_of, transmute_copy};
+
+/// A Rust type whose values the C calling convention passes like values of some LLVM types.
+pub trait HostType: private::SealedHostType {
+    /// Returns whether values of this type are passed like values of `ty`.
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool;
+
+    /// Returns whether values of this type are returned like values of `ty`, or nothing for `void`.
+    fn matches_return_type(ty: Option<BasicTypeEnum<'_>>) -> bool {
+        ty.map_or(false, Self::matches_type)
+    }
+}
+
+/// A host function which can be added to a `SymbolMap`, whose signature is checked against the
+/// `FunctionType` it's declared with. It's implemented for the `UnsafeFunctionPointer`s whose
+/// parameters and return type are `HostType`s.
+pub trait HostFunction: UnsafeFunctionPointer + private::SealedHostFunction {
+    /// Returns whether the function can be called as a function of type `fn_type`.
+    fn matches_fn_type(fn_type: FunctionType<'_>) -> bool;
+}
+
+mod private {
+    /// A sealed trait which ensures nobody outside this crate can implement `HostType`.
+    pub trait SealedHostType {}
+
+    /// A sealed trait which ensures nobody outside this crate can implement `HostFunction`.
+    pub trait SealedHostFunction {}
+}
+
+macro_rules! impl_host_int {
+    ($($ty:ty),*) => {
+        $(
+            impl private::SealedHostType for $ty {}
+
+            impl HostType for $ty {
+                fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+                    matches!(
+                        ty,
+                        BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() as usize == size_of::<$ty>() * 8
+                    )
+                }
+            }
+        )*
+    };
+}
+
+impl_host_int!(i8, u8, i16, u16, i32, u32, i64, u64, i128, u128, isize, usize);
+
+impl private::SealedHostType for bool {}
+
+impl HostType for bool {
+    /// Booleans are declared as `i1` or, by some frontends, `i8`.
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+        matches!(ty, BasicTypeEnum::IntType(int_type) if matches!(int_type.get_bit_width(), 1 | 8))
+    }
+}
+
+impl private::SealedHostType for f32 {}
+
+impl HostType for f32 {
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+        matches!(ty, BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type())
+    }
+}
+
+impl private::SealedHostType for f64 {}
+
+impl HostType for f64 {
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+        matches!(ty, BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type())
+    }
+}
+
+impl<T> private::SealedHostType for *const T {}
+
+impl<T> HostType for *const T {
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+        matches!(ty, BasicTypeEnum::PointerType(_))
+    }
+}
+
+impl<T> private::SealedHostType for *mut T {}
+
+impl<T> HostType for *mut T {
+    fn matches_type(ty: BasicTypeEnum<'_>) -> bool {
+        matches!(ty, BasicTypeEnum::PointerType(_))
+    }
+}
+
+impl private::SealedHostType for () {}
+
+impl HostType for () {
+    fn matches_type(_ty: BasicTypeEnum<'_>) -> bool {
+        false
+    }
+
+    fn matches_return_type(ty: Option<BasicTypeEnum<'_>>) -> bool {
+        ty.is_none()
+    }
+}
+
+macro_rules! impl_host_fn {
+    (@recurse $first:ident $( , $rest:ident )*) => {
+        impl_host_fn!($( $rest ),*);
+    };
+
+    (@recurse) => {};
+
+    ($( $param:ident ),*) => {
+        impl<Output, $( $param ),*> private::SealedHostFunction for unsafe extern "C" fn($( $param ),*) -> Output {}
+
+        impl<Output: HostType, $( $param: HostType ),*> HostFunction for unsafe extern "C" fn($( $param ),*) -> Output {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn matches_fn_type(fn_type: FunctionType<'_>) -> bool {
+                let mut param_types = fn_type.get_param_types().into_iter();
+
+                !fn_type.is_var_arg()
+                    $( && param_types.next().map_or(false, $param::matches_type) )*
+                    && param_types.next().is_none()
+                    && Output::matches_return_type(fn_type.get_return_type())
+            }
+        }
+
+        impl_host_fn!(@recurse $( $param ),*);
+    };
+}
+
+impl_host_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+
+/// An error attaching a `SymbolMap` to code which declares its symbols differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolMapError {
+    /// A function is declared with a type its host function can't be called as.
+    SignatureMismatch { name: String, fn_type: String },
+    /// A function is declared under the name of a static.
+    NotAFunction(String),
+    /// A global variable is declared under the name of a function.
+    NotAStatic(String),
+    /// A static is declared as a global variable which isn't constant, so code could write to it.
+    MutableStatic(String),
+}
+
+impl Error for SymbolMapError {}
+
+impl Display for SymbolMapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolMapError::SignatureMismatch { name, fn_type } => {
+                write!(f, "host function `{}` can't be called as `{}`", name, fn_type)
+            },
+            SymbolMapError::NotAFunction(name) => write!(f, "`{}` is a static but is declared as a function", name),
+            SymbolMapError::NotAStatic(name) => write!(f, "`{}` is a function but is declared as a global", name),
+            SymbolMapError::MutableStatic(name) => {
+                write!(f, "`{}` is a static but is declared as a mutable global", name)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SymbolKind {
+    Function(for<'a> fn(FunctionType<'a>) -> bool),
+    Static,
+    /// Added by address, without anything to check declarations against.
+    Unchecked,
+}
+
+impl Debug for SymbolKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolKind::Function(_) => f.write_str("Function"),
+            SymbolKind::Static => f.write_str("Static"),
+            SymbolKind::Unchecked => f.write_str("Unchecked"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HostSymbol {
+    address: usize,
+    kind: SymbolKind,
+}
+
+/// Host functions and statics by name, which define the symbols code declares but doesn't define.
+///
+/// A `SymbolMap` is attached with `ExecutionEngine::add_symbol_map` to MCJIT,
+/// `ForeignFunctionRegistry::add_symbol_map` to the interpreter, or `LLJIT::add_symbol_map` to a
+/// `JITDylib`.
+///
+/// # Example
+///
+/// ```no_run
+/// use inkwell::context::Context;
+/// use inkwell::symbol_map::SymbolMap;
+/// use inkwell::OptimizationLevel;
+///
+/// extern "C" fn sumf(a: f64, b: f64) -> f64 {
+///     a + b
+/// }
+///
+/// static LIMIT: u64 = 42;
+///
+/// let context = Context::create();
+/// let module = context.create_module("test");
+/// let f64_type = context.f64_type();
+/// module.add_function("sumf", f64_type.fn_type(&[f64_type.into(), f64_type.into()], false), None);
+/// let mut symbols = SymbolMap::new();
+///
+/// symbols.add_function::<unsafe extern "C" fn(f64, f64) -> f64>("sumf", sumf);
+/// symbols.add_static("LIMIT", &LIMIT);
+///
+/// let execution_engine = module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
+///
+/// execution_engine.add_symbol_map(&module, &symbols).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    symbols: HashMap<String, HostSymbol>,
+    search_process: bool,
+}
+
+impl SymbolMap {
+    /// Creates an empty `SymbolMap`, which doesn't search the current process.
+    pub fn new() -> Self {
+        SymbolMap::default()
+    }
+
+    /// Defines `name` as `function`, replacing any previous definition.
+    pub fn add_function<F: HostFunction>(&mut self, name: &str, function: F) {
+        assert_eq!(
+            size_of::<F>(),
+            size_of::<usize>(),
+            "The type `F` must have the same size as a function pointer"
+        );
+
+        let symbol = HostSymbol {
+            address: unsafe { transmute_copy(&function) },
+            kind: SymbolKind::Function(F::matches_fn_type),
+        };
+
+        self.symbols.insert(name.to_owned(), symbol);
+    }
+
+    /// Defines `name` as `value`, replacing any previous definition. Code may only read from it, so
+    /// it has to be declared as a constant global variable.
+    pub fn add_static<T: Sync>(&mut self, name: &str, value: &'static T) {
+        let symbol = HostSymbol {
+            address: value as *const T as usize,
+            kind: SymbolKind::Static,
+        };
+
+        self.symbols.insert(name.to_owned(), symbol);
+    }
+
+    /// Defines `name` as whatever lies at `address`, replacing any previous definition.
+    ///
+    /// # Safety
+    ///
+    /// Nothing is checked about how `name` is declared, so it's up to the caller to ensure that
+    /// code uses it according to its actual type, for as long as the code can run.
+    pub unsafe fn add_address(&mut self, name: &str, address: usize) {
+        let symbol = HostSymbol {
+            address,
+            kind: SymbolKind::Unchecked,
+        };
+
+        self.symbols.insert(name.to_owned(), symbol);
+    }
+
+    /// Sets whether symbols which aren't in the map are looked up in the current process, the
+    /// symbols of which are made visible with `support::load_visible_symbols` when the map is
+    /// attached. Their signatures aren't checked.
+    pub fn set_search_process(&mut self, search_process: bool) {
+        self.search_process = search_process;
+    }
+
+    /// Gets whether symbols which aren't in the map are looked up in the current process.
+    pub fn get_search_process(&self) -> bool {
+        self.search_process
+    }
+
+    /// Gets the address `name` is defined as, searching the current process if enabled.
+    pub fn get_address(&self, name: &str) -> Option<usize> {
+        match self.symbols.get(name) {
+            Some(symbol) => Some(symbol.address),
+            None if self.search_process => search_for_address_of_symbol(name),
+            None => None,
+        }
+    }
+
+    /// Checks that every symbol of the map which `module` declares is declared as what it is: a
+    /// function of a type its host function can be called as, or a constant global variable for a
+    /// static.
+    pub fn check(&self, module: &Module<'_>) -> Result<(), SymbolMapError> {
+        self.resolve(module).map(|_| ())
+    }
+
+    /// Iterates over the names of the symbols in the map, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.keys().map(String::as_str)
+    }
+
+    /// Iterates over the symbols in the map along with whether each is a function.
+    pub(crate) fn symbols(&self) -> impl Iterator<Item = (&str, usize, bool)> {
+        self.symbols.iter().map(|(name, symbol)| {
+            let is_function = !matches!(symbol.kind, SymbolKind::Static);
+
+            (name.as_str(), symbol.address, is_function)
+        })
+    }
+
+    /// Checks the declarations of `module` and pairs each one the map defines with its address.
+    pub(crate) fn resolve<'ctx>(
+        &self,
+        module: &Module<'ctx>,
+    ) -> Result<Vec<(GlobalValue<'ctx>, usize)>, SymbolMapError> {
+        if self.search_process {
+            load_visible_symbols();
+        }
+
+        let mut resolved = Vec::new();
+
+        for function in module.get_functions() {
+            if !function.as_global_value().is_declaration() || function.get_intrinsic_id() != 0 {
+                continue;
+            }
+
+            let name = function.get_name().to_string_lossy().into_owned();
+            let address = match self.symbols.get(&name) {
+                Some(HostSymbol {
+                    kind: SymbolKind::Static,
+                    ..
+                }) => return Err(SymbolMapError::NotAFunction(name)),
+                Some(HostSymbol {
+                    address,
+                    kind: SymbolKind::Function(matches_fn_type),
+                }) => {
+                    if !matches_fn_type(function.get_type()) {
+                        let fn_type = function.get_type().print_to_string().to_string();
+
+                        return Err(SymbolMapError::SignatureMismatch { name, fn_type });
+                    }
+
+                    *address
+                },
+                Some(HostSymbol { address, .. }) => *address,
+                None => match self.get_address(&name) {
+                    Some(address) => address,
+                    None => continue,
+                },
+            };
+
+            resolved.push((function.as_global_value(), address));
+        }
+
+        for global in module.get_globals() {
+            if !global.is_declaration() {
+                continue;
+            }
+
+            let name = global.get_name().to_string_lossy().into_owned();
+            let address = match self.symbols.get(&name) {
+                Some(HostSymbol {
+                    kind: SymbolKind::Function(_),
+                    ..
+                }) => return Err(SymbolMapError::NotAStatic(name)),
+                Some(HostSymbol {
+                    kind: SymbolKind::Static,
+                    ..
+                }) if !global.is_constant() => return Err(SymbolMapError::MutableStatic(name)),
+                Some(HostSymbol { address, .. }) => *address,
+                None => match self.get_address(&name) {
+                    Some(address) => address,
+                    None => continue,
+                },
+            };
+
+            resolved.push((global, address));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Gets the function `name` is defined as, if it's a function which can be called as a
+    /// function of type `fn_type`.
+    pub(crate) fn get_function(&self, name: &str, fn_type: FunctionType<'_>) -> Option<usize> {
+        match self.symbols.get(name)? {
+            HostSymbol {
+                address,
+                kind: SymbolKind::Function(matches_fn_type),
+            } if matches_fn_type(fn_type) => Some(*address),
+            HostSymbol {
+                address,
+                kind: SymbolKind::Unchecked,
+            } => Some(*address),
+            _ => None,
+        }
+    }
+}
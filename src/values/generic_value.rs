@@ -17,6 +17,9 @@ use std::{marker::PhantomData, mem};
 
 //A version of GenericValue that isn't reponsible for dropping the LLVMGenericValueRef
 //This is used in the ExecutionEngine to avoid double frees
+//
+//The lifetime is that of whatever owns the underlying value: a borrowed GenericValue, an
+//InterpreterThread for its exit value, or a single hook call for the values passed to MiriHooks.
 #[derive(Debug, Copy, Clone)]
 pub struct GenericValueRef<'ctx> {
     pub(crate) generic_value: LLVMGenericValueRef,
@@ -24,7 +27,8 @@ pub struct GenericValueRef<'ctx> {
 }
 
 impl<'ctx> GenericValueRef<'ctx> {
-    pub unsafe fn new(generic_value: LLVMGenericValueRef) -> GenericValueRef<'static> {
+    /// Wraps a raw value. The caller must ensure `'ctx` doesn't outlive the value.
+    pub unsafe fn new(generic_value: LLVMGenericValueRef) -> Self {
         assert!(!generic_value.is_null());
         GenericValueRef {
             generic_value,
@@ -32,6 +36,12 @@ impl<'ctx> GenericValueRef<'ctx> {
         }
     }
 
+    /// Detaches this reference from the lifetime of whatever owns the underlying value. The caller
+    /// must ensure it isn't used once that value has been freed.
+    pub unsafe fn into_static(self) -> GenericValueRef<'static> {
+        GenericValueRef::new(self.generic_value)
+    }
+
     #[inline]
     fn get_field_type(&self, index: u64) -> Option<BasicTypeEnum<'ctx>> {
         match self.get_type_tag() {
@@ -80,7 +90,7 @@ impl<'ctx> GenericValueRef<'ctx> {
         self.get_type_tag().unwrap()
     }
 
-    pub fn set_type_tag(&self, bte: &BasicTypeEnum<'ctx>) {
+    pub fn set_type_tag(&self, bte: &BasicTypeEnum<'_>) {
         unsafe {
             LLVMGenericValueSetTypeTag(self.generic_value, bte.as_type_ref());
         }
@@ -104,7 +114,7 @@ impl<'ctx> GenericValueRef<'ctx> {
         let apint_slice = unsafe { std::slice::from_raw_parts(apint_pointer.data, apint_pointer.words as usize) };
         assert!(
             apint_slice.len() <= 2,
-            "GenericValue::as_int() supports values of a maximum size of 128 bits."
+            "GenericValue::as_int() supports values of a maximum size of 128 bits. Use as_wide_int() for wider values."
         );
         let apint_byte_slice = apint_slice
             .iter()
@@ -117,6 +127,29 @@ impl<'ctx> GenericValueRef<'ctx> {
         }
     }
 
+    /// Gets the integer value as `u64` words, least significant first. Bits above `int_width` are zero.
+    pub fn as_int_words(&self) -> Vec<u64> {
+        let apint_pointer = unsafe { LLVMGenericValueToInt(self.generic_value) };
+        let mut words =
+            unsafe { std::slice::from_raw_parts(apint_pointer.data, apint_pointer.words as usize) }.to_vec();
+
+        WideInt::clear_unused_bits(&mut words, self.int_width());
+
+        words
+    }
+
+    /// Gets the integer value of any width, interpreted as being `int_width` bits wide.
+    pub fn as_wide_int(&self) -> WideInt {
+        WideInt::from_words(self.as_int_words(), self.int_width())
+    }
+
+    /// Gets the integer value sign extended from `int_width` bits. Panics if it is wider than 128 bits.
+    pub fn as_signed_int(&self) -> i128 {
+        self.as_wide_int()
+            .to_i128()
+            .expect("GenericValue::as_signed_int() supports values of a maximum size of 128 bits.")
+    }
+
     // SubType: impl only for GenericValue<FloatValue>
     pub fn as_float(&self, float_type: &FloatType<'_>) -> f64 {
         unsafe { LLVMGenericValueToFloat(float_type.as_type_ref(), self.generic_value) }
@@ -147,6 +180,18 @@ impl<'ctx> GenericValueRef<'ctx> {
         unsafe { LLVMGenericValueSetIntValue(self.generic_value, value_slice.as_ptr(), bytes) }
     }
 
+    /// Sets the integer value from `u64` words, least significant first. Only the lowest `int_width`
+    /// bits are kept and missing words are zero.
+    pub fn set_int_words(&mut self, words: &[u64]) {
+        let bit_width = self.int_width();
+        let mut value = words.to_vec();
+
+        value.resize(WideInt::words_for(bit_width), 0);
+        WideInt::clear_unused_bits(&mut value, bit_width);
+
+        unsafe { LLVMGenericValueSetIntValue(self.generic_value, value.as_ptr(), u64::from(self.int_width_bytes())) }
+    }
+
     pub fn set_bytes(&mut self, bytes: &[u8]) {
         unsafe { LLVMGenericValueSetDataValue(self.generic_value, bytes.as_ptr(), bytes.len().try_into().unwrap()) }
     }
@@ -261,6 +306,25 @@ impl<'ctx> GenericValue<'ctx> {
         }
     }
 
+    /// Creates an integer of type `int_type` from `u64` words, least significant first.
+    pub fn new_int_words(words: &[u64], int_type: &IntType<'ctx>) -> Self {
+        let mut value = GenericValue::new_int(0, int_type, false);
+
+        value.as_mut().set_int_words(words);
+        value
+    }
+
+    /// Creates an integer of type `int_type` holding `value`, which must have the same width.
+    pub fn new_wide_int(value: &WideInt, int_type: &IntType<'ctx>) -> Self {
+        assert_eq!(
+            value.get_bit_width(),
+            int_type.get_bit_width(),
+            "GenericValue::new_wide_int() requires a value of the same width as int_type."
+        );
+
+        GenericValue::new_int_words(value.as_words(), int_type)
+    }
+
     pub fn from_byte_slice(bytes: &[u8]) -> Self {
         unsafe {
             let value = LLVMCreateGenericValueOfData(bytes.as_ptr() as *const u8, bytes.len() as u32);
@@ -280,12 +344,14 @@ impl<'ctx> GenericValue<'ctx> {
         GenericValue::new(value)
     }
 
-    pub fn as_ref(&self) -> &GenericValueRef<'ctx> {
+    /// Borrows this value. The reference can't outlive the borrow, since the value is freed on drop.
+    pub fn as_ref(&self) -> &GenericValueRef<'_> {
         &self.generic_value_ref
     }
 
-    pub fn as_mut(&mut self) -> &mut GenericValueRef<'ctx> {
-        &mut self.generic_value_ref
+    /// Mutably borrows this value. The reference can't outlive the borrow, since the value is freed on drop.
+    pub fn as_mut(&mut self) -> GenericValueRef<'_> {
+        self.generic_value_ref
     }
 
     pub unsafe fn into_raw(self) -> LLVMGenericValueRef {
@@ -311,7 +377,8 @@ pub struct GenericValueArrayRef<'ctx> {
 }
 
 impl<'ctx> GenericValueArrayRef<'ctx> {
-    pub unsafe fn new(array_ref: LLVMGenericValueArrayRef) -> GenericValueArrayRef<'static> {
+    /// Wraps a raw array. The caller must ensure `'ctx` doesn't outlive the array.
+    pub unsafe fn new(array_ref: LLVMGenericValueArrayRef) -> Self {
         assert!(!array_ref.is_null());
 
         GenericValueArrayRef {
@@ -338,3 +405,163 @@ impl<'ctx> GenericValueArrayRef<'ctx> {
         }
     }
 }
+
+/// An owned integer of any bit width, stored as `u64` words, least significant first.
+///
+/// Bits above the width are always zero, so two `WideInt`s of the same width and value are equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WideInt {
+    words: Vec<u64>,
+    bit_width: u32,
+}
+
+impl WideInt {
+    fn words_for(bit_width: u32) -> usize {
+        (bit_width as usize + 63) / 64
+    }
+
+    fn clear_unused_bits(words: &mut Vec<u64>, bit_width: u32) {
+        words.truncate(WideInt::words_for(bit_width));
+
+        let used = bit_width % 64;
+
+        if let (Some(last), true) = (words.last_mut(), used != 0) {
+            *last &= (1 << used) - 1;
+        }
+    }
+
+    /// Creates a `bit_width` bits wide integer from `u64` words, least significant first. Missing words
+    /// are zero and bits above `bit_width` are dropped.
+    pub fn from_words(mut words: Vec<u64>, bit_width: u32) -> Self {
+        words.resize(WideInt::words_for(bit_width), 0);
+        WideInt::clear_unused_bits(&mut words, bit_width);
+
+        WideInt { words, bit_width }
+    }
+
+    /// Creates a `bit_width` bits wide integer from a `u128`, truncating it if needed.
+    pub fn from_u128(value: u128, bit_width: u32) -> Self {
+        WideInt::from_words(vec![value as u64, (value >> 64) as u64], bit_width)
+    }
+
+    /// Creates a `bit_width` bits wide integer from an `i128`, sign extending or truncating it as needed.
+    pub fn from_i128(value: i128, bit_width: u32) -> Self {
+        let fill = if value < 0 { u64::MAX } else { 0 };
+        let mut words = vec![fill; WideInt::words_for(bit_width).max(2)];
+
+        words[0] = value as u64;
+        words[1] = (value >> 64) as u64;
+
+        WideInt::from_words(words, bit_width)
+    }
+
+    pub fn get_bit_width(&self) -> u32 {
+        self.bit_width
+    }
+
+    /// Gets the words of this integer, least significant first.
+    pub fn as_words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn into_words(self) -> Vec<u64> {
+        self.words
+    }
+
+    /// Gets the little-endian bytes of this integer, rounded up to a whole number of bytes.
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.words.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        bytes.truncate((self.bit_width as usize + 7) / 8);
+        bytes
+    }
+
+    /// Whether the sign bit is set when this integer is interpreted as signed.
+    pub fn is_negative(&self) -> bool {
+        self.bit_width != 0 && self.get_bit(self.bit_width - 1)
+    }
+
+    pub fn get_bit(&self, bit: u32) -> bool {
+        bit < self.bit_width && (self.words[bit as usize / 64] >> (bit % 64)) & 1 == 1
+    }
+
+    /// Widens this integer to `bit_width` bits, filling the new bits with zeroes. Narrower widths truncate.
+    pub fn zero_extend(&self, bit_width: u32) -> Self {
+        WideInt::from_words(self.words.clone(), bit_width)
+    }
+
+    /// Widens this integer to `bit_width` bits, filling the new bits with its sign bit. Narrower widths truncate.
+    pub fn sign_extend(&self, bit_width: u32) -> Self {
+        if !self.is_negative() || bit_width <= self.bit_width {
+            return self.zero_extend(bit_width);
+        }
+
+        let mut words = self.words.clone();
+        let used = self.bit_width % 64;
+
+        if let (Some(last), true) = (words.last_mut(), used != 0) {
+            *last |= u64::MAX << used;
+        }
+
+        words.resize(WideInt::words_for(bit_width), u64::MAX);
+
+        WideInt::from_words(words, bit_width)
+    }
+
+    /// Gets the value of this integer if it fits in a `u128` when interpreted as unsigned.
+    pub fn to_u128(&self) -> Option<u128> {
+        if self.words.iter().skip(2).any(|&word| word != 0) {
+            return None;
+        }
+
+        let low = self.words.first().copied().unwrap_or(0);
+        let high = self.words.get(1).copied().unwrap_or(0);
+
+        Some((u128::from(high) << 64) | u128::from(low))
+    }
+
+    /// Gets the value of this integer if it fits in an `i128` when interpreted as signed.
+    pub fn to_i128(&self) -> Option<i128> {
+        let extended = self.sign_extend(self.bit_width.max(128));
+        let fill = if self.is_negative() { u64::MAX } else { 0 };
+
+        if extended.words.iter().skip(2).any(|&word| word != fill) {
+            return None;
+        }
+
+        // The top word's sign bit must agree with the discarded words
+        let value = (u128::from(extended.words[1]) << 64) | u128::from(extended.words[0]);
+
+        if ((value as i128) < 0) != self.is_negative() {
+            return None;
+        }
+
+        Some(value as i128)
+    }
+}
+
+#[test]
+fn test_wide_int() {
+    let minus_one = WideInt::from_i128(-1, 256);
+
+    assert_eq!(minus_one.as_words(), &[u64::MAX; 4]);
+    assert!(minus_one.is_negative());
+    assert_eq!(minus_one.to_i128(), Some(-1));
+    assert_eq!(minus_one.to_u128(), None);
+
+    let narrow = WideInt::from_u128(0x80, 8);
+
+    assert_eq!(narrow.to_i128(), Some(-128));
+    assert_eq!(
+        narrow.sign_extend(200).as_words(),
+        &[0xFFFF_FFFF_FFFF_FF80, u64::MAX, u64::MAX, 0xFF]
+    );
+    assert_eq!(narrow.zero_extend(200).as_words(), &[0x80, 0, 0, 0]);
+    assert_eq!(narrow.sign_extend(200).sign_extend(8), narrow);
+
+    let wide = WideInt::from_words(vec![1, 2, 3, 4, 5], 193);
+
+    assert_eq!(wide.as_words(), &[1, 2, 3, 0]);
+    assert_eq!(wide.to_u128(), None);
+    assert_eq!(wide.to_le_bytes().len(), 25);
+}
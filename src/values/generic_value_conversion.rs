@@ -0,0 +1,474 @@
+//! Conversions between `GenericValue`s and Rust values, checked against the LLVM type of the value.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use llvm_sys::miri::MiriPointer;
+
+use crate::types::{BasicType, BasicTypeEnum};
+use crate::values::{GenericValue, GenericValueRef, WideInt};
+
+/// An error converting between a `GenericValue` and a Rust value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenericValueError {
+    /// The LLVM type can't hold the Rust type.
+    TypeMismatch { rust_type: &'static str, llvm_type: String },
+    /// An aggregate has a different number of elements than the Rust value.
+    LengthMismatch {
+        llvm_type: String,
+        expected: u64,
+        found: u64,
+    },
+    /// The type tag of the value differs from the type it was read as.
+    TagMismatch { type_tag: String, llvm_type: String },
+}
+
+impl Error for GenericValueError {}
+
+impl Display for GenericValueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GenericValueError::TypeMismatch { rust_type, llvm_type } => write!(
+                f,
+                "GenericValueError(TypeMismatch: `{}` can't be represented as `{}`)",
+                rust_type, llvm_type
+            ),
+            GenericValueError::LengthMismatch {
+                llvm_type,
+                expected,
+                found,
+            } => write!(
+                f,
+                "GenericValueError(LengthMismatch: `{}` has {} elements but the value has {})",
+                llvm_type, expected, found
+            ),
+            GenericValueError::TagMismatch { type_tag, llvm_type } => write!(
+                f,
+                "GenericValueError(TagMismatch: value is tagged `{}` but was read as `{}`)",
+                type_tag, llvm_type
+            ),
+        }
+    }
+}
+
+/// A Rust value which can be passed to the interpreter as a `GenericValue` of a given type.
+///
+/// This can be derived for structs with `#[derive(ToGenericValue)]`, which converts each
+/// field in order into the matching field of an LLVM struct type.
+pub trait ToGenericValue<'ctx> {
+    /// Creates a `GenericValue` of type `ty` holding this value. Aggregates are tagged with `ty`.
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError>;
+}
+
+/// A Rust value which can be read back from a `GenericValue` of a given type.
+///
+/// This can be derived for structs with `#[derive(FromGenericValue)]`, which reads each
+/// field in order from the matching field of an LLVM struct type.
+pub trait FromGenericValue<'ctx>: Sized {
+    /// Reads a value of type `ty` out of `value`. If `value` has a type tag, it must be `ty`.
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError>;
+}
+
+fn type_mismatch<T: ?Sized>(ty: BasicTypeEnum<'_>) -> GenericValueError {
+    GenericValueError::TypeMismatch {
+        rust_type: std::any::type_name::<T>(),
+        llvm_type: ty.print_to_string().to_string(),
+    }
+}
+
+fn check_type_tag(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'_>) -> Result<(), GenericValueError> {
+    match value.get_type_tag() {
+        Some(type_tag) if type_tag != ty => Err(GenericValueError::TagMismatch {
+            type_tag: type_tag.print_to_string().to_string(),
+            llvm_type: ty.print_to_string().to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Gets the element types of an LLVM aggregate, which must have `len` elements.
+#[doc(hidden)]
+pub fn aggregate_field_types<'ctx, T: ?Sized>(
+    ty: BasicTypeEnum<'ctx>,
+    len: u64,
+) -> Result<Vec<BasicTypeEnum<'ctx>>, GenericValueError> {
+    let field_types = match ty {
+        BasicTypeEnum::StructType(struct_type) => struct_type.get_field_types(),
+        BasicTypeEnum::ArrayType(array_type) => vec![array_type.get_element_type(); array_type.len() as usize],
+        BasicTypeEnum::VectorType(vector_type) => {
+            vec![vector_type.get_element_type(); vector_type.get_size() as usize]
+        },
+        _ => return Err(type_mismatch::<T>(ty)),
+    };
+
+    if field_types.len() as u64 != len {
+        return Err(GenericValueError::LengthMismatch {
+            llvm_type: ty.print_to_string().to_string(),
+            expected: field_types.len() as u64,
+            found: len,
+        });
+    }
+
+    Ok(field_types)
+}
+
+/// Creates an aggregate `GenericValue` of type `ty` from already converted fields.
+#[doc(hidden)]
+pub fn new_aggregate<'ctx>(
+    ty: BasicTypeEnum<'ctx>,
+    fields: Vec<GenericValue<'ctx>>,
+) -> Result<GenericValue<'ctx>, GenericValueError> {
+    let mut value = GenericValue::new_aggregate(fields.len() as u64);
+
+    for field in fields {
+        value.as_mut().append_aggregate_value(field);
+    }
+
+    value.as_ref().set_type_tag(&ty);
+
+    Ok(value)
+}
+
+/// Gets the fields of an aggregate `GenericValue` of type `ty`, which must have `len` elements.
+#[doc(hidden)]
+pub fn aggregate_fields<'v, 'ctx, T: ?Sized>(
+    value: &GenericValueRef<'v>,
+    ty: BasicTypeEnum<'ctx>,
+    len: u64,
+) -> Result<Vec<(GenericValueRef<'v>, BasicTypeEnum<'ctx>)>, GenericValueError> {
+    check_type_tag(value, ty)?;
+
+    let field_types = aggregate_field_types::<T>(ty, len)?;
+
+    if value.get_aggregate_size() != len {
+        return Err(GenericValueError::LengthMismatch {
+            llvm_type: ty.print_to_string().to_string(),
+            expected: len,
+            found: value.get_aggregate_size(),
+        });
+    }
+
+    value.set_type_tag(&ty);
+
+    let fields = value.get_fields().ok_or_else(|| type_mismatch::<T>(ty))?;
+
+    Ok(fields.into_iter().zip(field_types).collect())
+}
+
+impl<'ctx> ToGenericValue<'ctx> for bool {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        match ty {
+            BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == 1 => {
+                Ok(GenericValue::new_int(*self as u64, &int_type, false))
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> FromGenericValue<'ctx> for bool {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == 1 => Ok(value.as_int() & 1 == 1),
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+macro_rules! impl_int_conversion {
+    ($($ty:ty => $unsigned:ty, $signed:literal;)*) => {$(
+        impl<'ctx> ToGenericValue<'ctx> for $ty {
+            fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+                match ty {
+                    BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == <$unsigned>::BITS => {
+                        let bits = *self as $unsigned as u128;
+                        let mut value = GenericValue::new_int(bits as u64, &int_type, $signed);
+
+                        if <$unsigned>::BITS > u64::BITS {
+                            value.as_mut().set_int_value(bits, u64::from(<$unsigned>::BITS / 8));
+                        }
+
+                        Ok(value)
+                    },
+                    _ => Err(type_mismatch::<Self>(ty)),
+                }
+            }
+        }
+
+        impl<'ctx> FromGenericValue<'ctx> for $ty {
+            fn from_generic_value(
+                value: &GenericValueRef<'_>,
+                ty: BasicTypeEnum<'ctx>,
+            ) -> Result<Self, GenericValueError> {
+                check_type_tag(value, ty)?;
+
+                match ty {
+                    BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == <$unsigned>::BITS => {
+                        Ok(value.as_int() as $unsigned as $ty)
+                    },
+                    _ => Err(type_mismatch::<Self>(ty)),
+                }
+            }
+        }
+    )*};
+}
+
+impl_int_conversion! {
+    u8 => u8, false;
+    i8 => u8, true;
+    u16 => u16, false;
+    i16 => u16, true;
+    u32 => u32, false;
+    i32 => u32, true;
+    u64 => u64, false;
+    i64 => u64, true;
+    u128 => u128, false;
+    i128 => u128, true;
+    usize => usize, false;
+    isize => usize, true;
+}
+
+impl<'ctx> ToGenericValue<'ctx> for WideInt {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        match ty {
+            BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == self.get_bit_width() => {
+                Ok(GenericValue::new_wide_int(self, &int_type))
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> FromGenericValue<'ctx> for WideInt {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::IntType(int_type) => Ok(WideInt::from_words(value.as_int_words(), int_type.get_bit_width())),
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> ToGenericValue<'ctx> for f32 {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        match ty {
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                Ok(GenericValue::new_f32(*self))
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> FromGenericValue<'ctx> for f32 {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                Ok(value.as_f32())
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> ToGenericValue<'ctx> for f64 {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        match ty {
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                Ok(GenericValue::new_f64(*self))
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> FromGenericValue<'ctx> for f64 {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                Ok(value.as_f64())
+            },
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> ToGenericValue<'ctx> for MiriPointer {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        match ty {
+            BasicTypeEnum::PointerType(_) => Ok(unsafe { GenericValue::create_generic_value_of_miri_pointer(*self) }),
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+impl<'ctx> FromGenericValue<'ctx> for MiriPointer {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::PointerType(_) => Ok(value.as_miri_pointer()),
+            _ => Err(type_mismatch::<Self>(ty)),
+        }
+    }
+}
+
+/// Copies a value out of a `GenericValueRef` the interpreter owns, so that it can outlive it.
+impl<'ctx> FromGenericValue<'ctx> for GenericValue<'ctx> {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        check_type_tag(value, ty)?;
+
+        match ty {
+            BasicTypeEnum::IntType(int_type) => Ok(GenericValue::new_int_words(&value.as_int_words(), &int_type)),
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f32_type() => {
+                Ok(GenericValue::new_f32(value.as_f32()))
+            },
+            BasicTypeEnum::FloatType(float_type) if float_type == float_type.get_context().f64_type() => {
+                Ok(GenericValue::new_f64(value.as_f64()))
+            },
+            BasicTypeEnum::FloatType(float_type) => {
+                Ok(GenericValue::new_float(value.as_float(&float_type), &float_type))
+            },
+            BasicTypeEnum::PointerType(_) => {
+                Ok(unsafe { GenericValue::create_generic_value_of_miri_pointer(value.as_miri_pointer()) })
+            },
+            BasicTypeEnum::ArrayType(_) | BasicTypeEnum::StructType(_) | BasicTypeEnum::VectorType(_) => {
+                let fields = aggregate_fields::<Self>(value, ty, value.get_aggregate_size())?
+                    .into_iter()
+                    .map(|(field, field_type)| GenericValue::from_generic_value(&field, field_type))
+                    .collect::<Result<_, _>>()?;
+
+                new_aggregate(ty, fields)
+            },
+        }
+    }
+}
+
+impl<'ctx, T: ToGenericValue<'ctx>> ToGenericValue<'ctx> for [T] {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        if let BasicTypeEnum::StructType(_) = ty {
+            return Err(type_mismatch::<Self>(ty));
+        }
+
+        let field_types = aggregate_field_types::<Self>(ty, self.len() as u64)?;
+        let fields = self
+            .iter()
+            .zip(field_types)
+            .map(|(field, field_type)| field.to_generic_value(field_type))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        new_aggregate(ty, fields)
+    }
+}
+
+impl<'ctx, T: ToGenericValue<'ctx>, const N: usize> ToGenericValue<'ctx> for [T; N] {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        self.as_slice().to_generic_value(ty)
+    }
+}
+
+impl<'ctx, T: ToGenericValue<'ctx>> ToGenericValue<'ctx> for Vec<T> {
+    fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+        self.as_slice().to_generic_value(ty)
+    }
+}
+
+impl<'ctx, T: FromGenericValue<'ctx>> FromGenericValue<'ctx> for Vec<T> {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        let len = match ty {
+            BasicTypeEnum::ArrayType(array_type) => u64::from(array_type.len()),
+            BasicTypeEnum::VectorType(vector_type) => u64::from(vector_type.get_size()),
+            _ => return Err(type_mismatch::<Self>(ty)),
+        };
+
+        aggregate_fields::<Self>(value, ty, len)?
+            .iter()
+            .map(|(field, field_type)| T::from_generic_value(field, *field_type))
+            .collect()
+    }
+}
+
+impl<'ctx, T: FromGenericValue<'ctx>, const N: usize> FromGenericValue<'ctx> for [T; N] {
+    fn from_generic_value(value: &GenericValueRef<'_>, ty: BasicTypeEnum<'ctx>) -> Result<Self, GenericValueError> {
+        if let BasicTypeEnum::StructType(_) = ty {
+            return Err(type_mismatch::<Self>(ty));
+        }
+
+        let elements = aggregate_fields::<Self>(value, ty, N as u64)?
+            .iter()
+            .map(|(field, field_type)| T::from_generic_value(field, *field_type))
+            .collect::<Result<Vec<T>, _>>()?;
+
+        // The length was checked against N above
+        Ok(elements.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+macro_rules! impl_tuple_conversion {
+    ($(($($name:ident: $idx:tt),+);)*) => {$(
+        impl<'ctx, $($name: ToGenericValue<'ctx>),+> ToGenericValue<'ctx> for ($($name,)+) {
+            fn to_generic_value(&self, ty: BasicTypeEnum<'ctx>) -> Result<GenericValue<'ctx>, GenericValueError> {
+                if !ty.is_struct_type() {
+                    return Err(type_mismatch::<Self>(ty));
+                }
+
+                let len = [$($idx),+].len() as u64;
+                let field_types = aggregate_field_types::<Self>(ty, len)?;
+                let fields = vec![$(self.$idx.to_generic_value(field_types[$idx])?),+];
+
+                new_aggregate(ty, fields)
+            }
+        }
+
+        impl<'ctx, $($name: FromGenericValue<'ctx>),+> FromGenericValue<'ctx> for ($($name,)+) {
+            fn from_generic_value(
+                value: &GenericValueRef<'_>,
+                ty: BasicTypeEnum<'ctx>,
+            ) -> Result<Self, GenericValueError> {
+                if !ty.is_struct_type() {
+                    return Err(type_mismatch::<Self>(ty));
+                }
+
+                let len = [$($idx),+].len() as u64;
+                let fields = aggregate_fields::<Self>(value, ty, len)?;
+
+                Ok(($($name::from_generic_value(&fields[$idx].0, fields[$idx].1)?,)+))
+            }
+        }
+    )*};
+}
+
+impl_tuple_conversion! {
+    (A: 0);
+    (A: 0, B: 1);
+    (A: 0, B: 1, C: 2);
+    (A: 0, B: 1, C: 2, D: 3);
+    (A: 0, B: 1, C: 2, D: 3, E: 4);
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+}
+
+impl<'ctx> GenericValue<'ctx> {
+    /// Creates a `GenericValue` of type `ty` from any Rust value implementing `ToGenericValue`.
+    pub fn from_value<T: ToGenericValue<'ctx> + ?Sized>(
+        value: &T,
+        ty: impl BasicType<'ctx>,
+    ) -> Result<Self, GenericValueError> {
+        value.to_generic_value(ty.as_basic_type_enum())
+    }
+}
+
+impl GenericValueRef<'_> {
+    /// Reads this value as a Rust value of type `T`, treating it as having type `ty`.
+    pub fn to_value<'ctx, T: FromGenericValue<'ctx>>(&self, ty: impl BasicType<'ctx>) -> Result<T, GenericValueError> {
+        T::from_generic_value(self, ty.as_basic_type_enum())
+    }
+}
@@ -17,6 +17,11 @@ use llvm_sys::prelude::LLVMValueRef;
 use llvm_sys::LLVMOpcode;
 
 use std::{ffi::CStr, fmt, fmt::Display};
+#[llvm_versions(9..)]
+use std::marker::PhantomData;
+
+#[llvm_versions(9..)]
+use crate::debug_info::DILocation;
 
 use crate::values::{BasicValue, BasicValueEnum, BasicValueUse, MetadataValue, Value};
 use crate::{basic_block::BasicBlock, types::AnyTypeEnum};
@@ -831,6 +836,21 @@ impl<'ctx> InstructionValue<'ctx> {
         unsafe { Some(MetadataValue::new(metadata_value)) }
     }
 
+    /// Gets the debug location attached to this `Instruction`, if any.
+    #[llvm_versions(9..)]
+    pub fn get_debug_location(self) -> Option<DILocation<'ctx>> {
+        let metadata_ref = unsafe { llvm_sys::debuginfo::LLVMInstructionGetDebugLoc(self.as_value_ref()) };
+
+        if metadata_ref.is_null() {
+            return None;
+        }
+
+        Some(DILocation {
+            metadata_ref,
+            _marker: PhantomData,
+        })
+    }
+
     /// Determines whether or not this `Instruction` has any associated metadata
     /// `kind_id`.
     pub fn set_metadata(self, metadata: MetadataValue<'ctx>, kind_id: u32) -> Result<(), &'static str> {
@@ -10,6 +10,7 @@ mod enums;
 mod float_value;
 mod fn_value;
 mod generic_value;
+mod generic_value_conversion;
 mod global_value;
 mod instruction_value;
 mod int_value;
@@ -46,6 +47,14 @@ pub use crate::values::fn_value::FunctionValue;
 pub use crate::values::generic_value::GenericValue;
 pub use crate::values::generic_value::GenericValueArrayRef;
 pub use crate::values::generic_value::GenericValueRef;
+pub use crate::values::generic_value::WideInt;
+pub use crate::values::generic_value_conversion::{FromGenericValue, GenericValueError, ToGenericValue};
+#[doc(hidden)]
+pub use crate::values::generic_value_conversion::{
+    aggregate_field_types as __aggregate_field_types, aggregate_fields as __aggregate_fields,
+    new_aggregate as __new_aggregate,
+};
+pub use inkwell_internals::{FromGenericValue, ToGenericValue};
 
 pub use crate::values::global_value::GlobalValue;
 #[llvm_versions(7..)]
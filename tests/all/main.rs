@@ -13,8 +13,11 @@ mod test_execution_engine;
 mod test_instruction_conversion;
 mod test_instruction_values;
 mod test_intrinsics;
+mod test_miri;
 mod test_module;
 mod test_object_file;
+#[llvm_versions(12..)]
+mod test_orc;
 #[cfg(not(any(feature = "llvm17-0", feature = "llvm18-0")))]
 mod test_passes;
 mod test_targets;
@@ -1,5 +1,8 @@
 use inkwell::context::Context;
-use inkwell::execution_engine::FunctionLookupError;
+use inkwell::execution_engine::{FunctionLookupError, MainInvocation};
+use inkwell::hot_reload::{HotFunction, HotReloadError};
+use inkwell::jit_event_listener::JITEventListener;
+use inkwell::symbol_map::{SymbolMap, SymbolMapError};
 use inkwell::targets::{InitializationConfig, Target};
 use inkwell::{AddressSpace, IntPredicate, OptimizationLevel};
 
@@ -48,6 +51,34 @@ fn test_get_function_address() {
     }
 }
 
+#[test]
+fn test_register_event_listener() {
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = Context::create();
+    let module = context.create_module("listened");
+    let builder = context.create_builder();
+    let void_type = context.void_type();
+    let fn_value = module.add_function("func", void_type.fn_type(&[], false), None);
+    let basic_block = context.append_basic_block(fn_value, "entry");
+
+    builder.position_at_end(basic_block);
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
+
+    execution_engine.register_event_listener(JITEventListener::create_gdb_registration_listener());
+
+    if let Some(listener) = JITEventListener::create_perf_listener() {
+        execution_engine.register_event_listener(listener);
+    }
+
+    // The listeners are told about the object emitted for this lookup
+    let func = unsafe { execution_engine.get_function::<Thunk>("func") }.unwrap();
+
+    unsafe { func.call() };
+}
+
 #[test]
 fn test_jit_execution_engine() {
     let context = Context::create();
@@ -125,6 +156,234 @@ fn test_jit_execution_engine() {
     assert_eq!(ret, 42, "unexpected main return code: {}", ret);
 }
 
+#[test]
+fn test_main_invocation() {
+    let context = Context::create();
+    let module = context.create_module("main_module");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let main = module.add_function("main", i32_type.fn_type(&[i32_type.into()], false), None);
+    let entry = context.append_basic_block(main, "entry");
+
+    builder.position_at_end(entry);
+
+    let hello = builder
+        .build_global_string_ptr("hello", "hello")
+        .unwrap()
+        .as_pointer_value();
+    let puts = module.add_function("puts", i32_type.fn_type(&[hello.get_type().into()], false), None);
+
+    builder.build_call(puts, &[hello.into()], "puts").unwrap();
+    builder
+        .build_return(Some(&main.get_first_param().unwrap().into_int_value()))
+        .unwrap();
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let execution_engine = module
+        .create_jit_execution_engine(OptimizationLevel::None)
+        .expect("Could not create Execution Engine");
+    let dir = std::env::current_dir().unwrap();
+    let output = unsafe {
+        MainInvocation::new("program")
+            .args(vec!["foo", "bar"])
+            .env("KEY", "value")
+            .current_dir(std::env::temp_dir())
+            .capture_stdout()
+            .run(&execution_engine, main)
+    }
+    .unwrap();
+
+    assert_eq!(output.exit_code, 3);
+    assert_eq!(output.stdout, b"hello\n");
+    assert!(output.stderr.is_empty());
+    assert_eq!(std::env::current_dir().unwrap(), dir);
+}
+
+#[test]
+fn test_symbol_map() {
+    extern "C" fn sumf(a: f64, b: f64) -> f64 {
+        a + b
+    }
+
+    extern "C" fn sumi(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    static LIMIT: u64 = 42;
+
+    let context = Context::create();
+    let module = context.create_module("main_module");
+    let builder = context.create_builder();
+    let f64_type = context.f64_type();
+    let fn_type = f64_type.fn_type(&[f64_type.into(), f64_type.into()], false);
+    let sumf_value = module.add_function("sumf", fn_type, None);
+    let test_fn = module.add_function("test_fn", f64_type.fn_type(&[], false), None);
+
+    builder.position_at_end(context.append_basic_block(test_fn, "entry"));
+
+    let arg = f64_type.const_float(64.);
+    let sum = builder
+        .build_call(sumf_value, &[arg.into(), arg.into()], "sum")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&sum)).unwrap();
+
+    let mut symbols = SymbolMap::new();
+
+    symbols.add_function::<unsafe extern "C" fn(i32, i32) -> i32>("sumf", sumi);
+
+    assert_eq!(
+        symbols.check(&module),
+        Err(SymbolMapError::SignatureMismatch {
+            name: "sumf".into(),
+            fn_type: "double (double, double)".into(),
+        })
+    );
+
+    symbols.add_static("sumf", &LIMIT);
+
+    assert_eq!(symbols.check(&module), Err(SymbolMapError::NotAFunction("sumf".into())));
+
+    symbols.add_function::<unsafe extern "C" fn(f64, f64) -> f64>("sumf", sumf);
+
+    assert_eq!(symbols.check(&module), Ok(()));
+
+    let limit = module.add_global(context.i64_type(), None, "LIMIT");
+
+    symbols.add_static("LIMIT", &LIMIT);
+
+    assert_eq!(
+        symbols.check(&module),
+        Err(SymbolMapError::MutableStatic("LIMIT".into()))
+    );
+
+    limit.set_constant(true);
+
+    assert_eq!(symbols.check(&module), Ok(()));
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let execution_engine = module
+        .create_jit_execution_engine(OptimizationLevel::None)
+        .expect("Could not create Execution Engine");
+
+    execution_engine.add_symbol_map(&module, &symbols).unwrap();
+
+    let test_fn = unsafe { execution_engine.get_function::<unsafe extern "C" fn() -> f64>("test_fn") }.unwrap();
+
+    assert_eq!(unsafe { test_fn.call() }, 128.);
+}
+
+#[test]
+fn test_hot_function() {
+    let context = Context::create();
+    let module = context.create_module("live");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    let fn_type = i64_type.fn_type(&[i64_type.into()], false);
+    let answer = module.add_function("answer", fn_type, None);
+    let call_answer = module.add_function("call_answer", fn_type, None);
+
+    builder.position_at_end(context.append_basic_block(answer, "entry"));
+
+    let x = answer.get_first_param().unwrap().into_int_value();
+
+    builder
+        .build_return(Some(&builder.build_int_add(x, x, "double").unwrap()))
+        .unwrap();
+    builder.position_at_end(context.append_basic_block(call_answer, "entry"));
+
+    let arg = call_answer.get_first_param().unwrap();
+    let result = builder
+        .build_call(answer, &[arg.into()], "result")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let declaration = module.add_function("declared", fn_type, None);
+
+    assert_eq!(
+        HotFunction::prepare(&module, declaration).unwrap_err(),
+        HotReloadError::Declaration
+    );
+
+    let mut hot_answer = HotFunction::prepare(&module, answer).unwrap();
+
+    assert_eq!(hot_answer.get_stub().get_name().to_str(), Ok("answer"));
+    assert_eq!(hot_answer.get_body().get_name().to_str(), Ok("answer.hot0"));
+    assert!(module.verify().is_ok());
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let execution_engine = module
+        .create_jit_execution_engine(OptimizationLevel::None)
+        .expect("Could not create Execution Engine");
+    let call_answer =
+        unsafe { execution_engine.get_function::<unsafe extern "C" fn(u64) -> u64>("call_answer") }.unwrap();
+
+    assert_eq!(unsafe { call_answer.call(21) }, 42);
+
+    let next_module = context.create_module("live.1");
+    let bad_next = next_module.add_function("answer", i64_type.fn_type(&[], false), None);
+
+    assert_eq!(
+        hot_answer.replace(&execution_engine, next_module, bad_next),
+        Err(HotReloadError::SignatureMismatch)
+    );
+
+    // The new body calls the function recursively, which must go through the stub
+    let next_module = context.create_module("live.1");
+    let next = next_module.add_function("answer", fn_type, None);
+    let entry = context.append_basic_block(next, "entry");
+    let recurse = context.append_basic_block(next, "recurse");
+    let done = context.append_basic_block(next, "done");
+
+    builder.position_at_end(entry);
+
+    let x = next.get_first_param().unwrap().into_int_value();
+    let is_zero = builder
+        .build_int_compare(IntPredicate::EQ, x, i64_type.const_zero(), "is_zero")
+        .unwrap();
+
+    builder.build_conditional_branch(is_zero, done, recurse).unwrap();
+    builder.position_at_end(recurse);
+
+    let x_minus_one = builder
+        .build_int_sub(x, i64_type.const_int(1, false), "x_minus_one")
+        .unwrap();
+    let rest = builder
+        .build_call(next, &[x_minus_one.into()], "rest")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap()
+        .into_int_value();
+
+    builder
+        .build_return(Some(&builder.build_int_add(x, rest, "sum").unwrap()))
+        .unwrap();
+    builder.position_at_end(done);
+    builder.build_return(Some(&i64_type.const_zero())).unwrap();
+
+    hot_answer.replace(&execution_engine, next_module, next).unwrap();
+
+    assert_eq!(hot_answer.get_version(), 1);
+    assert_eq!(hot_answer.get_body().get_name().to_str(), Ok("answer.hot1"));
+    assert_eq!(unsafe { call_answer.call(4) }, 10);
+    assert_eq!(hot_answer.count_active_calls(&execution_engine), Ok(0));
+    assert_eq!(hot_answer.count_retired(), 1);
+    assert_eq!(hot_answer.collect(&execution_engine), 1);
+    assert_eq!(hot_answer.count_retired(), 0);
+    assert_eq!(unsafe { call_answer.call(3) }, 6);
+}
+
 // #[test]
 // fn test_execution_engine_empty_module() {
 //     let context = Context::create();
@@ -0,0 +1,1900 @@
+use inkwell::context::Context;
+use inkwell::execution_engine::{InterpreterError, MiriPointer};
+use inkwell::miri::{
+    CallCompletion, ForeignCallError, ForeignFallback, ForeignFunctionRegistry, InterpreterLimit, InterpreterLimits,
+    InterpreterThread, MemoryAccess, MemoryErrorKind, MiriHooks, RandomScheduler, Scheduler, SimpleMiriHost,
+    StepResult,
+};
+use inkwell::types::{BasicTypeEnum, FunctionType};
+use inkwell::values::{GenericValue, GenericValueRef, InstructionOpcode};
+use inkwell::{AddressSpace, AtomicOrdering, IntPredicate};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+struct NullHooks(Rc<()>);
+
+impl<'ctx> MiriHooks<'ctx> for NullHooks {
+    fn malloc(&mut self, _size: u64, _align: u64, _is_heap: bool) -> MiriPointer {
+        panic!("NullHooks can't allocate")
+    }
+
+    fn free(&mut self, _ptr: MiriPointer) -> bool {
+        false
+    }
+
+    fn load(&mut self, _dest: GenericValueRef<'_>, _src: MiriPointer, _ty: BasicTypeEnum<'ctx>, _align: u64) -> bool {
+        false
+    }
+
+    fn store(
+        &mut self,
+        _value: GenericValueRef<'_>,
+        _dest: MiriPointer,
+        _ty: BasicTypeEnum<'ctx>,
+        _align: u64,
+    ) -> bool {
+        false
+    }
+
+    fn get_element_pointer(&mut self, base: MiriPointer, _offset: u64) -> MiriPointer {
+        base
+    }
+
+    fn memset(&mut self, _dest: MiriPointer, _value: u8, _len: u64) -> bool {
+        false
+    }
+
+    fn memcpy(&mut self, _dest: MiriPointer, _src: &[u8]) -> bool {
+        false
+    }
+
+    fn int_to_ptr(&mut self, _addr: u64) -> MiriPointer {
+        panic!("NullHooks can't cast integers to pointers")
+    }
+
+    fn ptr_to_int(&mut self, ptr: MiriPointer) -> u64 {
+        ptr.addr
+    }
+
+    fn call_by_name(&mut self, _name: &str, _args: &[GenericValueRef<'_>], _fn_type: FunctionType<'ctx>) -> bool {
+        false
+    }
+
+    fn call_by_pointer(
+        &mut self,
+        _callee: MiriPointer,
+        _args: &[GenericValueRef<'_>],
+        _fn_type: FunctionType<'ctx>,
+    ) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_install_miri_hooks() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let alive = Rc::new(());
+
+    execution_engine.install_miri_hooks(Box::new(NullHooks(alive.clone())));
+
+    assert_eq!(Rc::strong_count(&alive), 2);
+
+    // Installing new hooks drops the previous ones
+    execution_engine.install_miri_hooks(Box::new(NullHooks(alive.clone())));
+
+    assert_eq!(Rc::strong_count(&alive), 2);
+
+    // The module holds a clone of the engine, which keeps the hooks alive
+    drop(execution_engine);
+
+    assert_eq!(Rc::strong_count(&alive), 2);
+
+    drop(module);
+
+    assert_eq!(Rc::strong_count(&alive), 1);
+}
+
+#[test]
+fn test_miri_hook_panic_is_caught() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let function = module.add_function("alloca", i32_type.fn_type(&[], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    let ptr = builder.build_alloca(i32_type, "slot").unwrap();
+
+    builder.build_store(ptr, i32_type.const_int(7, false)).unwrap();
+
+    let value = builder.build_load(i32_type, ptr, "value").unwrap();
+
+    builder.build_return(Some(&value)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+
+    execution_engine.install_miri_hooks(Box::new(NullHooks(Rc::new(()))));
+
+    let err = unsafe { execution_engine.interpret_function(function, &[]) }.unwrap_err();
+    let panic = match err {
+        InterpreterError::HookPanicked(panic) => panic,
+        err => panic!("expected a hook panic, got {}", err),
+    };
+
+    assert_eq!(panic.get_hook(), "malloc");
+    assert_eq!(panic.get_message(), Some("NullHooks can't allocate"));
+
+    // run_function resumes the panic once the interpreter is no longer on the stack
+    let payload = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        execution_engine.run_function(function, &[]);
+    }))
+    .unwrap_err();
+
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"NullHooks can't allocate"));
+}
+
+#[test]
+fn test_interpreter_threads() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let function = module.add_function("five", i32_type.fn_type(&[], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+    builder.build_return(Some(&i32_type.const_int(5, false))).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+
+    execution_engine.install_miri_hooks(Box::new(NullHooks(Rc::new(()))));
+
+    let mut first = InterpreterThread::spawn(&execution_engine, 1, function, &[]).unwrap();
+
+    assert!(matches!(
+        InterpreterThread::spawn(&execution_engine, 1, function, &[]),
+        Err(InterpreterError::ThreadIdInUse(1))
+    ));
+
+    // The thread isn't waiting on a call, so it can't be handed a return value
+    assert!(matches!(
+        first.set_pending_return(None),
+        Err(InterpreterError::ThreadNotBlocked(1))
+    ));
+
+    let second = InterpreterThread::spawn(&execution_engine, 2, function, &[]).unwrap();
+    let mut threads = [first, second];
+
+    assert!(unsafe { RandomScheduler::new(7).run(&mut threads) }.unwrap().is_none());
+
+    let mut exit_values = Vec::new();
+
+    for thread in &mut threads {
+        assert!(thread.has_exited());
+        assert_eq!(thread.get_exit_value().unwrap().as_int(), 5);
+
+        match unsafe { thread.step() }.unwrap() {
+            StepResult::Exited(value) => exit_values.push(value),
+            result => panic!("unexpected step result {:?}", result),
+        }
+    }
+
+    // The values threads exit with outlive them
+    drop(threads);
+
+    for value in exit_values {
+        assert_eq!(value.as_ref().as_int(), 5);
+    }
+}
+
+#[test]
+fn test_interpreter_thread_frames() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let fn_type = i32_type.fn_type(&[i32_type.into()], false);
+    let double = module.add_function("double", fn_type, None);
+    let function = module.add_function("double_plus_one", fn_type, None);
+
+    builder.position_at_end(context.append_basic_block(double, "entry"));
+
+    let x = double.get_first_param().unwrap().into_int_value();
+    let sum = builder.build_int_add(x, x, "sum").unwrap();
+
+    builder.build_return(Some(&sum)).unwrap();
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let call = builder
+        .build_call(double, &[function.get_first_param().unwrap().into()], "doubled")
+        .unwrap();
+    let doubled = call.try_as_basic_value().left().unwrap().into_int_value();
+    let result = builder
+        .build_int_add(doubled, i32_type.const_int(1, false), "result")
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+
+    execution_engine.install_miri_hooks(Box::new(NullHooks(Rc::new(()))));
+    execution_engine.instrument_module(&module);
+
+    let arg = GenericValue::new_int(20, &i32_type, false);
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, function, &[arg]).unwrap();
+    let call_instruction = doubled.as_instruction().unwrap();
+    let step = |thread: &mut InterpreterThread<'_>| match unsafe { thread.step() }.unwrap() {
+        StepResult::Running => {},
+        result => panic!("unexpected step result {:?}", result),
+    };
+
+    // A new thread stops before its first instruction
+    step(&mut thread);
+
+    assert_eq!(thread.get_current_instruction(), Some(call_instruction));
+
+    let frames = thread.get_frames();
+
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get_function(), function);
+    assert_eq!(frames[0].get_argument(0).unwrap().as_ref().as_int(), 20);
+
+    // Calls push a frame, and its arguments are bound on entry
+    step(&mut thread);
+
+    let frames = thread.get_frames();
+
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].get_instruction(), Some(call_instruction));
+    assert_eq!(frames[1].get_function(), double);
+    assert_eq!(frames[1].get_argument(0).unwrap().as_ref().as_int(), 20);
+    assert_eq!(thread.get_current_instruction(), sum.as_instruction());
+
+    step(&mut thread);
+
+    assert_eq!(thread.get_frames()[1].get_value(sum).unwrap().as_ref().as_int(), 40);
+    assert_eq!(
+        thread.get_current_instruction().unwrap().get_opcode(),
+        InstructionOpcode::Return
+    );
+
+    // Returning pops the frame and binds the call's value in the caller
+    step(&mut thread);
+
+    let frames = thread.get_frames();
+
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get_value(doubled).unwrap().as_ref().as_int(), 40);
+    assert_eq!(thread.get_current_instruction(), result.as_instruction());
+
+    step(&mut thread);
+
+    assert_eq!(thread.get_frames()[0].get_value(result).unwrap().as_ref().as_int(), 41);
+    assert!(matches!(unsafe { thread.step() }.unwrap(), StepResult::Exited(_)));
+    assert!(thread.get_frames().is_empty());
+    assert_eq!(thread.get_exit_value().unwrap().as_int(), 41);
+}
+
+#[test]
+fn test_instrument_musttail_call() {
+    use inkwell::memory_buffer::MemoryBuffer;
+
+    let ir = b"
+        define i32 @increment(i32 %x) {
+        entry:
+          %y = add i32 %x, 1
+          ret i32 %y
+        }
+
+        define i32 @forward(i32 %x) {
+        entry:
+          %r = musttail call i32 @increment(i32 %x)
+          ret i32 %r
+        }
+
+        define i32 @main(i32 %x) {
+        entry:
+          %r = call i32 @forward(i32 %x)
+          %s = mul i32 %r, 2
+          ret i32 %s
+        }
+    ";
+    let context = Context::create();
+    let memory_buffer = MemoryBuffer::create_from_memory_range(ir, "musttail");
+    let module = context.create_module_from_ir(memory_buffer).unwrap();
+    let function = module.get_function("main").unwrap();
+    let forward = module.get_function("forward").unwrap();
+    let call = function
+        .get_first_basic_block()
+        .unwrap()
+        .get_first_instruction()
+        .unwrap();
+    let product = call.get_next_instruction().unwrap();
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+
+    execution_engine.install_miri_hooks(Box::new(NullHooks(Rc::new(()))));
+    execution_engine.instrument_module(&module);
+
+    // Nothing is inserted between the call and its `ret`, which stays a `musttail` call
+    assert!(module.verify().is_ok());
+    assert!(forward.print_to_string().to_string().contains("musttail call"));
+
+    let arg = GenericValue::new_int(4, &context.i32_type(), false);
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, function, &[arg]).unwrap();
+
+    while thread.get_current_instruction() != Some(product) {
+        assert!(matches!(unsafe { thread.step() }.unwrap(), StepResult::Running));
+    }
+
+    // The frame which made the `musttail` call returned along with its callee
+    let frames = thread.get_frames();
+
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get_value(call).unwrap().as_ref().as_int(), 5);
+
+    while !matches!(unsafe { thread.step() }.unwrap(), StepResult::Exited(_)) {}
+
+    assert_eq!(thread.get_exit_value().unwrap().as_int(), 10);
+}
+
+#[test]
+fn test_simple_miri_host() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let pair_type = context.struct_type(&[i64_type.into(), ptr_type.into()], false);
+    let function = module.add_function("round_trip", i64_type.fn_type(&[ptr_type.into()], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    // Store a pointer to a stack slot in a struct, then read the slot back through it
+    let out = function.get_first_param().unwrap().into_pointer_value();
+    let slot = builder.build_alloca(i64_type, "slot").unwrap();
+    let pair = builder.build_alloca(pair_type, "pair").unwrap();
+    let field = builder.build_struct_gep(pair_type, pair, 1, "field").unwrap();
+
+    builder.build_store(slot, i64_type.const_int(42, false)).unwrap();
+    builder.build_store(field, slot).unwrap();
+
+    let loaded = builder
+        .build_load(ptr_type, field, "loaded")
+        .unwrap()
+        .into_pointer_value();
+    let value = builder.build_load(i64_type, loaded, "value").unwrap();
+
+    builder.build_store(out, value).unwrap();
+    builder.build_return(Some(&value)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    let out = host.allocate(8, 8);
+    let arg = unsafe { GenericValue::create_generic_value_of_miri_pointer(out) };
+    let result = unsafe { execution_engine.interpret_function(function, &[&arg]) }.unwrap();
+
+    assert_eq!(result.as_ref().as_int(), 42);
+    assert_eq!(host.read_bytes(out, 8), Some(42u64.to_le_bytes().to_vec()));
+    assert_eq!(host.read_bytes(out, 9), None);
+    assert_eq!(host.get_live_heap_allocations().len(), 1);
+
+    // Running a function leaves its module as it was
+    assert!(!module.print_to_string().to_string().contains("inkwell.miri"));
+}
+
+#[test]
+fn test_simple_miri_host_memory_errors() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let function = module.add_function("overflow", context.void_type().fn_type(&[ptr_type.into()], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    let out = function.get_first_param().unwrap().into_pointer_value();
+
+    builder.build_store(out, i64_type.const_int(42, false)).unwrap();
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    // An 8 byte store into a 4 byte allocation
+    let out = host.allocate(4, 4);
+    let arg = unsafe { GenericValue::create_generic_value_of_miri_pointer(out) };
+
+    assert!(matches!(
+        unsafe { execution_engine.interpret_function(function, &[&arg]) },
+        Err(InterpreterError::HookRejected { hook: "store" })
+    ));
+
+    let errors = host.take_errors();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        MemoryErrorKind::OutOfBounds {
+            access: MemoryAccess::Write,
+            offset: 0,
+            size: 8,
+            alloc_size: 4,
+        }
+    );
+    assert_eq!(errors[0].ptr.addr, out.addr);
+    assert_eq!(host.read_bytes(out, 4), Some(vec![0; 4]));
+
+    // Invalid frees and accesses through freed pointers, made by calling the hooks directly
+    let mut hooks = host.clone();
+
+    assert!(MiriHooks::free(&mut hooks, out));
+    assert!(!MiriHooks::free(&mut hooks, out));
+    assert!(!MiriHooks::memset(&mut hooks, out, 0, 4));
+
+    let buffer = host.allocate(8, 8);
+    let inner = MiriHooks::get_element_pointer(&mut hooks, buffer, 4);
+
+    assert!(!MiriHooks::free(&mut hooks, inner));
+    assert!(!MiriHooks::memcpy(&mut hooks, inner, &[1; 8]));
+    assert!(!MiriHooks::memset(&mut hooks, buffer, 0, u64::MAX));
+
+    // Sizes the host can't hold are rejected rather than aborting
+    let huge = MiriHooks::malloc(&mut hooks, u64::MAX, 8, true);
+
+    assert_eq!(huge.addr, 0);
+    assert_eq!(host.allocate(u64::MAX, 8).addr, 0);
+
+    let kinds: Vec<_> = host.take_errors().into_iter().map(|error| error.kind).collect();
+
+    assert_eq!(
+        kinds,
+        [
+            MemoryErrorKind::DoubleFree,
+            MemoryErrorKind::UseAfterFree {
+                access: MemoryAccess::Write,
+                size: 4,
+            },
+            MemoryErrorKind::InvalidFree,
+            MemoryErrorKind::OutOfBounds {
+                access: MemoryAccess::Write,
+                offset: 4,
+                size: 8,
+                alloc_size: 8,
+            },
+            MemoryErrorKind::OutOfBounds {
+                access: MemoryAccess::Write,
+                offset: 0,
+                size: u64::MAX,
+                alloc_size: 8,
+            },
+            MemoryErrorKind::AllocationFailed { size: u64::MAX },
+            MemoryErrorKind::AllocationFailed { size: u64::MAX },
+        ]
+    );
+    assert!(host.get_errors().is_empty());
+}
+
+#[test]
+fn test_simple_miri_host_uninitialized_read() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let pair_type = context.struct_type(&[i32_type.into(), i32_type.into()], false);
+    let function = module.add_function("uninit", i32_type.fn_type(&[], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    // Only the first field of the pair is ever written
+    let pair = builder.build_alloca(pair_type, "pair").unwrap();
+    let first = builder.build_struct_gep(pair_type, pair, 0, "first").unwrap();
+
+    builder.build_store(first, i32_type.const_int(1, false)).unwrap();
+
+    let value = builder
+        .build_load(pair_type, pair, "value")
+        .unwrap()
+        .into_struct_value();
+    let field = builder.build_extract_value(value, 0, "field").unwrap();
+
+    builder.build_return(Some(&field)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    assert!(matches!(
+        unsafe { execution_engine.interpret_function(function, &[]) },
+        Err(InterpreterError::HookRejected { hook: "load" })
+    ));
+
+    let errors = host.get_errors();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, MemoryErrorKind::Uninitialized { offset: 4, size: 4 });
+}
+
+#[test]
+fn test_undefined_branch() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    let function = module.add_function("undefined_branch", i32_type.fn_type(&[i32_type.into()], false), None);
+    let entry = context.append_basic_block(function, "entry");
+    let then_block = context.append_basic_block(function, "then");
+    let else_block = context.append_basic_block(function, "else");
+
+    builder.position_at_end(entry);
+
+    // Adding the argument doesn't make the sum any less undefined
+    let arg = function.get_first_param().unwrap().into_int_value();
+    let sum = builder.build_int_add(i32_type.get_undef(), arg, "sum").unwrap();
+    let is_zero = builder
+        .build_int_compare(IntPredicate::EQ, sum, i32_type.const_zero(), "is_zero")
+        .unwrap();
+
+    builder
+        .build_conditional_branch(is_zero, then_block, else_block)
+        .unwrap();
+    builder.position_at_end(then_block);
+    builder.build_return(Some(&i32_type.const_int(1, false))).unwrap();
+    builder.position_at_end(else_block);
+    builder.build_return(Some(&i32_type.const_int(2, false))).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host));
+    execution_engine.instrument_module(&module);
+
+    let arg = GenericValue::new_int(7, &i32_type, false);
+
+    match unsafe { execution_engine.interpret_function(function, &[&arg]) } {
+        Err(InterpreterError::UndefinedBranch { instruction }) => assert!(instruction.contains("br i1 %is_zero")),
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+/// A `Write` whose output can still be read after it was handed to a `ForeignFunctionRegistry`.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Accepts as many bytes as it holds, then fails.
+struct BrokenPipe(usize);
+
+impl Write for BrokenPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.0 -= buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_tracing_hooks() {
+    use inkwell::miri::trace::{
+        TraceEvent, TraceInstruction, TracePointer, TraceReader, TraceValue, TraceWriter, TracingHooks,
+    };
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i32_type = context.i32_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let square = module.add_function("square", i32_type.fn_type(&[i32_type.into()], false), None);
+    let function = module.add_function("main", i32_type.fn_type(&[ptr_type.into()], false), None);
+
+    builder.position_at_end(context.append_basic_block(square, "entry"));
+
+    let x = square.get_first_param().unwrap().into_int_value();
+    let squared = builder.build_int_mul(x, x, "squared").unwrap();
+
+    builder.build_return(Some(&squared)).unwrap();
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let slot = function.get_first_param().unwrap().into_pointer_value();
+
+    builder.build_store(slot, i32_type.const_int(7, false)).unwrap();
+
+    let value = builder.build_load(i32_type, slot, "value").unwrap();
+    let result = builder
+        .build_call(square, &[value.into()], "result")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let trace = SharedBuffer::default();
+    let writer = TraceWriter::new(trace.clone()).unwrap();
+    let hooks = TracingHooks::new(host.clone(), writer);
+    let error = hooks.get_error();
+
+    execution_engine.install_miri_hooks(Box::new(hooks));
+    execution_engine.instrument_module(&module);
+
+    let slot = host.allocate(4, 4);
+    let arg = unsafe { GenericValue::create_generic_value_of_miri_pointer(slot) };
+    let args = [&arg];
+    let result = unsafe { execution_engine.interpret_function(function, &args) }.unwrap();
+
+    assert_eq!(result.as_ref().as_int(), 49);
+    assert!(error.take().is_none());
+
+    let bytes = trace.0.borrow().clone();
+    let events = TraceReader::new(bytes.as_slice())
+        .unwrap()
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    let int = |value| TraceValue::Int { bits: 32, value };
+    let instruction = |function: &str, index| {
+        TraceEvent::Instruction(TraceInstruction {
+            function: function.into(),
+            block: 0,
+            index,
+        })
+    };
+
+    assert_eq!(
+        events,
+        [
+            TraceEvent::Enter {
+                function: "main".into()
+            },
+            instruction("main", 0),
+            TraceEvent::Store {
+                ptr: TracePointer::from(slot),
+                value: int(7),
+            },
+            instruction("main", 1),
+            TraceEvent::Load {
+                ptr: TracePointer::from(slot),
+                value: int(7),
+            },
+            instruction("main", 2),
+            TraceEvent::Enter {
+                function: "square".into()
+            },
+            instruction("square", 0),
+            instruction("square", 1),
+            TraceEvent::Return {
+                function: "square".into(),
+                value: Some(int(49)),
+            },
+            instruction("main", 3),
+            TraceEvent::Return {
+                function: "main".into(),
+                value: Some(int(49)),
+            },
+        ]
+    );
+
+    // A trace which can't be written stops being recorded, and the error is kept for later
+    let writer = TraceWriter::new(BrokenPipe(9)).unwrap();
+    let hooks = TracingHooks::new(host.clone(), writer);
+    let error = hooks.get_error();
+
+    execution_engine.install_miri_hooks(Box::new(hooks));
+
+    let result = unsafe { execution_engine.interpret_function(function, &args) };
+
+    assert!(matches!(result, Err(InterpreterError::HookRejected { .. })));
+    assert_eq!(error.take().unwrap().kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn test_foreign_function_registry() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let strlen = module.add_function("strlen", i64_type.fn_type(&[ptr_type.into()], false), None);
+    let add = module.add_function(
+        "add_i64",
+        i64_type.fn_type(&[i64_type.into(), i64_type.into()], false),
+        None,
+    );
+    let function = module.add_function("len_plus_ten", i64_type.fn_type(&[ptr_type.into()], false), None);
+    let entry = context.append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    let string = function.get_first_param().unwrap();
+    let len = builder
+        .build_call(strlen, &[string.into()], "len")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+    let sum = builder
+        .build_call(add, &[len.into(), i64_type.const_int(10, false).into()], "sum")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&sum)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let mut foreign_functions = ForeignFunctionRegistry::with_libc_shims();
+
+    foreign_functions.register_fn("add_*", |(lhs, rhs): (i64, i64)| lhs + rhs);
+    host.set_foreign_functions(foreign_functions);
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    let string = host.allocate(4, 1);
+
+    assert!(host.write_bytes(string, b"abc\0"));
+
+    let arg = unsafe { GenericValue::create_generic_value_of_miri_pointer(string) };
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, function, &[arg]).unwrap();
+
+    loop {
+        match unsafe { thread.step() }.unwrap() {
+            StepResult::Running => {},
+            StepResult::Blocked => thread.set_pending_return(host.take_return_value()).unwrap(),
+            StepResult::Exited(_) => break,
+            result => panic!("unexpected step result {:?}", result),
+        }
+    }
+
+    assert_eq!(thread.get_exit_value().unwrap().as_int(), 13);
+    assert!(host.take_call_errors().is_empty());
+
+    // Shims can also be called directly, with the host as their memory
+    let i32_type = context.i32_type();
+    let output = SharedBuffer::default();
+    let mut foreign_functions = ForeignFunctionRegistry::with_libc_shims();
+    let format = host.allocate(16, 1);
+
+    foreign_functions.set_stdout(Box::new(output.clone()));
+
+    assert!(host.write_bytes(format, b"%s=%-3d|%05.1f\n\0"));
+
+    let args = [
+        unsafe { GenericValue::create_generic_value_of_miri_pointer(format) },
+        unsafe { GenericValue::create_generic_value_of_miri_pointer(string) },
+        GenericValue::new_int(-7i64 as u64, &i32_type, true),
+        GenericValue::new_f64(6.07),
+    ];
+    let args: Vec<GenericValueRef<'_>> = args.iter().map(|arg| *arg.as_ref()).collect();
+    let printf_type = i32_type.fn_type(&[ptr_type.into()], true);
+    let written = foreign_functions
+        .call(&host, "printf", &args, printf_type)
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(output.0.borrow().as_slice(), b"abc=-7 |006.1\n");
+    assert_eq!(written.as_ref().as_int(), 14);
+
+    let missing_type = i32_type.fn_type(&[], false);
+
+    assert!(matches!(
+        foreign_functions.call(&host, "missing", &[], missing_type),
+        Err(ForeignCallError::Unresolved(name)) if name == "missing"
+    ));
+
+    unsafe { foreign_functions.set_fallback(ForeignFallback::Stub) };
+
+    let stubbed = foreign_functions
+        .call(&host, "missing", &[], missing_type)
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(stubbed.as_ref().as_int(), 0);
+}
+
+#[llvm_versions(15..)]
+#[test]
+fn test_function_pointers() {
+    use inkwell::miri::FunctionPointerTarget;
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let unary_type = i64_type.fn_type(&[i64_type.into()], false);
+    let double = module.add_function("double", unary_type, None);
+    let apply = module.add_function(
+        "apply",
+        i64_type.fn_type(&[ptr_type.into(), i64_type.into()], false),
+        None,
+    );
+
+    builder.position_at_end(context.append_basic_block(double, "entry"));
+
+    let value = double.get_first_param().unwrap().into_int_value();
+    let doubled = builder.build_int_add(value, value, "doubled").unwrap();
+
+    builder.build_return(Some(&doubled)).unwrap();
+    builder.position_at_end(context.append_basic_block(apply, "entry"));
+
+    let callee = apply.get_first_param().unwrap().into_pointer_value();
+    let arg = apply.get_nth_param(1).unwrap();
+    let result = builder
+        .build_indirect_call(unary_type, callee, &[arg.into()], "result")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+
+    assert!(execution_engine.get_function_pointer(double).is_none());
+
+    execution_engine.install_miri_hooks(Box::new(SimpleMiriHost::new(execution_engine.get_target_data())));
+
+    let double_ptr = execution_engine.get_function_pointer(double).unwrap();
+    let increment_ptr = execution_engine
+        .register_host_function(unary_type, move |args| {
+            Some(GenericValue::new_int(args[0].as_int() as u64 + 1, &i64_type, false))
+        })
+        .unwrap();
+    let narrow_type = context.i32_type().fn_type(&[i64_type.into()], false);
+    let narrow_ptr = execution_engine
+        .register_host_function(narrow_type, |_| unreachable!())
+        .unwrap();
+
+    assert_eq!(
+        execution_engine.get_function_pointer(double).unwrap().addr,
+        double_ptr.addr
+    );
+    assert_eq!(
+        execution_engine.resolve_function_pointer(double_ptr),
+        Some(FunctionPointerTarget::Function(double))
+    );
+    assert_eq!(
+        execution_engine.resolve_function_pointer(increment_ptr),
+        Some(FunctionPointerTarget::Host(unary_type))
+    );
+
+    let run = |ptr| {
+        let args = [
+            unsafe { GenericValue::create_generic_value_of_miri_pointer(ptr) },
+            GenericValue::new_int(21, &i64_type, false),
+        ];
+        let mut thread = InterpreterThread::spawn(&execution_engine, 1, apply, &args)?;
+
+        while !thread.has_exited() {
+            unsafe { thread.step() }?;
+        }
+
+        Ok::<_, InterpreterError>(thread.get_exit_value().unwrap().as_int())
+    };
+
+    assert_eq!(run(double_ptr).unwrap(), 42);
+    assert_eq!(run(increment_ptr).unwrap(), 22);
+    assert!(matches!(
+        run(narrow_ptr),
+        Err(InterpreterError::SignatureMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_breakpoints() {
+    use inkwell::miri::{BreakpointLocation, Trigger, WatchKind};
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let unary_type = i64_type.fn_type(&[i64_type.into()], false);
+    let increment = module.add_function("increment", unary_type, None);
+    let update = module.add_function(
+        "update",
+        context.void_type().fn_type(&[ptr_type.into(), ptr_type.into()], false),
+        None,
+    );
+
+    let increment_entry = context.append_basic_block(increment, "entry");
+
+    builder.position_at_end(increment_entry);
+
+    let value = increment.get_first_param().unwrap().into_int_value();
+    let incremented = builder
+        .build_int_add(value, i64_type.const_int(1, false), "incremented")
+        .unwrap();
+
+    builder.build_return(Some(&incremented)).unwrap();
+    builder.position_at_end(context.append_basic_block(update, "entry"));
+
+    // Read a counter, update it through a function pointer and write it back
+    let counter = update.get_first_param().unwrap().into_pointer_value();
+    let callee = update.get_nth_param(1).unwrap().into_pointer_value();
+    let value = builder.build_load(i64_type, counter, "value").unwrap();
+    let result = builder
+        .build_indirect_call(unary_type, callee, &[value.into()], "result")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+    let store = builder.build_store(counter, result).unwrap();
+
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+    execution_engine.instrument_module(&module);
+
+    let counter = host.allocate(8, 8);
+    let increment_ptr = execution_engine.get_function_pointer(increment).unwrap();
+    let mut other_allocation = counter;
+
+    other_allocation.prov.alloc_id += 1000;
+
+    assert!(host.write_bytes(counter, &41u64.to_ne_bytes()));
+
+    let read = execution_engine.add_watchpoint(counter, 8, WatchKind::Read);
+    let write = execution_engine.add_watchpoint(counter, 8, WatchKind::Write);
+    let removed = execution_engine.add_watchpoint(counter, 8, WatchKind::ReadWrite);
+    let elsewhere = execution_engine.add_watchpoint(other_allocation, 8, WatchKind::ReadWrite);
+    let call = execution_engine.add_breakpoint(increment);
+    let block = execution_engine.add_breakpoint(increment_entry);
+    let before_store = execution_engine.add_breakpoint(store);
+
+    assert!(execution_engine.remove_breakpoint(removed));
+    assert!(!execution_engine.remove_breakpoint(removed));
+
+    let args = [
+        unsafe { GenericValue::create_generic_value_of_miri_pointer(counter) },
+        unsafe { GenericValue::create_generic_value_of_miri_pointer(increment_ptr) },
+    ];
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, update, &args).unwrap();
+    let mut hits = Vec::new();
+
+    loop {
+        match unsafe { thread.step() }.unwrap() {
+            StepResult::Running => {},
+            StepResult::Triggered(triggers) => hits.push((triggers, thread.get_current_instruction())),
+            StepResult::Exited(_) => break,
+            result => panic!("unexpected step result {:?}", result),
+        }
+    }
+
+    assert_eq!(hits.len(), 4);
+
+    // The load stops on the read watchpoint only
+    assert!(matches!(
+        hits[0].0[..],
+        [Trigger::Watchpoint { id, ptr, size: 8, access: MemoryAccess::Read }]
+            if id == read && ptr.addr == counter.addr
+    ));
+
+    // The call through the function pointer stops before the first instruction of `increment`
+    assert!(matches!(
+        hits[1].0[..],
+        [
+            Trigger::Breakpoint { id: first, location: BreakpointLocation::Function(function) },
+            Trigger::Breakpoint { id: second, location: BreakpointLocation::BasicBlock(entry) },
+        ] if first == call && function == increment && second == block && entry == increment_entry
+    ));
+    assert_eq!(hits[1].1, incremented.as_instruction());
+    assert!(matches!(
+        hits[2].0[..],
+        [Trigger::Breakpoint { id, location: BreakpointLocation::Instruction(instruction) }]
+            if id == before_store && instruction == store
+    ));
+    assert_eq!(hits[2].1.unwrap().get_opcode(), InstructionOpcode::Store);
+    assert!(matches!(
+        hits[3].0[..],
+        [Trigger::Watchpoint { id, access: MemoryAccess::Write, .. }] if id == write
+    ));
+
+    // Removed watchpoints and those on other allocations at the same address never fire
+    assert!(hits
+        .iter()
+        .flat_map(|(triggers, _)| triggers)
+        .all(|trigger| !matches!(trigger, Trigger::Watchpoint { id, .. } if *id == removed || *id == elsewhere)));
+
+    assert_eq!(host.read_bytes(counter, 8).unwrap(), 42u64.to_ne_bytes());
+}
+
+#[test]
+fn test_materialize_globals() {
+    use inkwell::miri::{GlobalImage, GlobalLayoutError, Relocation, RelocationTarget};
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i8_type = context.i8_type();
+    let i16_type = context.i16_type();
+    let i32_type = context.i32_type();
+    let get = module.add_function("get", i32_type.fn_type(&[], false), None);
+    let counter = module.add_global(i32_type, None, "counter");
+    let counter_ptr = counter.as_pointer_value();
+    let get_ptr = get.as_global_value().as_pointer_value();
+    let table_type = context.struct_type(
+        &[
+            i8_type.into(),
+            i32_type.into(),
+            counter_ptr.get_type().into(),
+            get_ptr.get_type().into(),
+            i16_type.array_type(2).into(),
+        ],
+        false,
+    );
+    let table = module.add_global(table_type, None, "table");
+
+    module.add_global(i32_type, None, "external");
+    counter.set_initializer(&i32_type.const_int(7, false));
+    table.set_initializer(
+        &context.const_struct(
+            &[
+                i8_type.const_int(1, false).into(),
+                i32_type.const_int(0x01020304, false).into(),
+                counter_ptr.into(),
+                get_ptr.into(),
+                i16_type
+                    .const_array(&[i16_type.const_int(3, false), i16_type.const_int(4, false)])
+                    .into(),
+            ],
+            false,
+        ),
+    );
+
+    builder.position_at_end(context.append_basic_block(get, "entry"));
+    builder.build_return(Some(&i32_type.const_int(0, false))).unwrap();
+
+    // Reads the counter through the pointer the table holds
+    let read_counter = module.add_function(
+        "read_counter",
+        i32_type.fn_type(&[table.as_pointer_value().get_type().into()], false),
+        None,
+    );
+
+    builder.position_at_end(context.append_basic_block(read_counter, "entry"));
+
+    let table_arg = read_counter.get_first_param().unwrap().into_pointer_value();
+    let field = builder.build_struct_gep(table_type, table_arg, 2, "field").unwrap();
+    let loaded = builder
+        .build_load(counter_ptr.get_type(), field, "loaded")
+        .unwrap()
+        .into_pointer_value();
+    let value = builder.build_load(i32_type, loaded, "value").unwrap();
+
+    builder.build_return(Some(&value)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let target_data = execution_engine.get_target_data();
+    let image = GlobalImage::new(table, target_data).unwrap();
+    let offset_of = |index| target_data.offset_of_element(&table_type, index).unwrap() as usize;
+
+    assert_eq!(image.size, target_data.get_abi_size(&table_type));
+    assert_eq!(image.bytes[offset_of(0)], 1);
+    assert_eq!(image.bytes[offset_of(1)..offset_of(1) + 4], 0x01020304u32.to_le_bytes());
+    assert_eq!(image.bytes[offset_of(4)..offset_of(4) + 4], [3, 0, 4, 0]);
+    assert_eq!(
+        image.relocations,
+        [
+            Relocation {
+                offset: offset_of(2) as u64,
+                ptr_type: counter_ptr.get_type(),
+                target: RelocationTarget::Global(counter),
+                addend: 0,
+            },
+            Relocation {
+                offset: offset_of(3) as u64,
+                ptr_type: get_ptr.get_type(),
+                target: RelocationTarget::Function(get),
+                addend: 0,
+            },
+        ]
+    );
+    assert_eq!(
+        execution_engine.materialize_globals(&module).unwrap_err(),
+        GlobalLayoutError::NoHooks
+    );
+
+    let host = SimpleMiriHost::new(target_data);
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    let globals = execution_engine.materialize_globals(&module).unwrap();
+
+    assert_eq!(globals.len(), 2);
+
+    let (_, counter_addr) = globals.iter().find(|(global, _)| *global == counter).unwrap();
+    let (_, table_addr) = globals.iter().find(|(global, _)| *global == table).unwrap();
+
+    assert_eq!(host.read_bytes(*counter_addr, 4), Some(7u32.to_le_bytes().to_vec()));
+    let field_addr = MiriPointer {
+        addr: table_addr.addr + offset_of(1) as u64,
+        prov: table_addr.prov,
+    };
+
+    assert_eq!(
+        host.read_bytes(field_addr, 4),
+        Some(0x01020304u32.to_le_bytes().to_vec())
+    );
+
+    let arg = unsafe { GenericValue::create_generic_value_of_miri_pointer(*table_addr) };
+    let result = unsafe { execution_engine.interpret_function(read_counter, &[&arg]) }.unwrap();
+
+    assert_eq!(result.as_ref().as_int(), 7);
+    assert!(host.get_errors().is_empty());
+}
+
+#[test]
+fn test_interpreter_limits() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    let answer = module.add_function("answer", i64_type.fn_type(&[], false), None);
+    let spin = module.add_function("spin", context.void_type().fn_type(&[], false), None);
+
+    builder.position_at_end(context.append_basic_block(answer, "entry"));
+
+    let slot = builder.build_alloca(i64_type, "slot").unwrap();
+
+    builder.build_store(slot, i64_type.const_int(42, false)).unwrap();
+
+    let value = builder.build_load(i64_type, slot, "value").unwrap();
+
+    builder.build_return(Some(&value)).unwrap();
+
+    // Never terminates
+    let entry = context.append_basic_block(spin, "entry");
+    let body = context.append_basic_block(spin, "body");
+
+    builder.position_at_end(entry);
+    builder.build_unconditional_branch(body).unwrap();
+    builder.position_at_end(body);
+    builder.build_unconditional_branch(body).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let limits = InterpreterLimits {
+        fuel: Some(100),
+        ..InterpreterLimits::default()
+    };
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+
+    assert!(!execution_engine.get_interpreter_limits().is_limited());
+    assert_eq!(execution_engine.get_remaining_fuel(), None);
+
+    execution_engine.set_interpreter_limits(limits);
+
+    assert_eq!(execution_engine.get_interpreter_limits(), limits);
+
+    let result = unsafe { execution_engine.interpret_function(answer, &[]) }.unwrap();
+    let remaining_fuel = execution_engine.get_remaining_fuel().unwrap();
+
+    assert_eq!(result.as_ref().as_int(), 42);
+    assert!(remaining_fuel > 0 && remaining_fuel < 100);
+
+    let err = unsafe { execution_engine.interpret_function(spin, &[]) }.unwrap_err();
+
+    assert!(matches!(err, InterpreterError::LimitExceeded(InterpreterLimit::Fuel)));
+    assert_eq!(execution_engine.get_remaining_fuel(), Some(0));
+
+    // run_function stops the same way, without panicking
+    execution_engine.set_interpreter_limits(limits);
+
+    unsafe { execution_engine.run_function(spin, &[]) };
+
+    assert_eq!(execution_engine.get_remaining_fuel(), Some(0));
+
+    // Threads share the fuel, and can be refilled
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, spin, &[]).unwrap();
+
+    assert!(matches!(
+        unsafe { thread.step() },
+        Err(InterpreterError::LimitExceeded(InterpreterLimit::Fuel))
+    ));
+    assert!(thread.has_exited());
+
+    execution_engine.set_interpreter_limits(limits);
+
+    let mut thread = InterpreterThread::spawn(&execution_engine, 2, spin, &[]).unwrap();
+    let mut steps = 0;
+
+    while unsafe { thread.step() }.is_ok() {
+        steps += 1;
+    }
+
+    assert_eq!(steps, 100);
+    assert!(host.get_errors().is_empty());
+}
+
+#[test]
+fn test_interpreter_call_depth_limit() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    let countdown = module.add_function("countdown", i64_type.fn_type(&[i64_type.into()], false), None);
+    let entry = context.append_basic_block(countdown, "entry");
+    let done = context.append_basic_block(countdown, "done");
+    let recurse = context.append_basic_block(countdown, "recurse");
+
+    // Calls itself directly until `n` is zero, so it has `n + 1` frames at its deepest
+    builder.position_at_end(entry);
+
+    let n = countdown.get_first_param().unwrap().into_int_value();
+    let is_zero = builder
+        .build_int_compare(IntPredicate::EQ, n, i64_type.const_zero(), "is_zero")
+        .unwrap();
+
+    builder.build_conditional_branch(is_zero, done, recurse).unwrap();
+    builder.position_at_end(done);
+    builder.build_return(Some(&i64_type.const_zero())).unwrap();
+    builder.position_at_end(recurse);
+
+    let next = builder.build_int_sub(n, i64_type.const_int(1, false), "next").unwrap();
+    let result = builder
+        .build_call(countdown, &[next.into()], "result")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host));
+    execution_engine.instrument_module(&module);
+    execution_engine.set_interpreter_limits(InterpreterLimits {
+        max_call_depth: Some(3),
+        ..InterpreterLimits::default()
+    });
+
+    let two = GenericValue::new_int(2, &i64_type, false);
+    let three = GenericValue::new_int(3, &i64_type, false);
+
+    assert!(unsafe { execution_engine.interpret_function(countdown, &[&two]) }.is_ok());
+    assert!(matches!(
+        unsafe { execution_engine.interpret_function(countdown, &[&three]) },
+        Err(InterpreterError::LimitExceeded(InterpreterLimit::CallDepth))
+    ));
+}
+
+#[test]
+fn test_interpreter_heap_size_limit_foreign_allocations() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = context.i8_type().ptr_type(AddressSpace::default());
+    let malloc = module.add_function("malloc", ptr_type.fn_type(&[i64_type.into()], false), None);
+    let free = module.add_function("free", context.void_type().fn_type(&[ptr_type.into()], false), None);
+    let function = module.add_function("allocate", context.void_type().fn_type(&[i64_type.into()], false), None);
+
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let size = function.get_first_param().unwrap();
+    let ptr = builder
+        .build_call(malloc, &[size.into()], "ptr")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_call(free, &[ptr.into()], "").unwrap();
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    host.set_foreign_functions(ForeignFunctionRegistry::with_libc_shims());
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+    execution_engine.set_interpreter_limits(InterpreterLimits {
+        max_heap_size: Some(16),
+        ..InterpreterLimits::default()
+    });
+
+    // The shims' allocations count towards the limit, and their frees give it back
+    let small = GenericValue::new_int(16, &i64_type, false);
+    let large = GenericValue::new_int(64, &i64_type, false);
+
+    assert!(unsafe { execution_engine.interpret_function(function, &[&small]) }.is_ok());
+    assert!(unsafe { execution_engine.interpret_function(function, &[&small]) }.is_ok());
+    assert!(matches!(
+        unsafe { execution_engine.interpret_function(function, &[&large]) },
+        Err(InterpreterError::LimitExceeded(InterpreterLimit::HeapSize))
+    ));
+    assert!(host.get_live_heap_allocations().is_empty());
+}
+
+#[test]
+fn test_simple_miri_host_snapshot() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let buffer = host.allocate(4, 4);
+
+    assert!(host.write_bytes(buffer, &[1, 2, 3, 4]));
+
+    let snapshot = host.snapshot();
+    let scratch = host.allocate(8, 8);
+
+    assert!(host.write_bytes(buffer, &[5, 6, 7, 8]));
+    assert!(host.write_bytes(scratch, &[9; 8]));
+
+    host.restore(&snapshot);
+
+    assert_eq!(host.read_bytes(buffer, 4), Some(vec![1, 2, 3, 4]));
+    assert_eq!(host.read_bytes(scratch, 8), None);
+    assert_eq!(host.get_live_heap_allocations().len(), 1);
+
+    // The same allocation is made again after restoring
+    let again = host.allocate(8, 8);
+
+    assert_eq!(again.addr, scratch.addr);
+    assert_eq!(host.read_bytes(again, 8), Some(vec![0; 8]));
+}
+
+#[test]
+fn test_interpreter_snapshot() {
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = i64_type.ptr_type(AddressSpace::default());
+    let next = module.add_function("next", i64_type.fn_type(&[i64_type.into()], false), None);
+    let function = module.add_function("accumulate", i64_type.fn_type(&[ptr_type.into()], false), None);
+
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let counter = function.get_first_param().unwrap().into_pointer_value();
+    let loaded = builder.build_load(i64_type, counter, "loaded").unwrap();
+    let call = builder.build_call(next, &[loaded.into()], "next").unwrap();
+    let next_value = call.try_as_basic_value().left().unwrap().into_int_value();
+
+    builder.build_store(counter, next_value).unwrap();
+
+    let result = builder
+        .build_int_add(next_value, i64_type.const_int(1, false), "result")
+        .unwrap();
+
+    builder.build_return(Some(&result)).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let mut foreign_functions = ForeignFunctionRegistry::new();
+
+    foreign_functions.register_fn("next", |(value,): (i64,)| value + 10);
+    host.set_foreign_functions(foreign_functions);
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+    execution_engine.instrument_module(&module);
+
+    let ptr = host.allocate(8, 8);
+    let args = || [unsafe { GenericValue::create_generic_value_of_miri_pointer(ptr) }];
+
+    // Threads spawned before snapshots are enabled can't be snapshotted
+    let thread = InterpreterThread::spawn(&execution_engine, 1, function, &args()).unwrap();
+
+    assert!(execution_engine.take_interpreter_snapshot(&[thread]).is_none());
+
+    execution_engine.enable_interpreter_snapshots();
+
+    assert!(host.write_bytes(ptr, &5u64.to_le_bytes()));
+
+    let mut thread = InterpreterThread::spawn(&execution_engine, 1, function, &args()).unwrap();
+    let step = |thread: &mut InterpreterThread<'_>| unsafe { thread.step() }.unwrap();
+    let run = |thread: &mut InterpreterThread<'_>| loop {
+        if let StepResult::Exited(value) = step(thread) {
+            return value.as_ref().as_int();
+        }
+    };
+
+    // Snapshot the thread once the call it's blocked on has been completed
+    assert!(matches!(step(&mut thread), StepResult::Running));
+    assert!(matches!(step(&mut thread), StepResult::Running));
+    assert!(matches!(step(&mut thread), StepResult::Blocked));
+
+    thread.set_pending_return(host.take_return_value()).unwrap();
+
+    let snapshot = execution_engine
+        .take_interpreter_snapshot(std::slice::from_ref(&thread))
+        .unwrap();
+
+    assert_eq!(snapshot.get_thread_ids(), [1]);
+    assert_eq!(run(&mut thread), 16);
+    assert_eq!(host.read_bytes(ptr, 8), Some(15u64.to_le_bytes().to_vec()));
+
+    // The thread has to be dropped before it can be restored
+    assert!(matches!(
+        unsafe { execution_engine.restore_interpreter_snapshot(&snapshot) },
+        Err(InterpreterError::ThreadIdInUse(1))
+    ));
+
+    drop(thread);
+
+    assert!(host.write_bytes(ptr, &100u64.to_le_bytes()));
+
+    let mut threads = unsafe { execution_engine.restore_interpreter_snapshot(&snapshot) }.unwrap();
+    let mut thread = threads.pop().unwrap();
+    let frames = thread.get_frames();
+
+    assert!(threads.is_empty());
+    assert_eq!(thread.get_id(), 1);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get_value(loaded).unwrap().as_ref().as_int(), 5);
+    assert_eq!(thread.get_current_instruction(), next_value.as_instruction());
+    assert_eq!(host.read_bytes(ptr, 8), Some(5u64.to_le_bytes().to_vec()));
+
+    // The thread picks up where it was, with the return value of the call still pending
+    assert!(matches!(step(&mut thread), StepResult::Running));
+    assert_eq!(
+        thread.get_frames()[0].get_value(next_value).unwrap().as_ref().as_int(),
+        15
+    );
+    assert_eq!(run(&mut thread), 16);
+    assert_eq!(host.read_bytes(ptr, 8), Some(15u64.to_le_bytes().to_vec()));
+}
+
+#[test]
+fn test_model_checker() {
+    use inkwell::miri::{Finding, ModelChecker};
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = i64_type.ptr_type(AddressSpace::default());
+    let function = module.add_function(
+        "write",
+        context.void_type().fn_type(&[ptr_type.into(), i64_type.into()], false),
+        None,
+    );
+
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let ptr = function.get_first_param().unwrap().into_pointer_value();
+    let value = function.get_nth_param(1).unwrap().into_int_value();
+    let store = builder.build_store(ptr, value).unwrap();
+
+    builder.build_return(None).unwrap();
+
+    // The same store, made atomically
+    let atomic_function = module.add_function("write_atomic", function.get_type(), None);
+
+    builder.position_at_end(context.append_basic_block(atomic_function, "entry"));
+
+    let ptr = atomic_function.get_first_param().unwrap().into_pointer_value();
+    let value = atomic_function.get_nth_param(1).unwrap().into_int_value();
+    let atomic_store = builder.build_store(ptr, value).unwrap();
+
+    atomic_store.set_alignment(8).unwrap();
+    atomic_store
+        .set_atomic_ordering(AtomicOrdering::SequentiallyConsistent)
+        .unwrap();
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+    execution_engine.instrument_module(&module);
+
+    let shared = host.allocate(8, 8);
+    let snapshot = host.snapshot();
+    let setup = |function| {
+        host.restore(&snapshot);
+
+        (1..=2)
+            .map(|id| {
+                let args = [
+                    unsafe { GenericValue::create_generic_value_of_miri_pointer(shared) },
+                    GenericValue::new_int(id, &i64_type, false),
+                ];
+
+                InterpreterThread::spawn(&execution_engine, id, function, &args)
+            })
+            .collect()
+    };
+
+    // Both orders of the two stores are explored, and they race
+    let exploration = unsafe { ModelChecker::new().explore(&execution_engine, || setup(function)) }.unwrap();
+
+    assert!(exploration.complete);
+    assert_eq!(exploration.executions, 2);
+    assert_eq!(exploration.findings.len(), 1);
+
+    match &exploration.findings[0] {
+        Finding::DataRace(race) => {
+            assert_eq!(race.first.thread_id, 1);
+            assert_eq!(race.first.access, MemoryAccess::Write);
+            assert_eq!(race.second.thread_id, 2);
+            assert_eq!(race.second.ptr.addr, shared.addr);
+            assert_eq!(race.schedule, [1, 1, 2]);
+
+            // Each access is traced back to the store which made it
+            for access in [&race.first, &race.second] {
+                assert_eq!(access.stack_trace.traces.len(), 1);
+                assert_eq!(access.stack_trace.traces[0].function, Some(function));
+                assert_eq!(access.stack_trace.traces[0].instruction, Some(store));
+            }
+        },
+        finding => panic!("unexpected finding {:?}", finding),
+    }
+
+    // Atomic stores don't race
+    let exploration = unsafe { ModelChecker::new().explore(&execution_engine, || setup(atomic_function)) }.unwrap();
+
+    assert!(exploration.complete);
+    assert!(exploration.findings.is_empty());
+}
+
+#[test]
+fn test_model_checker_orderings() {
+    use inkwell::miri::{Finding, ModelChecker};
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = i64_type.ptr_type(AddressSpace::default());
+    let fn_type = context.void_type().fn_type(&[ptr_type.into(), ptr_type.into()], false);
+
+    // One function writes the data and then sets the flag, the other only reads the data once the
+    // flag is set
+    let build = |name: &str, store_ordering, load_ordering| {
+        let publish = module.add_function(&format!("publish_{}", name), fn_type, None);
+
+        builder.position_at_end(context.append_basic_block(publish, "entry"));
+
+        let data = publish.get_nth_param(0).unwrap().into_pointer_value();
+        let flag = publish.get_nth_param(1).unwrap().into_pointer_value();
+
+        builder.build_store(data, i64_type.const_int(42, false)).unwrap();
+
+        let store = builder.build_store(flag, i64_type.const_int(1, false)).unwrap();
+
+        store.set_alignment(8).unwrap();
+        store.set_atomic_ordering(store_ordering).unwrap();
+        builder.build_return(None).unwrap();
+
+        let consume = module.add_function(&format!("consume_{}", name), fn_type, None);
+        let entry = context.append_basic_block(consume, "entry");
+        let read = context.append_basic_block(consume, "read");
+        let done = context.append_basic_block(consume, "done");
+
+        builder.position_at_end(entry);
+
+        let data = consume.get_nth_param(0).unwrap().into_pointer_value();
+        let flag = consume.get_nth_param(1).unwrap().into_pointer_value();
+        let ready = builder.build_load(i64_type, flag, "ready").unwrap();
+        let load = ready.as_instruction_value().unwrap();
+
+        load.set_alignment(8).unwrap();
+        load.set_atomic_ordering(load_ordering).unwrap();
+
+        let is_ready = builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                ready.into_int_value(),
+                i64_type.const_int(1, false),
+                "is_ready",
+            )
+            .unwrap();
+
+        builder.build_conditional_branch(is_ready, read, done).unwrap();
+        builder.position_at_end(read);
+        builder.build_load(i64_type, data, "data").unwrap();
+        builder.build_unconditional_branch(done).unwrap();
+        builder.position_at_end(done);
+        builder.build_return(None).unwrap();
+
+        [publish, consume]
+    };
+    let relaxed = build("relaxed", AtomicOrdering::Monotonic, AtomicOrdering::Monotonic);
+    let release_acquire = build("release_acquire", AtomicOrdering::Release, AtomicOrdering::Acquire);
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+
+    execution_engine.install_miri_hooks(Box::new(host.clone()));
+    execution_engine.instrument_module(&module);
+
+    let data = host.allocate(8, 8);
+    let flag = host.allocate(8, 8);
+    let explore = |functions: [_; 2]| {
+        let setup = || {
+            assert!(host.write_bytes(data, &[0; 8]));
+            assert!(host.write_bytes(flag, &[0; 8]));
+
+            functions
+                .iter()
+                .zip(1..)
+                .map(|(&function, id)| {
+                    let args = unsafe {
+                        [
+                            GenericValue::create_generic_value_of_miri_pointer(data),
+                            GenericValue::create_generic_value_of_miri_pointer(flag),
+                        ]
+                    };
+
+                    InterpreterThread::spawn(&execution_engine, id, function, &args)
+                })
+                .collect()
+        };
+
+        unsafe { ModelChecker::new().explore(&execution_engine, setup) }.unwrap()
+    };
+
+    // A relaxed flag doesn't order the accesses to the data around it
+    let exploration = explore(relaxed);
+
+    assert!(exploration.complete);
+    assert_eq!(exploration.findings.len(), 1);
+
+    match &exploration.findings[0] {
+        Finding::DataRace(race) => {
+            assert_eq!(race.first.thread_id, 1);
+            assert_eq!(race.first.access, MemoryAccess::Write);
+            assert_eq!(race.second.thread_id, 2);
+            assert_eq!(race.second.access, MemoryAccess::Read);
+            assert_eq!(race.second.ptr.addr, data.addr);
+        },
+        finding => panic!("unexpected finding {:?}", finding),
+    }
+
+    // Releasing the flag and acquiring it does
+    let exploration = explore(release_acquire);
+
+    assert!(exploration.complete);
+    assert!(exploration.findings.is_empty());
+}
+
+/// The locks the calls to `lock` and `unlock` made by threads a `ModelChecker` explores take and
+/// release.
+#[derive(Default)]
+struct Locks {
+    /// The lock of the last call, and whether it takes it.
+    last_call: Option<(u64, bool)>,
+    /// The calls each thread is blocked on.
+    pending: HashMap<u64, (u64, bool)>,
+    /// The thread holding each lock.
+    owners: HashMap<u64, u64>,
+}
+
+/// Completes calls to `lock` only once the lock is free, and hands everything else to a `SimpleMiriHost`.
+struct LockingHooks<'ctx> {
+    host: SimpleMiriHost<'ctx>,
+    locks: Rc<RefCell<Locks>>,
+}
+
+impl<'ctx> MiriHooks<'ctx> for LockingHooks<'ctx> {
+    fn malloc(&mut self, size: u64, align: u64, is_heap: bool) -> MiriPointer {
+        self.host.malloc(size, align, is_heap)
+    }
+
+    fn free(&mut self, ptr: MiriPointer) -> bool {
+        self.host.free(ptr)
+    }
+
+    fn load(&mut self, dest: GenericValueRef<'_>, src: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool {
+        self.host.load(dest, src, ty, align)
+    }
+
+    fn store(&mut self, value: GenericValueRef<'_>, dest: MiriPointer, ty: BasicTypeEnum<'ctx>, align: u64) -> bool {
+        self.host.store(value, dest, ty, align)
+    }
+
+    fn get_element_pointer(&mut self, base: MiriPointer, offset: u64) -> MiriPointer {
+        self.host.get_element_pointer(base, offset)
+    }
+
+    fn memset(&mut self, dest: MiriPointer, value: u8, len: u64) -> bool {
+        self.host.memset(dest, value, len)
+    }
+
+    fn memcpy(&mut self, dest: MiriPointer, src: &[u8]) -> bool {
+        self.host.memcpy(dest, src)
+    }
+
+    fn int_to_ptr(&mut self, addr: u64) -> MiriPointer {
+        self.host.int_to_ptr(addr)
+    }
+
+    fn ptr_to_int(&mut self, ptr: MiriPointer) -> u64 {
+        self.host.ptr_to_int(ptr)
+    }
+
+    fn call_by_name(&mut self, name: &str, args: &[GenericValueRef<'_>], fn_type: FunctionType<'ctx>) -> bool {
+        let taking = match name {
+            "lock" => true,
+            "unlock" => false,
+            _ => return self.host.call_by_name(name, args, fn_type),
+        };
+
+        self.locks.borrow_mut().last_call = Some((args[0].as_miri_pointer().addr, taking));
+
+        true
+    }
+
+    fn call_by_pointer(
+        &mut self,
+        callee: MiriPointer,
+        args: &[GenericValueRef<'_>],
+        fn_type: FunctionType<'ctx>,
+    ) -> bool {
+        self.host.call_by_pointer(callee, args, fn_type)
+    }
+
+    fn complete_call(&mut self, thread_id: u64) -> CallCompletion<'ctx> {
+        let mut locks = self.locks.borrow_mut();
+
+        // The first time a call is completed, it's the last one made. Other threads may still be
+        // waiting on theirs
+        if !locks.pending.contains_key(&thread_id) {
+            if let Some(call) = locks.last_call.take() {
+                locks.pending.insert(thread_id, call);
+            }
+        }
+
+        let (lock, taking) = match locks.pending.get(&thread_id) {
+            Some(&call) => call,
+            None => return CallCompletion::Returned(self.host.take_return_value()),
+        };
+
+        if !taking {
+            locks.owners.remove(&lock);
+        } else if *locks.owners.entry(lock).or_insert(thread_id) != thread_id {
+            return CallCompletion::Waiting;
+        }
+
+        locks.pending.remove(&thread_id);
+
+        CallCompletion::Returned(None)
+    }
+}
+
+#[test]
+fn test_model_checker_deadlock() {
+    use inkwell::miri::{Finding, ModelChecker};
+
+    let context = Context::create();
+    let module = context.create_module("miri");
+    let builder = context.create_builder();
+    let i64_type = context.i64_type();
+    #[allow(deprecated)]
+    let ptr_type = i64_type.ptr_type(AddressSpace::default());
+    let lock_type = context.void_type().fn_type(&[ptr_type.into()], false);
+    let lock = module.add_function("lock", lock_type, None);
+    let unlock = module.add_function("unlock", lock_type, None);
+    let function = module.add_function(
+        "lock_both",
+        context.void_type().fn_type(&[ptr_type.into(), ptr_type.into()], false),
+        None,
+    );
+
+    builder.position_at_end(context.append_basic_block(function, "entry"));
+
+    let first = function.get_nth_param(0).unwrap().into_pointer_value();
+    let second = function.get_nth_param(1).unwrap().into_pointer_value();
+
+    for ptr in [first, second] {
+        // Marking the lock as taken is what makes taking it conflict with other threads
+        let store = builder.build_store(ptr, i64_type.const_int(1, false)).unwrap();
+
+        store.set_alignment(8).unwrap();
+        store
+            .set_atomic_ordering(AtomicOrdering::SequentiallyConsistent)
+            .unwrap();
+        builder.build_call(lock, &[ptr.into()], "").unwrap();
+    }
+
+    builder.build_call(unlock, &[second.into()], "").unwrap();
+    builder.build_call(unlock, &[first.into()], "").unwrap();
+    builder.build_return(None).unwrap();
+
+    let execution_engine = module.create_interpreter_execution_engine().unwrap();
+    let host = SimpleMiriHost::new(execution_engine.get_target_data());
+    let locks = Rc::new(RefCell::new(Locks::default()));
+
+    execution_engine.install_miri_hooks(Box::new(LockingHooks {
+        host: host.clone(),
+        locks: locks.clone(),
+    }));
+    execution_engine.instrument_module(&module);
+
+    let a = host.allocate(8, 8);
+    let b = host.allocate(8, 8);
+
+    // The threads take the same two locks in opposite orders
+    let setup = || {
+        *locks.borrow_mut() = Locks::default();
+
+        [(a, b), (b, a)]
+            .iter()
+            .zip(1..)
+            .map(|(&(first, second), id)| {
+                let args = unsafe {
+                    [
+                        GenericValue::create_generic_value_of_miri_pointer(first),
+                        GenericValue::create_generic_value_of_miri_pointer(second),
+                    ]
+                };
+
+                InterpreterThread::spawn(&execution_engine, id, function, &args)
+            })
+            .collect()
+    };
+    let exploration = unsafe { ModelChecker::new().explore(&execution_engine, setup) }.unwrap();
+
+    assert!(exploration.complete);
+    assert_eq!(exploration.findings.len(), 1);
+
+    match &exploration.findings[0] {
+        Finding::Deadlock { threads, .. } => {
+            let ids: Vec<u64> = threads.iter().map(|(id, _)| *id).collect();
+
+            assert_eq!(ids, [1, 2]);
+
+            // Each thread is stuck taking its second lock
+            for (_, stack_trace) in threads {
+                let call = stack_trace.traces.last().unwrap().instruction.unwrap();
+
+                assert_eq!(call.get_opcode(), InstructionOpcode::Call);
+                assert_eq!(call.get_operand(0).unwrap().left(), Some(second.into()));
+            }
+        },
+        finding => panic!("unexpected finding {:?}", finding),
+    }
+}
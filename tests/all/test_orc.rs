@@ -0,0 +1,413 @@
+use inkwell::orc::{ThreadSafeContext, ThreadSafeModule, LLJIT};
+use inkwell::symbol_map::SymbolMap;
+use inkwell::targets::{InitializationConfig, Target};
+
+type Sum = unsafe extern "C" fn(u64, u64) -> u64;
+
+fn sum_module(context: &ThreadSafeContext, name: &str) -> ThreadSafeModule {
+    let module = context.context().create_module(name);
+    let builder = context.context().create_builder();
+    let i64_type = context.context().i64_type();
+    let fn_type = i64_type.fn_type(&[i64_type.into(), i64_type.into()], false);
+    let function = module.add_function("sum", fn_type, None);
+    let entry = context.context().append_basic_block(function, "entry");
+
+    builder.position_at_end(entry);
+
+    let x = function.get_nth_param(0).unwrap().into_int_value();
+    let y = function.get_nth_param(1).unwrap().into_int_value();
+    let sum = builder.build_int_add(x, y, "sum").unwrap();
+
+    builder.build_return(Some(&sum)).unwrap();
+
+    ThreadSafeModule::create(context, module).unwrap()
+}
+
+#[test]
+fn test_lljit() {
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = ThreadSafeContext::create();
+    let jit = LLJIT::create().unwrap();
+    let main_dylib = jit.get_main_jit_dylib();
+
+    assert_eq!(jit.get_jit_dylib("<main>"), Some(main_dylib));
+    assert!(jit.get_jit_dylib("other").is_none());
+
+    let other = jit.create_jit_dylib("other").unwrap();
+
+    assert_eq!(jit.get_jit_dylib("other"), Some(other));
+    assert!(jit.create_jit_dylib("other").is_err());
+
+    let tracker = main_dylib.create_resource_tracker();
+
+    jit.add_module_with_tracker(&tracker, sum_module(&context, "sum"))
+        .unwrap();
+
+    let sum = unsafe { jit.get_function::<Sum>("sum") }.unwrap();
+
+    assert_eq!(unsafe { sum.call(1, 2) }, 3);
+
+    tracker.remove().unwrap();
+
+    assert!(jit.get_function_address("sum").is_err());
+
+    jit.add_module(&main_dylib, sum_module(&context, "sum_again")).unwrap();
+
+    let sum = unsafe { jit.get_function::<Sum>("sum") }.unwrap();
+
+    // The function keeps the JIT alive
+    drop(jit);
+
+    assert_eq!(unsafe { sum.call(2, 3) }, 5);
+}
+
+#[test]
+fn test_lljit_symbol_map() {
+    extern "C" fn triple(x: u64) -> u64 {
+        x * 3
+    }
+
+    type Triple = unsafe extern "C" fn(u64) -> u64;
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = ThreadSafeContext::create();
+    let module = context.context().create_module("host");
+    let builder = context.context().create_builder();
+    let i64_type = context.context().i64_type();
+    let fn_type = i64_type.fn_type(&[i64_type.into()], false);
+    let host_triple = module.add_function("host_triple", fn_type, None);
+    let function = module.add_function("call_triple", fn_type, None);
+
+    builder.position_at_end(context.context().append_basic_block(function, "entry"));
+
+    let x = function.get_first_param().unwrap();
+    let tripled = builder
+        .build_call(host_triple, &[x.into()], "tripled")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&tripled)).unwrap();
+
+    let mut symbols = SymbolMap::new();
+
+    symbols.add_function::<Triple>("host_triple", triple);
+    symbols.check(&module).unwrap();
+
+    let jit = LLJIT::create().unwrap();
+    let main_dylib = jit.get_main_jit_dylib();
+
+    jit.add_symbol_map(&main_dylib, &symbols).unwrap();
+    jit.add_module(&main_dylib, ThreadSafeModule::create(&context, module).unwrap())
+        .unwrap();
+
+    let call_triple = unsafe { jit.get_function::<Triple>("call_triple") }.unwrap();
+
+    assert_eq!(unsafe { call_triple.call(14) }, 42);
+    assert!(jit.add_symbol_map(&main_dylib, &symbols).is_err());
+}
+
+#[test]
+fn test_thread_safe_module_context() {
+    let context = ThreadSafeContext::create();
+    let other_context = ThreadSafeContext::create();
+    let module = other_context.context().create_module("module");
+
+    assert!(ThreadSafeModule::create(&context, module).is_err());
+}
+
+#[llvm_versions(13..)]
+#[test]
+fn test_lljit_lazy_compilation() {
+    use inkwell::module::Linkage;
+    use inkwell::orc::LAZY_BODY_SUFFIX;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Double = unsafe extern "C" fn(u64) -> u64;
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = ThreadSafeContext::create();
+    let module = context.context().create_module("lazy");
+    let builder = context.context().create_builder();
+    let i64_type = context.context().i64_type();
+    let add = module.add_function(
+        "add",
+        i64_type.fn_type(&[i64_type.into(), i64_type.into()], false),
+        Some(Linkage::Internal),
+    );
+    let double = module.add_function("double", i64_type.fn_type(&[i64_type.into()], false), None);
+
+    builder.position_at_end(context.context().append_basic_block(add, "entry"));
+
+    let x = add.get_nth_param(0).unwrap().into_int_value();
+    let y = add.get_nth_param(1).unwrap().into_int_value();
+    let sum = builder.build_int_add(x, y, "sum").unwrap();
+
+    builder.build_return(Some(&sum)).unwrap();
+    builder.position_at_end(context.context().append_basic_block(double, "entry"));
+
+    let x = double.get_first_param().unwrap();
+    let doubled = builder
+        .build_call(add, &[x.into(), x.into()], "doubled")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&doubled)).unwrap();
+
+    let jit = LLJIT::create().unwrap();
+    let materialized = Rc::new(RefCell::new(Vec::new()));
+    let materialized_clone = materialized.clone();
+
+    jit.set_materialization_hook(move |function| {
+        let name = function.get_name().to_str().unwrap().to_owned();
+
+        materialized_clone.borrow_mut().push(name);
+    });
+    jit.add_module_lazily(&jit.get_main_jit_dylib(), &context, module)
+        .unwrap();
+
+    let double = unsafe { jit.get_function::<Double>("double") }.unwrap();
+
+    // Looking up a function only creates its stub
+    assert!(materialized.borrow().is_empty());
+    assert_eq!(unsafe { double.call(21) }, 42);
+
+    let mut materialized = materialized.borrow().clone();
+
+    materialized.sort();
+
+    assert_eq!(
+        materialized,
+        [
+            format!("add.lazy0{}", LAZY_BODY_SUFFIX),
+            format!("double{}", LAZY_BODY_SUFFIX)
+        ]
+    );
+}
+
+#[llvm_versions(13..)]
+#[test]
+fn test_lljit_lazy_compilation_private_symbols() {
+    use inkwell::module::Linkage;
+    use inkwell::values::PointerValue;
+
+    type Compute = unsafe extern "C" fn(u64) -> u64;
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = ThreadSafeContext::create();
+    let module = context.context().create_module("private");
+    let builder = context.context().create_builder();
+    let i64_type = context.context().i64_type();
+    let fn_type = i64_type.fn_type(&[i64_type.into()], false);
+    let factor = module.add_global(i64_type, None, "factor");
+    let offset = module.add_global(i64_type, None, "");
+    let scale = module.add_function("scale", fn_type, Some(Linkage::Private));
+    let shift = module.add_function("shift", fn_type, Some(Linkage::Internal));
+    let compute = module.add_function("compute", fn_type, None);
+    let shift_pointer = shift.as_global_value().as_pointer_value();
+    let callback = module.add_global(shift_pointer.get_type(), None, "callback");
+
+    factor.set_linkage(Linkage::Private);
+    factor.set_initializer(&i64_type.const_int(3, false));
+    offset.set_linkage(Linkage::Internal);
+    offset.set_initializer(&i64_type.const_int(4, false));
+    callback.set_constant(true);
+    callback.set_initializer(&shift_pointer);
+
+    let load = |pointer: PointerValue, name: &str| {
+        #[cfg(any(feature = "llvm13-0", feature = "llvm14-0"))]
+        let value = builder.build_load(pointer, name);
+        #[cfg(any(
+            feature = "llvm15-0",
+            feature = "llvm16-0",
+            feature = "llvm17-0",
+            feature = "llvm18-0"
+        ))]
+        let value = builder.build_load(i64_type, pointer, name);
+
+        value.unwrap().into_int_value()
+    };
+
+    // scale(x) = x * factor
+    builder.position_at_end(context.context().append_basic_block(scale, "entry"));
+
+    let x = scale.get_first_param().unwrap().into_int_value();
+    let scaled = builder
+        .build_int_mul(x, load(factor.as_pointer_value(), "factor"), "scaled")
+        .unwrap();
+
+    builder.build_return(Some(&scaled)).unwrap();
+
+    // shift(x) = scale(x) + offset
+    builder.position_at_end(context.context().append_basic_block(shift, "entry"));
+
+    let x = shift.get_first_param().unwrap();
+    let scaled = builder
+        .build_call(scale, &[x.into()], "scaled")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap()
+        .into_int_value();
+    let shifted = builder
+        .build_int_add(scaled, load(offset.as_pointer_value(), "offset"), "shifted")
+        .unwrap();
+
+    builder.build_return(Some(&shifted)).unwrap();
+
+    // compute(x) = shift(x)
+    builder.position_at_end(context.context().append_basic_block(compute, "entry"));
+
+    let x = compute.get_first_param().unwrap();
+    let computed = builder
+        .build_call(shift, &[x.into()], "computed")
+        .unwrap()
+        .try_as_basic_value()
+        .left()
+        .unwrap();
+
+    builder.build_return(Some(&computed)).unwrap();
+
+    let jit = LLJIT::create().unwrap();
+
+    jit.add_module_lazily(&jit.get_main_jit_dylib(), &context, module)
+        .unwrap();
+
+    let compute = unsafe { jit.get_function::<Compute>("compute") }.unwrap();
+
+    // Each body reaches the private symbols of the others through their new names
+    assert_eq!(unsafe { compute.call(5) }, 19);
+
+    // The global variables reach the bodies through their stubs
+    let callback = jit.get_function_address("callback").unwrap();
+    let shift: Compute = unsafe { std::mem::transmute(*(callback as *const usize)) };
+
+    assert_eq!(unsafe { shift(5) }, 19);
+
+    // Private and internal symbols stay hidden
+    assert!(unsafe { jit.get_function::<Compute>("scale") }.is_err());
+    assert!(unsafe { jit.get_function::<Compute>("shift") }.is_err());
+}
+
+#[llvm_versions(13..)]
+#[test]
+fn test_lljit_lazy_compilation_failure() {
+    use inkwell::orc::LAZY_BODY_SUFFIX;
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let context = ThreadSafeContext::create();
+    let module = context.context().create_module("clashing");
+    let builder = context.context().create_builder();
+    let i64_type = context.context().i64_type();
+    let fn_type = i64_type.fn_type(&[i64_type.into(), i64_type.into()], false);
+
+    for name in ["product", "sum"] {
+        let function = module.add_function(name, fn_type, None);
+
+        builder.position_at_end(context.context().append_basic_block(function, "entry"));
+
+        let x = function.get_nth_param(0).unwrap().into_int_value();
+        let y = function.get_nth_param(1).unwrap().into_int_value();
+        let result = builder.build_int_mul(x, y, name).unwrap();
+
+        builder.build_return(Some(&result)).unwrap();
+    }
+
+    let jit = LLJIT::create().unwrap();
+    let dylib = jit.get_main_jit_dylib();
+
+    jit.add_module(&dylib, sum_module(&context, "sum")).unwrap();
+
+    // The stub for sum clashes with the sum which is already defined
+    assert!(jit.add_module_lazily(&dylib, &context, module).is_err());
+
+    // Which leaves nothing of the module behind
+    assert!(jit.get_function_address("product").is_err());
+    assert!(jit
+        .get_function_address(&format!("product{}", LAZY_BODY_SUFFIX))
+        .is_err());
+
+    let sum = unsafe { jit.get_function::<Sum>("sum") }.unwrap();
+
+    assert_eq!(unsafe { sum.call(2, 3) }, 5);
+}
+
+#[llvm_versions(13..)]
+#[test]
+fn test_lljit_event_listeners() {
+    use inkwell::jit_event_listener::{JITEventListener, JitEvent, PerfMap};
+
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    Target::initialize_native(&InitializationConfig::default()).expect("Failed to initialize native target");
+
+    let mut listeners = vec![JITEventListener::create_gdb_registration_listener()];
+
+    listeners.extend(JITEventListener::create_perf_listener());
+
+    let context = ThreadSafeContext::create();
+    let jit = LLJIT::create_with_event_listeners(&listeners).unwrap();
+    let objects = Rc::new(RefCell::new(0));
+    let symbols = Rc::new(RefCell::new(Vec::new()));
+    let objects_clone = objects.clone();
+    let symbols_clone = symbols.clone();
+    let perf_map_path = std::env::temp_dir().join(format!("inkwell-perf-{}.map", std::process::id()));
+    let mut perf_map = PerfMap::create_at(&perf_map_path).unwrap();
+
+    assert_eq!(perf_map.get_path(), perf_map_path);
+
+    jit.set_event_callback(move |event| {
+        match *event {
+            JitEvent::ObjectEmitted { object } => {
+                assert!(!object.is_empty());
+
+                *objects_clone.borrow_mut() += 1;
+            },
+            JitEvent::SymbolLoaded { name, address, size } => {
+                symbols_clone.borrow_mut().push((name.to_owned(), address, size));
+            },
+        }
+
+        perf_map.record(event).unwrap();
+    });
+    jit.add_module(&jit.get_main_jit_dylib(), sum_module(&context, "sum"))
+        .unwrap();
+
+    // Nothing is compiled until it's looked up
+    assert_eq!(*objects.borrow(), 0);
+
+    let sum = unsafe { jit.get_function::<Sum>("sum") }.unwrap();
+
+    // The symbols are reported as the object is loaded, before the lookup returns
+    assert_eq!(symbols.borrow().len(), 1);
+
+    let address = jit.get_function_address("sum").unwrap();
+
+    assert_eq!(unsafe { sum.call(1, 2) }, 3);
+    assert_eq!(*objects.borrow(), 1);
+
+    let symbols = symbols.borrow();
+
+    assert_eq!(symbols.len(), 1);
+    assert_eq!(symbols[0].0, "sum");
+    assert_eq!(symbols[0].1, address);
+    assert!(symbols[0].2 > 0);
+
+    let perf_map = fs::read_to_string(&perf_map_path).unwrap();
+
+    fs::remove_file(&perf_map_path).unwrap();
+
+    assert_eq!(perf_map, format!("{:x} {:x} sum\n", address, symbols[0].2));
+}
@@ -4,7 +4,9 @@ use inkwell::comdat::ComdatSelectionKind;
 use inkwell::context::Context;
 use inkwell::module::Linkage::*;
 use inkwell::types::{AnyTypeEnum, StringRadix, VectorType};
-use inkwell::values::{AnyValue, InstructionOpcode::*, FIRST_CUSTOM_METADATA_KIND_ID};
+use inkwell::values::{
+    AnyValue, FromGenericValue, GenericValueError, InstructionOpcode::*, ToGenericValue, FIRST_CUSTOM_METADATA_KIND_ID,
+};
 use inkwell::{AddressSpace, DLLStorageClass, GlobalVisibility, ThreadLocalMode};
 
 #[llvm_versions(18..)]
@@ -1596,3 +1598,51 @@ fn test_constant_expression() {
     assert!(expr.is_const());
     assert!(!expr.is_constant_int());
 }
+
+#[derive(Debug, PartialEq, ToGenericValue, FromGenericValue)]
+struct Pair {
+    tag: u8,
+    values: [i32; 2],
+}
+
+#[test]
+fn test_generic_value_conversion() {
+    let context = Context::create();
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let f64_type = context.f64_type();
+    let array_type = i32_type.array_type(2);
+    let pair_type = context.struct_type(&[i8_type.into(), array_type.into()], false);
+
+    let value = (-7i32).to_generic_value(i32_type.into()).unwrap();
+
+    assert_eq!(i32::from_generic_value(value.as_ref(), i32_type.into()), Ok(-7));
+    assert_eq!(
+        f64::from_generic_value(value.as_ref(), f64_type.into()).unwrap_err(),
+        GenericValueError::TypeMismatch {
+            rust_type: "f64",
+            llvm_type: "double".into(),
+        }
+    );
+
+    let pair = Pair {
+        tag: 3,
+        values: [1, -2],
+    };
+    let value = pair.to_generic_value(pair_type.into()).unwrap();
+
+    assert_eq!(value.as_ref().get_type_tag(), Some(pair_type.into()));
+    assert_eq!(Pair::from_generic_value(value.as_ref(), pair_type.into()), Ok(pair));
+    assert_eq!(
+        <(u8, Vec<i32>)>::from_generic_value(value.as_ref(), pair_type.into()),
+        Ok((3, vec![1, -2]))
+    );
+    assert!(matches!(
+        [1i32, 2, 3].to_generic_value(array_type.into()),
+        Err(GenericValueError::LengthMismatch {
+            expected: 2,
+            found: 3,
+            ..
+        })
+    ));
+}